@@ -0,0 +1,262 @@
+use clap::Parser;
+use futures::{SinkExt, StreamExt};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{net::TcpStream, sync::Barrier};
+use tokio_util::codec::{Framed, LinesCodec};
+
+#[derive(Parser, Clone)]
+#[command(author, version, long_about = None)]
+struct Cli {
+    address: String,
+    /// Number of concurrent client connections
+    #[arg(long, default_value_t = 10)]
+    clients: usize,
+    /// Number of rooms the clients are spread across
+    #[arg(long, default_value_t = 1)]
+    rooms: usize,
+    /// Messages each client sends
+    #[arg(long, default_value_t = 100)]
+    messages: usize,
+    /// Messages per second each client sends
+    #[arg(long, default_value_t = 10)]
+    rate: u64,
+    /// Seconds to wait for every message to arrive before giving up
+    #[arg(long, default_value_t = 30)]
+    timeout: u64,
+}
+
+/// What one client saw during a run.
+#[derive(Debug, Default)]
+struct ClientReport {
+    sent: usize,
+    latencies: Vec<Duration>,
+}
+
+/// The combined results of a run.
+#[derive(Debug)]
+struct Report {
+    sent: usize,
+    expected: usize,
+    elapsed: Duration,
+    /// Sorted from fastest to slowest.
+    latencies: Vec<Duration>,
+}
+
+impl Report {
+    fn received(&self) -> usize {
+        self.latencies.len()
+    }
+
+    /// The latency that `percent` of the received messages were at or under.
+    fn percentile(&self, percent: usize) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::ZERO;
+        }
+        let index = (self.latencies.len() * percent / 100).min(self.latencies.len() - 1);
+        self.latencies[index]
+    }
+}
+
+impl std::fmt::Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let seconds = self.elapsed.as_secs_f64();
+        writeln!(f, "sent:       {} messages", self.sent)?;
+        writeln!(
+            f,
+            "received:   {} of {} deliveries",
+            self.received(),
+            self.expected
+        )?;
+        writeln!(f, "elapsed:    {:.2}s", seconds)?;
+        writeln!(
+            f,
+            "throughput: {:.0} deliveries/s",
+            self.received() as f64 / seconds
+        )?;
+        write!(
+            f,
+            "latency:    p50 {:?}, p90 {:?}, p99 {:?}",
+            self.percentile(50),
+            self.percentile(90),
+            self.percentile(99)
+        )
+    }
+}
+
+/// Connect one client, register it, join its room, wait for every other client to do the same, then send
+/// `messages` messages, one every `period`, while timing every message that arrives from the other members of the
+/// room. Each message carries the microseconds since `start` that it was sent at.
+async fn run_client(
+    cli: Cli,
+    index: usize,
+    expected: usize,
+    start: Instant,
+    period: Duration,
+    ready: Arc<Barrier>,
+) -> anyhow::Result<ClientReport> {
+    let tcp_stream = TcpStream::connect(&cli.address).await?;
+    let (mut sink, mut stream) = Framed::new(tcp_stream, LinesCodec::new()).split();
+    let room = format!("#bench-{}", index % cli.rooms);
+
+    sink.send(format!("NAME @bench-{}", index)).await?;
+    sink.send(format!("JOIN {}", room)).await?;
+    // commands are handled in order, so a ROOM line means the JOIN is done
+    sink.send("ROOMS".to_string()).await?;
+    while let Some(line) = stream.next().await {
+        if line?.starts_with("ROOM ") {
+            break;
+        }
+    }
+    ready.wait().await;
+
+    // count deliveries from the other clients
+    let deadline = start + Duration::from_secs(cli.timeout);
+    let reader = tokio::spawn(async move {
+        let mut latencies = vec![];
+        while latencies.len() < expected {
+            let line = match tokio::time::timeout_at(deadline.into(), stream.next()).await {
+                Ok(Some(Ok(line))) => line,
+                _ => break,
+            };
+            // "<room> <user> SAID <seq> <micros>"
+            if let Some((_, sent_at)) = line
+                .split(" SAID ")
+                .nth(1)
+                .and_then(|said| said.split_once(' '))
+            {
+                if let Ok(micros) = sent_at.parse::<u64>() {
+                    let sent_at = Duration::from_micros(micros);
+                    latencies.push(start.elapsed().saturating_sub(sent_at));
+                }
+            }
+        }
+        latencies
+    });
+
+    let mut report = ClientReport::default();
+    let mut interval = tokio::time::interval(period);
+    for _ in 0..cli.messages {
+        interval.tick().await;
+        sink.send(format!("SAY {} {}", room, start.elapsed().as_micros()))
+            .await?;
+        report.sent += 1;
+    }
+    report.latencies = reader.await?;
+    sink.send("QUIT".to_string()).await?;
+    Ok(report)
+}
+
+/// Run every client at once and combine what they saw.
+async fn run(cli: Cli) -> anyhow::Result<Report> {
+    let rooms = cli.rooms.max(1);
+    let cli = Cli { rooms, ..cli };
+    let rate = u32::try_from(cli.rate.max(1))
+        .map_err(|_| anyhow::anyhow!("rate {} is more than {} a second", cli.rate, u32::MAX))?;
+    let period = Duration::from_secs(1) / rate;
+    let start = Instant::now();
+    let ready = Arc::new(Barrier::new(cli.clients));
+
+    let mut clients = vec![];
+    let mut expected = 0;
+    for index in 0..cli.clients {
+        // every other member of the room hears each message
+        let members = (0..cli.clients)
+            .filter(|i| i % rooms == index % rooms)
+            .count();
+        let client_expected = cli.messages * (members - 1);
+        expected += client_expected;
+        clients.push(tokio::spawn(run_client(
+            cli.clone(),
+            index,
+            client_expected,
+            start,
+            period,
+            ready.clone(),
+        )));
+    }
+
+    let mut sent = 0;
+    let mut latencies = vec![];
+    for client in clients {
+        let report = client.await??;
+        sent += report.sent;
+        latencies.extend(report.latencies);
+    }
+    latencies.sort();
+    Ok(Report {
+        sent,
+        expected,
+        elapsed: start.elapsed(),
+        latencies,
+    })
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    // parse command line arguments
+    let cli = Cli::parse();
+
+    println!(
+        "{} clients across {} rooms sending {} messages each at {}/s",
+        cli.clients, cli.rooms, cli.messages, cli.rate
+    );
+    let report = run(cli).await?;
+    println!("{}", report);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chat_project::{
+        server::{run_server, ConnectionConfig},
+        server_state::ServerState,
+    };
+    use tokio::{net::TcpListener, sync::Mutex};
+
+    #[tokio::test]
+    async fn test_run() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server_state = Arc::new(Mutex::new(ServerState::new()));
+        tokio::spawn(run_server(
+            server_state,
+            listener,
+            ConnectionConfig::default(),
+        ));
+
+        let report = run(Cli {
+            address,
+            clients: 4,
+            rooms: 2,
+            messages: 5,
+            rate: 1000,
+            timeout: 10,
+        })
+        .await
+        .unwrap();
+        assert_eq!(report.sent, 20);
+        // two clients per room, so each message reaches one other client
+        assert_eq!(report.expected, 20);
+        assert_eq!(report.received(), 20);
+        assert!(report.percentile(50) <= report.percentile(99));
+    }
+
+    #[tokio::test]
+    async fn test_run_rate_too_high() {
+        let result = run(Cli {
+            address: "127.0.0.1:1".to_string(),
+            clients: 1,
+            rooms: 1,
+            messages: 1,
+            rate: u64::from(u32::MAX) + 1,
+            timeout: 1,
+        })
+        .await;
+        // refused before connecting to anything
+        assert!(result.unwrap_err().to_string().starts_with("rate "));
+    }
+}
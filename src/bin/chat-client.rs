@@ -1,38 +1,410 @@
 use anyhow::anyhow;
+use chat_project::{command::Command, messages::OutgoingMsg};
 use clap::Parser;
 use futures::SinkExt;
-use tokio::{net::TcpStream, sync::mpsc::unbounded_channel};
+use rustyline::{
+    completion::Completer, error::ReadlineError, highlight::Highlighter, hint::Hinter,
+    history::DefaultHistory, validate::Validator, Editor, Helper,
+};
+use std::{
+    collections::BTreeSet,
+    fs::{File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+    sync::mpsc::{unbounded_channel, UnboundedReceiver},
+};
 use tokio_stream::StreamExt;
-use tokio_util::codec::{Framed, LinesCodec};
+use tokio_util::codec::{Framed, LinesCodec, LinesCodecError};
 
 #[derive(Parser)]
 #[command(author, version, long_about = None)]
 struct Cli {
     address: String,
+    /// File that every line sent to and received from the server is appended to
+    #[arg(long)]
+    log_file: Option<PathBuf>,
+    /// Command to send instead of reading from the terminal. May be given more than once; they are sent in
+    /// order, every line from the server is printed, and the client exits after the last one
+    #[arg(long)]
+    exec: Vec<String>,
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    // parse command line arguments
-    let cli = Cli::parse();
+/// Friendly explanations for the errors the server sends, keyed by the words after ERROR. Whatever follows
+/// them, such as a room or user name, is shown alongside.
+const ERROR_TEXTS: &[(&str, &str)] = &[
+    (
+        "bad arguments",
+        "that command has the wrong number of arguments",
+    ),
+    (
+        "bad name format",
+        "user names start with @ and use letters, numbers, - and _",
+    ),
+    (
+        "bad room name format",
+        "room names start with # and use letters, numbers, - and _",
+    ),
+    ("max length reached", "that message is too long"),
+    ("user not in room", "that user isn't in the room"),
+    ("not room owner", "only the room owner can do that"),
+    ("room unknown", "that room doesn't exist"),
+    ("user already exists", "that name is already taken"),
+    ("user unknown", "that user doesn't exist"),
+];
 
-    // connect to server
-    let tcp_stream = TcpStream::connect(cli.address).await?;
+/// Pause between the lines sent by /load so a long file doesn't trip the server's rate limits.
+const LOAD_DELAY: Duration = Duration::from_millis(250);
 
-    // server frame
-    let mut server_frame = Framed::new(tcp_stream, LinesCodec::new_with_max_length(1024));
+/// How long --exec waits without hearing from the server before it takes a command's replies to be done.
+const EXEC_SETTLE: Duration = Duration::from_millis(500);
 
-    // io bridge
-    let (iosend, mut iorecv) = unbounded_channel();
-    std::thread::spawn(move || {
-        for line in std::io::stdin().lines() {
-            iosend.send(line).unwrap();
+/// The room or user a SAY was sent to. The last one is where /load sends its lines.
+fn say_target(input: &str) -> Option<&str> {
+    let mut pieces = input.splitn(3, ' ');
+    match (pieces.next(), pieces.next(), pieces.next()) {
+        (Some("SAY"), Some(target), Some(_)) => Some(target),
+        _ => None,
+    }
+}
+
+/// Every line sent to and received from the server, appended to a file for debugging. Each line is written
+/// as `<seconds since the Unix epoch> <direction> <line>`, where the direction is `>` for sent and `<` for
+/// received, and flushed right away so a crash doesn't lose the end of the log.
+struct TrafficLog {
+    file: File,
+}
+
+impl TrafficLog {
+    fn open(path: &Path) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { file })
+    }
+
+    fn record(&mut self, direction: char, line: &str) -> std::io::Result<()> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        writeln!(
+            self.file,
+            "{}.{:03} {} {}",
+            now.as_secs(),
+            now.subsec_millis(),
+            direction,
+            line
+        )?;
+        self.file.flush()
+    }
+}
+
+/// Write a line to the traffic log, if there is one. The log is only for debugging, so a failed write is
+/// reported and the line still goes through.
+fn log_line(traffic_log: &mut Option<TrafficLog>, direction: char, line: &str) {
+    if let Some(traffic_log) = traffic_log {
+        if let Err(e) = traffic_log.record(direction, line) {
+            println!("Log error: {}", e);
+        }
+    }
+}
+
+/// Send a line to the server, logging it first if there is a traffic log.
+async fn send_line<S: AsyncRead + AsyncWrite + Unpin>(
+    server_frame: &mut Framed<S, LinesCodec>,
+    traffic_log: &mut Option<TrafficLog>,
+    line: String,
+) -> anyhow::Result<()> {
+    log_line(traffic_log, '>', &line);
+    server_frame.send(line).await?;
+    Ok(())
+}
+
+/// Receive a line from the server, logging it if there is a traffic log.
+async fn recv_line<S: AsyncRead + AsyncWrite + Unpin>(
+    server_frame: &mut Framed<S, LinesCodec>,
+    traffic_log: &mut Option<TrafficLog>,
+) -> Option<Result<String, LinesCodecError>> {
+    let result = server_frame.next().await;
+    if let Some(Ok(line)) = &result {
+        log_line(traffic_log, '<', line);
+    }
+    result
+}
+
+/// Send each non-empty line of the file at `path` to `target` as a SAY, pausing `delay` between lines.
+async fn load<S: AsyncRead + AsyncWrite + Unpin>(
+    server_frame: &mut Framed<S, LinesCodec>,
+    traffic_log: &mut Option<TrafficLog>,
+    path: &str,
+    target: &str,
+    delay: Duration,
+) -> anyhow::Result<()> {
+    let contents = std::fs::read_to_string(path)?;
+    for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+        send_line(
+            server_frame,
+            traffic_log,
+            format!("SAY {} {}", target, line),
+        )
+        .await?;
+        tokio::time::sleep(delay).await;
+    }
+    Ok(())
+}
+
+/// Pass lines from the server to `output` until it has been quiet for `settle`. Returns `false` if the
+/// server disconnected instead.
+async fn read_until_quiet<S: AsyncRead + AsyncWrite + Unpin>(
+    server_frame: &mut Framed<S, LinesCodec>,
+    traffic_log: &mut Option<TrafficLog>,
+    settle: Duration,
+    output: &mut impl FnMut(&str),
+) -> anyhow::Result<bool> {
+    loop {
+        match tokio::time::timeout(settle, recv_line(server_frame, traffic_log)).await {
+            Err(_) => return Ok(true),
+            Ok(None) => return Ok(false),
+            Ok(Some(Err(e))) => return Err(anyhow!(e)),
+            Ok(Some(Ok(line))) => output(&line),
+        }
+    }
+}
+
+/// Send `commands` to the server one at a time for --exec, passing every line from the server to `output`.
+/// Each command's replies are read until the server goes quiet before the next command is sent, so the
+/// output lines up with the script. Stops early if the server disconnects, such as after a QUIT.
+async fn exec<S: AsyncRead + AsyncWrite + Unpin>(
+    server_frame: &mut Framed<S, LinesCodec>,
+    traffic_log: &mut Option<TrafficLog>,
+    commands: &[String],
+    settle: Duration,
+    mut output: impl FnMut(&str),
+) -> anyhow::Result<()> {
+    // CONNECTED comes first
+    if !read_until_quiet(server_frame, traffic_log, settle, &mut output).await? {
+        return Ok(());
+    }
+    for command in commands {
+        send_line(server_frame, traffic_log, command.clone()).await?;
+        if !read_until_quiet(server_frame, traffic_log, settle, &mut output).await? {
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+/// Room and user names seen in lines from the server, for tab completion. Running ROOMS or USERS fills it
+/// in, and so does every message that names a room or user.
+#[derive(Debug, Default)]
+struct Names {
+    rooms: BTreeSet<String>,
+    users: BTreeSet<String>,
+}
+
+impl Names {
+    /// Remember the room and user names at the start of a line from the server, where every message puts
+    /// them. Anything later is a payload and may only look like a name.
+    fn observe(&mut self, line: &str) {
+        let mut words = line.split(' ');
+        let (first, second) = (words.next().unwrap_or_default(), words.next());
+        if first == "DELETED" {
+            if let Some(room) = second {
+                self.rooms.remove(room);
+            }
+            return;
+        }
+        for word in std::iter::once(first).chain(second) {
+            if word.len() > 1 && word.starts_with('#') {
+                self.rooms.insert(word.to_string());
+            } else if word.len() > 1 && word.starts_with('@') {
+                self.users.insert(word.to_string());
+            }
+        }
+    }
+
+    /// Complete the room or user name that ends at `pos`. Returns where the name starts and every known
+    /// name it could be.
+    fn complete(&self, line: &str, pos: usize) -> (usize, Vec<String>) {
+        let start = line[..pos].rfind(' ').map_or(0, |space| space + 1);
+        let word = &line[start..pos];
+        let names = match word.chars().next() {
+            Some('#') => &self.rooms,
+            Some('@') => &self.users,
+            _ => return (start, vec![]),
+        };
+        let matches = names
+            .iter()
+            .filter(|name| name.starts_with(word))
+            .cloned()
+            .collect();
+        (start, matches)
+    }
+}
+
+/// Tab completes room names after `#` and user names after `@`.
+struct NameCompleter {
+    names: Arc<Mutex<Names>>,
+}
+
+impl Completer for NameCompleter {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &rustyline::Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        Ok(self.names.lock().unwrap().complete(line, pos))
+    }
+}
+
+impl Hinter for NameCompleter {
+    type Hint = String;
+}
+
+impl Highlighter for NameCompleter {}
+
+impl Validator for NameCompleter {}
+
+impl Helper for NameCompleter {}
+
+/// Turn a line typed by the user into the line sent to the server. Lines that start with a command verb or
+/// a `/` are sent as-is. Anything else is said to the current room, or `None` if there isn't one.
+fn to_server(input: &str, room: Option<&str>) -> Option<String> {
+    let verb = input.split(' ').next().unwrap_or_default();
+    if input.is_empty() || verb.starts_with('/') || Command::from_verb(verb).is_some() {
+        return Some(input.to_string());
+    }
+    room.map(|room| format!("SAY {} {}", room, input))
+}
+
+/// What /lasterror shows: the most recent ERROR from the server with its explanation, if there was one.
+fn last_error(error: Option<&str>) -> Option<String> {
+    error.map(render)
+}
+
+/// A line from the server, as far as the client understands it.
+#[derive(Debug, PartialEq)]
+enum ServerLine {
+    Message(OutgoingMsg),
+    /// An ERROR reply, which isn't an [OutgoingMsg]. Holds everything after `ERROR `.
+    Error(String),
+    /// Anything else, such as a message from a newer server. Shown as-is.
+    Raw(String),
+}
+
+impl ServerLine {
+    fn parse(line: &str) -> Self {
+        if let Some(error) = line.strip_prefix("ERROR ") {
+            return Self::Error(error.to_string());
+        }
+        match OutgoingMsg::parse(line) {
+            Some(message) => Self::Message(message),
+            None => Self::Raw(line.to_string()),
+        }
+    }
+}
+
+/// Turn a line from the server into the text shown to the user. Known errors are explained in red and
+/// private messages are shown in cyan. Everything else is shown as-is.
+fn render(line: &str) -> String {
+    match ServerLine::parse(line) {
+        ServerLine::Error(error) => {
+            let known = ERROR_TEXTS.iter().find_map(|(text, explanation)| {
+                let detail = error.strip_prefix(text)?;
+                match detail.strip_prefix(' ') {
+                    Some(detail) => Some((explanation, Some(detail))),
+                    None if detail.is_empty() => Some((explanation, None)),
+                    None => None,
+                }
+            });
+            match known {
+                Some((explanation, Some(detail))) => {
+                    format!("\x1b[31merror: {} ({})\x1b[0m", explanation, detail)
+                }
+                Some((explanation, None)) => format!("\x1b[31merror: {}\x1b[0m", explanation),
+                None => line.to_string(),
+            }
+        }
+        ServerLine::Message(OutgoingMsg::SaidUser(..)) => format!("\x1b[36m{}\x1b[0m", line),
+        ServerLine::Message(_) | ServerLine::Raw(_) => line.to_string(),
+    }
+}
+
+/// Handle a line from the server. PINGs are answered with a PONG and not shown, and ERRORs are remembered for
+/// /lasterror. Returns the text to show the user, if any.
+async fn on_server_line<S: AsyncRead + AsyncWrite + Unpin>(
+    server_frame: &mut Framed<S, LinesCodec>,
+    traffic_log: &mut Option<TrafficLog>,
+    latest_error: &mut Option<String>,
+    line: String,
+) -> anyhow::Result<Option<String>> {
+    match ServerLine::parse(&line) {
+        ServerLine::Message(OutgoingMsg::Ping) => {
+            send_line(server_frame, traffic_log, "PONG".to_string()).await?;
+            Ok(None)
+        }
+        ServerLine::Error(_) => {
+            let shown = render(&line);
+            *latest_error = Some(line);
+            Ok(Some(shown))
+        }
+        ServerLine::Message(_) | ServerLine::Raw(_) => Ok(Some(render(&line))),
+    }
+}
+
+/// A line typed at the terminal, or why reading them stopped.
+type IoLine = Result<String, ReadlineError>;
+
+/// Start the thread that reads lines with `read_line` and passes them to the main loop. It stops at the end of
+/// input, after passing on an error, or as soon as a line has nowhere to go because the main loop is done.
+fn spawn_io_bridge<F>(mut read_line: F) -> (UnboundedReceiver<IoLine>, std::thread::JoinHandle<()>)
+where
+    F: FnMut() -> IoLine + Send + 'static,
+{
+    let (iosend, iorecv) = unbounded_channel();
+    let bridge = std::thread::spawn(move || loop {
+        match read_line() {
+            Ok(line) => {
+                if iosend.send(Ok(line)).is_err() {
+                    return;
+                }
+            }
+            // ctrl-d and ctrl-c end the client
+            Err(ReadlineError::Eof | ReadlineError::Interrupted) => return,
+            Err(e) => {
+                let _ = iosend.send(Err(e));
+                return;
+            }
         }
     });
+    (iorecv, bridge)
+}
+
+/// Pass lines between the server and the terminal until either side is done.
+async fn chat<S: AsyncRead + AsyncWrite + Unpin>(
+    server_frame: &mut Framed<S, LinesCodec>,
+    traffic_log: &mut Option<TrafficLog>,
+    mut iorecv: UnboundedReceiver<IoLine>,
+    names: Arc<Mutex<Names>>,
+) -> anyhow::Result<()> {
+    // where /load sends its lines
+    let mut context: Option<String> = None;
+
+    // the most recent ERROR line, for /lasterror
+    let mut latest_error: Option<String> = None;
+
+    // where lines that aren't commands are said, set by /room
+    let mut room: Option<String> = None;
 
     loop {
         tokio::select! {
-            server_result = server_frame.next() => match server_result {
+            server_result = recv_line(server_frame, traffic_log) => match server_result {
                 None => {
                     println!("Server disconnected.");
                     return Ok(());
@@ -42,7 +414,10 @@ async fn main() -> anyhow::Result<()> {
                     return Err(anyhow!(e));
                 },
                 Some(Ok(message)) => {
-                    println!("{}", message);
+                    names.lock().unwrap().observe(&message);
+                    if let Some(shown) = on_server_line(server_frame, traffic_log, &mut latest_error, message).await? {
+                        println!("{}", shown);
+                    }
                 }
             },
             io_result = iorecv.recv() => match io_result {
@@ -54,10 +429,473 @@ async fn main() -> anyhow::Result<()> {
                     println!("IO error: {}", e);
                     return Err(anyhow!(e));
                 },
-                Some(Ok(input)) => {
-                    server_frame.send(input).await?;
+                // /load <path> - send a file to the room or user last sent a SAY
+                Some(Ok(input)) if input.starts_with("/load ") => {
+                    let path = input["/load ".len()..].trim();
+                    match &context {
+                        Some(target) => {
+                            if let Err(e) = load(server_frame, traffic_log, path, target, LOAD_DELAY).await {
+                                println!("Load error: {}", e);
+                            }
+                        }
+                        None => println!("Load error: SAY something to a room or user first"),
+                    }
+                }
+                // /lasterror - show the most recent error again
+                Some(Ok(input)) if input.trim() == "/lasterror" => {
+                    if let Some(error) = last_error(latest_error.as_deref()) {
+                        println!("{}", error);
+                    }
+                }
+                // /room #name - say lines that aren't commands to a room
+                Some(Ok(input)) if input.starts_with("/room ") => {
+                    let name = input["/room ".len()..].trim();
+                    if name.starts_with('#') {
+                        println!("Talking in {}.", name);
+                        room = Some(name.to_string());
+                    } else {
+                        println!("Room error: room names start with #");
+                    }
+                }
+                Some(Ok(input)) => match to_server(&input, room.as_deref()) {
+                    Some(line) => {
+                        if let Some(target) = say_target(&line) {
+                            context = Some(target.to_string());
+                        }
+                        send_line(server_frame, traffic_log, line).await?;
+                    }
+                    None => println!("Room error: pick a room with /room #name or start the line with a command"),
                 }
             }
         }
     }
 }
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    // parse command line arguments
+    let cli = Cli::parse();
+
+    // connect to server
+    let tcp_stream = TcpStream::connect(&cli.address).await?;
+
+    // server frame
+    let mut server_frame = Framed::new(tcp_stream, LinesCodec::new_with_max_length(1024));
+
+    // every line to and from the server, for debugging
+    let mut traffic_log = match &cli.log_file {
+        Some(path) => Some(TrafficLog::open(path)?),
+        None => None,
+    };
+
+    // run a script instead of reading from the terminal
+    if !cli.exec.is_empty() {
+        return exec(
+            &mut server_frame,
+            &mut traffic_log,
+            &cli.exec,
+            EXEC_SETTLE,
+            |line| println!("{}", line),
+        )
+        .await;
+    }
+
+    // names for tab completion, learned from the server
+    let names = Arc::new(Mutex::new(Names::default()));
+
+    // io bridge
+    let mut editor = Editor::<NameCompleter, DefaultHistory>::new()?;
+    editor.set_helper(Some(NameCompleter {
+        names: names.clone(),
+    }));
+    let (iorecv, bridge) = spawn_io_bridge(move || {
+        let line = editor.readline("")?;
+        let _ = editor.add_history_entry(line.as_str());
+        Ok(line)
+    });
+
+    let result = chat(&mut server_frame, &mut traffic_log, iorecv, names).await;
+    // the bridge may be blocked reading the terminal, where it can't be interrupted. it stops on its own the
+    // next time it has a line to pass on, so only wait for it if it already has, and otherwise let the
+    // process exit end it.
+    if bridge.is_finished() && bridge.join().is_err() {
+        println!("IO error: terminal reader panicked");
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        assert_eq!(
+            render("ERROR room unknown #karate"),
+            "\x1b[31merror: that room doesn't exist (#karate)\x1b[0m"
+        );
+        assert_eq!(
+            render("ERROR bad arguments"),
+            "\x1b[31merror: that command has the wrong number of arguments\x1b[0m"
+        );
+        assert_eq!(
+            render("ERROR user not in room @dave #sports"),
+            "\x1b[31merror: that user isn't in the room (@dave #sports)\x1b[0m"
+        );
+        // other errors are shown raw
+        assert_eq!(
+            render("ERROR not operator @dave"),
+            "ERROR not operator @dave"
+        );
+        assert_eq!(render("ERROR room unknownish"), "ERROR room unknownish");
+        assert_eq!(
+            render("#sports @robert SAID 3 hi"),
+            "#sports @robert SAID 3 hi"
+        );
+        // private messages stand out
+        assert_eq!(render("@robert SAID hi"), "\x1b[36m@robert SAID hi\x1b[0m");
+    }
+
+    #[test]
+    fn test_server_line_parse() {
+        assert_eq!(
+            ServerLine::parse("#sports @robert SAID 3 @kelsey SAID hi"),
+            ServerLine::Message(OutgoingMsg::SaidRoom(
+                "#sports".to_string(),
+                "@robert".to_string(),
+                3,
+                "@kelsey SAID hi".to_string()
+            ))
+        );
+        assert_eq!(
+            ServerLine::parse("@robert SAID #sports @kelsey SAID 3 hi"),
+            ServerLine::Message(OutgoingMsg::SaidUser(
+                "@robert".to_string(),
+                "#sports @kelsey SAID 3 hi".to_string(),
+                false
+            ))
+        );
+        assert_eq!(
+            ServerLine::parse("ERROR room unknown #karate"),
+            ServerLine::Error("room unknown #karate".to_string())
+        );
+        assert_eq!(
+            ServerLine::parse("SPARKLE #sports"),
+            ServerLine::Raw("SPARKLE #sports".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_on_server_line() {
+        let (client_stream, server_stream) = tokio::io::duplex(4096);
+        let mut client_frame = Framed::new(client_stream, LinesCodec::new());
+        let mut server_frame = Framed::new(server_stream, LinesCodec::new());
+        let mut latest_error = None;
+
+        // PINGs are answered and not shown
+        let shown = on_server_line(
+            &mut client_frame,
+            &mut None,
+            &mut latest_error,
+            "PING".to_string(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(shown, None);
+        assert_eq!(server_frame.next().await.unwrap().unwrap(), "PONG");
+
+        let shown = on_server_line(
+            &mut client_frame,
+            &mut None,
+            &mut latest_error,
+            "ERROR room unknown #karate".to_string(),
+        )
+        .await
+        .unwrap();
+        // shown explained, but remembered as it was sent
+        assert_eq!(
+            shown.as_deref(),
+            Some("\x1b[31merror: that room doesn't exist (#karate)\x1b[0m")
+        );
+        assert_eq!(latest_error.as_deref(), Some("ERROR room unknown #karate"));
+
+        let shown = on_server_line(
+            &mut client_frame,
+            &mut None,
+            &mut latest_error,
+            "ROOM #sports".to_string(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(shown.as_deref(), Some("ROOM #sports"));
+        assert_eq!(latest_error.as_deref(), Some("ERROR room unknown #karate"));
+    }
+
+    #[test]
+    fn test_last_error() {
+        assert_eq!(last_error(None), None);
+        assert_eq!(
+            last_error(Some("ERROR user unknown @kelsey")),
+            Some("\x1b[31merror: that user doesn't exist (@kelsey)\x1b[0m".to_string())
+        );
+        assert_eq!(
+            last_error(Some("ERROR not operator @kelsey")),
+            Some("ERROR not operator @kelsey".to_string())
+        );
+    }
+
+    #[test]
+    fn test_to_server() {
+        // plain lines are said to the current room
+        assert_eq!(
+            to_server("good game everyone", Some("#sports")),
+            Some("SAY #sports good game everyone".to_string())
+        );
+        assert_eq!(to_server("good game everyone", None), None);
+        // commands and local commands are sent as they are
+        for input in ["JOIN #news", "SAY @robert hi", "QUIT", "/unknown", ""] {
+            assert_eq!(to_server(input, Some("#sports")), Some(input.to_string()));
+        }
+        // verbs are case sensitive
+        assert_eq!(
+            to_server("join me", Some("#sports")),
+            Some("SAY #sports join me".to_string())
+        );
+    }
+
+    #[test]
+    fn test_names() {
+        let mut names = Names::default();
+        for line in [
+            "ROOM #sports",
+            "ROOM #news",
+            "USER @robert",
+            "#sports @rachel JOINED",
+            "@kelsey SAID check out #secret and @sneaky",
+            "ROOM #snooker",
+            "DELETED #snooker",
+        ] {
+            names.observe(line);
+        }
+        assert_eq!(
+            names.complete("JOIN #s", 7),
+            (5, vec!["#sports".to_string()])
+        );
+        assert_eq!(
+            names.complete("SAY @r hi", 6),
+            (4, vec!["@rachel".to_string(), "@robert".to_string()])
+        );
+        assert_eq!(names.complete("@k", 2), (0, vec!["@kelsey".to_string()]));
+        // names from payloads aren't learned
+        assert_eq!(names.complete("SAY @sn", 7), (4, vec![]));
+        // only names are completed
+        assert_eq!(names.complete("JOI", 3), (0, vec![]));
+        assert_eq!(
+            names.complete("SAY # ", 5),
+            (4, vec!["#news".to_string(), "#sports".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_say_target() {
+        assert_eq!(say_target("SAY #sports good game"), Some("#sports"));
+        assert_eq!(say_target("SAY @robert hi"), Some("@robert"));
+        assert_eq!(say_target("SAY #sports"), None);
+        assert_eq!(say_target("JOIN #sports"), None);
+    }
+
+    #[tokio::test]
+    async fn test_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("lines.txt");
+        std::fs::write(&path, "first line\n\nsecond line\n").unwrap();
+
+        let (client_stream, server_stream) = tokio::io::duplex(4096);
+        let mut client_frame = Framed::new(client_stream, LinesCodec::new());
+        let mut server_frame = Framed::new(server_stream, LinesCodec::new());
+        load(
+            &mut client_frame,
+            &mut None,
+            path.to_str().unwrap(),
+            "#sports",
+            Duration::ZERO,
+        )
+        .await
+        .unwrap();
+        drop(client_frame);
+
+        let mut lines = vec![];
+        while let Some(line) = server_frame.next().await {
+            lines.push(line.unwrap());
+        }
+        assert_eq!(lines, ["SAY #sports first line", "SAY #sports second line"]);
+
+        // a missing file is an error and nothing is sent
+        let (client_stream, _server_stream) = tokio::io::duplex(4096);
+        let mut client_frame = Framed::new(client_stream, LinesCodec::new());
+        assert!(load(
+            &mut client_frame,
+            &mut None,
+            dir.path().join("missing.txt").to_str().unwrap(),
+            "#sports",
+            Duration::ZERO,
+        )
+        .await
+        .is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_exec() {
+        use chat_project::{
+            server::{client_connection, ConnectionConfig},
+            server_state::ServerState,
+        };
+
+        let (client_stream, server_stream) = tokio::io::duplex(4096);
+        tokio::spawn(client_connection(
+            Arc::new(tokio::sync::Mutex::new(ServerState::new())),
+            server_stream,
+            "127.0.0.1:5456".parse().unwrap(),
+            ConnectionConfig::default(),
+        ));
+        let mut server_frame = Framed::new(client_stream, LinesCodec::new());
+        let commands = [
+            "NAME @bot",
+            "JOIN #bots",
+            "SAY #bots hi",
+            "ROOMS",
+            "QUIT",
+            "USERS #bots",
+        ]
+        .map(String::from);
+        let mut lines = vec![];
+        exec(
+            &mut server_frame,
+            &mut None,
+            &commands,
+            EXEC_SETTLE,
+            |line| lines.push(line.to_string()),
+        )
+        .await
+        .unwrap();
+        // nothing after the QUIT is sent
+        assert_eq!(lines, ["CONNECTED", "REGISTERED", "ROOM #bots"]);
+    }
+
+    #[tokio::test]
+    async fn test_traffic_log() {
+        use chat_project::{
+            server::{client_connection, ConnectionConfig},
+            server_state::ServerState,
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("traffic.log");
+        let mut traffic_log = Some(TrafficLog::open(&path).unwrap());
+
+        let (client_stream, server_stream) = tokio::io::duplex(4096);
+        tokio::spawn(client_connection(
+            Arc::new(tokio::sync::Mutex::new(ServerState::new())),
+            server_stream,
+            "127.0.0.1:5456".parse().unwrap(),
+            ConnectionConfig::default(),
+        ));
+        let mut server_frame = Framed::new(client_stream, LinesCodec::new());
+        assert_eq!(
+            recv_line(&mut server_frame, &mut traffic_log)
+                .await
+                .unwrap()
+                .unwrap(),
+            "CONNECTED"
+        );
+        send_line(
+            &mut server_frame,
+            &mut traffic_log,
+            "NAME @robert".to_string(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            recv_line(&mut server_frame, &mut traffic_log)
+                .await
+                .unwrap()
+                .unwrap(),
+            "REGISTERED"
+        );
+
+        // each line is a timestamp, a direction, and the line itself
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = contents
+            .lines()
+            .map(|line| line.split_once(' ').unwrap().1)
+            .collect();
+        assert_eq!(lines, ["< CONNECTED", "> NAME @robert", "< REGISTERED"]);
+        assert!(contents.lines().all(|line| line
+            .split(' ')
+            .next()
+            .unwrap()
+            .parse::<f64>()
+            .is_ok()));
+    }
+
+    #[tokio::test]
+    async fn test_traffic_log_write_error() {
+        use chat_project::{
+            server::{client_connection, ConnectionConfig},
+            server_state::ServerState,
+        };
+
+        // every write to /dev/full fails, which is reported but doesn't stop traffic either way
+        let mut traffic_log = Some(TrafficLog::open(Path::new("/dev/full")).unwrap());
+        let (client_stream, server_stream) = tokio::io::duplex(4096);
+        tokio::spawn(client_connection(
+            Arc::new(tokio::sync::Mutex::new(ServerState::new())),
+            server_stream,
+            "127.0.0.1:5456".parse().unwrap(),
+            ConnectionConfig::default(),
+        ));
+        let mut server_frame = Framed::new(client_stream, LinesCodec::new());
+        assert_eq!(
+            recv_line(&mut server_frame, &mut traffic_log)
+                .await
+                .unwrap()
+                .unwrap(),
+            "CONNECTED"
+        );
+        send_line(
+            &mut server_frame,
+            &mut traffic_log,
+            "NAME @robert".to_string(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            recv_line(&mut server_frame, &mut traffic_log)
+                .await
+                .unwrap()
+                .unwrap(),
+            "REGISTERED"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_server_disconnect_stops_io_bridge() {
+        let (client_stream, server_stream) = tokio::io::duplex(4096);
+        let mut server = Framed::new(server_stream, LinesCodec::new());
+        let mut server_frame = Framed::new(client_stream, LinesCodec::new());
+        let (typed, lines) = std::sync::mpsc::channel::<String>();
+        let (iorecv, bridge) =
+            spawn_io_bridge(move || lines.recv().map_err(|_| ReadlineError::Eof));
+
+        server.send("CONNECTED").await.unwrap();
+        drop(server);
+        let names = Arc::new(Mutex::new(Names::default()));
+        assert!(chat(&mut server_frame, &mut None, iorecv, names)
+            .await
+            .is_ok());
+
+        // the next line typed has nowhere to go, so the bridge stops instead of panicking
+        typed.send("SAY #sports hi".to_string()).unwrap();
+        assert!(bridge.join().is_ok());
+    }
+}
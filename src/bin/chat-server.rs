@@ -1,12 +1,130 @@
-use chat_project::server_state::ServerState;
+use chat_project::{
+    config::ServerConfig,
+    link, logging,
+    parser::{EscapePolicy, ParserConfig, MAX_LINE_LENGTH},
+    server::{flush_history, run_server, sweep_idle_rooms, ConnectionConfig},
+    server_state::ServerState,
+};
 use clap::Parser;
-use std::sync::Arc;
-use tokio::{net::TcpListener, sync::Mutex};
+use std::{net::IpAddr, path::PathBuf, sync::Arc, time::Duration};
+use tokio::{
+    net::TcpListener,
+    signal::unix::{signal, SignalKind},
+    sync::Mutex,
+};
 
 #[derive(Parser)]
 #[command(author, version, long_about = None)]
 struct Cli {
     address: String,
+    /// Minimum length of a user name, not counting the leading @
+    #[arg(long, default_value_t = 3)]
+    name_min_length: usize,
+    /// Maximum length of a user name, not counting the leading @
+    #[arg(long, default_value_t = 20)]
+    name_max_length: usize,
+    /// Minimum length of a room name, not counting the leading #
+    #[arg(long, default_value_t = 3)]
+    room_min_length: usize,
+    /// Maximum length of a room name, not counting the leading #
+    #[arg(long, default_value_t = 20)]
+    room_max_length: usize,
+    /// What to do with terminal escape sequences in client input: strip, reject, or allow
+    #[arg(long, default_value = "strip")]
+    escapes: EscapePolicy,
+    /// Most users a single SAY may send a private message to
+    #[arg(long, default_value_t = 5)]
+    max_recipients: usize,
+    /// Keep rooms after their last user leaves
+    #[arg(long)]
+    persistent_rooms: bool,
+    /// Only let operators create rooms, with CREATE, instead of creating them on JOIN. Created rooms are only
+    /// kept across restarts with --persistent-rooms and --store
+    #[arg(long)]
+    closed_rooms: bool,
+    /// JSON file that persistent rooms are saved to and restored from
+    #[arg(long, requires = "persistent_rooms")]
+    store: Option<PathBuf>,
+    /// List the rooms a private message's sender and recipient share in its DELIVERED confirmation
+    #[arg(long)]
+    shared_rooms_on_delivery: bool,
+    /// File whose lines are sent to each client after it registers. Re-read on SIGHUP
+    #[arg(long)]
+    motd: Option<PathBuf>,
+    /// File of user names, one per line, that clients may not take. Re-read on SIGHUP
+    #[arg(long)]
+    reserved_names: Option<PathBuf>,
+    /// Deliver urgent private messages (SAY!) to users that have do not disturb on
+    #[arg(long)]
+    urgent_bypasses_dnd: bool,
+    /// Room that every user joins as soon as they register. May be given more than once
+    #[arg(long)]
+    auto_join: Vec<String>,
+    /// Password that OPER takes to make a user a server operator [default: no operators]
+    #[arg(long)]
+    oper_password: Option<String>,
+    /// Recent messages kept for operators to REVIEW. 0 keeps none
+    #[arg(long, default_value_t = 0)]
+    review_log: usize,
+    /// Messages kept per room and replayed to users as they join, saved to the store every 5 seconds. 0 keeps
+    /// none
+    #[arg(long, default_value_t = 0)]
+    history: usize,
+    /// Seconds a user has to wait between name changes
+    #[arg(long, default_value_t = 0)]
+    rename_cooldown: u64,
+    /// Seconds the name of a user who disconnects or renames stays locked so nobody else can take it
+    #[arg(long, default_value_t = 0)]
+    name_lock: u64,
+    /// Longest line, in bytes, that a client may send
+    #[arg(long, default_value_t = MAX_LINE_LENGTH)]
+    max_line_length: usize,
+    /// Seconds a room may go without a message before it is archived [default: never]
+    #[arg(long)]
+    archive_after: Option<u64>,
+    /// Most connections that may be waiting to register at once [default: no limit]
+    #[arg(long)]
+    max_pending: Option<usize>,
+    /// Most rooms one user may be in at once [default: no limit]
+    #[arg(long)]
+    max_rooms: Option<usize>,
+    /// Seconds a client has to register a name before it is disconnected
+    #[arg(long, default_value_t = 60)]
+    registration_timeout: u64,
+    /// JOINs and LEAVEs a client may send per churn window before the rest are dropped
+    #[arg(long, default_value_t = 20)]
+    churn_limit: usize,
+    /// Seconds in a churn window
+    #[arg(long, default_value_t = 10)]
+    churn_window: u64,
+    /// Malformed commands in a row before a client is slowed down. Twice as many disconnect it
+    #[arg(long, default_value_t = 10)]
+    malformed_limit: usize,
+    /// Commands a client may pipeline that are handled back to back before its connection lets others run
+    #[arg(long, default_value_t = 32)]
+    command_burst: usize,
+    /// Answer unknown commands with a NOTICE pointing at COMMANDS instead of ignoring them
+    #[arg(long)]
+    unknown_command_notice: bool,
+    /// Let clients compress their connection with COMPRESS
+    #[arg(long)]
+    compression: bool,
+    /// Seconds the holder of a taken name, if silent for 90 seconds, has to answer a PING before a new client
+    /// takes the name over [default: taken names are refused]
+    #[arg(long)]
+    ghost_probe: Option<u64>,
+    /// Name that tags events relayed to linked servers [default: ADDRESS]
+    #[arg(long)]
+    server_name: Option<String>,
+    /// Address a peer server may LINK from. May be given more than once [default: no peers]
+    #[arg(long)]
+    link_peer: Vec<IpAddr>,
+    /// Address of a server to link to
+    #[arg(long, requires = "link_room")]
+    link: Option<String>,
+    /// Room to relay from the linked server
+    #[arg(long, requires = "link")]
+    link_room: Option<String>,
 }
 
 #[tokio::main]
@@ -15,31 +133,90 @@ async fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
 
     // initialize logging
-    env_logger::init();
+    logging::init();
+
+    // per-connection rules
+    let connection_config = ConnectionConfig {
+        parser: ParserConfig {
+            name_min_length: cli.name_min_length,
+            name_max_length: cli.name_max_length,
+            room_min_length: cli.room_min_length,
+            room_max_length: cli.room_max_length,
+            escapes: cli.escapes,
+            max_recipients: cli.max_recipients,
+            max_line_length: cli.max_line_length,
+        },
+        registration_timeout: Duration::from_secs(cli.registration_timeout),
+        churn_limit: cli.churn_limit,
+        churn_window: Duration::from_secs(cli.churn_window),
+        malformed_limit: cli.malformed_limit,
+        command_burst: cli.command_burst,
+        unknown_command_notice: cli.unknown_command_notice,
+        compression: cli.compression,
+        ghost_probe: cli.ghost_probe.map(Duration::from_secs),
+    };
+    let config = ServerConfig::builder()
+        .connection(connection_config)
+        .server_name(cli.server_name.unwrap_or(cli.address.clone()))
+        .persistent_rooms(cli.persistent_rooms)
+        .closed_rooms(cli.closed_rooms)
+        .store(cli.store)
+        .shared_rooms_on_delivery(cli.shared_rooms_on_delivery)
+        .urgent_bypasses_dnd(cli.urgent_bypasses_dnd)
+        .motd(cli.motd)
+        .reserved_names(cli.reserved_names)
+        .auto_join(cli.auto_join)
+        .oper_password(cli.oper_password)
+        .review_log_size(cli.review_log)
+        .history_size(cli.history)
+        .rename_cooldown(Duration::from_secs(cli.rename_cooldown))
+        .name_lock(Duration::from_secs(cli.name_lock))
+        .max_pending_registrations(cli.max_pending)
+        .max_rooms_per_user(cli.max_rooms)
+        .archive_after(cli.archive_after.map(Duration::from_secs))
+        .link_peers(cli.link_peer)
+        .build()?;
 
     // the shared server state amongst all connected clients
-    let server_state = Arc::new(Mutex::new(ServerState::new()));
+    let server_state = ServerState::with_config(&config)?;
+    let server_state = Arc::new(Mutex::new(server_state));
 
-    // socket bind to address
-    let listener = TcpListener::bind(&cli.address).await?;
+    // re-read the MOTD and reserved names on SIGHUP without dropping any connections
+    let mut hangup = signal(SignalKind::hangup())?;
+    let reload_state = server_state.clone();
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            match reload_state.lock().await.reload() {
+                Ok(()) => log::info!("reloaded MOTD and reserved names"),
+                Err(e) => log::info!("reload error = {:?}", e),
+            }
+        }
+    });
 
-    log::info!("listening for connections on {}", cli.address);
+    // archive rooms that go quiet, checking often enough that none is more than a minute late
+    if let Some(archive_after) = config.archive_after {
+        let period = archive_after.min(Duration::from_secs(60));
+        tokio::spawn(sweep_idle_rooms(server_state.clone(), period));
+    }
 
-    loop {
-        // accept new client connection
-        let (stream, addr) = listener.accept().await?;
-        // clone references to shared server state
-        let server_state = server_state.clone();
+    // write room history to the store every few seconds rather than on every message
+    if config.history_size > 0 {
+        tokio::spawn(flush_history(server_state.clone(), Duration::from_secs(5)));
+    }
 
-        // spawn new async process
+    // relay a room from another server
+    if let (Some(link), Some(link_room)) = (cli.link, cli.link_room) {
+        let server_state = server_state.clone();
         tokio::spawn(async move {
-            log::info!("client connection accepted {}", addr);
             if let Err(e) =
-                chat_project::server::client_connection(server_state, stream, addr).await
+                link::link_room(server_state, &link, &link_room, cli.max_line_length).await
             {
-                log::info!("error = {:?}", e);
+                log::info!("link error = {:?}", e);
             }
-            log::info!("client connection closed {}", addr);
         });
     }
+
+    // socket bind to address
+    let listener = TcpListener::bind(&cli.address).await?;
+    run_server(server_state, listener, config.connection).await
 }
@@ -0,0 +1,221 @@
+//! The codec every connection is framed with. It starts out as plain newline-delimited lines, the same as
+//! [LinesCodec], and can be switched to compressed frames once both sides have agreed to it with COMPRESS.
+//!
+//! A compressed frame is a 4-byte big-endian length followed by that many bytes of deflate data holding one
+//! line. Each direction keeps a single deflate stream for the life of the connection and flushes it at the
+//! end of every frame, so later lines compress against earlier ones and repeated names and rooms cost
+//! almost nothing.
+//!
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress};
+use std::io;
+use tokio_util::{
+    bytes::{Buf, BufMut, BytesMut},
+    codec::{Decoder, Encoder, LinesCodec, LinesCodecError},
+};
+
+/// Bytes in the length that starts every compressed frame.
+const FRAME_HEADER: usize = 4;
+
+/// Room for deflate's own overhead on top of the longest line, when checking a compressed frame's length.
+const FRAME_SLACK: usize = 64;
+
+/// Lines, or compressed frames of one line each after [ChatCodec::compress].
+#[derive(Debug)]
+pub struct ChatCodec {
+    lines: LinesCodec,
+    max_length: usize,
+    compression: Option<Box<Streams>>,
+}
+
+/// The deflate streams of a compressed connection, one per direction.
+#[derive(Debug)]
+struct Streams {
+    compress: Compress,
+    decompress: Decompress,
+}
+
+impl ChatCodec {
+    /// A codec for lines of at most `max_length` bytes, in either mode.
+    pub fn new_with_max_length(max_length: usize) -> Self {
+        Self {
+            lines: LinesCodec::new_with_max_length(max_length),
+            max_length,
+            compression: None,
+        }
+    }
+
+    /// Switch both directions to compressed frames. Whatever has already been read but not decoded is
+    /// treated as compressed, so the switch has to happen right after the line that agreed to it.
+    pub fn compress(&mut self) {
+        self.compression = Some(Box::new(Streams {
+            compress: Compress::new(Compression::default(), false),
+            decompress: Decompress::new(false),
+        }));
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.compression.is_some()
+    }
+}
+
+fn invalid_data(message: &str) -> LinesCodecError {
+    LinesCodecError::Io(io::Error::new(io::ErrorKind::InvalidData, message))
+}
+
+impl Decoder for ChatCodec {
+    type Item = String;
+    type Error = LinesCodecError;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<String>, LinesCodecError> {
+        let Some(streams) = &mut self.compression else {
+            return self.lines.decode(buf);
+        };
+        if buf.len() < FRAME_HEADER {
+            return Ok(None);
+        }
+        let length = u32::from_be_bytes(buf[..FRAME_HEADER].try_into().unwrap()) as usize;
+        // a frame can't be skipped like an overlong line, since the stream after it depends on it
+        if length > self.max_length + FRAME_SLACK {
+            return Err(invalid_data("compressed frame too long"));
+        }
+        if buf.len() < FRAME_HEADER + length {
+            buf.reserve(FRAME_HEADER + length - buf.len());
+            return Ok(None);
+        }
+        buf.advance(FRAME_HEADER);
+        let frame = buf.split_to(length);
+        // one byte more than a line may hold, to tell a line that is exactly the limit from a longer one
+        let mut line = Vec::with_capacity(self.max_length + 1);
+        let before = streams.decompress.total_in();
+        streams
+            .decompress
+            .decompress_vec(&frame, &mut line, FlushDecompress::Sync)
+            .map_err(|_| invalid_data("bad compressed frame"))?;
+        if line.len() > self.max_length {
+            return Err(invalid_data("compressed line too long"));
+        }
+        if ((streams.decompress.total_in() - before) as usize) < frame.len() {
+            return Err(invalid_data("bad compressed frame"));
+        }
+        String::from_utf8(line)
+            .map(Some)
+            .map_err(|_| invalid_data("compressed line is not UTF-8"))
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<String>, LinesCodecError> {
+        if self.compression.is_none() {
+            return self.lines.decode_eof(buf);
+        }
+        // a frame cut off by the end of the stream is dropped
+        self.decode(buf)
+    }
+}
+
+impl<T: AsRef<str>> Encoder<T> for ChatCodec {
+    type Error = LinesCodecError;
+
+    fn encode(&mut self, line: T, buf: &mut BytesMut) -> Result<(), LinesCodecError> {
+        let Some(streams) = &mut self.compression else {
+            return self.lines.encode(line, buf);
+        };
+        let mut line = line.as_ref().as_bytes();
+        // deflate grows data by only a few bytes, plus the flush marker
+        let mut frame = Vec::with_capacity(line.len() + FRAME_SLACK);
+        loop {
+            let before = streams.compress.total_in();
+            streams
+                .compress
+                .compress_vec(line, &mut frame, FlushCompress::Sync)
+                .map_err(|_| invalid_data("compression failed"))?;
+            line = &line[(streams.compress.total_in() - before) as usize..];
+            // the flush is only done if it didn't run out of room
+            if line.is_empty() && frame.len() < frame.capacity() {
+                break;
+            }
+            frame.reserve(line.len() + FRAME_SLACK);
+        }
+        buf.reserve(FRAME_HEADER + frame.len());
+        buf.put_u32(frame.len() as u32);
+        buf.put_slice(&frame);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut sender = ChatCodec::new_with_max_length(1024);
+        let mut receiver = ChatCodec::new_with_max_length(1024);
+        let mut wire = BytesMut::new();
+
+        // plain lines until both sides switch
+        sender.encode("COMPRESS", &mut wire).unwrap();
+        assert_eq!(&wire[..], b"COMPRESS\n");
+        assert_eq!(
+            receiver.decode(&mut wire).unwrap(),
+            Some("COMPRESS".to_string())
+        );
+        sender.compress();
+        receiver.compress();
+
+        let lines = [
+            "#sports @robert SAID 1 good game".to_string(),
+            "#sports @robert SAID 2 good game".to_string(),
+            String::new(),
+            "x".repeat(1024),
+        ];
+        for line in &lines {
+            sender.encode(line, &mut wire).unwrap();
+        }
+        // a frame split across reads waits for the rest
+        let mut partial = wire.split_to(3);
+        assert_eq!(receiver.decode(&mut partial).unwrap(), None);
+        partial.unsplit(wire);
+        let mut wire = partial;
+        for line in &lines {
+            assert_eq!(receiver.decode(&mut wire).unwrap().as_ref(), Some(line));
+        }
+        assert!(wire.is_empty());
+    }
+
+    #[test]
+    fn test_repeats_compress() {
+        let mut sender = ChatCodec::new_with_max_length(1024);
+        sender.compress();
+        let line = "#sports @robert SAID 1 what a game that was, what a game";
+        let mut first = BytesMut::new();
+        sender.encode(line, &mut first).unwrap();
+        let mut second = BytesMut::new();
+        sender.encode(line, &mut second).unwrap();
+        // the second copy refers back to the first
+        assert!(second.len() < first.len());
+        assert!(second.len() < line.len() / 2);
+    }
+
+    #[test]
+    fn test_rejects_bad_frames() {
+        // longer than any line could compress to
+        let mut receiver = ChatCodec::new_with_max_length(16);
+        receiver.compress();
+        let mut wire = BytesMut::from(&[0, 0, 1, 0][..]);
+        assert!(receiver.decode(&mut wire).is_err());
+
+        // a line over the limit once decompressed
+        let mut sender = ChatCodec::new_with_max_length(1024);
+        sender.compress();
+        let mut wire = BytesMut::new();
+        sender.encode("y".repeat(17), &mut wire).unwrap();
+        let mut receiver = ChatCodec::new_with_max_length(16);
+        receiver.compress();
+        assert!(receiver.decode(&mut wire).is_err());
+
+        // not deflate at all
+        let mut receiver = ChatCodec::new_with_max_length(16);
+        receiver.compress();
+        let mut wire = BytesMut::from(&[0, 0, 0, 4, 0xff, 0xff, 0xff, 0xff][..]);
+        assert!(receiver.decode(&mut wire).is_err());
+    }
+}
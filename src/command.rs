@@ -0,0 +1,369 @@
+//! The commands a client can send, with the verb and argument shape of each, and counts of how often each
+//! one has been received. Both the parser and the messages it produces refer to commands, so they live here
+//! rather than in either.
+use std::{
+    fmt::Display,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// The supported incoming commands.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Command {
+    Name,
+    Join,
+    Leave,
+    Say,
+    Users,
+    Rooms,
+    Pong,
+    Debug,
+    Mute,
+    Unmute,
+    Link,
+    Preview,
+    Status,
+    Whois,
+    Watch,
+    Quit,
+    Syntax,
+    Dnd,
+    UrgentSay,
+    Reset,
+    Commands,
+    MyStats,
+    Oper,
+    Kill,
+    Limits,
+    ReadOnly,
+    Review,
+    MaxLen,
+    HistLen,
+    Echo,
+    Shared,
+    Bridge,
+    ConnStats,
+    Switch,
+    Buddy,
+    Prefs,
+    Unarchive,
+    ProtoStats,
+    Context,
+    Mode,
+    Membership,
+    Time,
+    Edit,
+    Delete,
+    MyRooms,
+    Create,
+    Destroy,
+    Recent,
+    EchoTest,
+    Compress,
+    JoinAll,
+    Topic,
+    TopicHistory,
+    Invite,
+}
+
+impl Display for Command {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Name => write!(f, "Name"),
+            Self::Join => write!(f, "Join"),
+            Self::Leave => write!(f, "Leave"),
+            Self::Say => write!(f, "Say"),
+            Self::Users => write!(f, "Users"),
+            Self::Rooms => write!(f, "Rooms"),
+            Self::Pong => write!(f, "Pong"),
+            Self::Debug => write!(f, "Debug"),
+            Self::Mute => write!(f, "Mute"),
+            Self::Unmute => write!(f, "Unmute"),
+            Self::Link => write!(f, "Link"),
+            Self::Preview => write!(f, "Preview"),
+            Self::Status => write!(f, "Status"),
+            Self::Whois => write!(f, "Whois"),
+            Self::Watch => write!(f, "Watch"),
+            Self::Quit => write!(f, "Quit"),
+            Self::Syntax => write!(f, "Syntax"),
+            Self::Dnd => write!(f, "Dnd"),
+            Self::UrgentSay => write!(f, "UrgentSay"),
+            Self::Reset => write!(f, "Reset"),
+            Self::Commands => write!(f, "Commands"),
+            Self::MyStats => write!(f, "MyStats"),
+            Self::Oper => write!(f, "Oper"),
+            Self::Kill => write!(f, "Kill"),
+            Self::Limits => write!(f, "Limits"),
+            Self::ReadOnly => write!(f, "ReadOnly"),
+            Self::Review => write!(f, "Review"),
+            Self::MaxLen => write!(f, "MaxLen"),
+            Self::HistLen => write!(f, "HistLen"),
+            Self::Echo => write!(f, "Echo"),
+            Self::Shared => write!(f, "Shared"),
+            Self::Bridge => write!(f, "Bridge"),
+            Self::ConnStats => write!(f, "ConnStats"),
+            Self::Switch => write!(f, "Switch"),
+            Self::Buddy => write!(f, "Buddy"),
+            Self::Prefs => write!(f, "Prefs"),
+            Self::Unarchive => write!(f, "Unarchive"),
+            Self::ProtoStats => write!(f, "ProtoStats"),
+            Self::Context => write!(f, "Context"),
+            Self::Mode => write!(f, "Mode"),
+            Self::Membership => write!(f, "Membership"),
+            Self::Time => write!(f, "Time"),
+            Self::Edit => write!(f, "Edit"),
+            Self::Delete => write!(f, "Delete"),
+            Self::MyRooms => write!(f, "MyRooms"),
+            Self::Create => write!(f, "Create"),
+            Self::Destroy => write!(f, "Destroy"),
+            Self::Recent => write!(f, "Recent"),
+            Self::EchoTest => write!(f, "EchoTest"),
+            Self::Compress => write!(f, "Compress"),
+            Self::JoinAll => write!(f, "JoinAll"),
+            Self::Topic => write!(f, "Topic"),
+            Self::TopicHistory => write!(f, "TopicHistory"),
+            Self::Invite => write!(f, "Invite"),
+        }
+    }
+}
+
+/// Every command with the verb that starts it and the shape of its arguments.
+const COMMANDS: &[(Command, &str, &str)] = &[
+    (Command::Name, "NAME", "NAME <user>"),
+    (Command::Join, "JOIN", "JOIN <room>"),
+    (Command::Leave, "LEAVE", "LEAVE <room>"),
+    (Command::Switch, "SWITCH", "SWITCH <room> <room>"),
+    (Command::Say, "SAY", "SAY <room|user[,user...]> <message>"),
+    (Command::UrgentSay, "SAY!", "SAY! <user> <message>"),
+    (Command::Users, "USERS", "USERS <room> [active]"),
+    (Command::Rooms, "ROOMS", "ROOMS [empty|active]"),
+    (Command::MyRooms, "MYROOMS", "MYROOMS [full]"),
+    (Command::JoinAll, "JOINALL", "JOINALL <room> <room> ..."),
+    (Command::Pong, "PONG", "PONG"),
+    #[cfg(feature = "debug")]
+    (Command::Debug, "DEBUG", "DEBUG ON|OFF"),
+    (Command::Mute, "MUTE", "MUTE <room> <user>"),
+    (Command::Unmute, "UNMUTE", "UNMUTE <room> <user>"),
+    (Command::ReadOnly, "READONLY", "READONLY <room> ON|OFF"),
+    (Command::Mode, "MODE", "MODE <room> [+i|-i|+m|-m|+t|-t]"),
+    (Command::Invite, "INVITE", "INVITE <room> <user>"),
+    (
+        Command::Membership,
+        "MEMBERSHIP",
+        "MEMBERSHIP <room> ON|OFF",
+    ),
+    (Command::Unarchive, "UNARCHIVE", "UNARCHIVE <room>"),
+    (Command::Edit, "EDIT", "EDIT <room> <seq> <message>"),
+    (Command::Delete, "DELETE", "DELETE <room> <seq>"),
+    (Command::Recent, "RECENT", "RECENT <room> <count>"),
+    (Command::Topic, "TOPIC", "TOPIC <room> <topic>"),
+    (Command::TopicHistory, "TOPICHISTORY", "TOPICHISTORY <room>"),
+    (Command::HistLen, "HISTLEN", "HISTLEN <count>"),
+    (Command::Link, "LINK", "LINK <room> <server>"),
+    (Command::Preview, "PREVIEW", "PREVIEW SAY <room> <message>"),
+    (Command::Status, "STATUS", "STATUS [message]"),
+    (Command::Whois, "WHOIS", "WHOIS <user>"),
+    (Command::Shared, "SHARED", "SHARED <user>"),
+    (Command::Watch, "WATCH", "WATCH ON|OFF"),
+    (Command::Buddy, "BUDDY", "BUDDY <user>"),
+    (Command::Dnd, "DND", "DND ON|OFF"),
+    (Command::Context, "CONTEXT", "CONTEXT ON|OFF"),
+    (Command::Echo, "ECHO", "ECHO ROOM ON|OFF"),
+    (Command::Reset, "RESET", "RESET"),
+    (Command::Quit, "QUIT", "QUIT"),
+    (Command::Syntax, "SYNTAX", "SYNTAX <command>"),
+    (Command::Commands, "COMMANDS", "COMMANDS"),
+    (Command::MyStats, "MYSTATS", "MYSTATS"),
+    (Command::ConnStats, "CONNSTATS", "CONNSTATS"),
+    (Command::Prefs, "PREFS", "PREFS"),
+    (Command::Limits, "LIMITS", "LIMITS"),
+    (Command::Time, "TIME", "TIME"),
+    (Command::EchoTest, "ECHOTEST", "ECHOTEST <token>"),
+    (Command::Compress, "COMPRESS", "COMPRESS"),
+    (Command::MaxLen, "MAXLEN", "MAXLEN <length>"),
+    (Command::Oper, "OPER", "OPER <password>"),
+    (Command::Kill, "KILL", "KILL <user> <reason>"),
+    (Command::Review, "REVIEW", "REVIEW"),
+    (Command::ProtoStats, "PROTOSTATS", "PROTOSTATS"),
+    (Command::Bridge, "BRIDGE", "BRIDGE <room> <user> <message>"),
+    (Command::Create, "CREATE", "CREATE <room>"),
+    (Command::Destroy, "DESTROY", "DESTROY <room>"),
+];
+
+impl Command {
+    /// The verbs of every command this build of the server understands.
+    pub fn verbs() -> Vec<&'static str> {
+        COMMANDS.iter().map(|(_, verb, _)| *verb).collect()
+    }
+
+    /// Look up a command by the verb that starts it.
+    pub fn from_verb(verb: &str) -> Option<Self> {
+        COMMANDS
+            .iter()
+            .find(|(_, v, _)| *v == verb)
+            .map(|(command, _, _)| *command)
+    }
+
+    /// The verb that starts the command.
+    pub fn verb(&self) -> &'static str {
+        self.entry().1
+    }
+
+    /// The shape of the command's arguments, such as `JOIN <room>`.
+    pub fn syntax(&self) -> &'static str {
+        self.entry().2
+    }
+
+    fn entry(&self) -> &'static (Command, &'static str, &'static str) {
+        &COMMANDS[self.index()]
+    }
+
+    /// Where the command's entry is in COMMANDS.
+    fn index(&self) -> usize {
+        // every command has an entry in COMMANDS
+        COMMANDS.iter().position(|(c, _, _)| c == self).unwrap()
+    }
+}
+
+/// How many times each command has been received since the server started. Counters are atomic so that
+/// connections can count without taking the server state lock.
+#[derive(Debug)]
+pub struct CommandCounts {
+    /// One counter per entry in COMMANDS, in the same order, so every command has one.
+    counts: [AtomicU64; COMMANDS.len()],
+}
+
+impl CommandCounts {
+    pub fn new() -> Self {
+        Self {
+            counts: [const { AtomicU64::new(0) }; COMMANDS.len()],
+        }
+    }
+
+    pub fn count(&self, command: Command) {
+        self.counts[command.index()].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The verb and count of every command, in the order of COMMANDS.
+    pub fn counts(&self) -> Vec<(&'static str, u64)> {
+        COMMANDS
+            .iter()
+            .zip(&self.counts)
+            .map(|((_, verb, _), count)| (*verb, count.load(Ordering::Relaxed)))
+            .collect()
+    }
+}
+
+impl Default for CommandCounts {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_table() {
+        for (command, verb, syntax) in COMMANDS {
+            assert_eq!(Command::from_verb(verb), Some(*command));
+            assert_eq!(command.verb(), *verb);
+            assert!(syntax.starts_with(verb));
+        }
+    }
+
+    #[test]
+    fn test_command_counts() {
+        let counts = CommandCounts::new();
+        counts.count(Command::Join);
+        counts.count(Command::Join);
+        counts.count(Command::ProtoStats);
+        let counts = counts.counts();
+        assert_eq!(counts.len(), Command::verbs().len());
+        assert!(counts.contains(&("JOIN", 2)));
+        assert!(counts.contains(&("PROTOSTATS", 1)));
+        assert!(counts.contains(&("LEAVE", 0)));
+    }
+
+    #[test]
+    fn test_every_command_has_an_entry() {
+        // each command names the one declared after it, so a variant added to Command stops this
+        // compiling until it is chained in, and the walk below then reaches it
+        fn next(command: Command) -> Option<Command> {
+            match command {
+                Command::Name => Some(Command::Join),
+                Command::Join => Some(Command::Leave),
+                Command::Leave => Some(Command::Say),
+                Command::Say => Some(Command::Users),
+                Command::Users => Some(Command::Rooms),
+                Command::Rooms => Some(Command::Pong),
+                Command::Pong => Some(Command::Debug),
+                Command::Debug => Some(Command::Mute),
+                Command::Mute => Some(Command::Unmute),
+                Command::Unmute => Some(Command::Link),
+                Command::Link => Some(Command::Preview),
+                Command::Preview => Some(Command::Status),
+                Command::Status => Some(Command::Whois),
+                Command::Whois => Some(Command::Watch),
+                Command::Watch => Some(Command::Quit),
+                Command::Quit => Some(Command::Syntax),
+                Command::Syntax => Some(Command::Dnd),
+                Command::Dnd => Some(Command::UrgentSay),
+                Command::UrgentSay => Some(Command::Reset),
+                Command::Reset => Some(Command::Commands),
+                Command::Commands => Some(Command::MyStats),
+                Command::MyStats => Some(Command::Oper),
+                Command::Oper => Some(Command::Kill),
+                Command::Kill => Some(Command::Limits),
+                Command::Limits => Some(Command::ReadOnly),
+                Command::ReadOnly => Some(Command::Review),
+                Command::Review => Some(Command::MaxLen),
+                Command::MaxLen => Some(Command::HistLen),
+                Command::HistLen => Some(Command::Echo),
+                Command::Echo => Some(Command::Shared),
+                Command::Shared => Some(Command::Bridge),
+                Command::Bridge => Some(Command::ConnStats),
+                Command::ConnStats => Some(Command::Switch),
+                Command::Switch => Some(Command::Buddy),
+                Command::Buddy => Some(Command::Prefs),
+                Command::Prefs => Some(Command::Unarchive),
+                Command::Unarchive => Some(Command::ProtoStats),
+                Command::ProtoStats => Some(Command::Context),
+                Command::Context => Some(Command::Mode),
+                Command::Mode => Some(Command::Membership),
+                Command::Membership => Some(Command::Time),
+                Command::Time => Some(Command::Edit),
+                Command::Edit => Some(Command::Delete),
+                Command::Delete => Some(Command::MyRooms),
+                Command::MyRooms => Some(Command::Create),
+                Command::Create => Some(Command::Destroy),
+                Command::Destroy => Some(Command::Recent),
+                Command::Recent => Some(Command::EchoTest),
+                Command::EchoTest => Some(Command::Compress),
+                Command::Compress => Some(Command::JoinAll),
+                Command::JoinAll => Some(Command::Topic),
+                Command::Topic => Some(Command::TopicHistory),
+                Command::TopicHistory => Some(Command::Invite),
+                Command::Invite => None,
+            }
+        }
+
+        let mut commands = vec![Command::Name];
+        while let Some(command) = next(*commands.last().unwrap()) {
+            commands.push(command);
+        }
+        // the chain follows the declaration order, so it can't skip over a variant
+        for (n, command) in commands.iter().enumerate() {
+            assert_eq!(*command as usize, n, "{}", command);
+        }
+
+        for command in &commands {
+            if cfg!(not(feature = "debug")) && *command == Command::Debug {
+                continue;
+            }
+            assert!(COMMANDS.iter().any(|(c, _, _)| c == command), "{}", command);
+        }
+        let built = commands.len() - usize::from(cfg!(not(feature = "debug")));
+        assert_eq!(COMMANDS.len(), built);
+    }
+}
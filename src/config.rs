@@ -0,0 +1,315 @@
+//! Everything that can be tuned about a server, gathered in one place. `chat-server` fills a [ServerConfig]
+//! from its command line, and programs that embed the server can build one with [ServerConfig::builder].
+//!
+use crate::server::ConnectionConfig;
+use std::{net::IpAddr, path::PathBuf, time::Duration};
+
+/// The settings of a whole server. [ServerState::with_config](crate::server_state::ServerState::with_config)
+/// applies the server-wide ones, and `connection` is handed to each client connection.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerConfig {
+    pub connection: ConnectionConfig,
+    /// Tags events relayed to linked servers.
+    pub server_name: String,
+    /// Keep rooms after their last user leaves.
+    pub persistent_rooms: bool,
+    /// Only operators create rooms, with CREATE, and rooms are kept after their last user leaves.
+    pub closed_rooms: bool,
+    /// Most rooms one user may be in at once. `None` means no limit.
+    pub max_rooms_per_user: Option<usize>,
+    /// JSON file that persistent rooms and history are saved to and restored from.
+    pub store: Option<PathBuf>,
+    /// List the rooms a private message's sender and recipient share in its DELIVERED.
+    pub shared_rooms_on_delivery: bool,
+    /// Deliver urgent private messages to users that have do not disturb on.
+    pub urgent_bypasses_dnd: bool,
+    /// File of the message of the day, re-read on reload.
+    pub motd: Option<PathBuf>,
+    /// File of names no client may take, re-read on reload.
+    pub reserved_names: Option<PathBuf>,
+    /// Rooms every user joins as soon as they register.
+    pub auto_join: Vec<String>,
+    /// Password that OPER takes. Nobody can become an operator without one.
+    pub oper_password: Option<String>,
+    /// Recent messages kept for operators to REVIEW.
+    pub review_log_size: usize,
+    /// Messages kept per room and replayed to users as they join.
+    pub history_size: usize,
+    /// How long a user has to wait between name changes.
+    pub rename_cooldown: Duration,
+    /// How long the name of a user who disconnects or renames stays locked. Zero, the default, frees it right
+    /// away.
+    pub name_lock: Duration,
+    /// Most connections that may be waiting to register at once. `None` means no limit.
+    pub max_pending_registrations: Option<usize>,
+    /// How long a room may go without a message before it is archived. `None` never archives rooms.
+    pub archive_after: Option<Duration>,
+    /// Addresses of the peer servers allowed to LINK. Nobody may link when it is empty, the default.
+    pub link_peers: Vec<IpAddr>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            connection: ConnectionConfig::default(),
+            server_name: "chat-server".to_string(),
+            persistent_rooms: false,
+            closed_rooms: false,
+            max_rooms_per_user: None,
+            store: None,
+            shared_rooms_on_delivery: false,
+            urgent_bypasses_dnd: false,
+            motd: None,
+            reserved_names: None,
+            auto_join: vec![],
+            oper_password: None,
+            review_log_size: 0,
+            history_size: 0,
+            rename_cooldown: Duration::ZERO,
+            name_lock: Duration::ZERO,
+            max_pending_registrations: None,
+            archive_after: None,
+            link_peers: vec![],
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Start from the defaults and change only what's needed.
+    pub fn builder() -> ServerConfigBuilder {
+        ServerConfigBuilder {
+            config: ServerConfig::default(),
+        }
+    }
+}
+
+/// Builds a [ServerConfig]. Every setting starts out at its default.
+#[derive(Debug, Clone)]
+pub struct ServerConfigBuilder {
+    config: ServerConfig,
+}
+
+impl ServerConfigBuilder {
+    pub fn connection(mut self, connection: ConnectionConfig) -> Self {
+        self.config.connection = connection;
+        self
+    }
+
+    pub fn server_name(mut self, server_name: impl Into<String>) -> Self {
+        self.config.server_name = server_name.into();
+        self
+    }
+
+    pub fn persistent_rooms(mut self, persistent_rooms: bool) -> Self {
+        self.config.persistent_rooms = persistent_rooms;
+        self
+    }
+
+    pub fn closed_rooms(mut self, closed_rooms: bool) -> Self {
+        self.config.closed_rooms = closed_rooms;
+        self
+    }
+
+    pub fn max_rooms_per_user(mut self, max_rooms_per_user: Option<usize>) -> Self {
+        self.config.max_rooms_per_user = max_rooms_per_user;
+        self
+    }
+
+    pub fn store(mut self, store: Option<PathBuf>) -> Self {
+        self.config.store = store;
+        self
+    }
+
+    pub fn shared_rooms_on_delivery(mut self, shared_rooms_on_delivery: bool) -> Self {
+        self.config.shared_rooms_on_delivery = shared_rooms_on_delivery;
+        self
+    }
+
+    pub fn urgent_bypasses_dnd(mut self, urgent_bypasses_dnd: bool) -> Self {
+        self.config.urgent_bypasses_dnd = urgent_bypasses_dnd;
+        self
+    }
+
+    pub fn motd(mut self, motd: Option<PathBuf>) -> Self {
+        self.config.motd = motd;
+        self
+    }
+
+    pub fn reserved_names(mut self, reserved_names: Option<PathBuf>) -> Self {
+        self.config.reserved_names = reserved_names;
+        self
+    }
+
+    pub fn auto_join(mut self, auto_join: Vec<String>) -> Self {
+        self.config.auto_join = auto_join;
+        self
+    }
+
+    pub fn oper_password(mut self, oper_password: Option<String>) -> Self {
+        self.config.oper_password = oper_password;
+        self
+    }
+
+    pub fn review_log_size(mut self, review_log_size: usize) -> Self {
+        self.config.review_log_size = review_log_size;
+        self
+    }
+
+    pub fn history_size(mut self, history_size: usize) -> Self {
+        self.config.history_size = history_size;
+        self
+    }
+
+    pub fn rename_cooldown(mut self, rename_cooldown: Duration) -> Self {
+        self.config.rename_cooldown = rename_cooldown;
+        self
+    }
+
+    pub fn name_lock(mut self, name_lock: Duration) -> Self {
+        self.config.name_lock = name_lock;
+        self
+    }
+
+    pub fn max_pending_registrations(mut self, max_pending_registrations: Option<usize>) -> Self {
+        self.config.max_pending_registrations = max_pending_registrations;
+        self
+    }
+
+    pub fn archive_after(mut self, archive_after: Option<Duration>) -> Self {
+        self.config.archive_after = archive_after;
+        self
+    }
+
+    pub fn link_peers(mut self, link_peers: Vec<IpAddr>) -> Self {
+        self.config.link_peers = link_peers;
+        self
+    }
+
+    /// Check the settings that depend on each other and return the config.
+    pub fn build(self) -> anyhow::Result<ServerConfig> {
+        let config = self.config;
+        // auto-join rooms have to be valid room names
+        if let Some(room) = config
+            .auto_join
+            .iter()
+            .find(|room| !config.connection.parser.is_valid_room(room))
+        {
+            anyhow::bail!("bad auto-join room name {}", room);
+        }
+        let parser = &config.connection.parser;
+        if parser.name_min_length > parser.name_max_length {
+            anyhow::bail!(
+                "name minimum length {} is over the maximum {}",
+                parser.name_min_length,
+                parser.name_max_length
+            );
+        }
+        if parser.room_min_length > parser.room_max_length {
+            anyhow::bail!(
+                "room minimum length {} is over the maximum {}",
+                parser.room_min_length,
+                parser.room_max_length
+            );
+        }
+        if parser.max_line_length == 0 {
+            anyhow::bail!("lines can't be limited to no bytes at all");
+        }
+        // a connection that may handle no commands between turns would never read one
+        if config.connection.command_burst == 0 {
+            anyhow::bail!("a command burst has to allow at least one command");
+        }
+        if config.store.is_some() && !config.persistent_rooms {
+            anyhow::bail!("a store needs persistent rooms");
+        }
+        if config.archive_after == Some(Duration::ZERO) {
+            anyhow::bail!("rooms can't be archived after no time at all");
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builder_defaults() {
+        assert_eq!(
+            ServerConfig::builder().build().unwrap(),
+            ServerConfig::default()
+        );
+    }
+
+    #[test]
+    fn test_builder() {
+        let config = ServerConfig::builder()
+            .server_name("alpha")
+            .history_size(10)
+            .auto_join(vec!["#lobby".to_string()])
+            .build()
+            .unwrap();
+        assert_eq!(config.server_name, "alpha");
+        assert_eq!(config.history_size, 10);
+        assert_eq!(config.auto_join, vec!["#lobby"]);
+        assert!(!config.persistent_rooms);
+
+        assert!(ServerConfig::builder()
+            .auto_join(vec!["lobby".to_string()])
+            .build()
+            .is_err());
+        assert!(ServerConfig::builder()
+            .store(Some(PathBuf::from("store.json")))
+            .build()
+            .is_err());
+        assert!(ServerConfig::builder()
+            .archive_after(Some(Duration::ZERO))
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn test_builder_length_limits() {
+        let mut connection = ConnectionConfig::default();
+        connection.parser.name_min_length = 8;
+        connection.parser.name_max_length = 8;
+        assert!(ServerConfig::builder()
+            .connection(connection)
+            .build()
+            .is_ok());
+
+        connection.parser.name_max_length = 7;
+        assert!(ServerConfig::builder()
+            .connection(connection)
+            .build()
+            .is_err());
+
+        let mut connection = ConnectionConfig::default();
+        connection.parser.room_min_length = 10;
+        connection.parser.room_max_length = 5;
+        assert!(ServerConfig::builder()
+            .connection(connection)
+            .build()
+            .is_err());
+
+        let mut connection = ConnectionConfig::default();
+        connection.parser.max_line_length = 0;
+        assert!(ServerConfig::builder()
+            .connection(connection)
+            .build()
+            .is_err());
+
+        let mut connection = ConnectionConfig {
+            command_burst: 0,
+            ..ConnectionConfig::default()
+        };
+        assert!(ServerConfig::builder()
+            .connection(connection)
+            .build()
+            .is_err());
+        connection.command_burst = 1;
+        assert!(ServerConfig::builder()
+            .connection(connection)
+            .build()
+            .is_ok());
+    }
+}
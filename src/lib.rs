@@ -1,7 +1,13 @@
 #[macro_use]
 extern crate lazy_static;
 
+pub mod codec;
+pub mod command;
+pub mod config;
+pub mod link;
+pub mod logging;
 pub mod messages;
 pub mod parser;
 pub mod server;
 pub mod server_state;
+pub mod store;
@@ -0,0 +1,352 @@
+//! Server-to-server linking. A server can link to a room on another server so that the room's SAID, JOINED,
+//! LEFT, EDITED, and REMOVED events are relayed to it and delivered to its own members of the room. Relaying is
+//! one-directional: the linking server only listens.
+//!
+//! Relayed events travel as `RELAY <origin> <event>` lines where `<origin>` is the name of the server the
+//! event happened on. A server never relays an event it received from a link, and it drops any event that
+//! carries its own name, so events can't loop between servers.
+//!
+use crate::{
+    messages::{Message, OutgoingMsg},
+    server_state::ServerState,
+};
+use anyhow::anyhow;
+use futures::SinkExt;
+use log::info;
+use std::{fmt::Display, sync::Arc};
+use tokio::{net::TcpStream, sync::Mutex};
+use tokio_stream::StreamExt;
+use tokio_util::{
+    bytes::BytesMut,
+    codec::{Decoder, Encoder, Framed, LinesCodec, LinesCodecError},
+};
+
+/// Room on top of a client's line for what relaying adds to it: the `RELAY <origin>` prefix, and the room,
+/// user, and sequence number of a SAID.
+const RELAY_OVERHEAD: usize = 256;
+
+/// A room event tagged with the name of the server it happened on.
+#[derive(Debug, PartialEq)]
+pub struct Relayed {
+    pub origin: String,
+    pub message: OutgoingMsg,
+}
+
+impl Relayed {
+    /// Parse a `RELAY <origin> <event>` line. Only room events can be relayed.
+    pub fn parse(input: &str) -> Option<Self> {
+        let mut pieces = input.splitn(3, ' ');
+        if pieces.next()? != "RELAY" {
+            return None;
+        }
+        let origin = pieces.next()?.to_string();
+        let message = parse_room_event(pieces.next()?)?;
+        Some(Self { origin, message })
+    }
+}
+
+impl Display for Relayed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "RELAY {} {}", self.origin, self.message)
+    }
+}
+
+/// A [Relayed] event can be sent to a peer server.
+impl Message for Relayed {}
+
+/// Parse the SAID, JOINED, LEFT, EDITED, and REMOVED forms of [OutgoingMsg].
+fn parse_room_event(input: &str) -> Option<OutgoingMsg> {
+    // EDITED and REMOVED lead with their verb
+    if let Some(event @ (OutgoingMsg::Edited(..) | OutgoingMsg::Removed(..))) =
+        OutgoingMsg::parse(input)
+    {
+        return Some(event);
+    }
+    let mut pieces = input.splitn(4, ' ');
+    let room = pieces.next()?.to_string();
+    let user = pieces.next()?.to_string();
+    if !room.starts_with('#') || !user.starts_with('@') {
+        return None;
+    }
+    match (pieces.next()?, pieces.next()) {
+        ("SAID", Some(said)) => {
+            let (seq, message) = said.split_once(' ')?;
+            Some(OutgoingMsg::SaidRoom(
+                room,
+                user,
+                seq.parse().ok()?,
+                message.to_string(),
+            ))
+        }
+        ("JOINED", None) => Some(OutgoingMsg::Joined(room, user)),
+        ("LEFT", None) => Some(OutgoingMsg::Left(room, user)),
+        _ => None,
+    }
+}
+
+/// Lines from a peer server. A line over the length limit is decoded as `None` and skipped, rather than as
+/// an error, which would end the framed stream.
+struct RelayCodec(LinesCodec);
+
+impl RelayCodec {
+    fn skip_long(
+        line: Result<Option<String>, LinesCodecError>,
+    ) -> Result<Option<Option<String>>, LinesCodecError> {
+        match line {
+            Ok(line) => Ok(line.map(Some)),
+            Err(LinesCodecError::MaxLineLengthExceeded) => Ok(Some(None)),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl Decoder for RelayCodec {
+    type Item = Option<String>;
+    type Error = LinesCodecError;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Option<String>>, LinesCodecError> {
+        Self::skip_long(self.0.decode(buf))
+    }
+
+    fn decode_eof(
+        &mut self,
+        buf: &mut BytesMut,
+    ) -> Result<Option<Option<String>>, LinesCodecError> {
+        Self::skip_long(self.0.decode_eof(buf))
+    }
+}
+
+impl Encoder<&str> for RelayCodec {
+    type Error = LinesCodecError;
+
+    fn encode(&mut self, line: &str, buf: &mut BytesMut) -> Result<(), LinesCodecError> {
+        self.0.encode(line, buf)
+    }
+}
+
+/// Link to `room_name` on the server at `address` and deliver its events to the local members of the room
+/// until the peer disconnects. `max_line_length` is the longest line the peer's clients may send, which
+/// relayed events are a little longer than. An event longer than that is skipped.
+pub async fn link_room(
+    server_state: Arc<Mutex<ServerState>>,
+    address: &str,
+    room_name: &str,
+    max_line_length: usize,
+) -> anyhow::Result<()> {
+    let server_name = server_state.lock().await.server_name().to_string();
+    let tcp_stream = TcpStream::connect(address).await?;
+    let mut framed = Framed::new(
+        tcp_stream,
+        RelayCodec(LinesCodec::new_with_max_length(
+            max_line_length + RELAY_OVERHEAD,
+        )),
+    );
+
+    framed
+        .send(format!("LINK {} {}", room_name, server_name).as_str())
+        .await?;
+    info!("linked {} on {}", room_name, address);
+
+    while let Some(line) = framed.next().await {
+        let Some(line) = line.map_err(|e| anyhow!(e))? else {
+            info!("link {} skipped an event over the line length", address);
+            continue;
+        };
+        if line == "PING" {
+            framed.send("PONG").await?;
+            continue;
+        }
+        match Relayed::parse(&line) {
+            // never deliver an event that started here
+            Some(relayed) if relayed.origin == server_name => {}
+            Some(relayed) => {
+                let mut state = server_state.lock().await;
+                if let Err(e) = state.deliver_relayed(&relayed.origin, relayed.message) {
+                    info!("link {} dropped event: {}", address, e);
+                }
+            }
+            // CONNECTED and anything else that isn't a relayed event
+            None => {}
+        }
+    }
+
+    info!("link to {} closed", address);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        parser::{ParserConfig, MAX_LINE_LENGTH},
+        server::{run_server, ConnectionConfig},
+    };
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+
+    /// Start a server on an ephemeral port and return its address.
+    async fn start_server(server_state: Arc<Mutex<ServerState>>) -> String {
+        start_server_with(server_state, ConnectionConfig::default()).await
+    }
+
+    async fn start_server_with(
+        server_state: Arc<Mutex<ServerState>>,
+        config: ConnectionConfig,
+    ) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(run_server(server_state, listener, config));
+        address
+    }
+
+    async fn connect_user(address: &str, commands: &[&str]) -> Framed<TcpStream, LinesCodec> {
+        let tcp_stream = TcpStream::connect(address).await.unwrap();
+        let mut framed = Framed::new(tcp_stream, LinesCodec::new());
+        assert_eq!(framed.next().await.unwrap().unwrap(), "CONNECTED");
+        for command in commands {
+            framed.send(command.to_string()).await.unwrap();
+        }
+        assert_eq!(framed.next().await.unwrap().unwrap(), "REGISTERED");
+        framed
+    }
+
+    #[test]
+    fn test_relayed_round_trip() {
+        for message in [
+            OutgoingMsg::SaidRoom(
+                "#sports".to_string(),
+                "@robert".to_string(),
+                1,
+                "good game".to_string(),
+            ),
+            OutgoingMsg::Joined("#sports".to_string(), "@robert".to_string()),
+            OutgoingMsg::Left("#sports".to_string(), "@robert".to_string()),
+            OutgoingMsg::Edited("#sports".to_string(), 1, "good game!".to_string()),
+            OutgoingMsg::Removed("#sports".to_string(), 1),
+        ] {
+            let relayed = Relayed {
+                origin: "alpha".to_string(),
+                message,
+            };
+            assert_eq!(Relayed::parse(&relayed.to_string()), Some(relayed));
+        }
+        assert_eq!(Relayed::parse("RELAY alpha @robert SAID hi"), None);
+        assert_eq!(Relayed::parse("RELAY alpha PING"), None);
+        assert_eq!(Relayed::parse("#sports @robert SAID hi"), None);
+    }
+
+    #[tokio::test]
+    async fn test_link_room() {
+        let mut alpha = ServerState::new();
+        alpha.set_server_name("alpha".to_string());
+        alpha.set_link_peers(vec!["127.0.0.1".parse().unwrap()]);
+        let alpha = Arc::new(Mutex::new(alpha));
+        let alpha_address = start_server(alpha.clone()).await;
+
+        let mut beta = ServerState::new();
+        beta.set_server_name("beta".to_string());
+        let beta = Arc::new(Mutex::new(beta));
+        let beta_address = start_server(beta.clone()).await;
+
+        // beta listens to #sports on alpha
+        let link_state = beta.clone();
+        let link_address = alpha_address.clone();
+        tokio::spawn(async move {
+            link_room(link_state, &link_address, "#sports", MAX_LINE_LENGTH).await
+        });
+        while !alpha.lock().await.is_linked("#sports") {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let mut kelsey = connect_user(&beta_address, &["NAME @kelsey", "JOIN #sports"]).await;
+        // kelsey's JOIN has been handled once the ROOMS reply arrives
+        kelsey.send("ROOMS").await.unwrap();
+        assert_eq!(kelsey.next().await.unwrap().unwrap(), "ROOM #sports");
+
+        let mut robert = connect_user(&alpha_address, &["NAME @robert", "JOIN #sports"]).await;
+        assert_eq!(
+            kelsey.next().await.unwrap().unwrap(),
+            "#sports @robert JOINED"
+        );
+
+        robert.send("SAY #sports good game").await.unwrap();
+        assert_eq!(
+            kelsey.next().await.unwrap().unwrap(),
+            "#sports @robert SAID 1 good game"
+        );
+
+        robert.send("LEAVE #sports").await.unwrap();
+        assert_eq!(
+            kelsey.next().await.unwrap().unwrap(),
+            "#sports @robert LEFT"
+        );
+
+        // the room went away with its last member, and the link with it
+        while alpha.lock().await.is_linked("#sports") {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_link_room_long_line() {
+        // alpha's clients may send longer lines than the link expects
+        let mut alpha = ServerState::new();
+        alpha.set_server_name("alpha".to_string());
+        alpha.set_link_peers(vec!["127.0.0.1".parse().unwrap()]);
+        let alpha = Arc::new(Mutex::new(alpha));
+        let alpha_address = start_server_with(
+            alpha.clone(),
+            ConnectionConfig {
+                parser: ParserConfig {
+                    max_line_length: 4 * MAX_LINE_LENGTH,
+                    ..ParserConfig::default()
+                },
+                ..ConnectionConfig::default()
+            },
+        )
+        .await;
+
+        let mut beta = ServerState::new();
+        beta.set_server_name("beta".to_string());
+        let beta = Arc::new(Mutex::new(beta));
+        let beta_address = start_server(beta.clone()).await;
+
+        let mut robert = connect_user(&alpha_address, &["NAME @robert", "JOIN #sports"]).await;
+        robert.send("ROOMS").await.unwrap();
+        assert_eq!(robert.next().await.unwrap().unwrap(), "ROOM #sports");
+        let link_state = beta.clone();
+        let link_address = alpha_address.clone();
+        let link = tokio::spawn(async move {
+            link_room(link_state, &link_address, "#sports", MAX_LINE_LENGTH).await
+        });
+        while !alpha.lock().await.is_linked("#sports") {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let mut kelsey = connect_user(&beta_address, &["NAME @kelsey", "JOIN #sports"]).await;
+        kelsey.send("ROOMS").await.unwrap();
+        assert_eq!(kelsey.next().await.unwrap().unwrap(), "ROOM #sports");
+
+        // a line that only fits within the relay overhead still goes through
+        let message = "a".repeat(MAX_LINE_LENGTH - "SAY #sports ".len());
+        robert
+            .send(format!("SAY #sports {}", message))
+            .await
+            .unwrap();
+        assert_eq!(
+            kelsey.next().await.unwrap().unwrap(),
+            format!("#sports @robert SAID 1 {}", message)
+        );
+
+        // one that doesn't is skipped, and the link carries on
+        robert
+            .send(format!("SAY #sports {}", "b".repeat(2 * MAX_LINE_LENGTH)))
+            .await
+            .unwrap();
+        robert.send("SAY #sports still here").await.unwrap();
+        assert_eq!(
+            kelsey.next().await.unwrap().unwrap(),
+            "#sports @robert SAID 2 still here"
+        );
+        assert!(!link.is_finished());
+    }
+}
@@ -0,0 +1,43 @@
+//! Logging for binaries and embedders. The library only ever logs through the [log] facade, so it never
+//! needs a logger: without one, log calls do nothing. Programs that want output can call [init], or install
+//! any other `log` implementation instead, such as `tracing_log::LogTracer` to route everything through
+//! `tracing`.
+//!
+
+/// Send log output to STDERR, filtered by the `RUST_LOG` environment variable. Returns `false`, and changes
+/// nothing, if a logger was already installed, so calling it more than once is harmless.
+pub fn init() -> bool {
+    env_logger::try_init().is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{parser::parse_incoming, server_state::ServerState, store::FileStore};
+
+    #[test]
+    fn test_logging_is_optional() {
+        // code that logs works the same before any logger is installed. saving rooms fails here
+        // because the store's directory doesn't exist, which is logged instead of returned.
+        let dir = tempfile::tempdir().unwrap();
+        let mut state = ServerState::new();
+        state.set_persistent_rooms(true);
+        state
+            .set_store(Box::new(
+                FileStore::open(dir.path().join("missing/rooms.json")).unwrap(),
+            ))
+            .unwrap();
+        assert!(parse_incoming("JOIN #sports")
+            .to_string()
+            .contains("JOIN #sports"));
+        let (sender, _receiver) = tokio::sync::mpsc::unbounded_channel();
+        state
+            .add_user(
+                "@robert".to_string(),
+                crate::server_state::User::new(sender),
+            )
+            .unwrap();
+        assert!(state
+            .join_room("#sports".to_string(), "@robert".to_string())
+            .is_ok());
+    }
+}
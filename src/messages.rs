@@ -1,7 +1,12 @@
 //! [OutgoingMsg] and [IncomingMsg] codify the messages that are sent between clients and the server. These messages
 //! are defined by the chat protocol. ERROR messages are codified for each error type in various other modules.
 //!
-use std::fmt::Display;
+//! Both are meant for reuse by clients written in Rust. Every message is written to the wire with [Display], and
+//! read back with [crate::parser::parse_incoming] for client messages or [OutgoingMsg::parse] for server
+//! messages.
+//!
+use crate::command::Command;
+use std::{fmt::Display, time::Duration};
 
 /// [Message] trait signifies to the rest of the code that a piece of data is able to be sent/received between
 /// the clients and the server.
@@ -16,10 +21,10 @@ pub enum OutgoingMsg {
     Connected,
     /// REGISTERED
     Registered,
-    /// SAID from message
-    SaidUser(String, String),
-    /// SAID room-name from message
-    SaidRoom(String, String, String),
+    /// SAID from message, or SAID! when urgent
+    SaidUser(String, String, bool),
+    /// room-name from SAID seq message
+    SaidRoom(String, String, u64, String),
     /// ROOM room-name
     Room(String),
     /// USER user-name
@@ -28,6 +33,66 @@ pub enum OutgoingMsg {
     Joined(String, String),
     /// LEFT room-name user-name
     Left(String, String),
+    /// NOTICE message
+    Notice(String),
+    /// STATUS user-name [message]
+    Status(String, Option<String>),
+    /// DELIVERED user-name [room-name ...]
+    Delivered(String, Vec<String>),
+    /// CREATED room-name
+    RoomCreated(String),
+    /// DELETED room-name
+    RoomDeleted(String),
+    /// ONLINE user-name
+    Online(String),
+    /// COMMANDS <verb> ... - every command the server understands
+    Commands(Vec<String>),
+    /// MYSTATS messages-sent rooms-joined session-seconds
+    MyStats(u64, usize, Duration),
+    /// DISCONNECT reason
+    Disconnect(String),
+    /// LIMIT name value
+    Limit(String, u64),
+    /// REVIEW sent-at to from message
+    Review(u64, String, String, String),
+    /// HISTORY line - a room message sent before the user joined
+    History(String),
+    /// ECHO room-name user-name SAID seq message - the user's own room message
+    EchoRoom(String, String, u64, String),
+    /// SHARED user-name [room-name ...]
+    Shared(String, Vec<String>),
+    /// CONNSTATS messages-in bytes-in messages-out bytes-out
+    ConnStats(u64, u64, u64, u64),
+    /// PRESENCE user-name ONLINE|OFFLINE - a user on the buddy list came or went
+    Presence(String, bool),
+    /// PREF name value - one of the client's own preferences
+    Pref(String, String),
+    /// PROTOSTAT verb count - how many times a command has been received
+    ProtoStat(String, u64),
+    /// CONTEXT user-name [room-name ...] - the rooms shared with the sender of the private message that follows
+    Context(String, Vec<String>),
+    /// MODE room-name +modes - the modes a room has set
+    Mode(String, Vec<RoomMode>),
+    /// MEMBER room-name user-name JOINED|LEFT member-count [status] - a membership change, for the owner
+    Member(String, String, bool, usize, Option<String>),
+    /// OK - the server is up, in reply to HEALTH
+    Healthy,
+    /// TIME millis - the server's clock, in milliseconds since the Unix epoch
+    Time(u64),
+    /// EDITED room-name seq message - a message in the room was edited
+    Edited(String, u64, String),
+    /// REMOVED room-name seq - a message in the room was deleted
+    Removed(String, u64),
+    /// ROOMINFO room-name member-count - a room the client is in, for MYROOMS full
+    RoomInfo(String, usize),
+    /// ECHOTEST token - the reply to ECHOTEST, with the same token
+    EchoTest(String),
+    /// COMPRESS - compression agreed; every line after this one is a compressed frame
+    Compress,
+    /// TOPIC room-name user-name topic - the room's topic was changed
+    Topic(String, String, String),
+    /// TOPICHISTORY set-at room-name user-name topic - an earlier topic of a room
+    TopicHistory(u64, String, String, String),
 }
 
 impl Message for OutgoingMsg {}
@@ -38,12 +103,359 @@ impl Display for OutgoingMsg {
             Self::Ping => write!(f, "PING"),
             Self::Connected => write!(f, "CONNECTED"),
             Self::Registered => write!(f, "REGISTERED"),
-            Self::SaidUser(from, message) => write!(f, "{} SAID {}", from, message),
-            Self::SaidRoom(room, from, message) => write!(f, "{} {} SAID {}", room, from, message),
+            Self::SaidUser(from, message, false) => write!(f, "{} SAID {}", from, message),
+            Self::SaidUser(from, message, true) => write!(f, "{} SAID! {}", from, message),
+            Self::SaidRoom(room, from, seq, message) => {
+                write!(f, "{} {} SAID {} {}", room, from, seq, message)
+            }
             Self::Room(room) => write!(f, "ROOM {}", room),
             Self::User(name) => write!(f, "USER {}", name),
             Self::Joined(room, user) => write!(f, "{} {} JOINED", room, user),
             Self::Left(room, user) => write!(f, "{} {} LEFT", room, user),
+            Self::Notice(message) => write!(f, "NOTICE {}", message),
+            Self::Status(name, Some(status)) => write!(f, "STATUS {} {}", name, status),
+            Self::Status(name, None) => write!(f, "STATUS {}", name),
+            Self::Delivered(name, rooms) => {
+                write!(f, "DELIVERED {}", name)?;
+                for room in rooms {
+                    write!(f, " {}", room)?;
+                }
+                Ok(())
+            }
+            Self::RoomCreated(room) => write!(f, "CREATED {}", room),
+            Self::RoomDeleted(room) => write!(f, "DELETED {}", room),
+            Self::Online(name) => write!(f, "ONLINE {}", name),
+            Self::Commands(verbs) => write!(f, "COMMANDS {}", verbs.join(" ")),
+            Self::MyStats(messages_sent, rooms, session) => {
+                write!(
+                    f,
+                    "MYSTATS {} {} {}",
+                    messages_sent,
+                    rooms,
+                    session.as_secs()
+                )
+            }
+            Self::Disconnect(reason) => write!(f, "DISCONNECT {}", reason),
+            Self::Limit(name, value) => write!(f, "LIMIT {} {}", name, value),
+            Self::Review(sent_at, to, from, message) => {
+                write!(f, "REVIEW {} {} {} {}", sent_at, to, from, message)
+            }
+            Self::History(line) => write!(f, "HISTORY {}", line),
+            Self::EchoRoom(room, from, seq, message) => {
+                write!(f, "ECHO {} {} SAID {} {}", room, from, seq, message)
+            }
+            Self::Shared(name, rooms) => {
+                write!(f, "SHARED {}", name)?;
+                for room in rooms {
+                    write!(f, " {}", room)?;
+                }
+                Ok(())
+            }
+            Self::ConnStats(messages_in, bytes_in, messages_out, bytes_out) => write!(
+                f,
+                "CONNSTATS {} {} {} {}",
+                messages_in, bytes_in, messages_out, bytes_out
+            ),
+            Self::Presence(name, true) => write!(f, "PRESENCE {} ONLINE", name),
+            Self::Presence(name, false) => write!(f, "PRESENCE {} OFFLINE", name),
+            Self::Pref(name, value) => write!(f, "PREF {} {}", name, value),
+            Self::ProtoStat(verb, count) => write!(f, "PROTOSTAT {} {}", verb, count),
+            Self::Context(name, rooms) => {
+                write!(f, "CONTEXT {}", name)?;
+                for room in rooms {
+                    write!(f, " {}", room)?;
+                }
+                Ok(())
+            }
+            Self::Mode(room, modes) => {
+                write!(f, "MODE {} +", room)?;
+                for mode in modes {
+                    write!(f, "{}", mode)?;
+                }
+                Ok(())
+            }
+            Self::Member(room, user, joined, members, status) => {
+                let change = if *joined { "JOINED" } else { "LEFT" };
+                write!(f, "MEMBER {} {} {} {}", room, user, change, members)?;
+                if let Some(status) = status {
+                    write!(f, " {}", status)?;
+                }
+                Ok(())
+            }
+            Self::Healthy => write!(f, "OK"),
+            Self::Time(millis) => write!(f, "TIME {}", millis),
+            Self::Edited(room, seq, message) => write!(f, "EDITED {} {} {}", room, seq, message),
+            Self::Removed(room, seq) => write!(f, "REMOVED {} {}", room, seq),
+            Self::RoomInfo(room, members) => write!(f, "ROOMINFO {} {}", room, members),
+            Self::EchoTest(token) => write!(f, "ECHOTEST {}", token),
+            Self::Compress => write!(f, "COMPRESS"),
+            Self::Topic(room, user, topic) => write!(f, "TOPIC {} {} {}", room, user, topic),
+            Self::TopicHistory(set_at, room, user, topic) => {
+                write!(f, "TOPICHISTORY {} {} {} {}", set_at, room, user, topic)
+            }
+        }
+    }
+}
+
+impl OutgoingMsg {
+    /// Turn a line from the server back into the message it was written from, the inverse of [Display], so
+    /// that clients can tell messages apart without matching strings. Returns `None` for anything that isn't
+    /// an [OutgoingMsg], such as an ERROR reply or a message from a newer server.
+    pub fn parse(line: &str) -> Option<Self> {
+        let (first, rest) = match line.split_once(' ') {
+            Some((first, rest)) => (first, Some(rest)),
+            None => (line, None),
+        };
+        let message = match (first, rest) {
+            ("PING", None) => Self::Ping,
+            ("CONNECTED", None) => Self::Connected,
+            ("REGISTERED", None) => Self::Registered,
+            ("OK", None) => Self::Healthy,
+            ("COMPRESS", None) => Self::Compress,
+            ("ROOM", Some(room)) => Self::Room(room.to_string()),
+            ("ROOMINFO", Some(rest)) => {
+                let (room, members) = rest.split_once(' ')?;
+                Self::RoomInfo(room.to_string(), members.parse().ok()?)
+            }
+            ("USER", Some(name)) => Self::User(name.to_string()),
+            ("NOTICE", Some(message)) => Self::Notice(message.to_string()),
+            ("STATUS", Some(rest)) => match rest.split_once(' ') {
+                Some((name, status)) => Self::Status(name.to_string(), Some(status.to_string())),
+                None => Self::Status(rest.to_string(), None),
+            },
+            ("DELIVERED", Some(rest)) => {
+                let (name, rooms) = name_and_rooms(rest)?;
+                Self::Delivered(name, rooms)
+            }
+            ("CREATED", Some(room)) => Self::RoomCreated(room.to_string()),
+            ("DELETED", Some(room)) => Self::RoomDeleted(room.to_string()),
+            ("ONLINE", Some(name)) => Self::Online(name.to_string()),
+            ("COMMANDS", Some(verbs)) => Self::Commands(
+                verbs
+                    .split(' ')
+                    .filter(|verb| !verb.is_empty())
+                    .map(String::from)
+                    .collect(),
+            ),
+            ("MYSTATS", Some(rest)) => {
+                let [messages_sent, rooms, session] = numbers(rest)?;
+                Self::MyStats(
+                    messages_sent,
+                    rooms.try_into().ok()?,
+                    Duration::from_secs(session),
+                )
+            }
+            ("DISCONNECT", Some(reason)) => Self::Disconnect(reason.to_string()),
+            ("LIMIT", Some(rest)) => {
+                let (name, value) = rest.split_once(' ')?;
+                Self::Limit(name.to_string(), value.parse().ok()?)
+            }
+            ("REVIEW", Some(rest)) => {
+                let mut pieces = rest.splitn(4, ' ');
+                Self::Review(
+                    pieces.next()?.parse().ok()?,
+                    pieces.next()?.to_string(),
+                    pieces.next()?.to_string(),
+                    pieces.next()?.to_string(),
+                )
+            }
+            ("HISTORY", Some(line)) => Self::History(line.to_string()),
+            ("TIME", Some(millis)) => Self::Time(millis.parse().ok()?),
+            ("EDITED", Some(rest)) => {
+                let mut pieces = rest.splitn(3, ' ');
+                Self::Edited(
+                    pieces.next()?.to_string(),
+                    pieces.next()?.parse().ok()?,
+                    pieces.next()?.to_string(),
+                )
+            }
+            ("ECHOTEST", Some(token)) => Self::EchoTest(token.to_string()),
+            ("TOPIC", Some(rest)) => {
+                let mut pieces = rest.splitn(3, ' ');
+                Self::Topic(
+                    pieces.next()?.to_string(),
+                    pieces.next()?.to_string(),
+                    pieces.next()?.to_string(),
+                )
+            }
+            ("TOPICHISTORY", Some(rest)) => {
+                let mut pieces = rest.splitn(4, ' ');
+                Self::TopicHistory(
+                    pieces.next()?.parse().ok()?,
+                    pieces.next()?.to_string(),
+                    pieces.next()?.to_string(),
+                    pieces.next()?.to_string(),
+                )
+            }
+            ("REMOVED", Some(rest)) => {
+                let (room, seq) = rest.split_once(' ')?;
+                Self::Removed(room.to_string(), seq.parse().ok()?)
+            }
+            ("ECHO", Some(rest)) => match Self::parse(rest)? {
+                Self::SaidRoom(room, from, seq, message) => {
+                    Self::EchoRoom(room, from, seq, message)
+                }
+                _ => return None,
+            },
+            ("SHARED", Some(rest)) => {
+                let (name, rooms) = name_and_rooms(rest)?;
+                Self::Shared(name, rooms)
+            }
+            ("CONNSTATS", Some(rest)) => {
+                let [messages_in, bytes_in, messages_out, bytes_out] = numbers(rest)?;
+                Self::ConnStats(messages_in, bytes_in, messages_out, bytes_out)
+            }
+            ("PRESENCE", Some(rest)) => match rest.split_once(' ')? {
+                (name, "ONLINE") => Self::Presence(name.to_string(), true),
+                (name, "OFFLINE") => Self::Presence(name.to_string(), false),
+                _ => return None,
+            },
+            ("PREF", Some(rest)) => {
+                let (name, value) = rest.split_once(' ')?;
+                Self::Pref(name.to_string(), value.to_string())
+            }
+            ("PROTOSTAT", Some(rest)) => {
+                let (verb, count) = rest.split_once(' ')?;
+                Self::ProtoStat(verb.to_string(), count.parse().ok()?)
+            }
+            ("CONTEXT", Some(rest)) => {
+                let (name, rooms) = name_and_rooms(rest)?;
+                Self::Context(name, rooms)
+            }
+            ("MODE", Some(rest)) => {
+                let (room, modes) = rest.split_once(' ')?;
+                let modes = modes
+                    .strip_prefix('+')?
+                    .chars()
+                    .map(RoomMode::from_letter)
+                    .collect::<Option<_>>()?;
+                Self::Mode(room.to_string(), modes)
+            }
+            ("MEMBER", Some(rest)) => {
+                let mut pieces = rest.splitn(5, ' ');
+                let (room, user) = (pieces.next()?, pieces.next()?);
+                let joined = match pieces.next()? {
+                    "JOINED" => true,
+                    "LEFT" => false,
+                    _ => return None,
+                };
+                Self::Member(
+                    room.to_string(),
+                    user.to_string(),
+                    joined,
+                    pieces.next()?.parse().ok()?,
+                    pieces.next().map(String::from),
+                )
+            }
+            // messages about a room start with the room's name
+            (room, Some(rest)) if room.starts_with('#') => {
+                let mut pieces = rest.splitn(3, ' ');
+                let (user, event) = (pieces.next()?, pieces.next()?);
+                match (event, pieces.next()) {
+                    ("JOINED", None) => Self::Joined(room.to_string(), user.to_string()),
+                    ("LEFT", None) => Self::Left(room.to_string(), user.to_string()),
+                    ("SAID", Some(rest)) => {
+                        let (seq, message) = rest.split_once(' ')?;
+                        Self::SaidRoom(
+                            room.to_string(),
+                            user.to_string(),
+                            seq.parse().ok()?,
+                            message.to_string(),
+                        )
+                    }
+                    _ => return None,
+                }
+            }
+            // private messages start with the sender's name
+            (from, Some(rest)) if from.starts_with('@') => match rest.split_once(' ')? {
+                ("SAID", message) => Self::SaidUser(from.to_string(), message.to_string(), false),
+                ("SAID!", message) => Self::SaidUser(from.to_string(), message.to_string(), true),
+                _ => return None,
+            },
+            _ => return None,
+        };
+        Some(message)
+    }
+}
+
+/// Split `<name> [<room> ...]`, as in DELIVERED, SHARED, and CONTEXT.
+fn name_and_rooms(rest: &str) -> Option<(String, Vec<String>)> {
+    let mut pieces = rest.split(' ');
+    let name = pieces.next().filter(|name| !name.is_empty())?;
+    Some((name.to_string(), pieces.map(String::from).collect()))
+}
+
+/// Parse exactly `N` whole numbers separated by spaces.
+fn numbers<const N: usize>(rest: &str) -> Option<[u64; N]> {
+    let numbers = rest
+        .split(' ')
+        .map(|number| number.parse().ok())
+        .collect::<Option<Vec<u64>>>()?;
+    numbers.try_into().ok()
+}
+
+/// How the users of a room are ordered in reply to USERS.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum UserSort {
+    /// Most recently active first.
+    Active,
+}
+
+impl Display for UserSort {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Active => write!(f, "active"),
+        }
+    }
+}
+
+/// Which rooms are listed in reply to ROOMS.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum RoomFilter {
+    /// Rooms that nobody is in, such as persistent rooms.
+    Empty,
+    /// Rooms with at least one member.
+    Active,
+}
+
+impl Display for RoomFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Empty => write!(f, "empty"),
+            Self::Active => write!(f, "active"),
+        }
+    }
+}
+
+/// A mode that a room's owner can set with MODE.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum RoomMode {
+    /// Nobody may join the room, so only its current members stay in it.
+    InviteOnly,
+    /// Only the owner may speak in the room, the same as READONLY.
+    Moderated,
+    /// Only the owner may change the topic.
+    TopicLocked,
+}
+
+impl RoomMode {
+    /// The mode a letter in a MODE message stands for.
+    pub fn from_letter(letter: char) -> Option<Self> {
+        match letter {
+            'i' => Some(Self::InviteOnly),
+            'm' => Some(Self::Moderated),
+            't' => Some(Self::TopicLocked),
+            _ => None,
+        }
+    }
+}
+
+impl Display for RoomMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InviteOnly => write!(f, "i"),
+            Self::Moderated => write!(f, "m"),
+            Self::TopicLocked => write!(f, "t"),
         }
     }
 }
@@ -59,16 +471,175 @@ pub enum IncomingMsg {
     Leave(String),
     /// SAY room-name message
     SayRoom(String, String),
-    /// SAY user-name message
-    SayUser(String, String),
-    /// USERS room-name
-    Users(String),
-    /// ROOMS
-    Rooms,
+    /// SAY user-name message, or SAY! when urgent
+    SayUser(String, String, bool),
+    /// SAY user-name,user-name,... message - the same private message to each user
+    SayUsers(Vec<String>, String),
+    /// USERS room-name [sort]
+    Users(String, Option<UserSort>),
+    /// ROOMS [filter]
+    Rooms(Option<RoomFilter>),
     /// QUIT
     Quit,
     /// PONG
     Pong,
+    /// DEBUG on|off
+    Debug(bool),
+    /// MUTE room-name user-name
+    Mute(String, String),
+    /// UNMUTE room-name user-name
+    Unmute(String, String),
+    /// LINK room-name server-name
+    Link(String, String),
+    /// PREVIEW SAY room-name message
+    PreviewSayRoom(String, String),
+    /// STATUS [message]
+    Status(Option<String>),
+    /// WHOIS user-name
+    Whois(String),
+    /// WATCH on|off
+    Watch(bool),
+    /// SYNTAX command
+    Syntax(Command),
+    /// DND on|off
+    Dnd(bool),
+    /// RESET
+    Reset,
+    /// COMMANDS
+    Commands,
+    /// MYSTATS
+    MyStats,
+    /// OPER password
+    Oper(String),
+    /// KILL user-name reason
+    Kill(String, String),
+    /// LIMITS
+    Limits,
+    /// READONLY room-name on|off
+    ReadOnly(String, bool),
+    /// REVIEW
+    Review,
+    /// MAXLEN length
+    MaxLen(usize),
+    /// HISTLEN count
+    HistLen(usize),
+    /// ECHO ROOM on|off
+    EchoRoom(bool),
+    /// SHARED user-name
+    Shared(String),
+    /// BRIDGE room-name display-name message
+    Bridge(String, String, String),
+    /// CONNSTATS
+    ConnStats,
+    /// SWITCH old-room-name new-room-name
+    Switch(String, String),
+    /// BUDDY user-name
+    Buddy(String),
+    /// PREFS
+    Prefs,
+    /// UNARCHIVE room-name
+    Unarchive(String),
+    /// PROTOSTATS
+    ProtoStats,
+    /// CONTEXT ON|OFF
+    Context(bool),
+    /// MODE room-name [+mode|-mode]
+    Mode(String, Option<(RoomMode, bool)>),
+    /// MEMBERSHIP room-name ON|OFF
+    Membership(String, bool),
+    /// HEALTH - a load balancer's liveness probe. Not listed by COMMANDS
+    Health,
+    /// TIME
+    Time,
+    /// EDIT room-name seq message
+    Edit(String, u64, String),
+    /// DELETE room-name seq
+    Delete(String, u64),
+    /// MYROOMS [full]
+    MyRooms(bool),
+    /// CREATE room-name
+    Create(String),
+    /// DESTROY room-name
+    Destroy(String),
+    /// RECENT room-name count
+    Recent(String, usize),
+    /// ECHOTEST token
+    EchoTest(String),
+    /// COMPRESS
+    Compress,
+    /// JOINALL room-name room-name ...
+    JoinAll(Vec<String>),
+    /// TOPIC room-name topic
+    Topic(String, String),
+    /// TOPICHISTORY room-name
+    TopicHistory(String),
+    /// INVITE room-name user-name
+    Invite(String, String),
+}
+
+impl IncomingMsg {
+    /// The text the client wants delivered to someone, for the messages that carry any. Every variant is
+    /// named so that a new one has to be sorted into one side or the other.
+    pub fn message(&self) -> Option<&str> {
+        match self {
+            Self::SayRoom(_, message)
+            | Self::SayUser(_, message, _)
+            | Self::SayUsers(_, message)
+            | Self::PreviewSayRoom(_, message)
+            | Self::Bridge(_, _, message)
+            | Self::Edit(_, _, message)
+            | Self::Topic(_, message) => Some(message),
+            Self::Name(..)
+            | Self::Join(..)
+            | Self::Leave(..)
+            | Self::Users(..)
+            | Self::Rooms(..)
+            | Self::Quit
+            | Self::Pong
+            | Self::Debug(..)
+            | Self::Mute(..)
+            | Self::Unmute(..)
+            | Self::Link(..)
+            | Self::Status(..)
+            | Self::Whois(..)
+            | Self::Watch(..)
+            | Self::Syntax(..)
+            | Self::Dnd(..)
+            | Self::Reset
+            | Self::Commands
+            | Self::MyStats
+            | Self::Oper(..)
+            | Self::Kill(..)
+            | Self::Limits
+            | Self::ReadOnly(..)
+            | Self::Review
+            | Self::MaxLen(..)
+            | Self::HistLen(..)
+            | Self::EchoRoom(..)
+            | Self::Shared(..)
+            | Self::ConnStats
+            | Self::Switch(..)
+            | Self::Buddy(..)
+            | Self::Prefs
+            | Self::Unarchive(..)
+            | Self::ProtoStats
+            | Self::Context(..)
+            | Self::Mode(..)
+            | Self::Membership(..)
+            | Self::Health
+            | Self::Time
+            | Self::Delete(..)
+            | Self::MyRooms(..)
+            | Self::Create(..)
+            | Self::Destroy(..)
+            | Self::Recent(..)
+            | Self::EchoTest(..)
+            | Self::Compress
+            | Self::JoinAll(..)
+            | Self::TopicHistory(..)
+            | Self::Invite(..) => None,
+        }
+    }
 }
 
 impl Display for IncomingMsg {
@@ -78,11 +649,197 @@ impl Display for IncomingMsg {
             Self::Join(room) => write!(f, "JOIN {}", room),
             Self::Leave(room) => write!(f, "LEAVE {}", room),
             Self::SayRoom(room, message) => write!(f, "SAY {} {}", room, message),
-            Self::SayUser(name, message) => write!(f, "SAY {} {}", name, message),
-            Self::Users(room) => write!(f, "USERS {}", room),
-            Self::Rooms => write!(f, "ROOMS"),
+            Self::SayUser(name, message, false) => write!(f, "SAY {} {}", name, message),
+            Self::SayUser(name, message, true) => write!(f, "SAY! {} {}", name, message),
+            Self::SayUsers(names, message) => write!(f, "SAY {} {}", names.join(","), message),
+            Self::Users(room, Some(sort)) => write!(f, "USERS {} {}", room, sort),
+            Self::Users(room, None) => write!(f, "USERS {}", room),
+            Self::Rooms(None) => write!(f, "ROOMS"),
+            Self::Rooms(Some(filter)) => write!(f, "ROOMS {}", filter),
             Self::Quit => write!(f, "QUIT"),
             Self::Pong => write!(f, "PONG"),
+            Self::Debug(true) => write!(f, "DEBUG ON"),
+            Self::Debug(false) => write!(f, "DEBUG OFF"),
+            Self::Mute(room, name) => write!(f, "MUTE {} {}", room, name),
+            Self::Unmute(room, name) => write!(f, "UNMUTE {} {}", room, name),
+            Self::Link(room, server) => write!(f, "LINK {} {}", room, server),
+            Self::PreviewSayRoom(room, message) => {
+                write!(f, "PREVIEW SAY {} {}", room, message)
+            }
+            Self::Status(Some(status)) => write!(f, "STATUS {}", status),
+            Self::Status(None) => write!(f, "STATUS"),
+            Self::Whois(name) => write!(f, "WHOIS {}", name),
+            Self::Watch(true) => write!(f, "WATCH ON"),
+            Self::Watch(false) => write!(f, "WATCH OFF"),
+            Self::Syntax(command) => write!(f, "SYNTAX {}", command.verb()),
+            Self::Dnd(true) => write!(f, "DND ON"),
+            Self::Dnd(false) => write!(f, "DND OFF"),
+            Self::Reset => write!(f, "RESET"),
+            Self::Commands => write!(f, "COMMANDS"),
+            Self::MyStats => write!(f, "MYSTATS"),
+            // keep the password out of logs and DEBUG output
+            Self::Oper(_) => write!(f, "OPER ********"),
+            Self::Kill(name, reason) => write!(f, "KILL {} {}", name, reason),
+            Self::Limits => write!(f, "LIMITS"),
+            Self::ReadOnly(room, true) => write!(f, "READONLY {} ON", room),
+            Self::ReadOnly(room, false) => write!(f, "READONLY {} OFF", room),
+            Self::Review => write!(f, "REVIEW"),
+            Self::MaxLen(length) => write!(f, "MAXLEN {}", length),
+            Self::HistLen(count) => write!(f, "HISTLEN {}", count),
+            Self::EchoRoom(true) => write!(f, "ECHO ROOM ON"),
+            Self::EchoRoom(false) => write!(f, "ECHO ROOM OFF"),
+            Self::Shared(name) => write!(f, "SHARED {}", name),
+            Self::Bridge(room, display_name, message) => {
+                write!(f, "BRIDGE {} {} {}", room, display_name, message)
+            }
+            Self::ConnStats => write!(f, "CONNSTATS"),
+            Self::Switch(old_room, new_room) => write!(f, "SWITCH {} {}", old_room, new_room),
+            Self::Buddy(name) => write!(f, "BUDDY {}", name),
+            Self::Prefs => write!(f, "PREFS"),
+            Self::Unarchive(room) => write!(f, "UNARCHIVE {}", room),
+            Self::ProtoStats => write!(f, "PROTOSTATS"),
+            Self::Context(true) => write!(f, "CONTEXT ON"),
+            Self::Context(false) => write!(f, "CONTEXT OFF"),
+            Self::Mode(room, None) => write!(f, "MODE {}", room),
+            Self::Mode(room, Some((mode, true))) => write!(f, "MODE {} +{}", room, mode),
+            Self::Mode(room, Some((mode, false))) => write!(f, "MODE {} -{}", room, mode),
+            Self::Membership(room, true) => write!(f, "MEMBERSHIP {} ON", room),
+            Self::Membership(room, false) => write!(f, "MEMBERSHIP {} OFF", room),
+            Self::Health => write!(f, "HEALTH"),
+            Self::Time => write!(f, "TIME"),
+            Self::Edit(room, seq, message) => write!(f, "EDIT {} {} {}", room, seq, message),
+            Self::Delete(room, seq) => write!(f, "DELETE {} {}", room, seq),
+            Self::MyRooms(false) => write!(f, "MYROOMS"),
+            Self::MyRooms(true) => write!(f, "MYROOMS full"),
+            Self::Create(room) => write!(f, "CREATE {}", room),
+            Self::Destroy(room) => write!(f, "DESTROY {}", room),
+            Self::Recent(room, count) => write!(f, "RECENT {} {}", room, count),
+            Self::EchoTest(token) => write!(f, "ECHOTEST {}", token),
+            Self::Compress => write!(f, "COMPRESS"),
+            Self::JoinAll(rooms) => write!(f, "JOINALL {}", rooms.join(" ")),
+            Self::Topic(room, topic) => write!(f, "TOPIC {} {}", room, topic),
+            Self::TopicHistory(room) => write!(f, "TOPICHISTORY {}", room),
+            Self::Invite(room, user) => write!(f, "INVITE {} {}", room, user),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_outgoing_msg_parse_round_trip() {
+        let messages = [
+            OutgoingMsg::Ping,
+            OutgoingMsg::Connected,
+            OutgoingMsg::Registered,
+            OutgoingMsg::SaidUser("@robert".to_string(), "hi there".to_string(), false),
+            OutgoingMsg::SaidUser("@robert".to_string(), "fire!".to_string(), true),
+            OutgoingMsg::SaidUser("@robert".to_string(), "".to_string(), false),
+            OutgoingMsg::SaidRoom(
+                "#sports".to_string(),
+                "@robert".to_string(),
+                7,
+                "good game".to_string(),
+            ),
+            OutgoingMsg::Room("#sports".to_string()),
+            OutgoingMsg::User("@robert".to_string()),
+            OutgoingMsg::Joined("#sports".to_string(), "@robert".to_string()),
+            OutgoingMsg::Left("#sports".to_string(), "@robert".to_string()),
+            OutgoingMsg::Notice("welcome to the server".to_string()),
+            OutgoingMsg::Status("@robert".to_string(), Some("grabbing coffee".to_string())),
+            OutgoingMsg::Status("@robert".to_string(), None),
+            OutgoingMsg::Delivered("@kelsey".to_string(), vec![]),
+            OutgoingMsg::Delivered(
+                "@kelsey".to_string(),
+                vec!["#news".to_string(), "#sports".to_string()],
+            ),
+            OutgoingMsg::RoomCreated("#sports".to_string()),
+            OutgoingMsg::RoomDeleted("#sports".to_string()),
+            OutgoingMsg::Online("@robert".to_string()),
+            OutgoingMsg::Commands(vec!["NAME".to_string(), "JOIN".to_string()]),
+            OutgoingMsg::MyStats(12, 3, Duration::from_secs(600)),
+            OutgoingMsg::Disconnect("killed by @admin: spam".to_string()),
+            OutgoingMsg::Limit("line-length".to_string(), 1024),
+            OutgoingMsg::Review(
+                1700000000,
+                "#sports".to_string(),
+                "@robert".to_string(),
+                "good game".to_string(),
+            ),
+            OutgoingMsg::History("#sports @robert SAID 1 good game".to_string()),
+            OutgoingMsg::EchoRoom(
+                "#sports".to_string(),
+                "@robert".to_string(),
+                8,
+                "good game".to_string(),
+            ),
+            OutgoingMsg::Shared("@kelsey".to_string(), vec!["#sports".to_string()]),
+            OutgoingMsg::ConnStats(3, 40, 5, 60),
+            OutgoingMsg::Presence("@kelsey".to_string(), true),
+            OutgoingMsg::Presence("@kelsey".to_string(), false),
+            OutgoingMsg::Pref("status".to_string(), "grabbing coffee".to_string()),
+            OutgoingMsg::ProtoStat("JOIN".to_string(), 42),
+            OutgoingMsg::Context("@kelsey".to_string(), vec![]),
+            OutgoingMsg::Mode("#sports".to_string(), vec![]),
+            OutgoingMsg::Mode(
+                "#sports".to_string(),
+                vec![RoomMode::InviteOnly, RoomMode::Moderated],
+            ),
+            OutgoingMsg::Member(
+                "#sports".to_string(),
+                "@kelsey".to_string(),
+                true,
+                3,
+                Some("grabbing coffee".to_string()),
+            ),
+            OutgoingMsg::Member("#sports".to_string(), "@kelsey".to_string(), false, 2, None),
+            OutgoingMsg::Healthy,
+            OutgoingMsg::Time(1700000000123),
+            OutgoingMsg::Edited("#sports".to_string(), 4, "good game!".to_string()),
+            OutgoingMsg::Removed("#sports".to_string(), 4),
+            OutgoingMsg::RoomInfo("#sports".to_string(), 3),
+            OutgoingMsg::EchoTest("probe-42".to_string()),
+            OutgoingMsg::Compress,
+            OutgoingMsg::Topic(
+                "#sports".to_string(),
+                "@robert".to_string(),
+                "the big game".to_string(),
+            ),
+            OutgoingMsg::TopicHistory(
+                1700000000,
+                "#sports".to_string(),
+                "@robert".to_string(),
+                "the big game".to_string(),
+            ),
+        ];
+        for message in messages {
+            assert_eq!(OutgoingMsg::parse(&message.to_string()), Some(message));
+        }
+    }
+
+    #[test]
+    fn test_outgoing_msg_parse_rejects() {
+        for line in [
+            "",
+            "ERROR room unknown #karate",
+            "PING now",
+            "ROOM",
+            "ROOMINFO #sports",
+            "#sports @robert SAID seven good game",
+            "#sports @robert WAVED",
+            "@robert WAVED hi",
+            "LIMIT line-length lots",
+            "MYSTATS 1 2",
+            "TOPIC #sports @robert",
+            "TOPICHISTORY yesterday #sports @robert the big game",
+            "MODE #sports im",
+            "MODE #sports +x",
+            "PRESENCE @kelsey AWAY",
+            "ECHO @robert SAID hi",
+        ] {
+            assert_eq!(OutgoingMsg::parse(line), None, "{}", line);
         }
     }
 }
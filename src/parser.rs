@@ -1,40 +1,110 @@
 //! Parse incoming messages into a well typed structure for use in the rest of the program.
-use std::fmt::Display;
+use std::{fmt::Display, str::FromStr};
 
-use crate::messages::{IncomingMsg, Message};
+use crate::{
+    command::Command,
+    messages::{IncomingMsg, Message, RoomFilter, RoomMode, UserSort},
+};
 use regex::Regex;
 
 lazy_static! {
-    static ref NAME_REGEX: Regex = Regex::new(r"^@[A-Za-z0-9\-\_]{3,20}$").unwrap();
-    static ref ROOM_REGEX: Regex = Regex::new(r"^#[A-Za-z0-9\-\_]{3,20}$").unwrap();
+    static ref NAME_REGEX: Regex = Regex::new(r"^@[A-Za-z0-9\-\_]+$").unwrap();
+    static ref ROOM_REGEX: Regex = Regex::new(r"^#[A-Za-z0-9\-\_]+$").unwrap();
+    static ref SERVER_NAME_REGEX: Regex = Regex::new(r"^[A-Za-z0-9\-\_\.:]+$").unwrap();
+    /// A terminal escape sequence: a control sequence such as a color or cursor move, an operating system
+    /// command such as a window title, or ESC and whatever single character follows it.
+    static ref ESCAPE_REGEX: Regex =
+        Regex::new(r"\x1b(\[[0-?]*[ -/]*[@-~]|\][^\x07\x1b]*(\x07|\x1b\\)?|.)?").unwrap();
 }
 
-/// The supported incoming commands.
-#[derive(Debug, PartialEq)]
-pub enum Command {
-    Name,
-    Join,
-    Leave,
-    Say,
-    Users,
-    Rooms,
-    Pong,
+/// Tunable rules the parser uses to validate incoming messages. Lengths are counted without the
+/// leading `@` or `#`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ParserConfig {
+    pub name_min_length: usize,
+    pub name_max_length: usize,
+    pub room_min_length: usize,
+    pub room_max_length: usize,
+    /// What to do with terminal escape sequences, which could take over other users' terminals.
+    pub escapes: EscapePolicy,
+    /// Most users one SAY may name, so that a private message can't be turned into a broadcast.
+    pub max_recipients: usize,
+    /// Longest line, in bytes, that the client may send.
+    pub max_line_length: usize,
 }
 
-impl Display for Command {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Self::Name => write!(f, "Name"),
-            Self::Join => write!(f, "Join"),
-            Self::Leave => write!(f, "Leave"),
-            Self::Say => write!(f, "Say"),
-            Self::Users => write!(f, "Users"),
-            Self::Rooms => write!(f, "Rooms"),
-            Self::Pong => write!(f, "Pong"),
+/// How the parser treats input holding terminal escape sequences.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EscapePolicy {
+    /// Remove the escape sequences and parse what is left.
+    Strip,
+    /// Refuse the whole command.
+    Reject,
+    /// Pass escape sequences through untouched.
+    Allow,
+}
+
+impl FromStr for EscapePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strip" => Ok(Self::Strip),
+            "reject" => Ok(Self::Reject),
+            "allow" => Ok(Self::Allow),
+            _ => Err(format!(
+                "unknown escape policy {}, expected strip, reject, or allow",
+                s
+            )),
+        }
+    }
+}
+
+/// Longest line, in bytes, that a client or peer server may send by default.
+pub const MAX_LINE_LENGTH: usize = 1024;
+
+/// The first character of every user name.
+pub const NAME_PREFIX: char = '@';
+/// The first character of every room name.
+pub const ROOM_PREFIX: char = '#';
+
+impl ParserConfig {
+    /// Check that a user name has the right prefix, characters, and length. The prefix is checked on its
+    /// own so that a room can never pass for a user, whatever characters the regex allows.
+    pub fn is_valid_name(&self, name: &str) -> bool {
+        name.starts_with(NAME_PREFIX)
+            && NAME_REGEX.is_match(name)
+            && (self.name_min_length..=self.name_max_length).contains(&(name.len() - 1))
+    }
+
+    /// Check that a room name has the right prefix, characters, and length. The prefix is checked on its
+    /// own so that a user can never pass for a room, whatever characters the regex allows.
+    pub fn is_valid_room(&self, room: &str) -> bool {
+        room.starts_with(ROOM_PREFIX)
+            && ROOM_REGEX.is_match(room)
+            && (self.room_min_length..=self.room_max_length).contains(&(room.len() - 1))
+    }
+}
+
+impl Default for ParserConfig {
+    fn default() -> Self {
+        Self {
+            name_min_length: 3,
+            name_max_length: 20,
+            room_min_length: 3,
+            room_max_length: 20,
+            escapes: EscapePolicy::Strip,
+            max_recipients: 5,
+            max_line_length: MAX_LINE_LENGTH,
         }
     }
 }
 
+/// Most pieces a line is split into. No command takes more than a few arguments and free text such as a
+/// SAY message is joined back together anyway, so everything past the last piece stays in it unsplit.
+/// This bounds the work done on a line that is nothing but spaces, and the rooms one JOINALL can name.
+const MAX_PIECES: usize = 16;
+
 /// Error states of the parser.
 #[derive(Debug, PartialEq)]
 pub enum ParseError {
@@ -44,6 +114,10 @@ pub enum ParseError {
     BadRoomNameFormat,
     /// The incoming message doesn't have a correct amount of arguments.
     BadArguments,
+    /// The message is longer than the limit the client set with MAXLEN.
+    MessageTooLong,
+    /// The input holds a terminal escape sequence and the server rejects them.
+    EscapeSequence,
 }
 
 impl Display for ParseError {
@@ -52,6 +126,8 @@ impl Display for ParseError {
             Self::BadArguments => write!(f, "ERROR bad arguments"),
             Self::BadNameFormat => write!(f, "ERROR bad name format"),
             Self::BadRoomNameFormat => write!(f, "ERROR bad room name format"),
+            Self::MessageTooLong => write!(f, "ERROR message too long"),
+            Self::EscapeSequence => write!(f, "ERROR escape sequence"),
         }
     }
 }
@@ -64,6 +140,8 @@ impl Message for ParseError {}
 pub enum ParsedAction {
     /// Ignore the input.
     None,
+    /// A line that doesn't start with a command this server understands.
+    Unknown,
     /// Process a well-formed message.
     Process(IncomingMsg),
     /// Error parsing a valid command.
@@ -74,25 +152,48 @@ impl Display for ParsedAction {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::None => write!(f, "ParsedAction::None"),
+            Self::Unknown => write!(f, "ParsedAction::Unknown"),
             Self::Process(msg) => write!(f, "ParsedAction::Process({})", msg),
             Self::Error(cmd, err) => write!(f, "ParsedAction::Error({}, {})", cmd, err),
         }
     }
 }
 
-/// Parse an incoming client message.
+/// Parse an incoming client message using the default [ParserConfig].
 pub fn parse_incoming(input: &str) -> ParsedAction {
+    parse_incoming_with(input, &ParserConfig::default())
+}
+
+/// Parse an incoming client message.
+pub fn parse_incoming_with(input: &str, config: &ParserConfig) -> ParsedAction {
     if input.is_empty() {
         return ParsedAction::None;
     }
 
-    let pieces: Vec<&str> = input.split(' ').collect();
+    let stripped;
+    let input = match config.escapes {
+        _ if !input.contains('\x1b') => input,
+        EscapePolicy::Strip => {
+            stripped = ESCAPE_REGEX.replace_all(input, "");
+            &stripped
+        }
+        // unknown commands are ignored whatever they hold
+        EscapePolicy::Reject => {
+            return match Command::from_verb(input.split(' ').next().unwrap_or_default()) {
+                Some(command) => ParsedAction::Error(command, ParseError::EscapeSequence),
+                None => ParsedAction::Unknown,
+            }
+        }
+        EscapePolicy::Allow => input,
+    };
+
+    let pieces: Vec<&str> = input.splitn(MAX_PIECES, ' ').collect();
 
     match pieces[0] {
         "QUIT" => ParsedAction::Process(IncomingMsg::Quit),
         "NAME" => {
             if pieces.len() == 2 {
-                if NAME_REGEX.is_match(pieces[1]) {
+                if config.is_valid_name(pieces[1]) {
                     ParsedAction::Process(IncomingMsg::Name(pieces[1].to_string()))
                 } else {
                     ParsedAction::Error(Command::Name, ParseError::BadNameFormat)
@@ -103,7 +204,7 @@ pub fn parse_incoming(input: &str) -> ParsedAction {
         }
         "JOIN" => {
             if pieces.len() == 2 {
-                if ROOM_REGEX.is_match(pieces[1]) {
+                if config.is_valid_room(pieces[1]) {
                     ParsedAction::Process(IncomingMsg::Join(pieces[1].to_string()))
                 } else {
                     ParsedAction::Error(Command::Join, ParseError::BadRoomNameFormat)
@@ -114,7 +215,7 @@ pub fn parse_incoming(input: &str) -> ParsedAction {
         }
         "LEAVE" => {
             if pieces.len() == 2 {
-                if ROOM_REGEX.is_match(pieces[1]) {
+                if config.is_valid_room(pieces[1]) {
                     ParsedAction::Process(IncomingMsg::Leave(pieces[1].to_string()))
                 } else {
                     ParsedAction::Error(Command::Leave, ParseError::BadRoomNameFormat)
@@ -125,162 +226,777 @@ pub fn parse_incoming(input: &str) -> ParsedAction {
         }
         "SAY" => {
             if pieces.len() >= 3 {
-                if ROOM_REGEX.is_match(pieces[1]) {
-                    ParsedAction::Process(IncomingMsg::SayRoom(
+                // the prefix alone decides whether the target is a room or a user
+                match pieces[1].chars().next() {
+                    Some(ROOM_PREFIX) if config.is_valid_room(pieces[1]) => ParsedAction::Process(
+                        IncomingMsg::SayRoom(pieces[1].to_string(), pieces[2..].join(" ")),
+                    ),
+                    Some(ROOM_PREFIX) => {
+                        ParsedAction::Error(Command::Say, ParseError::BadRoomNameFormat)
+                    }
+                    Some(NAME_PREFIX) if config.is_valid_name(pieces[1]) => ParsedAction::Process(
+                        IncomingMsg::SayUser(pieces[1].to_string(), pieces[2..].join(" "), false),
+                    ),
+                    // SAY @a,@b,@c sends the same private message to each
+                    Some(NAME_PREFIX) if pieces[1].contains(',') => {
+                        let mut names: Vec<String> = vec![];
+                        for name in pieces[1].split(',') {
+                            if !names.iter().any(|seen| seen == name) {
+                                names.push(name.to_string());
+                            }
+                        }
+                        if !names.iter().all(|name| config.is_valid_name(name)) {
+                            ParsedAction::Error(Command::Say, ParseError::BadNameFormat)
+                        } else if names.len() > config.max_recipients {
+                            ParsedAction::Error(Command::Say, ParseError::BadArguments)
+                        } else {
+                            ParsedAction::Process(IncomingMsg::SayUsers(
+                                names,
+                                pieces[2..].join(" "),
+                            ))
+                        }
+                    }
+                    Some(NAME_PREFIX) => {
+                        ParsedAction::Error(Command::Say, ParseError::BadNameFormat)
+                    }
+                    _ => ParsedAction::Error(Command::Say, ParseError::BadArguments),
+                }
+            } else {
+                ParsedAction::Error(Command::Say, ParseError::BadArguments)
+            }
+        }
+        "ROOMS" => match (pieces.len(), pieces.get(1)) {
+            (1, _) => ParsedAction::Process(IncomingMsg::Rooms(None)),
+            (2, Some(&"empty")) => {
+                ParsedAction::Process(IncomingMsg::Rooms(Some(RoomFilter::Empty)))
+            }
+            (2, Some(&"active")) => {
+                ParsedAction::Process(IncomingMsg::Rooms(Some(RoomFilter::Active)))
+            }
+            _ => ParsedAction::Error(Command::Rooms, ParseError::BadArguments),
+        },
+        "MYROOMS" => match (pieces.len(), pieces.get(1)) {
+            (1, _) => ParsedAction::Process(IncomingMsg::MyRooms(false)),
+            (2, Some(&"full")) => ParsedAction::Process(IncomingMsg::MyRooms(true)),
+            _ => ParsedAction::Error(Command::MyRooms, ParseError::BadArguments),
+        },
+        "USERS" => {
+            if pieces.len() == 2 || (pieces.len() == 3 && pieces[2] == "active") {
+                if config.is_valid_room(pieces[1]) {
+                    let sort = pieces.get(2).map(|_| UserSort::Active);
+                    ParsedAction::Process(IncomingMsg::Users(pieces[1].to_string(), sort))
+                } else {
+                    ParsedAction::Error(Command::Users, ParseError::BadRoomNameFormat)
+                }
+            } else {
+                ParsedAction::Error(Command::Users, ParseError::BadArguments)
+            }
+        }
+        "PONG" => {
+            if pieces.len() == 1 {
+                ParsedAction::Process(IncomingMsg::Pong)
+            } else {
+                ParsedAction::Error(Command::Pong, ParseError::BadArguments)
+            }
+        }
+        #[cfg(feature = "debug")]
+        "DEBUG" => {
+            if pieces.len() == 2 {
+                match pieces[1] {
+                    "ON" => ParsedAction::Process(IncomingMsg::Debug(true)),
+                    "OFF" => ParsedAction::Process(IncomingMsg::Debug(false)),
+                    _ => ParsedAction::Error(Command::Debug, ParseError::BadArguments),
+                }
+            } else {
+                ParsedAction::Error(Command::Debug, ParseError::BadArguments)
+            }
+        }
+        "MUTE" | "UNMUTE" => {
+            let (command, message): (Command, fn(String, String) -> IncomingMsg) =
+                if pieces[0] == "MUTE" {
+                    (Command::Mute, IncomingMsg::Mute)
+                } else {
+                    (Command::Unmute, IncomingMsg::Unmute)
+                };
+            if pieces.len() == 3 {
+                if !config.is_valid_room(pieces[1]) {
+                    ParsedAction::Error(command, ParseError::BadRoomNameFormat)
+                } else if !config.is_valid_name(pieces[2]) {
+                    ParsedAction::Error(command, ParseError::BadNameFormat)
+                } else {
+                    ParsedAction::Process(message(pieces[1].to_string(), pieces[2].to_string()))
+                }
+            } else {
+                ParsedAction::Error(command, ParseError::BadArguments)
+            }
+        }
+        "LINK" => {
+            if pieces.len() == 3 && SERVER_NAME_REGEX.is_match(pieces[2]) {
+                if config.is_valid_room(pieces[1]) {
+                    ParsedAction::Process(IncomingMsg::Link(
                         pieces[1].to_string(),
-                        pieces[2..].join(" "),
+                        pieces[2].to_string(),
                     ))
-                } else if NAME_REGEX.is_match(pieces[1]) {
+                } else {
+                    ParsedAction::Error(Command::Link, ParseError::BadRoomNameFormat)
+                }
+            } else {
+                ParsedAction::Error(Command::Link, ParseError::BadArguments)
+            }
+        }
+        "PREVIEW" => {
+            if pieces.len() >= 2 && pieces[1] == "SAY" {
+                // parse the previewed SAY like a real one
+                match parse_incoming_with(&pieces[1..].join(" "), config) {
+                    ParsedAction::Process(IncomingMsg::SayRoom(room, message)) => {
+                        ParsedAction::Process(IncomingMsg::PreviewSayRoom(room, message))
+                    }
+                    ParsedAction::Error(_, parse_error) => {
+                        ParsedAction::Error(Command::Preview, parse_error)
+                    }
+                    _ => ParsedAction::Error(Command::Preview, ParseError::BadArguments),
+                }
+            } else {
+                ParsedAction::Error(Command::Preview, ParseError::BadArguments)
+            }
+        }
+        "STATUS" => {
+            if pieces.len() == 1 {
+                ParsedAction::Process(IncomingMsg::Status(None))
+            } else {
+                ParsedAction::Process(IncomingMsg::Status(Some(pieces[1..].join(" "))))
+            }
+        }
+        "WHOIS" => {
+            if pieces.len() == 2 {
+                if config.is_valid_name(pieces[1]) {
+                    ParsedAction::Process(IncomingMsg::Whois(pieces[1].to_string()))
+                } else {
+                    ParsedAction::Error(Command::Whois, ParseError::BadNameFormat)
+                }
+            } else {
+                ParsedAction::Error(Command::Whois, ParseError::BadArguments)
+            }
+        }
+        "WATCH" => {
+            if pieces.len() == 2 {
+                match pieces[1] {
+                    "ON" => ParsedAction::Process(IncomingMsg::Watch(true)),
+                    "OFF" => ParsedAction::Process(IncomingMsg::Watch(false)),
+                    _ => ParsedAction::Error(Command::Watch, ParseError::BadArguments),
+                }
+            } else {
+                ParsedAction::Error(Command::Watch, ParseError::BadArguments)
+            }
+        }
+        "SYNTAX" => {
+            if pieces.len() == 2 {
+                match Command::from_verb(pieces[1]) {
+                    Some(command) => ParsedAction::Process(IncomingMsg::Syntax(command)),
+                    None => ParsedAction::Error(Command::Syntax, ParseError::BadArguments),
+                }
+            } else {
+                ParsedAction::Error(Command::Syntax, ParseError::BadArguments)
+            }
+        }
+        "SAY!" => {
+            if pieces.len() >= 3 {
+                if config.is_valid_name(pieces[1]) {
                     ParsedAction::Process(IncomingMsg::SayUser(
                         pieces[1].to_string(),
                         pieces[2..].join(" "),
+                        true,
                     ))
                 } else {
-                    let next = pieces[1].chars().next().unwrap();
-                    if next == '#' {
-                        ParsedAction::Error(Command::Say, ParseError::BadRoomNameFormat)
-                    } else if next == '@' {
-                        ParsedAction::Error(Command::Say, ParseError::BadNameFormat)
-                    } else {
-                        ParsedAction::Error(Command::Say, ParseError::BadArguments)
+                    // only private messages can be urgent
+                    ParsedAction::Error(Command::UrgentSay, ParseError::BadNameFormat)
+                }
+            } else {
+                ParsedAction::Error(Command::UrgentSay, ParseError::BadArguments)
+            }
+        }
+        "DND" => {
+            if pieces.len() == 2 {
+                match pieces[1] {
+                    "ON" => ParsedAction::Process(IncomingMsg::Dnd(true)),
+                    "OFF" => ParsedAction::Process(IncomingMsg::Dnd(false)),
+                    _ => ParsedAction::Error(Command::Dnd, ParseError::BadArguments),
+                }
+            } else {
+                ParsedAction::Error(Command::Dnd, ParseError::BadArguments)
+            }
+        }
+        "RESET" => {
+            if pieces.len() == 1 {
+                ParsedAction::Process(IncomingMsg::Reset)
+            } else {
+                ParsedAction::Error(Command::Reset, ParseError::BadArguments)
+            }
+        }
+        "COMMANDS" => {
+            if pieces.len() == 1 {
+                ParsedAction::Process(IncomingMsg::Commands)
+            } else {
+                ParsedAction::Error(Command::Commands, ParseError::BadArguments)
+            }
+        }
+        "MYSTATS" => {
+            if pieces.len() == 1 {
+                ParsedAction::Process(IncomingMsg::MyStats)
+            } else {
+                ParsedAction::Error(Command::MyStats, ParseError::BadArguments)
+            }
+        }
+        "OPER" => {
+            if pieces.len() == 2 {
+                ParsedAction::Process(IncomingMsg::Oper(pieces[1].to_string()))
+            } else {
+                ParsedAction::Error(Command::Oper, ParseError::BadArguments)
+            }
+        }
+        "KILL" => {
+            if pieces.len() >= 3 {
+                if config.is_valid_name(pieces[1]) {
+                    ParsedAction::Process(IncomingMsg::Kill(
+                        pieces[1].to_string(),
+                        pieces[2..].join(" "),
+                    ))
+                } else {
+                    ParsedAction::Error(Command::Kill, ParseError::BadNameFormat)
+                }
+            } else {
+                ParsedAction::Error(Command::Kill, ParseError::BadArguments)
+            }
+        }
+        "LIMITS" => {
+            if pieces.len() == 1 {
+                ParsedAction::Process(IncomingMsg::Limits)
+            } else {
+                ParsedAction::Error(Command::Limits, ParseError::BadArguments)
+            }
+        }
+        "READONLY" => {
+            if pieces.len() == 3 {
+                if !config.is_valid_room(pieces[1]) {
+                    ParsedAction::Error(Command::ReadOnly, ParseError::BadRoomNameFormat)
+                } else {
+                    match pieces[2] {
+                        "ON" => ParsedAction::Process(IncomingMsg::ReadOnly(
+                            pieces[1].to_string(),
+                            true,
+                        )),
+                        "OFF" => ParsedAction::Process(IncomingMsg::ReadOnly(
+                            pieces[1].to_string(),
+                            false,
+                        )),
+                        _ => ParsedAction::Error(Command::ReadOnly, ParseError::BadArguments),
                     }
                 }
             } else {
-                ParsedAction::Error(Command::Say, ParseError::BadArguments)
+                ParsedAction::Error(Command::ReadOnly, ParseError::BadArguments)
             }
         }
-        "ROOMS" => {
+        "REVIEW" => {
             if pieces.len() == 1 {
-                ParsedAction::Process(IncomingMsg::Rooms)
+                ParsedAction::Process(IncomingMsg::Review)
             } else {
-                ParsedAction::Error(Command::Rooms, ParseError::BadArguments)
+                ParsedAction::Error(Command::Review, ParseError::BadArguments)
             }
         }
-        "USERS" => {
+        "MAXLEN" => {
+            // no message can be longer than a line anyway
+            match pieces.get(1).map(|length| length.parse::<usize>()) {
+                Some(Ok(length))
+                    if pieces.len() == 2 && (1..=config.max_line_length).contains(&length) =>
+                {
+                    ParsedAction::Process(IncomingMsg::MaxLen(length))
+                }
+                _ => ParsedAction::Error(Command::MaxLen, ParseError::BadArguments),
+            }
+        }
+        "HISTLEN" => {
+            // the server clamps the count to however much history it keeps
+            match pieces.get(1).map(|count| count.parse::<usize>()) {
+                Some(Ok(count)) if pieces.len() == 2 => {
+                    ParsedAction::Process(IncomingMsg::HistLen(count))
+                }
+                _ => ParsedAction::Error(Command::HistLen, ParseError::BadArguments),
+            }
+        }
+        "ECHO" => {
+            // ROOM is the only kind of echo so far
+            match pieces[1..] {
+                ["ROOM", "ON"] => ParsedAction::Process(IncomingMsg::EchoRoom(true)),
+                ["ROOM", "OFF"] => ParsedAction::Process(IncomingMsg::EchoRoom(false)),
+                _ => ParsedAction::Error(Command::Echo, ParseError::BadArguments),
+            }
+        }
+        "SHARED" => {
             if pieces.len() == 2 {
-                if ROOM_REGEX.is_match(pieces[1]) {
-                    ParsedAction::Process(IncomingMsg::Users(pieces[1].to_string()))
+                if config.is_valid_name(pieces[1]) {
+                    ParsedAction::Process(IncomingMsg::Shared(pieces[1].to_string()))
                 } else {
-                    ParsedAction::Error(Command::Users, ParseError::BadRoomNameFormat)
+                    ParsedAction::Error(Command::Shared, ParseError::BadNameFormat)
                 }
             } else {
-                ParsedAction::Error(Command::Users, ParseError::BadArguments)
+                ParsedAction::Error(Command::Shared, ParseError::BadArguments)
             }
         }
-        "PONG" => {
+        "BRIDGE" => {
+            if pieces.len() >= 4 {
+                if !config.is_valid_room(pieces[1]) {
+                    ParsedAction::Error(Command::Bridge, ParseError::BadRoomNameFormat)
+                } else if !config.is_valid_name(pieces[2]) {
+                    // the display name has to look like any other sender
+                    ParsedAction::Error(Command::Bridge, ParseError::BadNameFormat)
+                } else {
+                    ParsedAction::Process(IncomingMsg::Bridge(
+                        pieces[1].to_string(),
+                        pieces[2].to_string(),
+                        pieces[3..].join(" "),
+                    ))
+                }
+            } else {
+                ParsedAction::Error(Command::Bridge, ParseError::BadArguments)
+            }
+        }
+        "CONNSTATS" => {
             if pieces.len() == 1 {
-                ParsedAction::Process(IncomingMsg::Pong)
+                ParsedAction::Process(IncomingMsg::ConnStats)
             } else {
-                ParsedAction::Error(Command::Pong, ParseError::BadArguments)
+                ParsedAction::Error(Command::ConnStats, ParseError::BadArguments)
             }
         }
-        // ignore unknown commands
-        _ => ParsedAction::None,
-    }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn test_name_regex() {
-        // good
-        assert!(NAME_REGEX.is_match("@robert"));
-        assert!(NAME_REGEX.is_match("@rgp"));
-        // bad
-        assert!(!NAME_REGEX.is_match("@012345678901234567891"));
-        assert!(!NAME_REGEX.is_match("@gj"));
-    }
-
-    #[test]
-    fn test_parse_incoming_empty_input() {
-        assert_eq!(parse_incoming(""), ParsedAction::None);
-    }
-
-    #[test]
-    fn test_parse_incoming_quit() {
-        assert_eq!(
-            parse_incoming("QUIT"),
-            ParsedAction::Process(IncomingMsg::Quit)
-        );
-        assert_eq!(
-            parse_incoming("QUIT other stuff"),
-            ParsedAction::Process(IncomingMsg::Quit)
-        );
-        assert_eq!(parse_incoming("quit other stuff"), ParsedAction::None);
-        assert_eq!(parse_incoming("quit"), ParsedAction::None);
-    }
-
-    #[test]
-    fn test_parse_incoming_name() {
-        assert_eq!(
-            parse_incoming("NAME @robert"),
-            ParsedAction::Process(IncomingMsg::Name("@robert".to_string()))
-        );
-        assert_eq!(
-            parse_incoming("NAME"),
-            ParsedAction::Error(Command::Name, ParseError::BadArguments)
-        );
-        assert_eq!(
-            parse_incoming("NAME @robert Steve"),
-            ParsedAction::Error(Command::Name, ParseError::BadArguments)
-        );
-        assert_eq!(
-            parse_incoming("NAME @robert**"),
-            ParsedAction::Error(Command::Name, ParseError::BadNameFormat)
-        );
-        assert_eq!(parse_incoming("name"), ParsedAction::None);
-    }
-
-    #[test]
-    fn test_parse_incoming_join() {
-        assert_eq!(
-            parse_incoming("JOIN #room1"),
-            ParsedAction::Process(IncomingMsg::Join("#room1".to_string()))
-        );
-        assert_eq!(
-            parse_incoming("JOIN"),
-            ParsedAction::Error(Command::Join, ParseError::BadArguments)
-        );
-        assert_eq!(
-            parse_incoming("JOIN #room #room2"),
-            ParsedAction::Error(Command::Join, ParseError::BadArguments)
-        );
-        assert_eq!(
-            parse_incoming("JOIN @room"),
-            ParsedAction::Error(Command::Join, ParseError::BadRoomNameFormat)
-        );
-        assert_eq!(parse_incoming("join"), ParsedAction::None);
-    }
-
-    #[test]
-    fn test_parse_incoming_leave() {
-        assert_eq!(
-            parse_incoming("LEAVE #room1"),
-            ParsedAction::Process(IncomingMsg::Leave("#room1".to_string()))
-        );
-        assert_eq!(
-            parse_incoming("LEAVE"),
-            ParsedAction::Error(Command::Leave, ParseError::BadArguments)
-        );
-        assert_eq!(
-            parse_incoming("LEAVE #room #room2"),
-            ParsedAction::Error(Command::Leave, ParseError::BadArguments)
-        );
-        assert_eq!(
-            parse_incoming("LEAVE @room"),
-            ParsedAction::Error(Command::Leave, ParseError::BadRoomNameFormat)
-        );
-        assert_eq!(parse_incoming("leave"), ParsedAction::None);
-    }
-
-    #[test]
-    fn test_parse_incoming_say() {
-        assert_eq!(
-            parse_incoming("SAY #room341 hello everyone!"),
-            ParsedAction::Process(IncomingMsg::SayRoom(
-                "#room341".to_string(),
+        "SWITCH" => {
+            if pieces.len() == 3 {
+                if config.is_valid_room(pieces[1]) && config.is_valid_room(pieces[2]) {
+                    ParsedAction::Process(IncomingMsg::Switch(
+                        pieces[1].to_string(),
+                        pieces[2].to_string(),
+                    ))
+                } else {
+                    ParsedAction::Error(Command::Switch, ParseError::BadRoomNameFormat)
+                }
+            } else {
+                ParsedAction::Error(Command::Switch, ParseError::BadArguments)
+            }
+        }
+        "BUDDY" => {
+            if pieces.len() == 2 {
+                if config.is_valid_name(pieces[1]) {
+                    ParsedAction::Process(IncomingMsg::Buddy(pieces[1].to_string()))
+                } else {
+                    ParsedAction::Error(Command::Buddy, ParseError::BadNameFormat)
+                }
+            } else {
+                ParsedAction::Error(Command::Buddy, ParseError::BadArguments)
+            }
+        }
+        "PREFS" => {
+            if pieces.len() == 1 {
+                ParsedAction::Process(IncomingMsg::Prefs)
+            } else {
+                ParsedAction::Error(Command::Prefs, ParseError::BadArguments)
+            }
+        }
+        "UNARCHIVE" => {
+            if pieces.len() == 2 {
+                if config.is_valid_room(pieces[1]) {
+                    ParsedAction::Process(IncomingMsg::Unarchive(pieces[1].to_string()))
+                } else {
+                    ParsedAction::Error(Command::Unarchive, ParseError::BadRoomNameFormat)
+                }
+            } else {
+                ParsedAction::Error(Command::Unarchive, ParseError::BadArguments)
+            }
+        }
+        "PROTOSTATS" => {
+            if pieces.len() == 1 {
+                ParsedAction::Process(IncomingMsg::ProtoStats)
+            } else {
+                ParsedAction::Error(Command::ProtoStats, ParseError::BadArguments)
+            }
+        }
+        "CONTEXT" => {
+            if pieces.len() == 2 {
+                match pieces[1] {
+                    "ON" => ParsedAction::Process(IncomingMsg::Context(true)),
+                    "OFF" => ParsedAction::Process(IncomingMsg::Context(false)),
+                    _ => ParsedAction::Error(Command::Context, ParseError::BadArguments),
+                }
+            } else {
+                ParsedAction::Error(Command::Context, ParseError::BadArguments)
+            }
+        }
+        "MODE" => {
+            if pieces.len() == 2 || pieces.len() == 3 {
+                if !config.is_valid_room(pieces[1]) {
+                    ParsedAction::Error(Command::Mode, ParseError::BadRoomNameFormat)
+                } else {
+                    let change = match pieces.get(2) {
+                        None => Ok(None),
+                        Some(&"+i") => Ok(Some((RoomMode::InviteOnly, true))),
+                        Some(&"-i") => Ok(Some((RoomMode::InviteOnly, false))),
+                        Some(&"+m") => Ok(Some((RoomMode::Moderated, true))),
+                        Some(&"-m") => Ok(Some((RoomMode::Moderated, false))),
+                        Some(&"+t") => Ok(Some((RoomMode::TopicLocked, true))),
+                        Some(&"-t") => Ok(Some((RoomMode::TopicLocked, false))),
+                        Some(_) => Err(ParseError::BadArguments),
+                    };
+                    match change {
+                        Ok(change) => {
+                            ParsedAction::Process(IncomingMsg::Mode(pieces[1].to_string(), change))
+                        }
+                        Err(e) => ParsedAction::Error(Command::Mode, e),
+                    }
+                }
+            } else {
+                ParsedAction::Error(Command::Mode, ParseError::BadArguments)
+            }
+        }
+        "MEMBERSHIP" => {
+            if pieces.len() == 3 {
+                if !config.is_valid_room(pieces[1]) {
+                    ParsedAction::Error(Command::Membership, ParseError::BadRoomNameFormat)
+                } else {
+                    match pieces[2] {
+                        "ON" => ParsedAction::Process(IncomingMsg::Membership(
+                            pieces[1].to_string(),
+                            true,
+                        )),
+                        "OFF" => ParsedAction::Process(IncomingMsg::Membership(
+                            pieces[1].to_string(),
+                            false,
+                        )),
+                        _ => ParsedAction::Error(Command::Membership, ParseError::BadArguments),
+                    }
+                }
+            } else {
+                ParsedAction::Error(Command::Membership, ParseError::BadArguments)
+            }
+        }
+        // a liveness probe for load balancers rather than a client command, so it isn't in COMMANDS
+        "HEALTH" if pieces.len() == 1 => ParsedAction::Process(IncomingMsg::Health),
+        "TIME" => {
+            if pieces.len() == 1 {
+                ParsedAction::Process(IncomingMsg::Time)
+            } else {
+                ParsedAction::Error(Command::Time, ParseError::BadArguments)
+            }
+        }
+        "COMPRESS" => {
+            if pieces.len() == 1 {
+                ParsedAction::Process(IncomingMsg::Compress)
+            } else {
+                ParsedAction::Error(Command::Compress, ParseError::BadArguments)
+            }
+        }
+        "ECHOTEST" => {
+            if pieces.len() == 2 && !pieces[1].is_empty() {
+                ParsedAction::Process(IncomingMsg::EchoTest(pieces[1].to_string()))
+            } else {
+                ParsedAction::Error(Command::EchoTest, ParseError::BadArguments)
+            }
+        }
+        "EDIT" => {
+            if pieces.len() >= 4 {
+                match (config.is_valid_room(pieces[1]), pieces[2].parse()) {
+                    (false, _) => ParsedAction::Error(Command::Edit, ParseError::BadRoomNameFormat),
+                    (true, Ok(seq)) => ParsedAction::Process(IncomingMsg::Edit(
+                        pieces[1].to_string(),
+                        seq,
+                        pieces[3..].join(" "),
+                    )),
+                    (true, Err(_)) => ParsedAction::Error(Command::Edit, ParseError::BadArguments),
+                }
+            } else {
+                ParsedAction::Error(Command::Edit, ParseError::BadArguments)
+            }
+        }
+        "DELETE" => {
+            if pieces.len() == 3 {
+                match (config.is_valid_room(pieces[1]), pieces[2].parse()) {
+                    (false, _) => {
+                        ParsedAction::Error(Command::Delete, ParseError::BadRoomNameFormat)
+                    }
+                    (true, Ok(seq)) => {
+                        ParsedAction::Process(IncomingMsg::Delete(pieces[1].to_string(), seq))
+                    }
+                    (true, Err(_)) => {
+                        ParsedAction::Error(Command::Delete, ParseError::BadArguments)
+                    }
+                }
+            } else {
+                ParsedAction::Error(Command::Delete, ParseError::BadArguments)
+            }
+        }
+        "CREATE" => {
+            if pieces.len() == 2 {
+                if config.is_valid_room(pieces[1]) {
+                    ParsedAction::Process(IncomingMsg::Create(pieces[1].to_string()))
+                } else {
+                    ParsedAction::Error(Command::Create, ParseError::BadRoomNameFormat)
+                }
+            } else {
+                ParsedAction::Error(Command::Create, ParseError::BadArguments)
+            }
+        }
+        "DESTROY" => {
+            if pieces.len() == 2 {
+                if config.is_valid_room(pieces[1]) {
+                    ParsedAction::Process(IncomingMsg::Destroy(pieces[1].to_string()))
+                } else {
+                    ParsedAction::Error(Command::Destroy, ParseError::BadRoomNameFormat)
+                }
+            } else {
+                ParsedAction::Error(Command::Destroy, ParseError::BadArguments)
+            }
+        }
+        "RECENT" => {
+            if pieces.len() == 3 {
+                match (config.is_valid_room(pieces[1]), pieces[2].parse()) {
+                    (false, _) => {
+                        ParsedAction::Error(Command::Recent, ParseError::BadRoomNameFormat)
+                    }
+                    (true, Ok(count)) if count > 0 => {
+                        ParsedAction::Process(IncomingMsg::Recent(pieces[1].to_string(), count))
+                    }
+                    (true, _) => ParsedAction::Error(Command::Recent, ParseError::BadArguments),
+                }
+            } else {
+                ParsedAction::Error(Command::Recent, ParseError::BadArguments)
+            }
+        }
+        "JOINALL" => {
+            // a last piece still holding a space means more rooms than the pieces allow
+            if pieces.len() >= 2 && !pieces[pieces.len() - 1].contains(' ') {
+                let mut rooms: Vec<String> = vec![];
+                for room in &pieces[1..] {
+                    if !rooms.iter().any(|seen| seen == room) {
+                        rooms.push(room.to_string());
+                    }
+                }
+                if rooms.iter().all(|room| config.is_valid_room(room)) {
+                    ParsedAction::Process(IncomingMsg::JoinAll(rooms))
+                } else {
+                    ParsedAction::Error(Command::JoinAll, ParseError::BadRoomNameFormat)
+                }
+            } else {
+                ParsedAction::Error(Command::JoinAll, ParseError::BadArguments)
+            }
+        }
+        "TOPIC" => {
+            if pieces.len() >= 3 {
+                if config.is_valid_room(pieces[1]) {
+                    ParsedAction::Process(IncomingMsg::Topic(
+                        pieces[1].to_string(),
+                        pieces[2..].join(" "),
+                    ))
+                } else {
+                    ParsedAction::Error(Command::Topic, ParseError::BadRoomNameFormat)
+                }
+            } else {
+                ParsedAction::Error(Command::Topic, ParseError::BadArguments)
+            }
+        }
+        "TOPICHISTORY" => {
+            if pieces.len() == 2 {
+                if config.is_valid_room(pieces[1]) {
+                    ParsedAction::Process(IncomingMsg::TopicHistory(pieces[1].to_string()))
+                } else {
+                    ParsedAction::Error(Command::TopicHistory, ParseError::BadRoomNameFormat)
+                }
+            } else {
+                ParsedAction::Error(Command::TopicHistory, ParseError::BadArguments)
+            }
+        }
+        "INVITE" => {
+            if pieces.len() == 3 {
+                if !config.is_valid_room(pieces[1]) {
+                    ParsedAction::Error(Command::Invite, ParseError::BadRoomNameFormat)
+                } else if !config.is_valid_name(pieces[2]) {
+                    ParsedAction::Error(Command::Invite, ParseError::BadNameFormat)
+                } else {
+                    ParsedAction::Process(IncomingMsg::Invite(
+                        pieces[1].to_string(),
+                        pieces[2].to_string(),
+                    ))
+                }
+            } else {
+                ParsedAction::Error(Command::Invite, ParseError::BadArguments)
+            }
+        }
+        // a line of spaces is as empty as no line at all
+        "" => ParsedAction::None,
+        _ => ParsedAction::Unknown,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_name_regex() {
+        let config = ParserConfig::default();
+        // good
+        assert!(config.is_valid_name("@robert"));
+        assert!(config.is_valid_name("@rgp"));
+        // bad
+        assert!(!config.is_valid_name("@012345678901234567891"));
+        assert!(!config.is_valid_name("@gj"));
+        assert!(!config.is_valid_name("#robert"));
+        assert!(!config.is_valid_name("@rob*rt"));
+    }
+
+    #[test]
+    fn test_parser_config_name_length() {
+        let config = ParserConfig {
+            name_min_length: 2,
+            name_max_length: 5,
+            ..ParserConfig::default()
+        };
+        assert_eq!(
+            parse_incoming_with("NAME @a", &config),
+            ParsedAction::Error(Command::Name, ParseError::BadNameFormat)
+        );
+        assert_eq!(
+            parse_incoming_with("NAME @ab", &config),
+            ParsedAction::Process(IncomingMsg::Name("@ab".to_string()))
+        );
+        assert_eq!(
+            parse_incoming_with("NAME @abcde", &config),
+            ParsedAction::Process(IncomingMsg::Name("@abcde".to_string()))
+        );
+        assert_eq!(
+            parse_incoming_with("NAME @abcdef", &config),
+            ParsedAction::Error(Command::Name, ParseError::BadNameFormat)
+        );
+        assert_eq!(
+            parse_incoming_with("SAY @abcdef hi", &config),
+            ParsedAction::Error(Command::Say, ParseError::BadNameFormat)
+        );
+    }
+
+    #[test]
+    fn test_parser_config_room_length() {
+        let config = ParserConfig {
+            room_min_length: 4,
+            room_max_length: 6,
+            ..ParserConfig::default()
+        };
+        assert_eq!(
+            parse_incoming_with("JOIN #abc", &config),
+            ParsedAction::Error(Command::Join, ParseError::BadRoomNameFormat)
+        );
+        assert_eq!(
+            parse_incoming_with("JOIN #abcd", &config),
+            ParsedAction::Process(IncomingMsg::Join("#abcd".to_string()))
+        );
+        assert_eq!(
+            parse_incoming_with("JOIN #abcdef", &config),
+            ParsedAction::Process(IncomingMsg::Join("#abcdef".to_string()))
+        );
+        assert_eq!(
+            parse_incoming_with("JOIN #abcdefg", &config),
+            ParsedAction::Error(Command::Join, ParseError::BadRoomNameFormat)
+        );
+        assert_eq!(
+            parse_incoming_with("SAY #abcdefg hi", &config),
+            ParsedAction::Error(Command::Say, ParseError::BadRoomNameFormat)
+        );
+    }
+
+    #[test]
+    fn test_parse_incoming_empty_input() {
+        assert_eq!(parse_incoming(""), ParsedAction::None);
+        assert_eq!(parse_incoming("   "), ParsedAction::None);
+        assert_eq!(parse_incoming("HELLO"), ParsedAction::Unknown);
+    }
+
+    #[test]
+    fn test_parse_incoming_quit() {
+        assert_eq!(
+            parse_incoming("QUIT"),
+            ParsedAction::Process(IncomingMsg::Quit)
+        );
+        assert_eq!(
+            parse_incoming("QUIT other stuff"),
+            ParsedAction::Process(IncomingMsg::Quit)
+        );
+        assert_eq!(parse_incoming("quit other stuff"), ParsedAction::Unknown);
+        assert_eq!(parse_incoming("quit"), ParsedAction::Unknown);
+    }
+
+    #[test]
+    fn test_parse_incoming_name() {
+        assert_eq!(
+            parse_incoming("NAME @robert"),
+            ParsedAction::Process(IncomingMsg::Name("@robert".to_string()))
+        );
+        assert_eq!(
+            parse_incoming("NAME"),
+            ParsedAction::Error(Command::Name, ParseError::BadArguments)
+        );
+        assert_eq!(
+            parse_incoming("NAME @robert Steve"),
+            ParsedAction::Error(Command::Name, ParseError::BadArguments)
+        );
+        assert_eq!(
+            parse_incoming("NAME @robert**"),
+            ParsedAction::Error(Command::Name, ParseError::BadNameFormat)
+        );
+        assert_eq!(parse_incoming("name"), ParsedAction::Unknown);
+    }
+
+    #[test]
+    fn test_parse_incoming_join() {
+        assert_eq!(
+            parse_incoming("JOIN #room1"),
+            ParsedAction::Process(IncomingMsg::Join("#room1".to_string()))
+        );
+        assert_eq!(
+            parse_incoming("JOIN"),
+            ParsedAction::Error(Command::Join, ParseError::BadArguments)
+        );
+        assert_eq!(
+            parse_incoming("JOIN #room #room2"),
+            ParsedAction::Error(Command::Join, ParseError::BadArguments)
+        );
+        assert_eq!(
+            parse_incoming("JOIN @room"),
+            ParsedAction::Error(Command::Join, ParseError::BadRoomNameFormat)
+        );
+        assert_eq!(parse_incoming("join"), ParsedAction::Unknown);
+    }
+
+    #[test]
+    fn test_parse_incoming_leave() {
+        assert_eq!(
+            parse_incoming("LEAVE #room1"),
+            ParsedAction::Process(IncomingMsg::Leave("#room1".to_string()))
+        );
+        assert_eq!(
+            parse_incoming("LEAVE"),
+            ParsedAction::Error(Command::Leave, ParseError::BadArguments)
+        );
+        assert_eq!(
+            parse_incoming("LEAVE #room #room2"),
+            ParsedAction::Error(Command::Leave, ParseError::BadArguments)
+        );
+        assert_eq!(
+            parse_incoming("LEAVE @room"),
+            ParsedAction::Error(Command::Leave, ParseError::BadRoomNameFormat)
+        );
+        assert_eq!(parse_incoming("leave"), ParsedAction::Unknown);
+    }
+
+    #[test]
+    fn test_parse_incoming_say() {
+        assert_eq!(
+            parse_incoming("SAY #room341 hello everyone!"),
+            ParsedAction::Process(IncomingMsg::SayRoom(
+                "#room341".to_string(),
                 "hello everyone!".to_string()
             ))
         );
@@ -288,7 +1004,8 @@ mod tests {
             parse_incoming("SAY @kelsey hi kelsey :)"),
             ParsedAction::Process(IncomingMsg::SayUser(
                 "@kelsey".to_string(),
-                "hi kelsey :)".to_string()
+                "hi kelsey :)".to_string(),
+                false
             ))
         );
         assert_eq!(
@@ -317,39 +1034,1157 @@ mod tests {
     fn test_parse_incoming_rooms() {
         assert_eq!(
             parse_incoming("ROOMS"),
-            ParsedAction::Process(IncomingMsg::Rooms)
+            ParsedAction::Process(IncomingMsg::Rooms(None))
         );
         assert_eq!(
-            parse_incoming("ROOMS stuff"),
-            ParsedAction::Error(Command::Rooms, ParseError::BadArguments)
+            parse_incoming("ROOMS empty"),
+            ParsedAction::Process(IncomingMsg::Rooms(Some(RoomFilter::Empty)))
+        );
+        assert_eq!(
+            parse_incoming("ROOMS active"),
+            ParsedAction::Process(IncomingMsg::Rooms(Some(RoomFilter::Active)))
         );
+        for input in ["ROOMS stuff", "ROOMS Empty", "ROOMS empty active"] {
+            assert_eq!(
+                parse_incoming(input),
+                ParsedAction::Error(Command::Rooms, ParseError::BadArguments),
+                "{}",
+                input
+            );
+        }
     }
 
     #[test]
     fn test_parse_incoming_users() {
         assert_eq!(
             parse_incoming("USERS #test123"),
-            ParsedAction::Process(IncomingMsg::Users("#test123".to_string()))
+            ParsedAction::Process(IncomingMsg::Users("#test123".to_string(), None))
         );
         assert_eq!(
-            parse_incoming("USERS"),
-            ParsedAction::Error(Command::Users, ParseError::BadArguments)
+            parse_incoming("USERS #test123 active"),
+            ParsedAction::Process(IncomingMsg::Users(
+                "#test123".to_string(),
+                Some(UserSort::Active)
+            ))
         );
         assert_eq!(
-            parse_incoming("USERS #juice #man"),
+            parse_incoming("USERS #test123 idle"),
             ParsedAction::Error(Command::Users, ParseError::BadArguments)
         );
-    }
-
-    #[test]
-    fn test_parse_incoming_pong() {
         assert_eq!(
-            parse_incoming("PONG"),
-            ParsedAction::Process(IncomingMsg::Pong)
+            parse_incoming("USERS test123 active"),
+            ParsedAction::Error(Command::Users, ParseError::BadRoomNameFormat)
+        );
+        assert_eq!(
+            parse_incoming("USERS #test123 active now"),
+            ParsedAction::Error(Command::Users, ParseError::BadArguments)
+        );
+        assert_eq!(
+            parse_incoming("USERS"),
+            ParsedAction::Error(Command::Users, ParseError::BadArguments)
+        );
+        assert_eq!(
+            parse_incoming("USERS #juice #man"),
+            ParsedAction::Error(Command::Users, ParseError::BadArguments)
+        );
+    }
+
+    #[test]
+    fn test_parse_incoming_pong() {
+        assert_eq!(
+            parse_incoming("PONG"),
+            ParsedAction::Process(IncomingMsg::Pong)
         );
         assert_eq!(
             parse_incoming("PONG abc def"),
             ParsedAction::Error(Command::Pong, ParseError::BadArguments)
         );
     }
+
+    #[test]
+    #[cfg(feature = "debug")]
+    fn test_parse_incoming_debug() {
+        assert_eq!(
+            parse_incoming("DEBUG ON"),
+            ParsedAction::Process(IncomingMsg::Debug(true))
+        );
+        assert_eq!(
+            parse_incoming("DEBUG OFF"),
+            ParsedAction::Process(IncomingMsg::Debug(false))
+        );
+        assert_eq!(
+            parse_incoming("DEBUG"),
+            ParsedAction::Error(Command::Debug, ParseError::BadArguments)
+        );
+        assert_eq!(
+            parse_incoming("DEBUG maybe"),
+            ParsedAction::Error(Command::Debug, ParseError::BadArguments)
+        );
+    }
+
+    #[test]
+    fn test_parse_incoming_watch() {
+        assert_eq!(
+            parse_incoming("WATCH ON"),
+            ParsedAction::Process(IncomingMsg::Watch(true))
+        );
+        assert_eq!(
+            parse_incoming("WATCH OFF"),
+            ParsedAction::Process(IncomingMsg::Watch(false))
+        );
+        assert_eq!(
+            parse_incoming("WATCH"),
+            ParsedAction::Error(Command::Watch, ParseError::BadArguments)
+        );
+        assert_eq!(
+            parse_incoming("WATCH #sports"),
+            ParsedAction::Error(Command::Watch, ParseError::BadArguments)
+        );
+    }
+
+    #[test]
+    fn test_parse_incoming_syntax() {
+        assert_eq!(
+            parse_incoming("SYNTAX JOIN"),
+            ParsedAction::Process(IncomingMsg::Syntax(Command::Join))
+        );
+        assert_eq!(
+            parse_incoming("SYNTAX SYNTAX"),
+            ParsedAction::Process(IncomingMsg::Syntax(Command::Syntax))
+        );
+        assert_eq!(
+            parse_incoming("SYNTAX DANCE"),
+            ParsedAction::Error(Command::Syntax, ParseError::BadArguments)
+        );
+        assert_eq!(
+            parse_incoming("SYNTAX join"),
+            ParsedAction::Error(Command::Syntax, ParseError::BadArguments)
+        );
+        assert_eq!(
+            parse_incoming("SYNTAX"),
+            ParsedAction::Error(Command::Syntax, ParseError::BadArguments)
+        );
+        assert_eq!(Command::Join.syntax(), "JOIN <room>");
+        assert_eq!(Command::Users.syntax(), "USERS <room> [active]");
+    }
+
+    #[test]
+    fn test_parse_incoming_urgent_say() {
+        assert_eq!(
+            parse_incoming("SAY! @kelsey wake up"),
+            ParsedAction::Process(IncomingMsg::SayUser(
+                "@kelsey".to_string(),
+                "wake up".to_string(),
+                true
+            ))
+        );
+        assert_eq!(
+            parse_incoming("SAY! #sports wake up"),
+            ParsedAction::Error(Command::UrgentSay, ParseError::BadNameFormat)
+        );
+        assert_eq!(
+            parse_incoming("SAY! @kelsey"),
+            ParsedAction::Error(Command::UrgentSay, ParseError::BadArguments)
+        );
+    }
+
+    #[test]
+    fn test_parse_incoming_dnd() {
+        assert_eq!(
+            parse_incoming("DND ON"),
+            ParsedAction::Process(IncomingMsg::Dnd(true))
+        );
+        assert_eq!(
+            parse_incoming("DND OFF"),
+            ParsedAction::Process(IncomingMsg::Dnd(false))
+        );
+        assert_eq!(
+            parse_incoming("DND"),
+            ParsedAction::Error(Command::Dnd, ParseError::BadArguments)
+        );
+    }
+
+    #[test]
+    fn test_parse_incoming_reset() {
+        assert_eq!(
+            parse_incoming("RESET"),
+            ParsedAction::Process(IncomingMsg::Reset)
+        );
+        assert_eq!(
+            parse_incoming("RESET @robert"),
+            ParsedAction::Error(Command::Reset, ParseError::BadArguments)
+        );
+    }
+
+    #[test]
+    fn test_parse_incoming_mute() {
+        assert_eq!(
+            parse_incoming("MUTE #sports @robert"),
+            ParsedAction::Process(IncomingMsg::Mute(
+                "#sports".to_string(),
+                "@robert".to_string()
+            ))
+        );
+        assert_eq!(
+            parse_incoming("UNMUTE #sports @robert"),
+            ParsedAction::Process(IncomingMsg::Unmute(
+                "#sports".to_string(),
+                "@robert".to_string()
+            ))
+        );
+        assert_eq!(
+            parse_incoming("MUTE #sports"),
+            ParsedAction::Error(Command::Mute, ParseError::BadArguments)
+        );
+        assert_eq!(
+            parse_incoming("MUTE @robert #sports"),
+            ParsedAction::Error(Command::Mute, ParseError::BadRoomNameFormat)
+        );
+        assert_eq!(
+            parse_incoming("UNMUTE #sports robert"),
+            ParsedAction::Error(Command::Unmute, ParseError::BadNameFormat)
+        );
+    }
+
+    #[test]
+    fn test_parse_incoming_link() {
+        assert_eq!(
+            parse_incoming("LINK #sports beta"),
+            ParsedAction::Process(IncomingMsg::Link("#sports".to_string(), "beta".to_string()))
+        );
+        assert_eq!(
+            parse_incoming("LINK #sports"),
+            ParsedAction::Error(Command::Link, ParseError::BadArguments)
+        );
+        assert_eq!(
+            parse_incoming("LINK #sports be ta"),
+            ParsedAction::Error(Command::Link, ParseError::BadArguments)
+        );
+        assert_eq!(
+            parse_incoming("LINK sports beta"),
+            ParsedAction::Error(Command::Link, ParseError::BadRoomNameFormat)
+        );
+    }
+
+    #[test]
+    fn test_parse_incoming_preview() {
+        assert_eq!(
+            parse_incoming("PREVIEW SAY #sports good game"),
+            ParsedAction::Process(IncomingMsg::PreviewSayRoom(
+                "#sports".to_string(),
+                "good game".to_string()
+            ))
+        );
+        assert_eq!(
+            parse_incoming("PREVIEW SAY @robert hi"),
+            ParsedAction::Error(Command::Preview, ParseError::BadArguments)
+        );
+        assert_eq!(
+            parse_incoming("PREVIEW SAY #sports"),
+            ParsedAction::Error(Command::Preview, ParseError::BadArguments)
+        );
+        assert_eq!(
+            parse_incoming("PREVIEW SAY #s++ hi"),
+            ParsedAction::Error(Command::Preview, ParseError::BadRoomNameFormat)
+        );
+        assert_eq!(
+            parse_incoming("PREVIEW JOIN #sports"),
+            ParsedAction::Error(Command::Preview, ParseError::BadArguments)
+        );
+    }
+
+    #[test]
+    fn test_parse_incoming_status() {
+        assert_eq!(
+            parse_incoming("STATUS busy grabbing coffee"),
+            ParsedAction::Process(IncomingMsg::Status(Some(
+                "busy grabbing coffee".to_string()
+            )))
+        );
+        assert_eq!(
+            parse_incoming("STATUS"),
+            ParsedAction::Process(IncomingMsg::Status(None))
+        );
+    }
+
+    #[test]
+    fn test_parse_incoming_whois() {
+        assert_eq!(
+            parse_incoming("WHOIS @kelsey"),
+            ParsedAction::Process(IncomingMsg::Whois("@kelsey".to_string()))
+        );
+        assert_eq!(
+            parse_incoming("WHOIS kelsey"),
+            ParsedAction::Error(Command::Whois, ParseError::BadNameFormat)
+        );
+        assert_eq!(
+            parse_incoming("WHOIS @kelsey @robert"),
+            ParsedAction::Error(Command::Whois, ParseError::BadArguments)
+        );
+    }
+
+    #[test]
+    fn test_parse_incoming_commands() {
+        assert_eq!(
+            parse_incoming("COMMANDS"),
+            ParsedAction::Process(IncomingMsg::Commands)
+        );
+        assert_eq!(
+            parse_incoming("COMMANDS all"),
+            ParsedAction::Error(Command::Commands, ParseError::BadArguments)
+        );
+    }
+
+    #[test]
+    fn test_command_verbs() {
+        let verbs = Command::verbs();
+        for verb in [
+            "NAME", "JOIN", "LEAVE", "SAY", "USERS", "ROOMS", "QUIT", "COMMANDS",
+        ] {
+            assert!(verbs.contains(&verb), "{}", verb);
+        }
+        // DEBUG is only part of builds with the debug feature
+        assert_eq!(verbs.contains(&"DEBUG"), cfg!(feature = "debug"));
+        assert_eq!(
+            parse_incoming("DEBUG ON") == ParsedAction::Unknown,
+            !cfg!(feature = "debug")
+        );
+    }
+
+    #[test]
+    fn test_parse_incoming_my_stats() {
+        assert_eq!(
+            parse_incoming("MYSTATS"),
+            ParsedAction::Process(IncomingMsg::MyStats)
+        );
+        assert_eq!(
+            parse_incoming("MYSTATS @robert"),
+            ParsedAction::Error(Command::MyStats, ParseError::BadArguments)
+        );
+    }
+
+    #[test]
+    fn test_parse_incoming_oper_and_kill() {
+        assert_eq!(
+            parse_incoming("OPER hunter2"),
+            ParsedAction::Process(IncomingMsg::Oper("hunter2".to_string()))
+        );
+        assert_eq!(
+            parse_incoming("OPER"),
+            ParsedAction::Error(Command::Oper, ParseError::BadArguments)
+        );
+        assert_eq!(
+            parse_incoming("OPER hunter2").to_string(),
+            "ParsedAction::Process(OPER ********)"
+        );
+        assert_eq!(
+            parse_incoming("KILL @robert spamming the room"),
+            ParsedAction::Process(IncomingMsg::Kill(
+                "@robert".to_string(),
+                "spamming the room".to_string()
+            ))
+        );
+        assert_eq!(
+            parse_incoming("KILL robert spam"),
+            ParsedAction::Error(Command::Kill, ParseError::BadNameFormat)
+        );
+        assert_eq!(
+            parse_incoming("KILL @robert"),
+            ParsedAction::Error(Command::Kill, ParseError::BadArguments)
+        );
+    }
+
+    #[test]
+    fn test_parse_incoming_many_pieces() {
+        // free text past the last piece is kept exactly as it was sent
+        let message = "a ".repeat(100_000) + "end";
+        assert_eq!(
+            parse_incoming(&format!("SAY #sports {}", message)),
+            ParsedAction::Process(IncomingMsg::SayRoom("#sports".to_string(), message.clone()))
+        );
+        assert_eq!(
+            parse_incoming(&format!("STATUS {}", message)),
+            ParsedAction::Process(IncomingMsg::Status(Some(message.clone())))
+        );
+        let spaces = " ".repeat(100_000);
+        assert_eq!(
+            parse_incoming(&format!("SAY #sports {}", spaces)),
+            ParsedAction::Process(IncomingMsg::SayRoom("#sports".to_string(), spaces.clone()))
+        );
+        // commands with a fixed number of arguments still reject the extras
+        assert_eq!(
+            parse_incoming(&format!("JOIN #sports {}", message)),
+            ParsedAction::Error(Command::Join, ParseError::BadArguments)
+        );
+        assert_eq!(
+            parse_incoming(&format!("ROOMS{}", spaces)),
+            ParsedAction::Error(Command::Rooms, ParseError::BadArguments)
+        );
+    }
+
+    #[test]
+    fn test_parse_incoming_limits() {
+        assert_eq!(
+            parse_incoming("LIMITS"),
+            ParsedAction::Process(IncomingMsg::Limits)
+        );
+        assert_eq!(
+            parse_incoming("LIMITS all"),
+            ParsedAction::Error(Command::Limits, ParseError::BadArguments)
+        );
+    }
+
+    #[test]
+    fn test_parse_incoming_readonly() {
+        assert_eq!(
+            parse_incoming("READONLY #news ON"),
+            ParsedAction::Process(IncomingMsg::ReadOnly("#news".to_string(), true))
+        );
+        assert_eq!(
+            parse_incoming("READONLY #news OFF"),
+            ParsedAction::Process(IncomingMsg::ReadOnly("#news".to_string(), false))
+        );
+        assert_eq!(
+            parse_incoming("READONLY news ON"),
+            ParsedAction::Error(Command::ReadOnly, ParseError::BadRoomNameFormat)
+        );
+        assert_eq!(
+            parse_incoming("READONLY #news maybe"),
+            ParsedAction::Error(Command::ReadOnly, ParseError::BadArguments)
+        );
+        assert_eq!(
+            parse_incoming("READONLY #news"),
+            ParsedAction::Error(Command::ReadOnly, ParseError::BadArguments)
+        );
+    }
+
+    #[test]
+    fn test_parse_incoming_review() {
+        assert_eq!(
+            parse_incoming("REVIEW"),
+            ParsedAction::Process(IncomingMsg::Review)
+        );
+        assert_eq!(
+            parse_incoming("REVIEW #sports"),
+            ParsedAction::Error(Command::Review, ParseError::BadArguments)
+        );
+    }
+
+    #[test]
+    fn test_parse_incoming_maxlen() {
+        assert_eq!(
+            parse_incoming("MAXLEN 200"),
+            ParsedAction::Process(IncomingMsg::MaxLen(200))
+        );
+        assert_eq!(
+            parse_incoming(&format!("MAXLEN {}", MAX_LINE_LENGTH)),
+            ParsedAction::Process(IncomingMsg::MaxLen(MAX_LINE_LENGTH))
+        );
+        // the limit can't be above the server's own
+        for input in [
+            format!("MAXLEN {}", MAX_LINE_LENGTH + 1),
+            "MAXLEN 0".to_string(),
+            "MAXLEN -5".to_string(),
+            "MAXLEN lots".to_string(),
+            "MAXLEN".to_string(),
+            "MAXLEN 200 300".to_string(),
+        ] {
+            assert_eq!(
+                parse_incoming(&input),
+                ParsedAction::Error(Command::MaxLen, ParseError::BadArguments),
+                "{}",
+                input
+            );
+        }
+
+        // nor above the line length the server was configured with
+        let config = ParserConfig {
+            max_line_length: 32,
+            ..ParserConfig::default()
+        };
+        assert_eq!(
+            parse_incoming_with("MAXLEN 32", &config),
+            ParsedAction::Process(IncomingMsg::MaxLen(32))
+        );
+        assert_eq!(
+            parse_incoming_with("MAXLEN 33", &config),
+            ParsedAction::Error(Command::MaxLen, ParseError::BadArguments)
+        );
+    }
+
+    #[test]
+    fn test_parse_incoming_histlen() {
+        assert_eq!(
+            parse_incoming("HISTLEN 10"),
+            ParsedAction::Process(IncomingMsg::HistLen(10))
+        );
+        // no history at all is fine
+        assert_eq!(
+            parse_incoming("HISTLEN 0"),
+            ParsedAction::Process(IncomingMsg::HistLen(0))
+        );
+        for input in ["HISTLEN -1", "HISTLEN lots", "HISTLEN", "HISTLEN 10 20"] {
+            assert_eq!(
+                parse_incoming(input),
+                ParsedAction::Error(Command::HistLen, ParseError::BadArguments),
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_incoming_echo() {
+        assert_eq!(
+            parse_incoming("ECHO ROOM ON"),
+            ParsedAction::Process(IncomingMsg::EchoRoom(true))
+        );
+        assert_eq!(
+            parse_incoming("ECHO ROOM OFF"),
+            ParsedAction::Process(IncomingMsg::EchoRoom(false))
+        );
+        for input in [
+            "ECHO",
+            "ECHO ROOM",
+            "ECHO ON",
+            "ECHO USER ON",
+            "ECHO ROOM ON now",
+        ] {
+            assert_eq!(
+                parse_incoming(input),
+                ParsedAction::Error(Command::Echo, ParseError::BadArguments),
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_prefixes_are_not_interchangeable() {
+        let config = ParserConfig::default();
+        assert!(config.is_valid_room("#sports"));
+        assert!(!config.is_valid_room("@sports"));
+        assert!(!config.is_valid_room("sports"));
+        assert!(!config.is_valid_room(""));
+        assert!(config.is_valid_name("@robert"));
+        assert!(!config.is_valid_name("#robert"));
+        assert!(!config.is_valid_name(""));
+
+        // commands that take a room never take a user, and the other way around
+        for input in ["JOIN @robert", "LEAVE @robert", "USERS @robert"] {
+            assert!(
+                matches!(
+                    parse_incoming(input),
+                    ParsedAction::Error(_, ParseError::BadRoomNameFormat)
+                ),
+                "{}",
+                input
+            );
+        }
+        assert_eq!(
+            parse_incoming("SAY! #sports now"),
+            ParsedAction::Error(Command::UrgentSay, ParseError::BadNameFormat)
+        );
+        assert_eq!(
+            parse_incoming("SAY sports hello"),
+            ParsedAction::Error(Command::Say, ParseError::BadArguments)
+        );
+        assert_eq!(
+            parse_incoming("SAY #sports hello"),
+            ParsedAction::Process(IncomingMsg::SayRoom(
+                "#sports".to_string(),
+                "hello".to_string()
+            ))
+        );
+        assert_eq!(
+            parse_incoming("SAY @robert hello"),
+            ParsedAction::Process(IncomingMsg::SayUser(
+                "@robert".to_string(),
+                "hello".to_string(),
+                false
+            ))
+        );
+    }
+
+    #[test]
+    fn test_parse_incoming_shared() {
+        assert_eq!(
+            parse_incoming("SHARED @robert"),
+            ParsedAction::Process(IncomingMsg::Shared("@robert".to_string()))
+        );
+        assert_eq!(
+            parse_incoming("SHARED #sports"),
+            ParsedAction::Error(Command::Shared, ParseError::BadNameFormat)
+        );
+        assert_eq!(
+            parse_incoming("SHARED"),
+            ParsedAction::Error(Command::Shared, ParseError::BadArguments)
+        );
+    }
+
+    #[test]
+    fn test_parse_incoming_bridge() {
+        assert_eq!(
+            parse_incoming("BRIDGE #sports @irc-robert good game"),
+            ParsedAction::Process(IncomingMsg::Bridge(
+                "#sports".to_string(),
+                "@irc-robert".to_string(),
+                "good game".to_string()
+            ))
+        );
+        assert_eq!(
+            parse_incoming("BRIDGE #sports robert good game"),
+            ParsedAction::Error(Command::Bridge, ParseError::BadNameFormat)
+        );
+        assert_eq!(
+            parse_incoming("BRIDGE @robert @kelsey hi"),
+            ParsedAction::Error(Command::Bridge, ParseError::BadRoomNameFormat)
+        );
+        assert_eq!(
+            parse_incoming("BRIDGE #sports @robert"),
+            ParsedAction::Error(Command::Bridge, ParseError::BadArguments)
+        );
+    }
+
+    #[test]
+    fn test_parse_incoming_connstats() {
+        assert_eq!(
+            parse_incoming("CONNSTATS"),
+            ParsedAction::Process(IncomingMsg::ConnStats)
+        );
+        assert_eq!(
+            parse_incoming("CONNSTATS @robert"),
+            ParsedAction::Error(Command::ConnStats, ParseError::BadArguments)
+        );
+    }
+
+    #[test]
+    fn test_parse_incoming_switch() {
+        assert_eq!(
+            parse_incoming("SWITCH #room1 #room2"),
+            ParsedAction::Process(IncomingMsg::Switch(
+                "#room1".to_string(),
+                "#room2".to_string()
+            ))
+        );
+        assert_eq!(
+            parse_incoming("SWITCH #room1"),
+            ParsedAction::Error(Command::Switch, ParseError::BadArguments)
+        );
+        assert_eq!(
+            parse_incoming("SWITCH #room1 #room2 #room3"),
+            ParsedAction::Error(Command::Switch, ParseError::BadArguments)
+        );
+        assert_eq!(
+            parse_incoming("SWITCH #room1 @robert"),
+            ParsedAction::Error(Command::Switch, ParseError::BadRoomNameFormat)
+        );
+    }
+
+    #[test]
+    fn test_parse_incoming_buddy() {
+        assert_eq!(
+            parse_incoming("BUDDY @robert"),
+            ParsedAction::Process(IncomingMsg::Buddy("@robert".to_string()))
+        );
+        assert_eq!(
+            parse_incoming("BUDDY #sports"),
+            ParsedAction::Error(Command::Buddy, ParseError::BadNameFormat)
+        );
+        assert_eq!(
+            parse_incoming("BUDDY @robert @kelsey"),
+            ParsedAction::Error(Command::Buddy, ParseError::BadArguments)
+        );
+    }
+
+    #[test]
+    fn test_parser_config_escapes() {
+        let input = "SAY #sports \x1b[2J\x1b]0;pwned\x07good \x1b[1;31mgame\x1b[0m\x1b";
+        assert_eq!(
+            parse_incoming(input),
+            ParsedAction::Process(IncomingMsg::SayRoom(
+                "#sports".to_string(),
+                "good game".to_string()
+            ))
+        );
+
+        let config = ParserConfig {
+            escapes: EscapePolicy::Reject,
+            ..ParserConfig::default()
+        };
+        assert_eq!(
+            parse_incoming_with(input, &config),
+            ParsedAction::Error(Command::Say, ParseError::EscapeSequence)
+        );
+        assert_eq!(
+            parse_incoming_with("SAY #sports good game", &config),
+            ParsedAction::Process(IncomingMsg::SayRoom(
+                "#sports".to_string(),
+                "good game".to_string()
+            ))
+        );
+        assert_eq!(
+            parse_incoming_with("\x1b[31mSAY #sports hi", &config),
+            ParsedAction::Unknown
+        );
+
+        let config = ParserConfig {
+            escapes: EscapePolicy::Allow,
+            ..ParserConfig::default()
+        };
+        assert_eq!(
+            parse_incoming_with("SAY #sports \x1b[31mred", &config),
+            ParsedAction::Process(IncomingMsg::SayRoom(
+                "#sports".to_string(),
+                "\x1b[31mred".to_string()
+            ))
+        );
+
+        assert_eq!("reject".parse(), Ok(EscapePolicy::Reject));
+        assert!("ignore".parse::<EscapePolicy>().is_err());
+    }
+
+    #[test]
+    fn test_parse_incoming_prefs() {
+        assert_eq!(
+            parse_incoming("PREFS"),
+            ParsedAction::Process(IncomingMsg::Prefs)
+        );
+        assert_eq!(
+            parse_incoming("PREFS @robert"),
+            ParsedAction::Error(Command::Prefs, ParseError::BadArguments)
+        );
+    }
+
+    #[test]
+    fn test_parse_incoming_unarchive() {
+        assert_eq!(
+            parse_incoming("UNARCHIVE #sports"),
+            ParsedAction::Process(IncomingMsg::Unarchive("#sports".to_string()))
+        );
+        assert_eq!(
+            parse_incoming("UNARCHIVE @robert"),
+            ParsedAction::Error(Command::Unarchive, ParseError::BadRoomNameFormat)
+        );
+        assert_eq!(
+            parse_incoming("UNARCHIVE"),
+            ParsedAction::Error(Command::Unarchive, ParseError::BadArguments)
+        );
+    }
+
+    #[test]
+    fn test_parse_incoming_proto_stats() {
+        assert_eq!(
+            parse_incoming("PROTOSTATS"),
+            ParsedAction::Process(IncomingMsg::ProtoStats)
+        );
+        assert_eq!(
+            parse_incoming("PROTOSTATS JOIN"),
+            ParsedAction::Error(Command::ProtoStats, ParseError::BadArguments)
+        );
+    }
+
+    #[test]
+    fn test_parse_incoming_context() {
+        assert_eq!(
+            parse_incoming("CONTEXT ON"),
+            ParsedAction::Process(IncomingMsg::Context(true))
+        );
+        assert_eq!(
+            parse_incoming("CONTEXT OFF"),
+            ParsedAction::Process(IncomingMsg::Context(false))
+        );
+        assert_eq!(
+            parse_incoming("CONTEXT @robert"),
+            ParsedAction::Error(Command::Context, ParseError::BadArguments)
+        );
+    }
+
+    #[test]
+    fn test_parse_incoming_mode() {
+        assert_eq!(
+            parse_incoming("MODE #sports"),
+            ParsedAction::Process(IncomingMsg::Mode("#sports".to_string(), None))
+        );
+        assert_eq!(
+            parse_incoming("MODE #sports +i"),
+            ParsedAction::Process(IncomingMsg::Mode(
+                "#sports".to_string(),
+                Some((RoomMode::InviteOnly, true))
+            ))
+        );
+        assert_eq!(
+            parse_incoming("MODE #sports -m"),
+            ParsedAction::Process(IncomingMsg::Mode(
+                "#sports".to_string(),
+                Some((RoomMode::Moderated, false))
+            ))
+        );
+        assert_eq!(
+            parse_incoming("MODE #sports +t"),
+            ParsedAction::Process(IncomingMsg::Mode(
+                "#sports".to_string(),
+                Some((RoomMode::TopicLocked, true))
+            ))
+        );
+        assert_eq!(
+            parse_incoming("MODE #sports +x"),
+            ParsedAction::Error(Command::Mode, ParseError::BadArguments)
+        );
+        assert_eq!(
+            parse_incoming("MODE @robert +i"),
+            ParsedAction::Error(Command::Mode, ParseError::BadRoomNameFormat)
+        );
+        assert_eq!(
+            parse_incoming("MODE #sports +i +m"),
+            ParsedAction::Error(Command::Mode, ParseError::BadArguments)
+        );
+    }
+
+    #[test]
+    fn test_parse_incoming_membership() {
+        assert_eq!(
+            parse_incoming("MEMBERSHIP #sports ON"),
+            ParsedAction::Process(IncomingMsg::Membership("#sports".to_string(), true))
+        );
+        assert_eq!(
+            parse_incoming("MEMBERSHIP #sports OFF"),
+            ParsedAction::Process(IncomingMsg::Membership("#sports".to_string(), false))
+        );
+        assert_eq!(
+            parse_incoming("MEMBERSHIP #sports"),
+            ParsedAction::Error(Command::Membership, ParseError::BadArguments)
+        );
+        assert_eq!(
+            parse_incoming("MEMBERSHIP sports ON"),
+            ParsedAction::Error(Command::Membership, ParseError::BadRoomNameFormat)
+        );
+    }
+
+    #[test]
+    fn test_parse_incoming_say_users() {
+        assert_eq!(
+            parse_incoming("SAY @robert,@kelsey,@dave hi all"),
+            ParsedAction::Process(IncomingMsg::SayUsers(
+                vec![
+                    "@robert".to_string(),
+                    "@kelsey".to_string(),
+                    "@dave".to_string()
+                ],
+                "hi all".to_string()
+            ))
+        );
+        // a name given twice only gets the message once
+        assert_eq!(
+            parse_incoming("SAY @robert,@robert hi"),
+            ParsedAction::Process(IncomingMsg::SayUsers(
+                vec!["@robert".to_string()],
+                "hi".to_string()
+            ))
+        );
+        for input in [
+            "SAY @robert,#sports hi",
+            "SAY @robert,,@kelsey hi",
+            "SAY @robert, hi",
+        ] {
+            assert_eq!(
+                parse_incoming(input),
+                ParsedAction::Error(Command::Say, ParseError::BadNameFormat)
+            );
+        }
+
+        let config = ParserConfig {
+            max_recipients: 2,
+            ..ParserConfig::default()
+        };
+        assert!(matches!(
+            parse_incoming_with("SAY @robert,@kelsey hi", &config),
+            ParsedAction::Process(IncomingMsg::SayUsers(_, _))
+        ));
+        assert_eq!(
+            parse_incoming_with("SAY @robert,@kelsey,@dave hi", &config),
+            ParsedAction::Error(Command::Say, ParseError::BadArguments)
+        );
+    }
+
+    #[test]
+    fn test_parse_incoming_health() {
+        assert_eq!(
+            parse_incoming("HEALTH"),
+            ParsedAction::Process(IncomingMsg::Health)
+        );
+        assert_eq!(parse_incoming("HEALTH check"), ParsedAction::Unknown);
+        assert_eq!(Command::from_verb("HEALTH"), None);
+    }
+
+    #[test]
+    fn test_parse_incoming_time() {
+        assert_eq!(
+            parse_incoming("TIME"),
+            ParsedAction::Process(IncomingMsg::Time)
+        );
+        assert_eq!(
+            parse_incoming("TIME now"),
+            ParsedAction::Error(Command::Time, ParseError::BadArguments)
+        );
+    }
+
+    #[test]
+    fn test_parse_incoming_edit_delete() {
+        assert_eq!(
+            parse_incoming("EDIT #sports 4 good game everyone"),
+            ParsedAction::Process(IncomingMsg::Edit(
+                "#sports".to_string(),
+                4,
+                "good game everyone".to_string()
+            ))
+        );
+        assert_eq!(
+            parse_incoming("DELETE #sports 4"),
+            ParsedAction::Process(IncomingMsg::Delete("#sports".to_string(), 4))
+        );
+        for (input, command) in [
+            ("EDIT #sports 4", Command::Edit),
+            ("EDIT #sports four good game", Command::Edit),
+            ("DELETE #sports", Command::Delete),
+            ("DELETE #sports -1", Command::Delete),
+            ("DELETE #sports 4 5", Command::Delete),
+        ] {
+            assert_eq!(
+                parse_incoming(input),
+                ParsedAction::Error(command, ParseError::BadArguments)
+            );
+        }
+        assert_eq!(
+            parse_incoming("DELETE sports 4"),
+            ParsedAction::Error(Command::Delete, ParseError::BadRoomNameFormat)
+        );
+    }
+
+    #[test]
+    fn test_parse_incoming_myrooms() {
+        assert_eq!(
+            parse_incoming("MYROOMS"),
+            ParsedAction::Process(IncomingMsg::MyRooms(false))
+        );
+        assert_eq!(
+            parse_incoming("MYROOMS full"),
+            ParsedAction::Process(IncomingMsg::MyRooms(true))
+        );
+        for input in ["MYROOMS Full", "MYROOMS counts", "MYROOMS full full"] {
+            assert_eq!(
+                parse_incoming(input),
+                ParsedAction::Error(Command::MyRooms, ParseError::BadArguments),
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_incoming_create() {
+        assert_eq!(
+            parse_incoming("CREATE #sports"),
+            ParsedAction::Process(IncomingMsg::Create("#sports".to_string()))
+        );
+        assert_eq!(
+            parse_incoming("CREATE sports"),
+            ParsedAction::Error(Command::Create, ParseError::BadRoomNameFormat)
+        );
+        for input in ["CREATE", "CREATE #sports #chess"] {
+            assert_eq!(
+                parse_incoming(input),
+                ParsedAction::Error(Command::Create, ParseError::BadArguments),
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_incoming_destroy() {
+        assert_eq!(
+            parse_incoming("DESTROY #sports"),
+            ParsedAction::Process(IncomingMsg::Destroy("#sports".to_string()))
+        );
+        assert_eq!(
+            parse_incoming("DESTROY sports"),
+            ParsedAction::Error(Command::Destroy, ParseError::BadRoomNameFormat)
+        );
+        for input in ["DESTROY", "DESTROY #sports #chess"] {
+            assert_eq!(
+                parse_incoming(input),
+                ParsedAction::Error(Command::Destroy, ParseError::BadArguments),
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_incoming_recent() {
+        assert_eq!(
+            parse_incoming("RECENT #sports 20"),
+            ParsedAction::Process(IncomingMsg::Recent("#sports".to_string(), 20))
+        );
+        assert_eq!(
+            parse_incoming("RECENT sports 20"),
+            ParsedAction::Error(Command::Recent, ParseError::BadRoomNameFormat)
+        );
+        for input in [
+            "RECENT #sports",
+            "RECENT #sports 0",
+            "RECENT #sports -1",
+            "RECENT #sports twenty",
+            "RECENT #sports 20 30",
+        ] {
+            assert_eq!(
+                parse_incoming(input),
+                ParsedAction::Error(Command::Recent, ParseError::BadArguments),
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_incoming_echotest() {
+        assert_eq!(
+            parse_incoming("ECHOTEST probe-42"),
+            ParsedAction::Process(IncomingMsg::EchoTest("probe-42".to_string()))
+        );
+        for input in ["ECHOTEST", "ECHOTEST ", "ECHOTEST probe 42"] {
+            assert_eq!(
+                parse_incoming(input),
+                ParsedAction::Error(Command::EchoTest, ParseError::BadArguments),
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_incoming_compress() {
+        assert_eq!(
+            parse_incoming("COMPRESS"),
+            ParsedAction::Process(IncomingMsg::Compress)
+        );
+        assert_eq!(
+            parse_incoming("COMPRESS deflate"),
+            ParsedAction::Error(Command::Compress, ParseError::BadArguments)
+        );
+    }
+
+    #[test]
+    fn test_parse_incoming_joinall() {
+        assert_eq!(
+            parse_incoming("JOINALL #sports"),
+            ParsedAction::Process(IncomingMsg::JoinAll(vec!["#sports".to_string()]))
+        );
+        // every room past the command's own pieces is kept, in order, once each
+        assert_eq!(
+            parse_incoming("JOINALL #sports #chess #sports #books #cards #movies #music"),
+            ParsedAction::Process(IncomingMsg::JoinAll(
+                ["#sports", "#chess", "#books", "#cards", "#movies", "#music"]
+                    .map(String::from)
+                    .to_vec()
+            ))
+        );
+        for input in [
+            "JOINALL #sports chess",
+            "JOINALL #sports  #chess",
+            "JOINALL @robert",
+        ] {
+            assert_eq!(
+                parse_incoming(input),
+                ParsedAction::Error(Command::JoinAll, ParseError::BadRoomNameFormat),
+                "{}",
+                input
+            );
+        }
+        assert_eq!(
+            parse_incoming("JOINALL"),
+            ParsedAction::Error(Command::JoinAll, ParseError::BadArguments)
+        );
+        // one room for every piece after the command, and no more
+        let rooms: Vec<String> = (1..MAX_PIECES).map(|n| format!("#room{}", n)).collect();
+        assert_eq!(
+            parse_incoming(&format!("JOINALL {}", rooms.join(" "))),
+            ParsedAction::Process(IncomingMsg::JoinAll(rooms.clone()))
+        );
+        assert_eq!(
+            parse_incoming(&format!("JOINALL {} #overflow", rooms.join(" "))),
+            ParsedAction::Error(Command::JoinAll, ParseError::BadArguments)
+        );
+        assert_eq!(
+            parse_incoming(&format!("JOINALL{}", " #sports".repeat(10_000))),
+            ParsedAction::Error(Command::JoinAll, ParseError::BadArguments)
+        );
+    }
+
+    #[test]
+    fn test_parse_incoming_topic() {
+        assert_eq!(
+            parse_incoming("TOPIC #sports the big game"),
+            ParsedAction::Process(IncomingMsg::Topic(
+                "#sports".to_string(),
+                "the big game".to_string()
+            ))
+        );
+        assert_eq!(
+            parse_incoming("TOPIC sports the big game"),
+            ParsedAction::Error(Command::Topic, ParseError::BadRoomNameFormat)
+        );
+        assert_eq!(
+            parse_incoming("TOPIC #sports"),
+            ParsedAction::Error(Command::Topic, ParseError::BadArguments)
+        );
+    }
+
+    #[test]
+    fn test_parse_incoming_topic_history() {
+        assert_eq!(
+            parse_incoming("TOPICHISTORY #sports"),
+            ParsedAction::Process(IncomingMsg::TopicHistory("#sports".to_string()))
+        );
+        assert_eq!(
+            parse_incoming("TOPICHISTORY sports"),
+            ParsedAction::Error(Command::TopicHistory, ParseError::BadRoomNameFormat)
+        );
+        for input in ["TOPICHISTORY", "TOPICHISTORY #sports #news"] {
+            assert_eq!(
+                parse_incoming(input),
+                ParsedAction::Error(Command::TopicHistory, ParseError::BadArguments),
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_incoming_invite() {
+        assert_eq!(
+            parse_incoming("INVITE #sports @kelsey"),
+            ParsedAction::Process(IncomingMsg::Invite(
+                "#sports".to_string(),
+                "@kelsey".to_string()
+            ))
+        );
+        assert_eq!(
+            parse_incoming("INVITE sports @kelsey"),
+            ParsedAction::Error(Command::Invite, ParseError::BadRoomNameFormat)
+        );
+        assert_eq!(
+            parse_incoming("INVITE #sports kelsey"),
+            ParsedAction::Error(Command::Invite, ParseError::BadNameFormat)
+        );
+        for input in ["INVITE #sports", "INVITE #sports @kelsey @dave"] {
+            assert_eq!(
+                parse_incoming(input),
+                ParsedAction::Error(Command::Invite, ParseError::BadArguments),
+                "{}",
+                input
+            );
+        }
+    }
 }
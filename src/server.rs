@@ -1,28 +1,41 @@
 //! The main asyncronous orchestrator of the server and all the client connections.
 //!
 use crate::{
-    messages::{IncomingMsg, Message, OutgoingMsg},
-    parser::{parse_incoming, Command, ParsedAction},
-    server_state::{ServerState, User},
+    codec::ChatCodec,
+    command::{Command, CommandCounts},
+    link::Relayed,
+    messages::{IncomingMsg, Message, OutgoingMsg, UserSort},
+    parser::{parse_incoming_with, ParseError, ParsedAction, ParserConfig},
+    server_state::{normalize_name, ServerError, ServerState, User, MAX_BUDDIES},
 };
 use anyhow::anyhow;
 use futures::SinkExt;
 use log::info;
 use std::{
+    collections::VecDeque,
     fmt::Display,
+    future::Future,
     net::SocketAddr,
-    sync::Arc,
-    time::{Duration, Instant},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 use tokio::{
-    net::TcpStream,
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream},
     sync::{
         mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
         Mutex,
     },
+    task::JoinHandle,
 };
 use tokio_stream::StreamExt;
-use tokio_util::codec::{Framed, LinesCodec, LinesCodecError};
+use tokio_util::{
+    codec::{Framed, LinesCodecError},
+    sync::CancellationToken,
+};
 
 enum PingPongBall {
     /// Send a PING message to the client.
@@ -33,12 +46,18 @@ enum PingPongBall {
     PongTimeout,
 }
 
+/// How often a connection's keep-alive checks run. A client that hasn't answered a PING for one interval is
+/// sent another, and one that hasn't for two is disconnected.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(90);
+
 /// [PingPongTable] encapsulates the asyncronous worker used to orchestrate PING and PONG commands for each
 /// connected client in order to determine if a client needs to be disconnected from the server.
 struct PingPongTable {
     sender: UnboundedSender<PingPongBall>,
     receiver: UnboundedReceiver<PingPongBall>,
     last_activity: Arc<Mutex<Instant>>,
+    /// The worker started by [PingPongTable::start_worker].
+    worker: Option<JoinHandle<()>>,
 }
 
 impl PingPongTable {
@@ -49,39 +68,39 @@ impl PingPongTable {
             sender,
             receiver,
             last_activity,
+            worker: None,
         }
     }
 
     /// Spawn an asyncronous worker that will periodically check a connections liveliness. If the client is
     /// inactive, a PING will be sent to the client. If a PONG is not received in time, a [PingPongBall::PongTimeout]
-    /// is triggered and the client will be disconnected.
-    pub fn start_worker(&self) {
+    /// is triggered and the client will be disconnected. The worker stops when `shutdown` is cancelled.
+    pub fn start_worker(&mut self, shutdown: &CancellationToken) {
         let sender = self.sender.clone();
         let last_activity = self.last_activity.clone();
-        tokio::spawn(async move {
+        let worker = spawn_helper(shutdown, async move {
             loop {
-                tokio::time::sleep(Duration::from_secs(90)).await;
-                let la = last_activity.lock().await;
-                let elapsed = la.elapsed().as_secs();
-                if elapsed >= 180 {
-                    if sender.is_closed() {
-                        // client disconnected and worker is dangling
-                        info!("client disconnected. closing ping pong worker.");
-                        return;
-                    }
+                tokio::time::sleep(KEEPALIVE_INTERVAL).await;
+                let elapsed = last_activity.lock().await.elapsed();
+                let ball = if elapsed >= 2 * KEEPALIVE_INTERVAL {
                     // PONG never received in time
-                    sender.send(PingPongBall::PongTimeout).unwrap();
-                } else if elapsed >= 90 {
-                    if sender.is_closed() {
-                        // client disconnected and worker is dangling
-                        info!("client disconnected. closing ping pong worker.");
-                        return;
-                    }
+                    PingPongBall::PongTimeout
+                } else if elapsed >= KEEPALIVE_INTERVAL {
                     // PING the client
-                    sender.send(PingPongBall::SendPing).unwrap();
+                    PingPongBall::SendPing
+                } else {
+                    continue;
+                };
+                if sender.send(ball).is_err() {
+                    // client disconnected and worker is dangling
+                    info!("client disconnected. closing ping pong worker.");
+                    return;
                 }
             }
         });
+        if let Some(old_worker) = self.worker.replace(worker) {
+            old_worker.abort();
+        }
     }
 
     pub async fn set_last_activity(&self) {
@@ -90,54 +109,475 @@ impl PingPongTable {
     }
 }
 
+/// Spawn a task that belongs to one connection. It runs until it finishes on its own or `shutdown` is
+/// cancelled, whichever comes first, so it can't outlive the connection.
+fn spawn_helper<F>(shutdown: &CancellationToken, task: F) -> JoinHandle<()>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let shutdown = shutdown.clone();
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = shutdown.cancelled() => {}
+            _ = task => {}
+        }
+    })
+}
+
+/// How much of a client's input is read ahead of the command being handled. The stream is only read again
+/// once every whole line already read has been handled, so lines pipelined past this wait in the socket,
+/// where TCP pushes back on the client. Lines longer than this still fit, since the buffer grows to hold one.
+const READ_AHEAD: usize = 4 * 1024;
+
+/// Settings that apply to each client connection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConnectionConfig {
+    pub parser: ParserConfig,
+    /// How long a client has to register a name before it is disconnected.
+    pub registration_timeout: Duration,
+    /// How many JOINs and LEAVEs a client may send per `churn_window`. Any more are dropped.
+    pub churn_limit: usize,
+    pub churn_window: Duration,
+    /// How many malformed commands in a row a client may send before the server slows down handling its
+    /// input. At twice as many the client is disconnected.
+    pub malformed_limit: usize,
+    /// How many commands from the client are handled back to back before the connection stops reading for a
+    /// turn, so a client pipelining thousands of lines can't hold up its own outgoing messages and keep-alive
+    /// checks, or other connections. At least 1.
+    pub command_burst: usize,
+    /// Answer lines that don't start with a known command with a NOTICE pointing at COMMANDS, for people
+    /// typing by hand. Off by default, so unknown commands are ignored in silence.
+    pub unknown_command_notice: bool,
+    /// Let clients switch their connection to compressed frames with COMPRESS. Off by default.
+    pub compression: bool,
+    /// When a client registers a name that is already taken by a holder that hasn't been heard from for a
+    /// keep-alive interval, PING the holder and give it this long to answer. A holder that doesn't is taken
+    /// for a ghost left by a crashed client, disconnected, and the name handed over. `None`, the default,
+    /// refuses the name right away.
+    pub ghost_probe: Option<Duration>,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        Self {
+            parser: ParserConfig::default(),
+            registration_timeout: Duration::from_secs(60),
+            churn_limit: 20,
+            churn_window: Duration::from_secs(10),
+            malformed_limit: 10,
+            command_burst: 32,
+            unknown_command_notice: false,
+            compression: false,
+            ghost_probe: None,
+        }
+    }
+}
+
+impl ConnectionConfig {
+    /// The limits a client can check its input against, as LIMIT messages.
+    pub fn limits(&self) -> Vec<OutgoingMsg> {
+        vec![
+            OutgoingMsg::Limit(
+                "line-length".to_string(),
+                self.parser.max_line_length as u64,
+            ),
+            OutgoingMsg::Limit(
+                "name-min-length".to_string(),
+                self.parser.name_min_length as u64,
+            ),
+            OutgoingMsg::Limit(
+                "name-max-length".to_string(),
+                self.parser.name_max_length as u64,
+            ),
+            OutgoingMsg::Limit(
+                "room-min-length".to_string(),
+                self.parser.room_min_length as u64,
+            ),
+            OutgoingMsg::Limit(
+                "room-max-length".to_string(),
+                self.parser.room_max_length as u64,
+            ),
+            OutgoingMsg::Limit("recipients".to_string(), self.parser.max_recipients as u64),
+            OutgoingMsg::Limit("churn-limit".to_string(), self.churn_limit as u64),
+            OutgoingMsg::Limit("churn-window".to_string(), self.churn_window.as_secs()),
+            OutgoingMsg::Limit("malformed-limit".to_string(), self.malformed_limit as u64),
+        ]
+    }
+}
+
+/// Lines, and their bytes including the newline, that have crossed one connection in each direction.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct ConnStats {
+    messages_in: u64,
+    bytes_in: u64,
+    messages_out: u64,
+    bytes_out: u64,
+}
+
+impl ConnStats {
+    fn count_in(&mut self, line: &str) {
+        self.messages_in += 1;
+        self.bytes_in += line.len() as u64 + 1;
+    }
+
+    fn count_out(&mut self, line: &str) {
+        self.messages_out += 1;
+        self.bytes_out += line.len() as u64 + 1;
+    }
+
+    fn report(&self) -> OutgoingMsg {
+        OutgoingMsg::ConnStats(
+            self.messages_in,
+            self.bytes_in,
+            self.messages_out,
+            self.bytes_out,
+        )
+    }
+}
+
+/// Counts a client's recent JOINs and LEAVEs so that rapidly creating and deleting rooms can be throttled.
+struct ChurnLimiter {
+    limit: usize,
+    window: Duration,
+    recent: VecDeque<tokio::time::Instant>,
+}
+
+impl ChurnLimiter {
+    pub fn new(limit: usize, window: Duration) -> Self {
+        Self {
+            limit,
+            window,
+            recent: VecDeque::new(),
+        }
+    }
+
+    /// Record a JOIN or LEAVE. Returns `false` if the client has already used up the current window.
+    pub fn allow(&mut self) -> bool {
+        let now = tokio::time::Instant::now();
+        while let Some(oldest) = self.recent.front() {
+            if now.duration_since(*oldest) < self.window {
+                break;
+            }
+            self.recent.pop_front();
+        }
+        if self.recent.len() >= self.limit {
+            return false;
+        }
+        self.recent.push_back(now);
+        true
+    }
+}
+
+/// Delay before handling the first malformed command past the limit. Each one after that waits twice as long.
+const MALFORMED_DELAY_MIN: Duration = Duration::from_millis(100);
+const MALFORMED_DELAY_MAX: Duration = Duration::from_secs(2);
+
+/// Counts a client's malformed commands in a row. Each one costs an ERROR reply, so a client that keeps
+/// sending them is slowed down and then disconnected.
+struct MalformedPenalty {
+    limit: usize,
+    in_a_row: usize,
+}
+
+impl MalformedPenalty {
+    pub fn new(limit: usize) -> Self {
+        Self { limit, in_a_row: 0 }
+    }
+
+    /// Record a malformed command. Returns how long to wait before handling it, or `None` once the client has
+    /// sent twice the limit in a row and has to be disconnected.
+    pub fn record(&mut self) -> Option<Duration> {
+        self.in_a_row += 1;
+        if self.in_a_row >= self.limit * 2 {
+            return None;
+        }
+        let Some(past_limit) = self.in_a_row.checked_sub(self.limit + 1) else {
+            return Some(Duration::ZERO);
+        };
+        let delay = MALFORMED_DELAY_MIN.saturating_mul(1 << past_limit.min(16));
+        Some(delay.min(MALFORMED_DELAY_MAX))
+    }
+
+    /// A well-formed command starts the count over.
+    pub fn reset(&mut self) {
+        self.in_a_row = 0;
+    }
+}
+
+/// The id of the next connection the server accepts.
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
 /// [ClientConn] is created for each connected client. This stores the connection information and
 /// the various asyncronous channels.
-struct ClientConn {
+struct ClientConn<S> {
+    /// Tells this connection's user apart from a later one registered under the same name.
+    id: u64,
     socket_addr: SocketAddr,
-    framed: Framed<TcpStream, LinesCodec>,
-    sender: UnboundedSender<OutgoingMsg>,
+    framed: Framed<S, ChatCodec>,
+    /// Messages for the client. The only sender is handed out by [ClientConn::open_channel].
     receiver: UnboundedReceiver<OutgoingMsg>,
     name: Option<String>,
     ppt: PingPongTable,
+    /// Echo the parsed form of every incoming line back to the client as a NOTICE.
+    debug: bool,
+    /// Longest SAY message the client wants the server to accept from it, set with MAXLEN.
+    max_message_length: usize,
+    config: ConnectionConfig,
+    churn: ChurnLimiter,
+    malformed: MalformedPenalty,
+    /// Commands handled since the connection last stopped reading for a turn. See
+    /// [ConnectionConfig::command_burst].
+    burst: usize,
+    /// Lines of ROOM and USER listings that haven't been sent yet. They go out one per turn of the main
+    /// loop so that a huge listing can't hold up PONG handling.
+    listing: VecDeque<OutgoingMsg>,
+    /// Traffic over the connection so far, for CONNSTATS.
+    stats: ConnStats,
+    /// Where the commands the client sends are counted, for PROTOSTATS.
+    command_counts: Arc<CommandCounts>,
+    /// Cancelled when the client is dropped at the end of [client_connection]. Every task spawned for the
+    /// connection with [spawn_helper] stops then.
+    shutdown: CancellationToken,
 }
 
-impl ClientConn {
-    pub fn new(tcp_stream: TcpStream, socket_addr: SocketAddr) -> Self {
-        let framed = Framed::new(tcp_stream, LinesCodec::new_with_max_length(1024));
-        let (sender, receiver) = unbounded_channel();
-        let ppt = PingPongTable::new();
-        ppt.start_worker();
+impl<S> Drop for ClientConn<S> {
+    fn drop(&mut self) {
+        self.shutdown.cancel();
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> ClientConn<S> {
+    pub fn new(
+        stream: S,
+        socket_addr: SocketAddr,
+        config: ConnectionConfig,
+        command_counts: Arc<CommandCounts>,
+    ) -> Self {
+        let framed = Framed::with_capacity(
+            stream,
+            ChatCodec::new_with_max_length(config.parser.max_line_length),
+            READ_AHEAD,
+        );
+        // nothing can be sent to the client until it registers
+        let (_, receiver) = unbounded_channel();
+        let shutdown = CancellationToken::new();
+        let mut ppt = PingPongTable::new();
+        ppt.start_worker(&shutdown);
 
         Self {
+            id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
             socket_addr,
             framed,
-            sender,
             receiver,
             name: None,
             ppt,
+            debug: false,
+            max_message_length: config.parser.max_line_length,
+            churn: ChurnLimiter::new(config.churn_limit, config.churn_window),
+            malformed: MalformedPenalty::new(config.malformed_limit),
+            burst: 0,
+            config,
+            listing: VecDeque::new(),
+            stats: ConnStats::default(),
+            command_counts,
+            shutdown,
         }
     }
 
+    /// Send a message and flush it, so every message is on the wire by the time this returns. Clients are
+    /// interactive and lines are short, so flushing each one beats batching them for latency.
     pub async fn send_message<T: Message>(&mut self, message: T) -> anyhow::Result<()> {
         info!("{} send_message --> {}", self.socket_addr, message);
-        self.framed.send(message.to_string()).await?;
+        let line = message.to_string();
+        self.stats.count_out(&line);
+        self.framed.send(line).await?;
+        Ok(())
+    }
+
+    /// Flush anything still buffered and shut down the writing half of the stream.
+    pub async fn close(&mut self) -> anyhow::Result<()> {
+        SinkExt::<String>::close(&mut self.framed).await?;
+        Ok(())
+    }
+
+    /// Open a fresh channel for messages to the client and return its sender. Once every clone of the
+    /// sender is dropped, the client knows that nothing else will ever be sent to it.
+    pub fn open_channel(&mut self) -> UnboundedSender<OutgoingMsg> {
+        let (sender, receiver) = unbounded_channel();
+        self.receiver = receiver;
+        sender
+    }
+
+    /// Whether the client has used up its burst of commands and reading has to wait a turn.
+    pub fn burst_spent(&self) -> bool {
+        self.burst >= self.config.command_burst
+    }
+
+    /// Send whatever is left of a listing, such as when the client quits right after asking for one.
+    pub async fn flush_listing(&mut self) -> anyhow::Result<()> {
+        while let Some(message) = self.listing.pop_front() {
+            self.send_message(message).await?;
+        }
         Ok(())
     }
 
     pub fn set_name(&mut self, name: String) {
         self.name = Some(name);
     }
+
+    /// The name of a registered client. A client in its main loop always has one, so a missing name is
+    /// reported to the client and returned as an error that ends the session instead of a panic.
+    pub async fn registered_name(&mut self) -> anyhow::Result<String> {
+        match self.name.clone() {
+            Some(name) => Ok(name),
+            None => {
+                self.send_message(OutgoingMsg::Notice(
+                    "disconnected: not registered".to_string(),
+                ))
+                .await?;
+                Err(anyhow!(
+                    "{} has no name after registering",
+                    self.socket_addr
+                ))
+            }
+        }
+    }
+
+    /// Handle a keep-alive event the same way in every phase. Returns `false` when the client has to be
+    /// disconnected because it stopped answering PINGs.
+    pub async fn handle_ping_pong(&mut self, ping_pong_ball: PingPongBall) -> anyhow::Result<bool> {
+        match ping_pong_ball {
+            PingPongBall::GotPong => self.ppt.set_last_activity().await,
+            PingPongBall::SendPing => self.send_message(OutgoingMsg::Ping).await?,
+            PingPongBall::PongTimeout => {
+                info!("{} PONG timeout", self.socket_addr);
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Handle the incoming data that every phase treats the same: stream errors, over-long lines,
+    /// disconnects, QUIT, PONG, DEBUG, TIME, and the penalty for malformed commands. Anything else is handed
+    /// back to the phase.
+    pub async fn handle_client_action(
+        &mut self,
+        result: anyhow::Result<ClientAction>,
+        phase: &str,
+    ) -> anyhow::Result<Step> {
+        let malformed = match &result {
+            Ok(ClientAction::Error(_) | ClientAction::Parsed(ParsedAction::Error(_, _))) => true,
+            // unknown commands cost nothing while they are ignored, but each NOTICE is a reply
+            Ok(ClientAction::Parsed(ParsedAction::Unknown)) => self.config.unknown_command_notice,
+            Ok(ClientAction::Parsed(ParsedAction::Process(_))) => {
+                self.malformed.reset();
+                false
+            }
+            _ => false,
+        };
+        if malformed {
+            match self.malformed.record() {
+                Some(delay) => tokio::time::sleep(delay).await,
+                None => {
+                    info!("{} too many malformed commands", self.socket_addr);
+                    self.send_message(OutgoingMsg::Notice(
+                        "too many malformed commands".to_string(),
+                    ))
+                    .await?;
+                    return Ok(Step::Quit);
+                }
+            }
+        }
+        let parsed_action = match result? {
+            // max length error or line break error
+            ClientAction::Error(e) => {
+                self.send_message(e).await?;
+                return Ok(Step::Handled);
+            }
+            // disconnected
+            ClientAction::Quit => return Ok(Step::Quit),
+            ClientAction::Parsed(parsed_action) => parsed_action,
+        };
+        info!("{} {} --> {}", self.socket_addr, phase, parsed_action);
+        self.send_debug(&parsed_action).await?;
+        match parsed_action {
+            // QUIT - end the connection
+            ParsedAction::Process(IncomingMsg::Quit) => Ok(Step::Quit),
+            // PONG - reset timer. if the keep-alive channel is closed there is no timer left to reset.
+            ParsedAction::Process(IncomingMsg::Pong) => {
+                let _ = self.ppt.sender.send(PingPongBall::GotPong);
+                Ok(Step::Handled)
+            }
+            // DEBUG ON|OFF - toggle debug mode
+            ParsedAction::Process(IncomingMsg::Debug(on)) => {
+                self.debug = on;
+                Ok(Step::Handled)
+            }
+            // TIME - the server's clock, so clients can line up timestamps
+            ParsedAction::Process(IncomingMsg::Time) => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default();
+                self.send_message(OutgoingMsg::Time(now.as_millis() as u64))
+                    .await?;
+                Ok(Step::Handled)
+            }
+            // COMPRESS - switch to compressed frames. the reply is the last plain line. a compressed stream
+            // costs the server memory, so only registered users get one.
+            ParsedAction::Process(IncomingMsg::Compress) => {
+                if !self.config.compression {
+                    self.send_message(ServerError::CompressionUnavailable)
+                        .await?;
+                } else if self.name.is_none() {
+                    self.send_message(ServerError::NotRegistered).await?;
+                } else if !self.framed.codec().is_compressed() {
+                    self.send_message(OutgoingMsg::Compress).await?;
+                    self.framed.codec_mut().compress();
+                }
+                Ok(Step::Handled)
+            }
+            // ECHOTEST <token> - prove to a monitor that lines are parsed, dispatched and answered
+            ParsedAction::Process(IncomingMsg::EchoTest(token)) => {
+                self.send_message(OutgoingMsg::EchoTest(token)).await?;
+                Ok(Step::Handled)
+            }
+            // unknown commands are ignored, unless the server points people typing by hand the right way
+            ParsedAction::Unknown => {
+                if self.config.unknown_command_notice {
+                    self.send_message(OutgoingMsg::Notice(
+                        "unknown command, send COMMANDS for the list and SYNTAX <command> for how to use one"
+                            .to_string(),
+                    ))
+                    .await?;
+                }
+                Ok(Step::Handled)
+            }
+            parsed_action => Ok(Step::Process(parsed_action)),
+        }
+    }
+
+    /// When debug mode is on, tell the client how the server interpreted their input.
+    pub async fn send_debug(&mut self, parsed_action: &ParsedAction) -> anyhow::Result<()> {
+        if self.debug {
+            self.send_message(OutgoingMsg::Notice(parsed_action.to_string()))
+                .await?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
 enum FormatError {
     MaxLineLengthExceeded,
+    /// The line holds a byte that could break how other clients split or display lines.
+    InvalidContent,
 }
 
 impl Display for FormatError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::MaxLineLengthExceeded => write!(f, "ERROR max length reached"),
+            Self::InvalidContent => write!(f, "ERROR invalid content"),
         }
     }
 }
@@ -151,67 +591,109 @@ enum ClientAction {
     Parsed(ParsedAction),
 }
 
+/// What is left for a phase to do after [ClientConn::handle_client_action].
+#[derive(Debug)]
+enum Step {
+    /// Nothing. The incoming data was fully handled.
+    Handled,
+    /// The client quit or disconnected.
+    Quit,
+    /// The phase has to handle this action itself.
+    Process(ParsedAction),
+}
+
 /// The main handler of incoming data from a client.
-async fn client_action(framed: &mut Framed<TcpStream, LinesCodec>) -> anyhow::Result<ClientAction> {
-    match framed.next().await {
+async fn client_action<S: AsyncRead + AsyncWrite + Unpin>(
+    framed: &mut Framed<S, ChatCodec>,
+    parser_config: &ParserConfig,
+    stats: &mut ConnStats,
+    command_counts: &CommandCounts,
+) -> anyhow::Result<ClientAction> {
+    let next = framed.next().await;
+    if let Some(Ok(input)) = &next {
+        stats.count_in(input);
+    }
+    match next {
         // disconnected
         None => Ok(ClientAction::Quit),
         // message too big
         Some(Err(LinesCodecError::MaxLineLengthExceeded)) => {
+            // after a codec error the framed stream yields a single `None` before it resumes reading.
+            // consume it here so it isn't mistaken for a disconnect.
+            framed.next().await;
             Ok(ClientAction::Error(FormatError::MaxLineLengthExceeded))
         }
         // error reading stream
         Some(Err(LinesCodecError::Io(e))) => Err(anyhow!(e)),
+        // NUL and carriage returns are never passed on, whatever the command
+        Some(Ok(input)) if input.contains(['\0', '\r']) => {
+            Ok(ClientAction::Error(FormatError::InvalidContent))
+        }
         // received data from client
-        Some(Ok(input)) => Ok(ClientAction::Parsed(parse_incoming(&input))),
+        Some(Ok(input)) => {
+            if let Some(command) = input.split(' ').next().and_then(Command::from_verb) {
+                command_counts.count(command);
+            }
+            Ok(ClientAction::Parsed(parse_incoming_with(
+                &input,
+                parser_config,
+            )))
+        }
     }
 }
 
+/// How the registration phase of a connection ended.
+enum Registration {
+    /// The client quit or the connection was lost.
+    Unregistered,
+    /// The client registered a user name.
+    User,
+    /// A peer server linked to the given room.
+    Peer(String),
+}
+
 /// The initial loop that registers a client. A client must provide a valid NAME before they are
 /// allowed full access to the server.
-async fn client_registration(
+async fn client_registration<S: AsyncRead + AsyncWrite + Unpin>(
     server_state: Arc<Mutex<ServerState>>,
-    client: &mut ClientConn,
-) -> anyhow::Result<bool> {
+    client: &mut ClientConn<S>,
+) -> anyhow::Result<Registration> {
+    // disconnect clients that never get around to registering
+    let deadline = tokio::time::sleep(client.config.registration_timeout);
+    tokio::pin!(deadline);
+
     // wait for a NAME in order to register the client
     loop {
+        // the branches are tried in order, see client_session
         tokio::select! {
-            // keep alive checker
-            Some(ping_pong_ball) = client.ppt.receiver.recv() => {
-                match ping_pong_ball {
-                    PingPongBall::GotPong => {
-                        client.ppt.set_last_activity().await;
-                    }
-                    PingPongBall::SendPing => {
-                        client.send_message(OutgoingMsg::Ping).await?;
-                    }
-                    PingPongBall::PongTimeout => {
-                        info!("{} PONG timeout", client.socket_addr);
-                        return Ok(false);
-                    }
-                }
+            biased;
+            // registration took too long
+            _ = &mut deadline => {
+                info!("{} registration timeout", client.socket_addr);
+                client
+                    .send_message(OutgoingMsg::Notice("registration timed out".to_string()))
+                    .await?;
+                return Ok(Registration::Unregistered);
             }
             // handle incoming client data
-            result = client_action(&mut client.framed) => match result {
-                Err(e) => return Err(anyhow!(e)),
-                // max length error or line break error
-                Ok(ClientAction::Error(e)) => {
-                    client.send_message(e).await?
-                },
-                Ok(ClientAction::Quit) => return Ok(false),
-                Ok(ClientAction::Parsed(parsed_action)) => {
-                    info!(
-                        "{} client_registration --> {}",
-                        client.socket_addr, parsed_action
-                    );
-                    match parsed_action {
+            result = client_action(&mut client.framed, &client.config.parser, &mut client.stats, &client.command_counts), if !client.burst_spent() => {
+                client.burst += 1;
+                match client.handle_client_action(result, "client_registration").await? {
+                    Step::Handled => {}
+                    Step::Quit => return Ok(Registration::Unregistered),
+                    Step::Process(parsed_action) => match parsed_action {
                         // received NAME <user-name>
                         ParsedAction::Process(IncomingMsg::Name(name)) => {
+                            let name = normalize_name(&name);
+                            if let Some(window) = client.config.ghost_probe {
+                                probe_ghost(&server_state, &name, window).await;
+                            }
                             let mut state = server_state.lock().await;
-                            match state.add_user(name.clone(), User::new(client.sender.clone())) {
+                            let sender = client.open_channel();
+                            match state.add_user(name.clone(), User::new(sender).on_connection(client.id)) {
                                 Ok(()) => {
                                     client.set_name(name);
-                                    return Ok(true);
+                                    return Ok(Registration::User);
                                 }
                                 // error trying to save client with given user name. possible error is duplicate user name.
                                 Err(server_error) => client.send_message(server_error).await?,
@@ -221,192 +703,2598 @@ async fn client_registration(
                         ParsedAction::Error(Command::Name, parse_error) => {
                             client.send_message(parse_error).await?
                         }
-                        // receive PONG
-                        ParsedAction::Process(IncomingMsg::Pong) => {
-                            // TODO: better errors
-                            client.ppt.sender.send(PingPongBall::GotPong).unwrap();
+                        // received HEALTH from a load balancer: answer and hang up without registering
+                        ParsedAction::Process(IncomingMsg::Health) => {
+                            client.send_message(OutgoingMsg::Healthy).await?;
+                            return Ok(Registration::Unregistered);
+                        }
+                        // received LINK <room-name> <server-name> from a peer server
+                        ParsedAction::Process(IncomingMsg::Link(room, server_name)) => {
+                            let check_link = server_state.lock().await.check_link(client.socket_addr.ip());
+                            match check_link {
+                                Ok(()) => {
+                                    info!("{} linked {} for {}", client.socket_addr, room, server_name);
+                                    return Ok(Registration::Peer(room));
+                                }
+                                Err(server_error) => {
+                                    info!("{} refused link for {}", client.socket_addr, server_name);
+                                    client.send_message(server_error).await?
+                                }
+                            }
+                        }
+                        // everything else needs a registered name
+                        ParsedAction::Process(_) | ParsedAction::Error(_, _) => {
+                            client.send_message(ServerError::NotRegistered).await?
                         }
-                        // received QUIT
-                        ParsedAction::Process(IncomingMsg::Quit) => return Ok(false),
-                        // ignore commands other than NAME and QUIT
-                        ParsedAction::Process(_) | ParsedAction::Error(_, _) | ParsedAction::None => {}
+                        // empty lines are ignored. unknown commands were handled above.
+                        ParsedAction::None | ParsedAction::Unknown => {}
                     }
                 }
             }
+            // stop reading for a turn after a burst of commands. yield_now is pending the first time it is
+            // polled, so anything else that is ready goes first.
+            _ = tokio::task::yield_now(), if client.burst_spent() => client.burst = 0,
+            // keep alive checker
+            Some(ping_pong_ball) = client.ppt.receiver.recv() => {
+                client.burst = 0;
+                if !client.handle_ping_pong(ping_pong_ball).await? {
+                    return Ok(Registration::Unregistered);
+                }
+            }
         }
     }
 }
 
-/// Remove a client from the servers global state.
-async fn client_teardown(
+/// If `name` is taken by a holder that has been silent for a keep-alive interval, PING it and wait `window` for
+/// any sign of life. A holder that stays silent is disconnected so the name is free to register.
+async fn probe_ghost(server_state: &Arc<Mutex<ServerState>>, name: &str, window: Duration) {
+    let since = tokio::time::Instant::now();
+    if !server_state
+        .lock()
+        .await
+        .ping_user(name, KEEPALIVE_INTERVAL)
+    {
+        return;
+    }
+    tokio::time::sleep(window).await;
+    if server_state.lock().await.reap_ghost(name, since) {
+        info!("{} reaped as a ghost", name);
+    }
+}
+
+/// Remove a client from the servers global state, unless its name has already passed to another connection.
+async fn client_teardown<S>(
     server_state: Arc<Mutex<ServerState>>,
-    client: &ClientConn,
+    client: &ClientConn<S>,
 ) -> anyhow::Result<()> {
     if let Some(name) = &client.name {
         let mut state = server_state.lock().await;
-        if let Err(e) = state.remove_user(name) {
+        if let Err(e) = state.remove_connection(name, client.id) {
             return Err(anyhow!(e));
         }
     }
     Ok(())
 }
 
-/// The entry point for a new client connection to the server.
-pub async fn client_connection(
+/// Accept connections on `listener` until accepting fails, running each one with [client_connection] in its
+/// own task. This is everything `chat-server` does once it has parsed its options, so the server can be
+/// started from tests or embedded in another program.
+pub async fn run_server(
     server_state: Arc<Mutex<ServerState>>,
-    tcp_stream: TcpStream,
-    socket_addr: SocketAddr,
+    listener: TcpListener,
+    config: ConnectionConfig,
 ) -> anyhow::Result<()> {
-    // create new client
-    let mut client = ClientConn::new(tcp_stream, socket_addr);
+    info!("listening for connections on {}", listener.local_addr()?);
+    accept_loop(server_state, listener, config).await
+}
 
-    // tell the client they are connected to the server
-    client.send_message(OutgoingMsg::Connected).await?;
+/// Shortest and longest pause after an accept error that isn't about a single connection, such as running
+/// out of file descriptors. The pause doubles with each error in a row.
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(10);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
 
-    // wait for a NAME in order to register the client and user into the server state
-    let registered = client_registration(server_state.clone(), &mut client).await?;
+/// Where [accept_loop] gets connections from. Tests stand in for a [TcpListener] to make accept fail.
+trait Listener {
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send + Sync + 'static;
 
-    // if the client wasn't registered then they quit or a connection was lost
-    if !registered {
-        return Ok(());
+    fn accept(
+        &mut self,
+    ) -> impl Future<Output = std::io::Result<(Self::Stream, SocketAddr)>> + Send;
+}
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+
+    fn accept(&mut self) -> impl Future<Output = std::io::Result<(TcpStream, SocketAddr)>> + Send {
+        TcpListener::accept(self)
     }
+}
 
-    // tell the client they are registered to the server
-    client.send_message(OutgoingMsg::Registered).await?;
+/// How the accept loop carries on after an accept error.
+#[derive(Debug, PartialEq)]
+enum AcceptError {
+    /// Only the connection being accepted failed, so accept the next one right away.
+    Connection,
+    /// The server is short of something, such as file descriptors, that may free up. Wait, then retry.
+    Transient,
+    /// The listener itself is broken and will never accept again.
+    Fatal,
+}
 
-    // main client loop
+impl AcceptError {
+    fn classify(e: &std::io::Error) -> Self {
+        use std::io::ErrorKind;
+        match e.kind() {
+            ErrorKind::ConnectionAborted
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionRefused
+            | ErrorKind::Interrupted
+            | ErrorKind::WouldBlock
+            | ErrorKind::TimedOut => Self::Connection,
+            ErrorKind::InvalidInput | ErrorKind::NotConnected => Self::Fatal,
+            _ => Self::Transient,
+        }
+    }
+}
+
+/// Accept connections and run each on its own task until the listener fails for good.
+async fn accept_loop<L: Listener>(
+    server_state: Arc<Mutex<ServerState>>,
+    mut listener: L,
+    config: ConnectionConfig,
+) -> anyhow::Result<()> {
+    // every connection counts its commands in the same table
+    let command_counts = server_state.lock().await.command_counts();
+    let mut backoff = ACCEPT_BACKOFF_MIN;
     loop {
-        tokio::select! {
-            // handle outgoing data to client
-            Some(message) = client.receiver.recv() => {
-                client.send_message(message).await?;
+        // accept new client connection
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => {
+                backoff = ACCEPT_BACKOFF_MIN;
+                accepted
             }
-            // keep alive checker
-            Some(ping_pong_ball) = client.ppt.receiver.recv() => {
-                match ping_pong_ball {
-                    PingPongBall::GotPong => {
-                        client.ppt.set_last_activity().await;
+            Err(e) => {
+                match AcceptError::classify(&e) {
+                    AcceptError::Connection => info!("accept error = {:?}", e),
+                    AcceptError::Transient => {
+                        info!("accept error = {:?}, retrying in {:?}", e, backoff);
+                        tokio::time::sleep(backoff).await;
+                        backoff = (backoff * 2).min(ACCEPT_BACKOFF_MAX);
                     }
-                    PingPongBall::SendPing => {
-                        client.send_message(OutgoingMsg::Ping).await?;
+                    AcceptError::Fatal => return Err(anyhow!(e)),
+                }
+                continue;
+            }
+        };
+        // clone references to shared server state
+        let server_state = server_state.clone();
+        let client = ClientConn::new(stream, addr, config, command_counts.clone());
+
+        // spawn new async process
+        tokio::spawn(async move {
+            info!("client connection accepted {}", addr);
+            if let Err(e) = run_client(server_state, client).await {
+                info!("error = {:?}", e);
+            }
+            info!("client connection closed {}", addr);
+        });
+    }
+}
+
+/// Archive the rooms that have gone quiet for as long as the server runs, checking every `period`. Rooms
+/// are only archived once [ServerState::set_archive_after] is set.
+pub async fn sweep_idle_rooms(server_state: Arc<Mutex<ServerState>>, period: Duration) {
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        for room_name in server_state.lock().await.archive_idle_rooms() {
+            info!("archived {}", room_name);
+        }
+    }
+}
+
+/// Write room history to the store every `period` for as long as the server runs, so that it can be replayed
+/// after a restart. Whatever was said since the last write is lost if the server stops.
+pub async fn flush_history(server_state: Arc<Mutex<ServerState>>, period: Duration) {
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        server_state.lock().await.flush_history();
+    }
+}
+
+/// The entry point for a new client connection to the server. The stream is usually a `TcpStream`, but
+/// any duplex byte stream works. [run_server] doesn't go through here, since it looks up the server's
+/// command counts once for all its connections.
+pub async fn client_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    server_state: Arc<Mutex<ServerState>>,
+    stream: S,
+    socket_addr: SocketAddr,
+    config: ConnectionConfig,
+) -> anyhow::Result<()> {
+    // count the client's commands along with everyone else's
+    let command_counts = server_state.lock().await.command_counts();
+    let client = ClientConn::new(stream, socket_addr, config, command_counts);
+    run_client(server_state, client).await
+}
+
+/// Run a client until its connection ends. The client is dropped on the way out, which stops its helper
+/// tasks.
+async fn run_client<S: AsyncRead + AsyncWrite + Unpin>(
+    server_state: Arc<Mutex<ServerState>>,
+    mut client: ClientConn<S>,
+) -> anyhow::Result<()> {
+    let result = serve_client(server_state, &mut client).await;
+    // make sure the last messages, such as a DISCONNECT, are out before the stream is shut down
+    if let Err(e) = client.close().await {
+        info!("{} close error = {:?}", client.socket_addr, e);
+    }
+    result
+}
+
+/// Take a client through registration and its session, again and again if it RESETs.
+async fn serve_client<S: AsyncRead + AsyncWrite + Unpin>(
+    server_state: Arc<Mutex<ServerState>>,
+    client: &mut ClientConn<S>,
+) -> anyhow::Result<()> {
+    loop {
+        // turn the client away if too many connections are already waiting to register
+        if !server_state.lock().await.begin_registration() {
+            info!("{} too many pending registrations", client.socket_addr);
+            client
+                .send_message(OutgoingMsg::Notice(
+                    "too many pending connections, try again later".to_string(),
+                ))
+                .await?;
+            return Ok(());
+        }
+
+        // tell the client they are connected to the server, then wait for a NAME in order to register
+        // the client and user into the server state
+        let registration = match client.send_message(OutgoingMsg::Connected).await {
+            Ok(()) => client_registration(server_state.clone(), client).await,
+            Err(e) => Err(e),
+        };
+        server_state.lock().await.end_registration();
+
+        match registration? {
+            // the client quit or a connection was lost
+            Registration::Unregistered => return Ok(()),
+            Registration::User => {
+                match client_session(server_state.clone(), client).await {
+                    // start over with registration on the same connection. the name was given up rather than
+                    // lost, so it isn't locked against registering it again.
+                    Ok(SessionEnd::Reset) => {
+                        if let Some(name) = client.name.take() {
+                            if let Err(e) = server_state.lock().await.reset_user(&name) {
+                                return Err(anyhow!(e));
+                            }
+                        }
                     }
-                    PingPongBall::PongTimeout => {
-                        info!("{} PONG timeout", client.socket_addr);
+                    // however else the session ends, remove the client from the server state
+                    result => {
+                        client_teardown(server_state.clone(), client).await?;
+                        result?;
                         return Ok(());
                     }
                 }
             }
-            // handle incoming client data
-            result = client_action(&mut client.framed) => match result {
-                // some kind of bad thing happened. remove the client from the state and raise an error.
-                Err(e) => {
-                    client_teardown(server_state.clone(), &client).await?;
-                    return Err(anyhow!(e))
-                },
-                // max length error or line break error
-                Ok(ClientAction::Error(e)) => {
-                    client.send_message(e).await?
+            Registration::Peer(room) => {
+                // however the link ends, stop relaying to it. only a weak sender is kept here so that the link
+                // ends when its room goes away.
+                let sender = client.open_channel();
+                let link = sender.downgrade();
+                let result = peer_session(server_state.clone(), client, room, sender).await;
+                if let Some(sender) = link.upgrade() {
+                    server_state.lock().await.remove_link(&sender);
                 }
-                // exit the loop for proper state cleanup
-                Ok(ClientAction::Quit) => break,
-                Ok(ClientAction::Parsed(parsed_action)) => {
-                    info!("{} client_connection --> {}", client.socket_addr, parsed_action);
-                    match parsed_action {
-                        // QUIT - exit the loop for proper state cleanup
-                        ParsedAction::Process(IncomingMsg::Quit) => break,
-                        // NAME <user-name> - rename the client
-                        ParsedAction::Process(IncomingMsg::Name(name)) => {
-                            let mut state = server_state.lock().await;
-                            match state.rename_user(&client.name.clone().unwrap(), &name) {
-                                Ok(()) => {
-                                    // change client name if server successfully changes state
-                                    client.set_name(name);
-                                },
-                                Err(server_error) => {
-                                    client.send_message(server_error).await?
-                                }
-                            }
-                        },
-                        // JOIN <room-name> - join a room
-                        ParsedAction::Process(IncomingMsg::Join(room)) => {
-                            let mut state = server_state.lock().await;
-                            match state.join_room(room, client.name.clone().unwrap()) {
-                                Ok(()) => {},
-                                Err(server_error) => {
-                                    client.send_message(server_error).await?
-                                }
-                            }
-                        },
-                        // SAY <room-name> <message> - send a message to a room
-                        ParsedAction::Process(IncomingMsg::SayRoom(room, message)) => {
-                            let mut state = server_state.lock().await;
-                            match state.say_to_room(&client.name.clone().unwrap(), &room, message) {
-                                Ok(()) => {},
-                                Err(server_error) => {
-                                    client.send_message(server_error).await?
-                                }
-                            }
-                        }
-                        // SAY <user-name> <message> - send a message to another client
-                        ParsedAction::Process(IncomingMsg::SayUser(user, message)) => {
-                            let state = server_state.lock().await;
-                            match state.say_to_user(&client.name.clone().unwrap(), &user, message) {
-                                Ok(()) => {},
-                                Err(server_error) => {
-                                    client.send_message(server_error).await?
-                                }
-                            }
-                        },
-                        // ROOMS - list all rooms
-                        ParsedAction::Process(IncomingMsg::Rooms) => {
-                            let state = server_state.lock().await;
-                            for room in state.rooms() {
-                                client.send_message(OutgoingMsg::Room(room)).await?;
-                            }
-                        },
-                        // LEAVE <room-name> - leave a room
-                        ParsedAction::Process(IncomingMsg::Leave(room)) => {
-                            let mut state = server_state.lock().await;
-                            match state.leave_room(&room, &client.name.clone().unwrap()) {
-                                Ok(()) => {},
-                                Err(server_error) => {
-                                    client.send_message(server_error).await?
-                                }
-                            }
-                        },
-                        // USERS <room-name> - list all users in a room
-                        ParsedAction::Process(IncomingMsg::Users(room)) => {
-                            let state = server_state.lock().await;
-                            match state.users(&room) {
-                                Ok(users) => {
-                                    for user in users {
-                                        client.send_message(OutgoingMsg::User(user)).await?;
-                                    }
-                                }
-                                Err(server_error) => {
-                                    client.send_message(server_error).await?
-                                }
-                            }
-                        },
-                        // PONG - reset timer
-                        ParsedAction::Process(IncomingMsg::Pong) => {
-                            // TODO: better errors
-                            client.ppt.sender.send(PingPongBall::GotPong).unwrap();
-                        }
-                        // send any command parsing errors to the client
-                        ParsedAction::Error(_, parse_error) => {
-                            client.send_message(parse_error).await?
-                        }
-                        // empty and unknown commands are ignored
-                        ParsedAction::None => {}
-                    }
+                return result;
+            }
+        }
+    }
+}
+
+/// The loop of a peer server linked to a room. Every event in the room is relayed to the peer, tagged with
+/// this server's name, until the room goes away. Anything the peer sends other than a PONG is ignored.
+async fn peer_session<S: AsyncRead + AsyncWrite + Unpin>(
+    server_state: Arc<Mutex<ServerState>>,
+    client: &mut ClientConn<S>,
+    room: String,
+    sender: UnboundedSender<OutgoingMsg>,
+) -> anyhow::Result<()> {
+    let origin = {
+        let mut state = server_state.lock().await;
+        state.add_link(room, sender);
+        state.server_name().to_string()
+    };
+
+    loop {
+        tokio::select! {
+            // relay room events to the peer
+            message = client.receiver.recv() => match message {
+                Some(message) => client.send_message(Relayed { origin: origin.clone(), message }).await?,
+                None => {
+                    info!("{} link ended with its room", client.socket_addr);
+                    return Ok(());
+                }
+            },
+            // watch for the peer going away
+            result = client_action(&mut client.framed, &client.config.parser, &mut client.stats, &client.command_counts) => match result {
+                Err(e) => return Err(anyhow!(e)),
+                Ok(ClientAction::Quit) => return Ok(()),
+                Ok(ClientAction::Parsed(ParsedAction::Process(IncomingMsg::Pong))) => {
+                    client.ppt.set_last_activity().await;
+                }
+                Ok(_) => {}
+            },
+            // keep alive checker
+            Some(ping_pong_ball) = client.ppt.receiver.recv() => {
+                if !client.handle_ping_pong(ping_pong_ball).await? {
+                    return Ok(());
                 }
             }
         }
     }
+}
 
-    // remove the client from the server state
-    client_teardown(server_state.clone(), &client).await?;
+/// How the session of a registered client ended.
+enum SessionEnd {
+    /// The client quit, the connection was lost, or the client was disconnected.
+    Quit,
+    /// The client gave up its name and wants to register again on the same connection.
+    Reset,
+}
 
-    Ok(())
+/// The main loop of a registered client. Returning from this function ends the session.
+async fn client_session<S: AsyncRead + AsyncWrite + Unpin>(
+    server_state: Arc<Mutex<ServerState>>,
+    client: &mut ClientConn<S>,
+) -> anyhow::Result<SessionEnd> {
+    // tell the client they are registered to the server
+    client.send_message(OutgoingMsg::Registered).await?;
+    let motd = server_state.lock().await.motd().to_vec();
+    for line in motd {
+        client.send_message(OutgoingMsg::Notice(line)).await?;
+    }
+    let client_name = client.registered_name().await?;
+    let auto_join = server_state.lock().await.auto_join(&client_name);
+    if let Err(server_error) = auto_join {
+        client.send_message(server_error).await?;
+    }
+
+    // main client loop
+    loop {
+        tokio::select! {
+            // the branches are tried in order. the client's own commands come first so that replies stay in step
+            // with them, and the burst limit makes sure everything below still gets its turn.
+            biased;
+            // handle incoming client data
+            result = client_action(&mut client.framed, &client.config.parser, &mut client.stats, &client.command_counts), if !client.burst_spent() => {
+                client.burst += 1;
+                // a PONG doesn't count as activity, but it does show a ghost probe that the client is there
+                if matches!(result, Ok(ClientAction::Parsed(ParsedAction::Process(IncomingMsg::Pong)))) {
+                    if let Some(name) = &client.name {
+                        server_state.lock().await.record_pong(name);
+                    }
+                }
+                let parsed_action = match client.handle_client_action(result, "client_connection").await? {
+                    Step::Handled => continue,
+                    Step::Quit => {
+                        client.flush_listing().await?;
+                        return Ok(SessionEnd::Quit);
+                    }
+                    Step::Process(parsed_action) => parsed_action,
+                };
+                // read the name fresh for every action, since NAME may have just changed it. nothing about
+                // a user is held between actions; the server state looks them up by name under the lock.
+                let client_name = client.registered_name().await?;
+                // refuse messages over the client's own limit before they reach anyone
+                if let ParsedAction::Process(message) = &parsed_action {
+                    if message.message().is_some_and(|message| message.len() > client.max_message_length) {
+                        client.send_message(ParseError::MessageTooLong).await?;
+                        continue;
+                    }
+                }
+                // record activity. PONG was handled above so keep-alive replies never count.
+                server_state.lock().await.touch_user(&client_name);
+                match parsed_action {
+                    // NAME <user-name> - rename the client
+                    ParsedAction::Process(IncomingMsg::Name(name)) => {
+                        let name = normalize_name(&name);
+                        let mut state = server_state.lock().await;
+                        if !state.rename_allowed(&client_name) {
+                            drop(state);
+                            client
+                                .send_message(OutgoingMsg::Notice(format!(
+                                    "renamed too recently, dropped NAME {}",
+                                    name
+                                )))
+                                .await?;
+                            continue;
+                        }
+                        match state.rename_user(&client_name, &name) {
+                            Ok(()) => {
+                                // change client name if server successfully changes state
+                                client.set_name(name);
+                            },
+                            Err(server_error) => {
+                                client.send_message(server_error).await?
+                            }
+                        }
+                    },
+                    // JOIN <room-name> - join a room
+                    ParsedAction::Process(IncomingMsg::Join(room)) => {
+                        if !client.churn.allow() {
+                            client
+                                .send_message(OutgoingMsg::Notice(format!(
+                                    "too many joins and leaves, dropped JOIN {}",
+                                    room
+                                )))
+                                .await?;
+                            continue;
+                        }
+                        let mut state = server_state.lock().await;
+                        match state.join_room(room, client_name) {
+                            Ok(()) => {},
+                            Err(server_error) => {
+                                client.send_message(server_error).await?
+                            }
+                        }
+                    },
+                    // JOINALL <room-name> ... - join several rooms at once, each as its own JOIN
+                    ParsedAction::Process(IncomingMsg::JoinAll(mut rooms)) => {
+                        if let Some(allowed) = rooms.iter().position(|_| !client.churn.allow()) {
+                            let dropped = rooms.split_off(allowed);
+                            client
+                                .send_message(OutgoingMsg::Notice(format!(
+                                    "too many joins and leaves, dropped JOINALL {}",
+                                    dropped.join(" ")
+                                )))
+                                .await?;
+                        }
+                        let results = server_state.lock().await.join_rooms(&rooms, &client_name);
+                        for server_error in results.into_iter().filter_map(Result::err) {
+                            client.send_message(server_error).await?;
+                        }
+                    },
+                    // SAY <room-name> <message> - send a message to a room
+                    ParsedAction::Process(IncomingMsg::SayRoom(room, message)) => {
+                        let mut state = server_state.lock().await;
+                        match state.say_to_room(&client_name, &room, message) {
+                            Ok(()) => {},
+                            Err(server_error) => {
+                                client.send_message(server_error).await?
+                            }
+                        }
+                    }
+                    // SAY <user-name> <message> - send a message to another client
+                    ParsedAction::Process(IncomingMsg::SayUser(user, message, urgent)) => {
+                        let mut state = server_state.lock().await;
+                        match state.say_to_user(&client_name, &user, message, urgent) {
+                            Ok(delivered) => client.send_message(delivered).await?,
+                            Err(server_error) => {
+                                client.send_message(server_error).await?
+                            }
+                        }
+                    },
+                    // SAY <user-name>,<user-name>,... <message> - send a message to several clients
+                    ParsedAction::Process(IncomingMsg::SayUsers(users, message)) => {
+                        let results = server_state.lock().await.say_to_users(&client_name, &users, message);
+                        for result in results {
+                            match result {
+                                Ok(delivered) => client.send_message(delivered).await?,
+                                Err(server_error) => client.send_message(server_error).await?,
+                            }
+                        }
+                    },
+                    // ROOMS [empty|active] - list all rooms, or only those with or without members
+                    ParsedAction::Process(IncomingMsg::Rooms(filter)) => {
+                        let rooms = server_state.lock().await.filtered_rooms(filter);
+                        client.listing.extend(rooms.into_iter().map(OutgoingMsg::Room));
+                    },
+                    // MYROOMS [full] - list the rooms the client is in, with member counts if full
+                    ParsedAction::Process(IncomingMsg::MyRooms(full)) => {
+                        let rooms = server_state.lock().await.rooms_with_counts(&client_name);
+                        match rooms {
+                            Ok(rooms) if full => client.listing.extend(
+                                rooms.into_iter().map(|(room, members)| OutgoingMsg::RoomInfo(room, members)),
+                            ),
+                            Ok(rooms) => client.listing.extend(
+                                rooms.into_iter().map(|(room, _)| OutgoingMsg::Room(room)),
+                            ),
+                            Err(server_error) => client.send_message(server_error).await?,
+                        }
+                    },
+                    // LEAVE <room-name> - leave a room
+                    ParsedAction::Process(IncomingMsg::Leave(room)) => {
+                        if !client.churn.allow() {
+                            client
+                                .send_message(OutgoingMsg::Notice(format!(
+                                    "too many joins and leaves, dropped LEAVE {}",
+                                    room
+                                )))
+                                .await?;
+                            continue;
+                        }
+                        let mut state = server_state.lock().await;
+                        match state.leave_room(&room, &client_name) {
+                            Ok(()) => {},
+                            Err(server_error) => {
+                                client.send_message(server_error).await?
+                            }
+                        }
+                    },
+                    // SWITCH <room-name> <room-name> - leave one room and join another in one step
+                    ParsedAction::Process(IncomingMsg::Switch(old_room, new_room)) => {
+                        if !client.churn.allow() {
+                            client
+                                .send_message(OutgoingMsg::Notice(format!(
+                                    "too many joins and leaves, dropped SWITCH {} {}",
+                                    old_room, new_room
+                                )))
+                                .await?;
+                            continue;
+                        }
+                        let mut state = server_state.lock().await;
+                        match state.switch_room(&old_room, new_room, client_name) {
+                            Ok(()) => {},
+                            Err(server_error) => {
+                                client.send_message(server_error).await?
+                            }
+                        }
+                    },
+                    // USERS <room-name> - list all users in a room
+                    ParsedAction::Process(IncomingMsg::Users(room, sort)) => {
+                        let users = {
+                            let state = server_state.lock().await;
+                            match sort {
+                                Some(UserSort::Active) => state.active_users(&room),
+                                None => state.users(&room),
+                            }
+                        };
+                        match users {
+                            Ok(users) => {
+                                client.listing.extend(users.into_iter().map(OutgoingMsg::User));
+                            }
+                            Err(server_error) => {
+                                client.send_message(server_error).await?
+                            }
+                        }
+                    },
+                    // MUTE <room-name> <user-name> - silence a user in an owned room
+                    ParsedAction::Process(IncomingMsg::Mute(room, user)) => {
+                        let mut state = server_state.lock().await;
+                        match state.mute_user(&room, &client_name, &user) {
+                            Ok(()) => {},
+                            Err(server_error) => {
+                                client.send_message(server_error).await?
+                            }
+                        }
+                    },
+                    // UNMUTE <room-name> <user-name> - let a muted user speak again
+                    ParsedAction::Process(IncomingMsg::Unmute(room, user)) => {
+                        let mut state = server_state.lock().await;
+                        match state.unmute_user(&room, &client_name, &user) {
+                            Ok(()) => {},
+                            Err(server_error) => {
+                                client.send_message(server_error).await?
+                            }
+                        }
+                    },
+                    // PREVIEW SAY <room-name> <message> - report who a room message would reach
+                    ParsedAction::Process(IncomingMsg::PreviewSayRoom(room, _)) => {
+                        let state = server_state.lock().await;
+                        match state.room_delivery(&client_name, &room) {
+                            Ok(delivery) => {
+                                client
+                                    .send_message(OutgoingMsg::Notice(format!(
+                                        "PREVIEW {} {}",
+                                        room, delivery
+                                    )))
+                                    .await?
+                            }
+                            Err(server_error) => {
+                                client.send_message(server_error).await?
+                            }
+                        }
+                    },
+                    // STATUS [message] - set or clear presence text
+                    ParsedAction::Process(IncomingMsg::Status(status)) => {
+                        let mut state = server_state.lock().await;
+                        match state.set_status(&client_name, status) {
+                            Ok(()) => {},
+                            Err(server_error) => {
+                                client.send_message(server_error).await?
+                            }
+                        }
+                    },
+                    // WHOIS <user-name> - look up another user
+                    ParsedAction::Process(IncomingMsg::Whois(name)) => {
+                        let state = server_state.lock().await;
+                        match state.whois(&name) {
+                            Ok(message) => client.send_message(message).await?,
+                            Err(server_error) => {
+                                client.send_message(server_error).await?
+                            }
+                        }
+                    },
+                    // BUDDY <user-name> - hear when a user comes online or goes offline
+                    ParsedAction::Process(IncomingMsg::Buddy(name)) => {
+                        let mut state = server_state.lock().await;
+                        match state.add_buddy(&client_name, &name) {
+                            Ok(message) => client.send_message(message).await?,
+                            Err(server_error) => {
+                                client.send_message(server_error).await?
+                            }
+                        }
+                    },
+                    // WATCH ON|OFF - hear about rooms being created and deleted
+                    ParsedAction::Process(IncomingMsg::Watch(watching)) => {
+                        let mut state = server_state.lock().await;
+                        match state.set_watching(&client_name, watching) {
+                            Ok(()) => {},
+                            Err(server_error) => {
+                                client.send_message(server_error).await?
+                            }
+                        }
+                    },
+                    // QUIT, PONG, DEBUG, TIME, ECHOTEST, and COMPRESS - already handled the same way as during registration
+                    ParsedAction::Process(IncomingMsg::Quit | IncomingMsg::Pong | IncomingMsg::Debug(_) | IncomingMsg::Time | IncomingMsg::EchoTest(_) | IncomingMsg::Compress) => {}
+                    // SYNTAX <command> - describe a command's arguments
+                    ParsedAction::Process(IncomingMsg::Syntax(command)) => {
+                        client.send_message(OutgoingMsg::Notice(command.syntax().to_string())).await?;
+                    },
+                    // DND ON|OFF - refuse private messages
+                    ParsedAction::Process(IncomingMsg::Dnd(dnd)) => {
+                        let mut state = server_state.lock().await;
+                        match state.set_dnd(&client_name, dnd) {
+                            Ok(()) => {},
+                            Err(server_error) => {
+                                client.send_message(server_error).await?
+                            }
+                        }
+                    },
+                    // CONTEXT ON|OFF - hear which rooms are shared with the sender of each private message
+                    ParsedAction::Process(IncomingMsg::Context(context)) => {
+                        let mut state = server_state.lock().await;
+                        match state.set_context(&client_name, context) {
+                            Ok(()) => {},
+                            Err(server_error) => {
+                                client.send_message(server_error).await?
+                            }
+                        }
+                    },
+                    // RESET - leave every room and give up the name, but stay connected
+                    ParsedAction::Process(IncomingMsg::Reset) => {
+                        client.flush_listing().await?;
+                        return Ok(SessionEnd::Reset);
+                    },
+                    // COMMANDS - list every command the server understands
+                    ParsedAction::Process(IncomingMsg::Commands) => {
+                        client.send_message(OutgoingMsg::Commands(Command::verbs().into_iter().map(String::from).collect())).await?;
+                    },
+                    // MYSTATS - report this session's activity
+                    ParsedAction::Process(IncomingMsg::MyStats) => {
+                        let stats = server_state.lock().await.my_stats(&client_name);
+                        match stats {
+                            Ok(message) => client.send_message(message).await?,
+                            Err(server_error) => {
+                                client.send_message(server_error).await?
+                            }
+                        }
+                    },
+                    // OPER <password> - become a server operator
+                    ParsedAction::Process(IncomingMsg::Oper(password)) => {
+                        let result = server_state.lock().await.oper(&client_name, &password);
+                        match result {
+                            Ok(()) => {
+                                client
+                                    .send_message(OutgoingMsg::Notice("you are now an operator".to_string()))
+                                    .await?
+                            }
+                            Err(server_error) => {
+                                client.send_message(server_error).await?
+                            }
+                        }
+                    },
+                    // KILL <user-name> <reason> - disconnect a user (operators only)
+                    ParsedAction::Process(IncomingMsg::Kill(user, reason)) => {
+                        let mut state = server_state.lock().await;
+                        match state.kill(&client_name, &user, reason) {
+                            Ok(()) => {},
+                            Err(server_error) => {
+                                client.send_message(server_error).await?
+                            }
+                        }
+                    },
+                    // CREATE <room-name> - create an empty room (operators only)
+                    ParsedAction::Process(IncomingMsg::Create(room)) => {
+                        let mut state = server_state.lock().await;
+                        match state.create_room(&client_name, &room) {
+                            Ok(()) => {},
+                            Err(server_error) => {
+                                client.send_message(server_error).await?
+                            }
+                        }
+                    },
+                    // DESTROY <room-name> - delete a room and everything in it (operators only)
+                    ParsedAction::Process(IncomingMsg::Destroy(room)) => {
+                        let mut state = server_state.lock().await;
+                        match state.destroy_room(&client_name, &room) {
+                            Ok(()) => {},
+                            Err(server_error) => {
+                                client.send_message(server_error).await?
+                            }
+                        }
+                    },
+                    // LIMITS - list the limits the client's input has to fit
+                    ParsedAction::Process(IncomingMsg::Limits) => {
+                        let mut limits = client.config.limits();
+                        let state = server_state.lock().await;
+                        limits.push(OutgoingMsg::Limit("history".to_string(), state.history_size() as u64));
+                        limits.push(OutgoingMsg::Limit("buddies".to_string(), MAX_BUDDIES as u64));
+                        if let Some(max_rooms) = state.max_rooms_per_user() {
+                            limits.push(OutgoingMsg::Limit("rooms".to_string(), max_rooms as u64));
+                        }
+                        drop(state);
+                        client.listing.extend(limits);
+                    },
+                    // PREFS - list the client's own preferences
+                    ParsedAction::Process(IncomingMsg::Prefs) => {
+                        let prefs = server_state.lock().await.prefs(&client_name);
+                        match prefs {
+                            Ok(prefs) => {
+                                client.listing.extend(prefs);
+                                client.listing.push_back(OutgoingMsg::Pref(
+                                    "maxlen".to_string(),
+                                    client.max_message_length.to_string(),
+                                ));
+                            }
+                            Err(server_error) => {
+                                client.send_message(server_error).await?
+                            }
+                        }
+                    },
+                    // READONLY <room-name> ON|OFF - let only the owner speak in a room
+                    ParsedAction::Process(IncomingMsg::ReadOnly(room, readonly)) => {
+                        let mut state = server_state.lock().await;
+                        match state.set_readonly(&room, &client_name, readonly) {
+                            Ok(()) => {},
+                            Err(server_error) => {
+                                client.send_message(server_error).await?
+                            }
+                        }
+                    },
+                    // MEMBERSHIP <room-name> ON|OFF - tell the owner about every join and leave
+                    ParsedAction::Process(IncomingMsg::Membership(room, on)) => {
+                        let mut state = server_state.lock().await;
+                        match state.set_owner_notices(&room, &client_name, on) {
+                            Ok(()) => {},
+                            Err(server_error) => {
+                                client.send_message(server_error).await?
+                            }
+                        }
+                    },
+                    // PROTOSTATS - count every command received since startup (operators only)
+                    ParsedAction::Process(IncomingMsg::ProtoStats) => {
+                        let protocol_stats = server_state.lock().await.protocol_stats(&client_name);
+                        match protocol_stats {
+                            Ok(protocol_stats) => client.listing.extend(protocol_stats),
+                            Err(server_error) => {
+                                client.send_message(server_error).await?
+                            }
+                        }
+                    },
+                    // MODE <room-name> [+mode|-mode] - show a room's modes, or change one (owner only)
+                    ParsedAction::Process(IncomingMsg::Mode(room, change)) => {
+                        let mut state = server_state.lock().await;
+                        let result = match change {
+                            Some((mode, on)) => state.set_mode(&room, &client_name, mode, on),
+                            None => state.modes(&room).map(|modes| client.listing.push_back(modes)),
+                        };
+                        if let Err(server_error) = result {
+                            client.send_message(server_error).await?
+                        }
+                    },
+                    // EDIT <room-name> <seq> <message> - change the text of one of the client's own room messages
+                    ParsedAction::Process(IncomingMsg::Edit(room, seq, message)) => {
+                        let mut state = server_state.lock().await;
+                        match state.edit_message(&client_name, &room, seq, message) {
+                            Ok(()) => {},
+                            Err(server_error) => {
+                                client.send_message(server_error).await?
+                            }
+                        }
+                    },
+                    // DELETE <room-name> <seq> - delete a room message (sender or room owner)
+                    ParsedAction::Process(IncomingMsg::Delete(room, seq)) => {
+                        let mut state = server_state.lock().await;
+                        match state.delete_message(&client_name, &room, seq) {
+                            Ok(()) => {},
+                            Err(server_error) => {
+                                client.send_message(server_error).await?
+                            }
+                        }
+                    },
+                    // UNARCHIVE <room-name> - bring back a room archived for going quiet
+                    ParsedAction::Process(IncomingMsg::Unarchive(room)) => {
+                        let mut state = server_state.lock().await;
+                        match state.unarchive_room(&room, &client_name) {
+                            Ok(()) => {},
+                            Err(server_error) => {
+                                client.send_message(server_error).await?
+                            }
+                        }
+                    },
+                    // RECENT <room-name> <count> - scroll back through a room's history without rejoining
+                    ParsedAction::Process(IncomingMsg::Recent(room, count)) => {
+                        let recent = server_state.lock().await.recent(&client_name, &room, count);
+                        match recent {
+                            Ok(messages) => client.listing.extend(messages),
+                            Err(server_error) => client.send_message(server_error).await?,
+                        }
+                    },
+                    // REVIEW - list recent messages for moderation (operators only)
+                    ParsedAction::Process(IncomingMsg::Review) => {
+                        let review = server_state.lock().await.review(&client_name);
+                        match review {
+                            Ok(messages) => client.listing.extend(messages),
+                            Err(server_error) => {
+                                client.send_message(server_error).await?
+                            }
+                        }
+                    },
+                    // MAXLEN <length> - refuse this client's own messages over a length
+                    ParsedAction::Process(IncomingMsg::MaxLen(length)) => {
+                        client.max_message_length = length;
+                    },
+                    // HISTLEN <count> - how much room history to replay on each JOIN
+                    ParsedAction::Process(IncomingMsg::HistLen(count)) => {
+                        let mut state = server_state.lock().await;
+                        match state.set_history_length(&client_name, count) {
+                            Ok(_) => {},
+                            Err(server_error) => {
+                                client.send_message(server_error).await?
+                            }
+                        }
+                    },
+                    // ECHO ROOM ON|OFF - send the client its own room messages too
+                    ParsedAction::Process(IncomingMsg::EchoRoom(echo_room)) => {
+                        let mut state = server_state.lock().await;
+                        match state.set_echo_room(&client_name, echo_room) {
+                            Ok(()) => {},
+                            Err(server_error) => {
+                                client.send_message(server_error).await?
+                            }
+                        }
+                    },
+                    // SHARED <user-name> - list the rooms the client has in common with a user
+                    ParsedAction::Process(IncomingMsg::Shared(name)) => {
+                        let state = server_state.lock().await;
+                        match state.shared_rooms(&client_name, &name) {
+                            Ok(rooms) => {
+                                client.send_message(OutgoingMsg::Shared(name, rooms)).await?
+                            }
+                            Err(server_error) => {
+                                client.send_message(server_error).await?
+                            }
+                        }
+                    },
+                    // BRIDGE <room-name> <display-name> <message> - post to a room on someone else's behalf
+                    ParsedAction::Process(IncomingMsg::Bridge(room, display_name, message)) => {
+                        let mut state = server_state.lock().await;
+                        match state.say_to_room_as(&client_name, &room, &display_name, message) {
+                            Ok(()) => {},
+                            Err(server_error) => {
+                                client.send_message(server_error).await?
+                            }
+                        }
+                    },
+                    // CONNSTATS - report the traffic over this connection
+                    ParsedAction::Process(IncomingMsg::ConnStats) => {
+                        let stats = client.stats.report();
+                        client.send_message(stats).await?;
+                    },
+                    // TOPIC <room-name> <topic> - change a room's topic
+                    ParsedAction::Process(IncomingMsg::Topic(room, topic)) => {
+                        let result = server_state.lock().await.set_topic(&client_name, &room, topic);
+                        match result {
+                            Ok(()) => {}
+                            Err(server_error) => client.send_message(server_error).await?,
+                        }
+                    },
+                    // TOPICHISTORY <room-name> - the room's recent topics, oldest first
+                    ParsedAction::Process(IncomingMsg::TopicHistory(room)) => {
+                        let history = server_state.lock().await.topic_history(&client_name, &room);
+                        match history {
+                            Ok(messages) => client.listing.extend(messages),
+                            Err(server_error) => client.send_message(server_error).await?,
+                        }
+                    },
+                    // INVITE <room-name> <user-name> - let a user join an owned room once, even when it is invite-only
+                    ParsedAction::Process(IncomingMsg::Invite(room, user)) => {
+                        let result = server_state.lock().await.invite_user(&room, &client_name, &user);
+                        match result {
+                            Ok(()) => {}
+                            Err(server_error) => client.send_message(server_error).await?,
+                        }
+                    },
+                    // LINK - only a peer server may link, and only before registration
+                    ParsedAction::Process(IncomingMsg::Link(_, _)) => {}
+                    // HEALTH - only answered before registration, where load balancers send it
+                    ParsedAction::Process(IncomingMsg::Health) => {}
+                    // send any command parsing errors to the client
+                    ParsedAction::Error(_, parse_error) => {
+                        client.send_message(parse_error).await?
+                    }
+                    // empty lines are ignored. unknown commands were handled above.
+                    ParsedAction::None | ParsedAction::Unknown => {}
+                }
+            }
+            // stop reading for a turn after a burst of commands, even when nothing below is ready, so other
+            // connections get to run
+            _ = tokio::task::yield_now(), if client.burst_spent() => client.burst = 0,
+            // handle outgoing data to client. anything other than reading getting a turn ends the burst.
+            message = client.receiver.recv() => {
+                client.burst = 0;
+                match message {
+                    // an operator killed the client, which already removed it from the server state
+                    Some(message @ OutgoingMsg::Disconnect(_)) => {
+                        info!("{} killed", client.socket_addr);
+                        client.name = None;
+                        client.send_message(message).await?;
+                        return Ok(SessionEnd::Quit);
+                    }
+                    Some(message) => client.send_message(message).await?,
+                    // the user was removed from the server state, so nothing can reach the client anymore
+                    None => {
+                        info!("{} removed from server state", client.socket_addr);
+                        client.name = None;
+                        client
+                            .send_message(OutgoingMsg::Notice("disconnected: removed from the server".to_string()))
+                            .await?;
+                        return Ok(SessionEnd::Quit);
+                    }
+                }
+            },
+            // keep alive checker
+            Some(ping_pong_ball) = client.ppt.receiver.recv() => {
+                client.burst = 0;
+                if !client.handle_ping_pong(ping_pong_ball).await? {
+                    return Ok(SessionEnd::Quit);
+                }
+            }
+            // send the next line of a listing. it comes last, so commands and messages go out between its lines.
+            _ = std::future::ready(()), if !client.listing.is_empty() => {
+                client.burst = 0;
+                if let Some(message) = client.listing.pop_front() {
+                    client.send_message(message).await?;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::ServerConfig, parser::MAX_LINE_LENGTH};
+    use std::{
+        collections::VecDeque,
+        io,
+        pin::Pin,
+        task::{Context, Poll},
+    };
+    use tokio::io::{DuplexStream, ReadBuf};
+    use tokio_util::codec::LinesCodec;
+
+    /// A stream whose reads and writes are scripted ahead of time so that transport failures can be
+    /// triggered at specific points. Reads are served in order and the stream reports end-of-file once
+    /// the script runs out. Writes succeed until `writes_left` reaches zero and fail afterwards.
+    struct MockStream {
+        reads: VecDeque<io::Result<Vec<u8>>>,
+        writes_left: usize,
+        written: Arc<std::sync::Mutex<Vec<u8>>>,
+        /// How many bytes had been written when the stream was shut down.
+        shutdown_at: Arc<std::sync::Mutex<Option<usize>>>,
+    }
+
+    impl MockStream {
+        fn new(reads: Vec<io::Result<&str>>) -> Self {
+            Self {
+                reads: reads
+                    .into_iter()
+                    .map(|read| read.map(|data| data.as_bytes().to_vec()))
+                    .collect(),
+                writes_left: usize::MAX,
+                written: Arc::new(std::sync::Mutex::new(Vec::new())),
+                shutdown_at: Arc::new(std::sync::Mutex::new(None)),
+            }
+        }
+
+        fn fail_writes_after(mut self, writes: usize) -> Self {
+            self.writes_left = writes;
+            self
+        }
+
+        /// A handle to everything the server managed to write, readable after the stream is consumed.
+        fn written(&self) -> Arc<std::sync::Mutex<Vec<u8>>> {
+            self.written.clone()
+        }
+    }
+
+    impl AsyncRead for MockStream {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            match self.reads.pop_front() {
+                None => Poll::Ready(Ok(())),
+                Some(Err(e)) => Poll::Ready(Err(e)),
+                Some(Ok(mut data)) => {
+                    if data.len() > buf.remaining() {
+                        let rest = data.split_off(buf.remaining());
+                        self.reads.push_front(Ok(rest));
+                    }
+                    buf.put_slice(&data);
+                    Poll::Ready(Ok(()))
+                }
+            }
+        }
+    }
+
+    impl AsyncWrite for MockStream {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            if self.writes_left == 0 {
+                return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+            }
+            self.writes_left -= 1;
+            self.written.lock().unwrap().extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            *self.shutdown_at.lock().unwrap() = Some(self.written.lock().unwrap().len());
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// Drive a whole connection over a [MockStream] and return its result and the server state.
+    async fn run_mock(stream: MockStream) -> (anyhow::Result<()>, ServerState) {
+        let server_state = Arc::new(Mutex::new(ServerState::new()));
+        let socket_addr = "127.0.0.1:5456".parse().unwrap();
+        let result = client_connection(
+            server_state.clone(),
+            stream,
+            socket_addr,
+            ConnectionConfig::default(),
+        )
+        .await;
+        let state = Arc::try_unwrap(server_state).unwrap().into_inner();
+        (result, state)
+    }
+
+    fn written_lines(written: &Arc<std::sync::Mutex<Vec<u8>>>) -> Vec<String> {
+        String::from_utf8(written.lock().unwrap().clone())
+            .unwrap()
+            .lines()
+            .map(|line| line.to_string())
+            .collect()
+    }
+
+    /// Run a client connection over an in-memory stream and return the client side of it.
+    fn connect(server_state: Arc<Mutex<ServerState>>) -> Framed<DuplexStream, LinesCodec> {
+        connect_with(server_state, ConnectionConfig::default())
+    }
+
+    fn connect_with(
+        server_state: Arc<Mutex<ServerState>>,
+        config: ConnectionConfig,
+    ) -> Framed<DuplexStream, LinesCodec> {
+        let (client_stream, server_stream) = tokio::io::duplex(4096);
+        let socket_addr = "127.0.0.1:5456".parse().unwrap();
+        tokio::spawn(client_connection(
+            server_state,
+            server_stream,
+            socket_addr,
+            config,
+        ));
+        Framed::new(client_stream, LinesCodec::new())
+    }
+
+    async fn recv(framed: &mut Framed<DuplexStream, LinesCodec>) -> String {
+        framed.next().await.unwrap().unwrap()
+    }
+
+    #[cfg(feature = "debug")]
+    #[tokio::test]
+    async fn test_client_connection_debug() {
+        use crate::parser::parse_incoming;
+
+        let server_state = Arc::new(Mutex::new(ServerState::new()));
+        let mut framed = connect(server_state);
+        assert_eq!(recv(&mut framed).await, "CONNECTED");
+
+        // debug is off by default
+        framed.send("NAME @robert").await.unwrap();
+        assert_eq!(recv(&mut framed).await, "REGISTERED");
+
+        framed.send("DEBUG ON").await.unwrap();
+        for input in ["JOIN #sports", "JOIN @sports", "LEAVE"] {
+            framed.send(input).await.unwrap();
+            assert_eq!(
+                recv(&mut framed).await,
+                OutgoingMsg::Notice(parse_incoming(input).to_string()).to_string()
+            );
+            if let ParsedAction::Error(_, parse_error) = parse_incoming(input) {
+                assert_eq!(recv(&mut framed).await, parse_error.to_string());
+            }
+        }
+
+        framed.send("DEBUG OFF").await.unwrap();
+        assert_eq!(
+            recv(&mut framed).await,
+            "NOTICE ParsedAction::Process(DEBUG OFF)"
+        );
+        framed.send("ROOMS").await.unwrap();
+        assert_eq!(recv(&mut framed).await, "ROOM #sports");
+    }
+
+    #[tokio::test]
+    async fn test_client_connection_send_failure_during_registration() {
+        // CONNECTED can't be written
+        let stream = MockStream::new(vec![Ok("NAME @robert\n")]).fail_writes_after(0);
+        let (result, state) = run_mock(stream).await;
+        assert!(result.is_err());
+        assert!(state.rooms().is_empty());
+
+        // an error reply to a bad NAME can't be written
+        let stream = MockStream::new(vec![Ok("NAME robert\n")]).fail_writes_after(1);
+        let (result, mut state) = run_mock(stream).await;
+        assert!(result.is_err());
+        assert_eq!(
+            state.remove_user("@robert"),
+            Err(ServerError::UserUnknown("@robert".to_string()))
+        );
+
+        // REGISTERED can't be written after the name was taken, so the name is given back
+        let stream = MockStream::new(vec![Ok("NAME @robert\n")]).fail_writes_after(1);
+        let (result, mut state) = run_mock(stream).await;
+        assert!(result.is_err());
+        assert_eq!(
+            state.remove_user("@robert"),
+            Err(ServerError::UserUnknown("@robert".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_client_connection_max_line_length_exceeded() {
+        let too_long = format!("SAY #sports {}\n", "a".repeat(2000));
+        let stream = MockStream::new(vec![
+            Ok(&too_long),
+            Ok("NAME @robert\n"),
+            Ok("JOIN #sports\n"),
+            Ok(&too_long),
+            Ok("ROOMS\n"),
+        ]);
+        let written = stream.written();
+        let (result, mut state) = run_mock(stream).await;
+        assert!(result.is_ok());
+        assert_eq!(
+            written_lines(&written),
+            vec![
+                "CONNECTED",
+                "ERROR max length reached",
+                "REGISTERED",
+                "ERROR max length reached",
+                "ROOM #sports",
+            ]
+        );
+        // end of stream tears the client down
+        assert!(state.rooms().is_empty());
+        assert_eq!(
+            state.remove_user("@robert"),
+            Err(ServerError::UserUnknown("@robert".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_client_connection_io_error_mid_session() {
+        let stream = MockStream::new(vec![
+            Ok("NAME @robert\n"),
+            Ok("JOIN #sports\n"),
+            Err(io::ErrorKind::ConnectionReset.into()),
+            Ok("SAY #sports never read\n"),
+        ]);
+        let (result, mut state) = run_mock(stream).await;
+        assert!(result.is_err());
+        assert!(state.rooms().is_empty());
+        assert_eq!(
+            state.remove_user("@robert"),
+            Err(ServerError::UserUnknown("@robert".to_string()))
+        );
+    }
+
+    #[cfg(feature = "debug")]
+    #[tokio::test]
+    async fn test_client_connection_phases_share_handling() {
+        // the same script is run before and after registration
+        let too_long = format!("SAY #sports {}\n", "a".repeat(2000));
+        let phase = ["DEBUG ON\n", too_long.as_str(), "PONG\n", "DEBUG OFF\n"];
+        let mut reads: Vec<io::Result<&str>> = phase.iter().map(|read| Ok(*read)).collect();
+        reads.push(Ok("NAME @robert\n"));
+        reads.extend(phase.iter().map(|read| Ok(*read)));
+        let stream = MockStream::new(reads);
+        let written = stream.written();
+        let (result, _) = run_mock(stream).await;
+        assert!(result.is_ok());
+
+        let phase_lines = [
+            "ERROR max length reached",
+            "NOTICE ParsedAction::Process(PONG)",
+            "NOTICE ParsedAction::Process(DEBUG OFF)",
+        ];
+        let mut expected = vec!["CONNECTED"];
+        expected.extend(phase_lines);
+        expected.push("REGISTERED");
+        expected.extend(phase_lines);
+        assert_eq!(written_lines(&written), expected);
+    }
+
+    #[tokio::test]
+    async fn test_client_connection_send_failure_mid_session() {
+        let stream = MockStream::new(vec![
+            Ok("NAME @robert\n"),
+            Ok("JOIN #sports\n"),
+            Ok("USERS #nope\n"),
+        ])
+        .fail_writes_after(2);
+        let (result, mut state) = run_mock(stream).await;
+        assert!(result.is_err());
+        assert!(state.rooms().is_empty());
+        assert_eq!(
+            state.remove_user("@robert"),
+            Err(ServerError::UserUnknown("@robert".to_string()))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_client_connection_registration_timeout() {
+        let server_state = Arc::new(Mutex::new(ServerState::new()));
+        let config = ConnectionConfig {
+            registration_timeout: Duration::from_secs(5),
+            ..ConnectionConfig::default()
+        };
+
+        // a client that never sends NAME is dropped once the window closes
+        let mut framed = connect_with(server_state.clone(), config);
+        assert_eq!(recv(&mut framed).await, "CONNECTED");
+        let started = tokio::time::Instant::now();
+        assert_eq!(recv(&mut framed).await, "NOTICE registration timed out");
+        assert!(framed.next().await.is_none());
+        assert_eq!(started.elapsed(), Duration::from_secs(5));
+
+        // a registered client outlives the window
+        let mut framed = connect_with(server_state, config);
+        assert_eq!(recv(&mut framed).await, "CONNECTED");
+        framed.send("NAME @robert").await.unwrap();
+        assert_eq!(recv(&mut framed).await, "REGISTERED");
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        framed.send("JOIN #sports").await.unwrap();
+        framed.send("ROOMS").await.unwrap();
+        assert_eq!(recv(&mut framed).await, "ROOM #sports");
+    }
+
+    #[tokio::test]
+    async fn test_client_connection_outgoing_channel_closed() {
+        let server_state = Arc::new(Mutex::new(ServerState::new()));
+        let mut framed = connect(server_state.clone());
+        assert_eq!(recv(&mut framed).await, "CONNECTED");
+        framed.send("NAME @robert").await.unwrap();
+        assert_eq!(recv(&mut framed).await, "REGISTERED");
+
+        // dropping the user drops the only sender for the client
+        assert!(server_state.lock().await.remove_user("@robert").is_ok());
+        assert_eq!(
+            recv(&mut framed).await,
+            "NOTICE disconnected: removed from the server"
+        );
+        assert!(framed.next().await.is_none());
+    }
+
+    #[cfg(feature = "debug")]
+    #[tokio::test]
+    async fn test_client_connection_keepalive_during_listing() {
+        let server_state = Arc::new(Mutex::new(ServerState::new()));
+        let mut receivers = vec![];
+        {
+            let mut state = server_state.lock().await;
+            for i in 0..1000 {
+                let name = format!("@user{}", i);
+                let (sender, receiver) = unbounded_channel();
+                receivers.push(receiver);
+                state.add_user(name.clone(), User::new(sender)).unwrap();
+                state.join_room("#big".to_string(), name).unwrap();
+                // throw away the JOINED messages as they pile up
+                if i % 100 == 0 {
+                    for receiver in receivers.iter_mut() {
+                        while receiver.try_recv().is_ok() {}
+                    }
+                }
+            }
+        }
+
+        let mut framed = connect(server_state.clone());
+        assert_eq!(recv(&mut framed).await, "CONNECTED");
+        framed.send("NAME @robert").await.unwrap();
+        assert_eq!(recv(&mut framed).await, "REGISTERED");
+        framed.send("DEBUG ON").await.unwrap();
+        // both lines in one write, so the PONG is waiting by the time the listing starts
+        tokio::io::AsyncWriteExt::write_all(framed.get_mut(), b"USERS #big\nPONG\n")
+            .await
+            .unwrap();
+        assert_eq!(
+            recv(&mut framed).await,
+            "NOTICE ParsedAction::Process(USERS #big)"
+        );
+
+        // the PONG is handled before any of the listing goes out
+        assert_eq!(
+            recv(&mut framed).await,
+            "NOTICE ParsedAction::Process(PONG)"
+        );
+        for _ in 0..1000 {
+            assert!(recv(&mut framed).await.starts_with("USER @user"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client_connection_motd_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let motd_file = dir.path().join("motd.txt");
+        std::fs::write(&motd_file, "welcome").unwrap();
+        let server_state = Arc::new(Mutex::new(ServerState::new()));
+        server_state
+            .lock()
+            .await
+            .set_reloadable_files(Some(motd_file.clone()), None)
+            .unwrap();
+
+        let mut robert = connect(server_state.clone());
+        assert_eq!(recv(&mut robert).await, "CONNECTED");
+        robert.send("NAME @robert").await.unwrap();
+        assert_eq!(recv(&mut robert).await, "REGISTERED");
+        assert_eq!(recv(&mut robert).await, "NOTICE welcome");
+
+        std::fs::write(&motd_file, "welcome back\nbe nice").unwrap();
+        server_state.lock().await.reload().unwrap();
+
+        let mut kelsey = connect(server_state.clone());
+        assert_eq!(recv(&mut kelsey).await, "CONNECTED");
+        kelsey.send("NAME @kelsey").await.unwrap();
+        assert_eq!(recv(&mut kelsey).await, "REGISTERED");
+        assert_eq!(recv(&mut kelsey).await, "NOTICE welcome back");
+        assert_eq!(recv(&mut kelsey).await, "NOTICE be nice");
+
+        // the reload didn't disturb the existing connection
+        robert.send("ROOMS").await.unwrap();
+        robert.send("SAY @kelsey hi").await.unwrap();
+        assert_eq!(recv(&mut robert).await, "DELIVERED @kelsey");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_client_connection_churn_limit() {
+        let server_state = Arc::new(Mutex::new(ServerState::new()));
+        let config = ConnectionConfig {
+            churn_limit: 3,
+            churn_window: Duration::from_secs(10),
+            ..ConnectionConfig::default()
+        };
+        let mut framed = connect_with(server_state.clone(), config);
+        assert_eq!(recv(&mut framed).await, "CONNECTED");
+        framed.send("NAME @robert").await.unwrap();
+        assert_eq!(recv(&mut framed).await, "REGISTERED");
+
+        for command in ["JOIN #spam", "LEAVE #spam", "JOIN #spam", "LEAVE #spam"] {
+            framed.send(command).await.unwrap();
+        }
+        assert_eq!(
+            recv(&mut framed).await,
+            "NOTICE too many joins and leaves, dropped LEAVE #spam"
+        );
+        // the dropped LEAVE left the user in the room
+        framed.send("ROOMS").await.unwrap();
+        assert_eq!(recv(&mut framed).await, "ROOM #spam");
+
+        // a new window lets the client churn again
+        tokio::time::advance(Duration::from_secs(10)).await;
+        framed.send("LEAVE #spam").await.unwrap();
+        framed.send("ROOMS").await.unwrap();
+        framed.send("QUIT").await.unwrap();
+        assert!(framed.next().await.is_none());
+        assert!(server_state.lock().await.rooms().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_client_connection_malformed_limit() {
+        let server_state = Arc::new(Mutex::new(ServerState::new()));
+        let config = ConnectionConfig {
+            malformed_limit: 3,
+            ..ConnectionConfig::default()
+        };
+        let mut framed = connect_with(server_state.clone(), config);
+        assert_eq!(recv(&mut framed).await, "CONNECTED");
+        framed.send("NAME @robert").await.unwrap();
+        assert_eq!(recv(&mut framed).await, "REGISTERED");
+
+        // a well-formed command starts the count over
+        for command in ["JOIN", "JOIN", "JOIN", "ROOMS", "JOIN", "JOIN", "JOIN"] {
+            framed.send(command).await.unwrap();
+        }
+        let start = tokio::time::Instant::now();
+        for _ in 0..6 {
+            assert_eq!(recv(&mut framed).await, "ERROR bad arguments");
+        }
+        assert_eq!(tokio::time::Instant::now(), start);
+
+        // past the limit each malformed command waits longer than the last
+        framed.send("JOIN").await.unwrap();
+        framed.send("JOIN").await.unwrap();
+        assert_eq!(recv(&mut framed).await, "ERROR bad arguments");
+        assert_eq!(tokio::time::Instant::now() - start, MALFORMED_DELAY_MIN);
+        assert_eq!(recv(&mut framed).await, "ERROR bad arguments");
+        assert_eq!(tokio::time::Instant::now() - start, MALFORMED_DELAY_MIN * 3);
+
+        // at twice the limit the client is disconnected
+        framed.send("JOIN").await.unwrap();
+        assert_eq!(
+            recv(&mut framed).await,
+            "NOTICE too many malformed commands"
+        );
+        assert!(framed.next().await.is_none());
+        assert!(server_state.lock().await.whois("@robert").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_client_connection_reset() {
+        let server_state = Arc::new(Mutex::new(ServerState::new()));
+        let mut framed = connect(server_state.clone());
+        assert_eq!(recv(&mut framed).await, "CONNECTED");
+        framed.send("NAME @robert").await.unwrap();
+        assert_eq!(recv(&mut framed).await, "REGISTERED");
+        framed.send("JOIN #sports").await.unwrap();
+        framed.send("RESET").await.unwrap();
+        assert_eq!(recv(&mut framed).await, "CONNECTED");
+        {
+            let state = server_state.lock().await;
+            assert!(state.rooms().is_empty());
+            assert!(state.whois("@robert").is_err());
+        }
+
+        // back in registration, so commands other than NAME are refused
+        framed.send("ROOMS").await.unwrap();
+        assert_eq!(recv(&mut framed).await, "ERROR not registered");
+        framed.send("NAME @kelsey").await.unwrap();
+        assert_eq!(recv(&mut framed).await, "REGISTERED");
+        framed.send("JOIN #news").await.unwrap();
+        framed.send("ROOMS").await.unwrap();
+        assert_eq!(recv(&mut framed).await, "ROOM #news");
+        assert!(server_state.lock().await.whois("@kelsey").is_ok());
+
+        // the old name is free again
+        let mut other = connect(server_state.clone());
+        assert_eq!(recv(&mut other).await, "CONNECTED");
+        other.send("NAME @robert").await.unwrap();
+        assert_eq!(recv(&mut other).await, "REGISTERED");
+    }
+
+    #[tokio::test]
+    async fn test_client_connection_reset_keeps_name_free() {
+        let server_state = Arc::new(Mutex::new(ServerState::new()));
+        server_state
+            .lock()
+            .await
+            .set_name_lock(Duration::from_secs(60));
+        let mut framed = connect(server_state.clone());
+        assert_eq!(recv(&mut framed).await, "CONNECTED");
+        framed.send("NAME @robert").await.unwrap();
+        assert_eq!(recv(&mut framed).await, "REGISTERED");
+        framed.send("RESET").await.unwrap();
+        assert_eq!(recv(&mut framed).await, "CONNECTED");
+
+        // a name given up with RESET isn't held by the name lock
+        framed.send("NAME @robert").await.unwrap();
+        assert_eq!(recv(&mut framed).await, "REGISTERED");
+        assert!(server_state.lock().await.whois("@robert").is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_client_connection_max_pending_registrations() {
+        let mut state = ServerState::new();
+        state.set_max_pending_registrations(Some(2));
+        let server_state = Arc::new(Mutex::new(state));
+
+        let mut pending = vec![];
+        for _ in 0..2 {
+            let mut framed = connect(server_state.clone());
+            assert_eq!(recv(&mut framed).await, "CONNECTED");
+            pending.push(framed);
+        }
+        let mut rejected = connect(server_state.clone());
+        assert_eq!(
+            recv(&mut rejected).await,
+            "NOTICE too many pending connections, try again later"
+        );
+        assert!(rejected.next().await.is_none());
+
+        // registering frees up a spot
+        pending[0].send("NAME @robert").await.unwrap();
+        assert_eq!(recv(&mut pending[0]).await, "REGISTERED");
+        let mut framed = connect(server_state.clone());
+        assert_eq!(recv(&mut framed).await, "CONNECTED");
+
+        // and so does disconnecting
+        pending[1].send("QUIT").await.unwrap();
+        assert!(pending[1].next().await.is_none());
+        let mut framed = connect(server_state.clone());
+        assert_eq!(recv(&mut framed).await, "CONNECTED");
+    }
+
+    #[tokio::test]
+    async fn test_client_session_without_name() {
+        let stream = MockStream::new(vec![Ok("JOIN #sports\n")]);
+        let written = stream.written();
+        let server_state = Arc::new(Mutex::new(ServerState::new()));
+        let socket_addr = "127.0.0.1:5456".parse().unwrap();
+        let mut client = ClientConn::new(
+            stream,
+            socket_addr,
+            ConnectionConfig::default(),
+            Arc::new(CommandCounts::new()),
+        );
+        let _sender = client.open_channel();
+
+        // a session that somehow starts without a name ends cleanly instead of panicking
+        let result = client_session(server_state.clone(), &mut client).await;
+        assert!(result.is_err());
+        assert_eq!(
+            written_lines(&written),
+            ["REGISTERED", "NOTICE disconnected: not registered"]
+        );
+        assert!(server_state.lock().await.rooms().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_client_connection_commands() {
+        let server_state = Arc::new(Mutex::new(ServerState::new()));
+        let mut framed = connect(server_state);
+        assert_eq!(recv(&mut framed).await, "CONNECTED");
+        framed.send("NAME @robert").await.unwrap();
+        assert_eq!(recv(&mut framed).await, "REGISTERED");
+        framed.send("COMMANDS").await.unwrap();
+        let line = recv(&mut framed).await;
+        let verbs: Vec<&str> = line.split(' ').collect();
+        assert_eq!(verbs[0], "COMMANDS");
+        assert!(verbs.contains(&"JOIN"));
+        assert!(verbs.contains(&"COMMANDS"));
+        assert_eq!(verbs.contains(&"DEBUG"), cfg!(feature = "debug"));
+    }
+
+    #[tokio::test]
+    async fn test_client_connection_auto_join() {
+        let mut state = ServerState::new();
+        state.set_auto_join(vec!["#lobby".to_string(), "#news".to_string()]);
+        let server_state = Arc::new(Mutex::new(state));
+
+        let mut robert = connect(server_state.clone());
+        assert_eq!(recv(&mut robert).await, "CONNECTED");
+        robert.send("NAME @robert").await.unwrap();
+        assert_eq!(recv(&mut robert).await, "REGISTERED");
+        assert_eq!(recv(&mut robert).await, "#lobby @robert JOINED");
+        assert_eq!(recv(&mut robert).await, "#news @robert JOINED");
+
+        // members of the room hear about the newcomer like any other JOIN
+        let mut kelsey = connect(server_state.clone());
+        assert_eq!(recv(&mut kelsey).await, "CONNECTED");
+        kelsey.send("NAME @kelsey").await.unwrap();
+        assert_eq!(recv(&mut kelsey).await, "REGISTERED");
+        assert_eq!(recv(&mut robert).await, "#lobby @kelsey JOINED");
+        assert_eq!(recv(&mut robert).await, "#news @kelsey JOINED");
+
+        // auto-joined rooms can be left like any other
+        kelsey.send("LEAVE #news").await.unwrap();
+        assert_eq!(recv(&mut robert).await, "#news @kelsey LEFT");
+        let state = server_state.lock().await;
+        assert_eq!(state.users("#lobby").unwrap(), ["@kelsey", "@robert"]);
+        assert_eq!(state.users("#news").unwrap(), ["@robert"]);
+    }
+
+    #[tokio::test]
+    async fn test_client_connection_say_after_leave() {
+        let server_state = Arc::new(Mutex::new(ServerState::new()));
+        let mut kelsey = connect(server_state.clone());
+        assert_eq!(recv(&mut kelsey).await, "CONNECTED");
+        kelsey.send("NAME @kelsey").await.unwrap();
+        assert_eq!(recv(&mut kelsey).await, "REGISTERED");
+        kelsey.send("JOIN #sports").await.unwrap();
+
+        let mut robert = connect(server_state.clone());
+        assert_eq!(recv(&mut robert).await, "CONNECTED");
+        robert.send("NAME @robert").await.unwrap();
+        assert_eq!(recv(&mut robert).await, "REGISTERED");
+        robert.send("JOIN #sports").await.unwrap();
+        assert_eq!(recv(&mut kelsey).await, "#sports @robert JOINED");
+
+        // commands are handled in order, so the SAY sees that the LEAVE already happened
+        robert
+            .send("LEAVE #sports\nSAY #sports still here?")
+            .await
+            .unwrap();
+        assert_eq!(
+            recv(&mut robert).await,
+            "ERROR user not in room @robert #sports"
+        );
+        assert_eq!(recv(&mut kelsey).await, "#sports @robert LEFT");
+        // and nothing reached the room
+        kelsey.send("ROOMS").await.unwrap();
+        assert_eq!(recv(&mut kelsey).await, "ROOM #sports");
+    }
+
+    #[tokio::test]
+    async fn test_client_connection_handshake() {
+        let dir = tempfile::tempdir().unwrap();
+        let motd_file = dir.path().join("motd.txt");
+        std::fs::write(&motd_file, "welcome to the server").unwrap();
+        let mut state = ServerState::new();
+        state.set_reloadable_files(Some(motd_file), None).unwrap();
+        state.set_auto_join(vec!["#lobby".to_string()]);
+        let server_state = Arc::new(Mutex::new(state));
+
+        let mut kelsey = connect(server_state.clone());
+        assert_eq!(recv(&mut kelsey).await, "CONNECTED");
+        kelsey.send("NAME @kelsey").await.unwrap();
+        assert_eq!(recv(&mut kelsey).await, "REGISTERED");
+
+        // every step of the handshake gets exactly one reply, in order
+        let mut robert = connect(server_state.clone());
+        let steps = [
+            ("NAME @r", vec!["ERROR bad name format"]),
+            ("NAME @kelsey", vec!["ERROR user already exists @kelsey"]),
+            ("JOIN #sports", vec!["ERROR not registered"]),
+            (
+                "NAME @robert",
+                vec![
+                    "REGISTERED",
+                    "NOTICE welcome to the server",
+                    "#lobby @robert JOINED",
+                ],
+            ),
+        ];
+        assert_eq!(recv(&mut robert).await, "CONNECTED");
+        for (input, replies) in steps {
+            robert.send(input).await.unwrap();
+            for reply in replies {
+                assert_eq!(recv(&mut robert).await, reply);
+            }
+        }
+
+        // the client is registered and can use every command
+        robert.send("ROOMS").await.unwrap();
+        assert_eq!(recv(&mut robert).await, "ROOM #lobby");
+    }
+
+    #[tokio::test]
+    async fn test_client_connection_health() {
+        let server_state = Arc::new(Mutex::new(ServerState::new()));
+        let mut framed = connect(server_state.clone());
+        assert_eq!(recv(&mut framed).await, "CONNECTED");
+        framed.send("HEALTH").await.unwrap();
+        assert_eq!(recv(&mut framed).await, "OK");
+        assert!(framed.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_client_connection_link_refused() {
+        let server_state = Arc::new(Mutex::new(ServerState::new()));
+        let mut framed = connect(server_state.clone());
+        assert_eq!(recv(&mut framed).await, "CONNECTED");
+        // no peers are allowed by default
+        framed.send("LINK #sports beta").await.unwrap();
+        assert_eq!(recv(&mut framed).await, "ERROR link refused");
+        assert!(!server_state.lock().await.is_linked("#sports"));
+
+        // the connection can still register as a user
+        framed.send("NAME @robert").await.unwrap();
+        assert_eq!(recv(&mut framed).await, "REGISTERED");
+
+        // a peer from an allowed address is linked
+        server_state
+            .lock()
+            .await
+            .set_link_peers(vec!["127.0.0.1".parse().unwrap()]);
+        let mut framed = connect(server_state.clone());
+        assert_eq!(recv(&mut framed).await, "CONNECTED");
+        framed.send("LINK #sports beta").await.unwrap();
+        while !server_state.lock().await.is_linked("#sports") {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client_connection_time() {
+        let server_state = Arc::new(Mutex::new(ServerState::new()));
+        let mut framed = connect(server_state);
+        assert_eq!(recv(&mut framed).await, "CONNECTED");
+
+        // TIME works before and after registration
+        let before = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        framed.send("TIME").await.unwrap();
+        let first = recv(&mut framed).await;
+        framed.send("NAME @robert").await.unwrap();
+        assert_eq!(recv(&mut framed).await, "REGISTERED");
+        framed.send("TIME").await.unwrap();
+        let second = recv(&mut framed).await;
+        let after = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+
+        let mut times = vec![];
+        for reply in [first, second] {
+            match OutgoingMsg::parse(&reply) {
+                Some(OutgoingMsg::Time(millis)) => times.push(millis),
+                _ => panic!("not a TIME reply: {}", reply),
+            }
+        }
+        assert!(before.as_millis() as u64 <= times[0]);
+        assert!(times[0] <= times[1]);
+        assert!(times[1] <= after.as_millis() as u64);
+    }
+
+    #[tokio::test]
+    async fn test_client_connection_not_registered() {
+        let server_state = Arc::new(Mutex::new(ServerState::new()));
+        let mut framed = connect(server_state.clone());
+        assert_eq!(recv(&mut framed).await, "CONNECTED");
+
+        // malformed or not, commands that need a name are refused; PONG is handled quietly
+        for command in ["JOIN #sports", "JOIN", "SAY @kelsey hi"] {
+            framed.send(command).await.unwrap();
+            assert_eq!(recv(&mut framed).await, "ERROR not registered");
+        }
+        framed.send("PONG").await.unwrap();
+        framed.send("NAME @robert").await.unwrap();
+        assert_eq!(recv(&mut framed).await, "REGISTERED");
+        assert!(server_state.lock().await.rooms().is_empty());
+
+        // QUIT still works before NAME
+        let mut other = connect(server_state.clone());
+        assert_eq!(recv(&mut other).await, "CONNECTED");
+        other.send("QUIT").await.unwrap();
+        assert!(other.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_client_connection_listings_sorted() {
+        let server_state = Arc::new(Mutex::new(ServerState::new()));
+        let mut clients = vec![];
+        for name in ["@sam", "@kelsey", "@robert"] {
+            let mut framed = connect(server_state.clone());
+            assert_eq!(recv(&mut framed).await, "CONNECTED");
+            framed.send(format!("NAME {}", name)).await.unwrap();
+            assert_eq!(recv(&mut framed).await, "REGISTERED");
+            clients.push(framed);
+        }
+        let robert = &mut clients[2];
+        for room in ["#tennis", "#news", "#sports"] {
+            robert.send(format!("JOIN {}", room)).await.unwrap();
+        }
+        clients[0].send("JOIN #sports").await.unwrap();
+        assert_eq!(recv(&mut clients[2]).await, "#sports @sam JOINED");
+        clients[1].send("JOIN #sports").await.unwrap();
+        assert_eq!(recv(&mut clients[2]).await, "#sports @kelsey JOINED");
+        let robert = &mut clients[2];
+
+        robert.send("ROOMS").await.unwrap();
+        for room in ["#news", "#sports", "#tennis"] {
+            assert_eq!(recv(robert).await, format!("ROOM {}", room));
+        }
+        robert.send("USERS #sports").await.unwrap();
+        for user in ["@kelsey", "@robert", "@sam"] {
+            assert_eq!(recv(robert).await, format!("USER {}", user));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client_connection_kill() {
+        let mut state = ServerState::new();
+        state.set_oper_password(Some("hunter2".to_string()));
+        let server_state = Arc::new(Mutex::new(state));
+
+        let mut robert = connect(server_state.clone());
+        assert_eq!(recv(&mut robert).await, "CONNECTED");
+        robert.send("NAME @robert").await.unwrap();
+        assert_eq!(recv(&mut robert).await, "REGISTERED");
+        robert.send("JOIN #sports").await.unwrap();
+
+        let mut admin = connect(server_state.clone());
+        assert_eq!(recv(&mut admin).await, "CONNECTED");
+        admin.send("NAME @admin").await.unwrap();
+        assert_eq!(recv(&mut admin).await, "REGISTERED");
+        admin.send("KILL @robert spamming").await.unwrap();
+        assert_eq!(recv(&mut admin).await, "ERROR not operator @admin");
+        admin.send("OPER hunter2").await.unwrap();
+        assert_eq!(recv(&mut admin).await, "NOTICE you are now an operator");
+        admin.send("JOIN #sports").await.unwrap();
+        assert_eq!(recv(&mut robert).await, "#sports @admin JOINED");
+        admin.send("KILL @robert spamming").await.unwrap();
+
+        // the killed client is told why and its connection is closed
+        assert_eq!(recv(&mut robert).await, "DISCONNECT spamming");
+        assert!(robert.next().await.is_none());
+        assert_eq!(recv(&mut admin).await, "#sports @robert LEFT");
+        let state = server_state.lock().await;
+        assert_eq!(
+            state.whois("@robert"),
+            Err(ServerError::UserUnknown("@robert".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_client_connection_limits() {
+        let server_state = Arc::new(Mutex::new(ServerState::new()));
+        server_state.lock().await.set_history_size(50);
+        let config = ConnectionConfig {
+            parser: ParserConfig {
+                name_min_length: 2,
+                name_max_length: 12,
+                room_min_length: 4,
+                room_max_length: 30,
+                ..ParserConfig::default()
+            },
+            churn_limit: 5,
+            churn_window: Duration::from_secs(60),
+            ..ConnectionConfig::default()
+        };
+        let mut framed = connect_with(server_state, config);
+        assert_eq!(recv(&mut framed).await, "CONNECTED");
+        framed.send("NAME @robert").await.unwrap();
+        assert_eq!(recv(&mut framed).await, "REGISTERED");
+        framed.send("LIMITS").await.unwrap();
+        for limit in [
+            "LIMIT line-length 1024",
+            "LIMIT name-min-length 2",
+            "LIMIT name-max-length 12",
+            "LIMIT room-min-length 4",
+            "LIMIT room-max-length 30",
+            "LIMIT recipients 5",
+            "LIMIT churn-limit 5",
+            "LIMIT churn-window 60",
+            "LIMIT malformed-limit 10",
+            "LIMIT history 50",
+            "LIMIT buddies 100",
+        ] {
+            assert_eq!(recv(&mut framed).await, limit);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client_connection_maxlen() {
+        let server_state = Arc::new(Mutex::new(ServerState::new()));
+        let mut kelsey = connect(server_state.clone());
+        assert_eq!(recv(&mut kelsey).await, "CONNECTED");
+        kelsey.send("NAME @kelsey").await.unwrap();
+        assert_eq!(recv(&mut kelsey).await, "REGISTERED");
+        kelsey.send("JOIN #sports").await.unwrap();
+
+        let mut robert = connect(server_state.clone());
+        assert_eq!(recv(&mut robert).await, "CONNECTED");
+        robert.send("NAME @robert").await.unwrap();
+        assert_eq!(recv(&mut robert).await, "REGISTERED");
+        robert.send("JOIN #sports").await.unwrap();
+        assert_eq!(recv(&mut kelsey).await, "#sports @robert JOINED");
+
+        robert.send("MAXLEN 5").await.unwrap();
+        robert.send("SAY #sports too long").await.unwrap();
+        assert_eq!(recv(&mut robert).await, "ERROR message too long");
+        robert.send("SAY @kelsey too long").await.unwrap();
+        assert_eq!(recv(&mut robert).await, "ERROR message too long");
+        robert.send("EDIT #sports 1 too long").await.unwrap();
+        assert_eq!(recv(&mut robert).await, "ERROR message too long");
+        robert.send("TOPIC #sports too long").await.unwrap();
+        assert_eq!(recv(&mut robert).await, "ERROR message too long");
+        robert
+            .send("BRIDGE #sports @irc-sam too long")
+            .await
+            .unwrap();
+        assert_eq!(recv(&mut robert).await, "ERROR message too long");
+        robert.send("PREVIEW SAY #sports too long").await.unwrap();
+        assert_eq!(recv(&mut robert).await, "ERROR message too long");
+        robert.send("SAY #sports short").await.unwrap();
+        // only the short message got through
+        assert_eq!(recv(&mut kelsey).await, "#sports @robert SAID 1 short");
+
+        // other clients aren't affected
+        kelsey.send("SAY #sports this is fine").await.unwrap();
+        assert_eq!(
+            recv(&mut robert).await,
+            "#sports @kelsey SAID 2 this is fine"
+        );
+
+        robert.send("MAXLEN 2000").await.unwrap();
+        assert_eq!(recv(&mut robert).await, "ERROR bad arguments");
+    }
+
+    #[tokio::test]
+    async fn test_client_connection_invalid_content() {
+        let server_state = Arc::new(Mutex::new(ServerState::new()));
+        let mut kelsey = connect(server_state.clone());
+        assert_eq!(recv(&mut kelsey).await, "CONNECTED");
+        kelsey.send("NAME @kelsey").await.unwrap();
+        assert_eq!(recv(&mut kelsey).await, "REGISTERED");
+        kelsey.send("JOIN #sports").await.unwrap();
+
+        let mut robert = connect(server_state.clone());
+        assert_eq!(recv(&mut robert).await, "CONNECTED");
+        // rejected during registration too
+        robert.send("NAME @rob\0ert").await.unwrap();
+        assert_eq!(recv(&mut robert).await, "ERROR invalid content");
+        robert.send("NAME @robert").await.unwrap();
+        assert_eq!(recv(&mut robert).await, "REGISTERED");
+        robert.send("JOIN #sports").await.unwrap();
+        assert_eq!(recv(&mut kelsey).await, "#sports @robert JOINED");
+
+        robert.send("SAY #sports null\0byte").await.unwrap();
+        assert_eq!(recv(&mut robert).await, "ERROR invalid content");
+        robert
+            .send("SAY #sports fake\r@kelsey SAID hi")
+            .await
+            .unwrap();
+        assert_eq!(recv(&mut robert).await, "ERROR invalid content");
+        // nothing reached the room, and the connection carries on
+        robert.send("SAY #sports clean").await.unwrap();
+        assert_eq!(recv(&mut kelsey).await, "#sports @robert SAID 1 clean");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_ping_pong_worker_stops_when_channel_closes() {
+        let mut ppt = PingPongTable::new();
+        *ppt.last_activity.lock().await = Instant::now() - Duration::from_secs(200);
+        ppt.receiver.close();
+        ppt.start_worker(&CancellationToken::new());
+
+        // the worker's first send fails, so it returns instead of panicking
+        let worker = ppt.worker.take().unwrap();
+        assert!(worker.await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_ping_pong_worker_ends_with_connection() {
+        let (_client_stream, server_stream) = tokio::io::duplex(64);
+        let socket_addr = "127.0.0.1:5456".parse().unwrap();
+        let client = ClientConn::new(
+            server_stream,
+            socket_addr,
+            ConnectionConfig::default(),
+            Arc::new(CommandCounts::new()),
+        );
+        let worker = client.ppt.worker.as_ref().unwrap().abort_handle();
+        assert!(!worker.is_finished());
+
+        drop(client);
+        tokio::task::yield_now().await;
+        assert!(worker.is_finished());
+    }
+
+    #[tokio::test]
+    async fn test_helper_tasks_end_with_connections() {
+        let server_state = Arc::new(Mutex::new(ServerState::new()));
+        let socket_addr = "127.0.0.1:5456".parse().unwrap();
+        let mut helpers = vec![];
+        let mut connections = vec![];
+        for i in 0..50 {
+            let (client_stream, server_stream) = tokio::io::duplex(4096);
+            let client = ClientConn::new(
+                server_stream,
+                socket_addr,
+                ConnectionConfig::default(),
+                Arc::new(CommandCounts::new()),
+            );
+            helpers.push(client.ppt.worker.as_ref().unwrap().abort_handle());
+            connections.push(tokio::spawn(run_client(server_state.clone(), client)));
+
+            let mut framed = Framed::new(client_stream, LinesCodec::new());
+            assert_eq!(recv(&mut framed).await, "CONNECTED");
+            framed.send(format!("NAME @user{}", i)).await.unwrap();
+            assert_eq!(recv(&mut framed).await, "REGISTERED");
+            // half quit, half just hang up
+            if i % 2 == 0 {
+                framed.send("QUIT").await.unwrap();
+            }
+        }
+        assert!(helpers.iter().any(|helper| !helper.is_finished()));
+
+        for connection in connections {
+            connection.await.unwrap().unwrap();
+        }
+        tokio::task::yield_now().await;
+        assert_eq!(
+            helpers
+                .iter()
+                .filter(|helper| !helper.is_finished())
+                .count(),
+            0
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_client_connection_rename_cooldown() {
+        let server_state = Arc::new(Mutex::new(ServerState::new()));
+        server_state
+            .lock()
+            .await
+            .set_rename_cooldown(Duration::from_secs(30));
+        let mut framed = connect(server_state.clone());
+        assert_eq!(recv(&mut framed).await, "CONNECTED");
+        framed.send("NAME @robert").await.unwrap();
+        assert_eq!(recv(&mut framed).await, "REGISTERED");
+
+        // registering doesn't count, so the first rename goes through
+        framed.send("NAME @bob").await.unwrap();
+        framed.send("NAME @bobby").await.unwrap();
+        assert_eq!(
+            recv(&mut framed).await,
+            "NOTICE renamed too recently, dropped NAME @bobby"
+        );
+        framed.send("WHOIS @bob").await.unwrap();
+        assert_eq!(recv(&mut framed).await, "STATUS @bob");
+
+        tokio::time::advance(Duration::from_secs(30)).await;
+        framed.send("NAME @bobby").await.unwrap();
+        framed.send("WHOIS @bobby").await.unwrap();
+        assert_eq!(recv(&mut framed).await, "STATUS @bobby");
+    }
+
+    #[tokio::test]
+    async fn test_client_connection_custom_max_line_length() {
+        let server_state = Arc::new(Mutex::new(ServerState::new()));
+        let config = ServerConfig::builder()
+            .connection(ConnectionConfig {
+                parser: ParserConfig {
+                    max_line_length: 32,
+                    ..ParserConfig::default()
+                },
+                ..ConnectionConfig::default()
+            })
+            .build()
+            .unwrap();
+        let mut framed = connect_with(server_state, config.connection);
+        assert_eq!(recv(&mut framed).await, "CONNECTED");
+        framed.send("NAME @robert").await.unwrap();
+        assert_eq!(recv(&mut framed).await, "REGISTERED");
+
+        framed
+            .send(format!("SAY @robert {}", "a".repeat(40)))
+            .await
+            .unwrap();
+        assert_eq!(recv(&mut framed).await, "ERROR max length reached");
+        // shorter lines still go through, and LIMITS reports the custom length
+        framed.send("SAY @robert short").await.unwrap();
+        assert_eq!(recv(&mut framed).await, "DELIVERED @robert");
+        assert_eq!(recv(&mut framed).await, "@robert SAID short");
+        framed.send("LIMITS").await.unwrap();
+        assert_eq!(recv(&mut framed).await, "LIMIT line-length 32");
+    }
+
+    #[tokio::test]
+    async fn test_client_connection_message_after_rename() {
+        let server_state = Arc::new(Mutex::new(ServerState::new()));
+        let mut robert = connect(server_state.clone());
+        assert_eq!(recv(&mut robert).await, "CONNECTED");
+        robert.send("NAME @robert").await.unwrap();
+        assert_eq!(recv(&mut robert).await, "REGISTERED");
+        let mut kelsey = connect(server_state.clone());
+        assert_eq!(recv(&mut kelsey).await, "CONNECTED");
+        kelsey.send("NAME @kelsey").await.unwrap();
+        assert_eq!(recv(&mut kelsey).await, "REGISTERED");
+
+        // messages sent right after the rename reach the new name on the same connection
+        robert.send("NAME @bob").await.unwrap();
+        robert.send("SAY @kelsey renamed").await.unwrap();
+        assert_eq!(recv(&mut kelsey).await, "@bob SAID renamed");
+        kelsey.send("SAY @bob welcome").await.unwrap();
+        assert_eq!(recv(&mut kelsey).await, "DELIVERED @bob");
+        assert_eq!(recv(&mut robert).await, "DELIVERED @kelsey");
+        assert_eq!(recv(&mut robert).await, "@kelsey SAID welcome");
+
+        // the old name is gone
+        kelsey.send("SAY @robert hello?").await.unwrap();
+        assert_eq!(recv(&mut kelsey).await, "ERROR user unknown @robert");
+    }
+
+    #[tokio::test]
+    async fn test_client_connection_connstats() {
+        let server_state = Arc::new(Mutex::new(ServerState::new()));
+        let mut framed = connect(server_state);
+        assert_eq!(recv(&mut framed).await, "CONNECTED");
+        framed.send("NAME @robert").await.unwrap();
+        assert_eq!(recv(&mut framed).await, "REGISTERED");
+
+        // the request itself counts, newlines included
+        framed.send("CONNSTATS").await.unwrap();
+        assert_eq!(recv(&mut framed).await, "CONNSTATS 2 23 2 21");
+
+        framed.send("SAY @robert hi").await.unwrap();
+        assert_eq!(recv(&mut framed).await, "DELIVERED @robert");
+        assert_eq!(recv(&mut framed).await, "@robert SAID hi");
+        framed.send("CONNSTATS").await.unwrap();
+        assert_eq!(recv(&mut framed).await, "CONNSTATS 4 48 5 75");
+    }
+
+    #[tokio::test]
+    async fn test_client_connection_flushed_before_shutdown() {
+        // a client turned away gets its NOTICE before the stream is shut down
+        let stream = MockStream::new(vec![]);
+        let written = stream.written();
+        let shutdown_at = stream.shutdown_at.clone();
+        let mut state = ServerState::new();
+        state.set_max_pending_registrations(Some(0));
+        let server_state = Arc::new(Mutex::new(state));
+        let socket_addr = "127.0.0.1:5456".parse().unwrap();
+        client_connection(
+            server_state,
+            stream,
+            socket_addr,
+            ConnectionConfig::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            written_lines(&written),
+            vec!["NOTICE too many pending connections, try again later"]
+        );
+        assert_eq!(
+            *shutdown_at.lock().unwrap(),
+            Some(written.lock().unwrap().len())
+        );
+
+        // so does a client that quits
+        let stream = MockStream::new(vec![Ok("NAME @robert\nQUIT\n")]);
+        let written = stream.written();
+        let shutdown_at = stream.shutdown_at.clone();
+        let (result, _) = run_mock(stream).await;
+        result.unwrap();
+        assert_eq!(written_lines(&written), vec!["CONNECTED", "REGISTERED"]);
+        assert_eq!(
+            *shutdown_at.lock().unwrap(),
+            Some(written.lock().unwrap().len())
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_client_connection_archive() {
+        let server_state = Arc::new(Mutex::new(ServerState::new()));
+        server_state
+            .lock()
+            .await
+            .set_archive_after(Some(Duration::from_secs(300)));
+        tokio::spawn(sweep_idle_rooms(
+            server_state.clone(),
+            Duration::from_secs(60),
+        ));
+        let mut framed = connect(server_state.clone());
+        assert_eq!(recv(&mut framed).await, "CONNECTED");
+        framed.send("NAME @robert").await.unwrap();
+        assert_eq!(recv(&mut framed).await, "REGISTERED");
+        framed.send("JOIN #sports").await.unwrap();
+
+        // the sweeper finds the room on its first check after it has been quiet for 300 seconds
+        tokio::time::advance(Duration::from_secs(300)).await;
+        assert_eq!(
+            recv(&mut framed).await,
+            "NOTICE #sports archived after 300 seconds without messages"
+        );
+        framed.send("SAY #sports anyone?").await.unwrap();
+        assert_eq!(recv(&mut framed).await, "ERROR room archived #sports");
+
+        framed.send("UNARCHIVE #sports").await.unwrap();
+        assert_eq!(
+            recv(&mut framed).await,
+            "NOTICE #sports unarchived by @robert"
+        );
+        // no error for the SAY, so the next reply is the one for the UNARCHIVE
+        framed.send("SAY #sports anyone?").await.unwrap();
+        framed.send("UNARCHIVE #sports").await.unwrap();
+        assert_eq!(
+            recv(&mut framed).await,
+            "NOTICE #sports unarchived by @robert"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_client_connection_protostats() {
+        let mut state = ServerState::new();
+        state.set_oper_password(Some("hunter2".to_string()));
+        let server_state = Arc::new(Mutex::new(state));
+        let protostats = |lines: &[String], verb: &str| {
+            lines
+                .iter()
+                .find_map(|line| line.strip_prefix(&format!("PROTOSTAT {} ", verb)))
+                .map(|count| count.parse::<u64>().unwrap())
+        };
+
+        let mut robert = connect(server_state.clone());
+        assert_eq!(recv(&mut robert).await, "CONNECTED");
+        robert.send("NAME @robert").await.unwrap();
+        assert_eq!(recv(&mut robert).await, "REGISTERED");
+        robert.send("PROTOSTATS").await.unwrap();
+        assert_eq!(recv(&mut robert).await, "ERROR not operator @robert");
+
+        let mut admin = connect(server_state.clone());
+        assert_eq!(recv(&mut admin).await, "CONNECTED");
+        admin.send("NAME @admin").await.unwrap();
+        assert_eq!(recv(&mut admin).await, "REGISTERED");
+        admin.send("OPER hunter2").await.unwrap();
+        assert_eq!(recv(&mut admin).await, "NOTICE you are now an operator");
+        let verbs = Command::verbs().len();
+        admin.send("PROTOSTATS").await.unwrap();
+        let mut before = vec![];
+        for _ in 0..verbs {
+            before.push(recv(&mut admin).await);
+        }
+        assert_eq!(protostats(&before, "NAME"), Some(2));
+        assert_eq!(protostats(&before, "PROTOSTATS"), Some(2));
+        assert_eq!(protostats(&before, "JOIN"), Some(0));
+
+        // badly formed commands count too, but lines that aren't commands don't
+        robert.send("JOIN #sports").await.unwrap();
+        robert.send("JOIN sports").await.unwrap();
+        assert_eq!(recv(&mut robert).await, "ERROR bad room name format");
+        robert.send("HELLO").await.unwrap();
+        robert.send("LEAVE #nowhere").await.unwrap();
+        assert_eq!(recv(&mut robert).await, "ERROR room unknown #nowhere");
+        admin.send("PROTOSTATS").await.unwrap();
+        let mut after = vec![];
+        for _ in 0..verbs {
+            after.push(recv(&mut admin).await);
+        }
+        assert_eq!(protostats(&after, "JOIN"), Some(2));
+        assert_eq!(protostats(&after, "LEAVE"), Some(1));
+        assert_eq!(protostats(&after, "PROTOSTATS"), Some(3));
+        assert_eq!(protostats(&after, "HELLO"), None);
+    }
+
+    /// A listener that fails or hands out in-memory connections as scripted, then waits forever.
+    struct ScriptedListener {
+        accepts: VecDeque<io::Result<DuplexStream>>,
+    }
+
+    impl Listener for ScriptedListener {
+        type Stream = DuplexStream;
+
+        async fn accept(&mut self) -> io::Result<(DuplexStream, SocketAddr)> {
+            match self.accepts.pop_front() {
+                Some(accept) => accept.map(|stream| (stream, "127.0.0.1:5456".parse().unwrap())),
+                None => std::future::pending().await,
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_accept_errors() {
+        assert_eq!(
+            AcceptError::classify(&io::ErrorKind::ConnectionAborted.into()),
+            AcceptError::Connection
+        );
+        // EMFILE, out of file descriptors
+        assert_eq!(
+            AcceptError::classify(&io::Error::from_raw_os_error(24)),
+            AcceptError::Transient
+        );
+        assert_eq!(
+            AcceptError::classify(&io::ErrorKind::InvalidInput.into()),
+            AcceptError::Fatal
+        );
+
+        // the server carries on past errors that aren't fatal
+        let (client_stream, server_stream) = tokio::io::duplex(1024);
+        let listener = ScriptedListener {
+            accepts: VecDeque::from([
+                Err(io::Error::from_raw_os_error(24)),
+                Err(io::Error::from_raw_os_error(24)),
+                Err(io::ErrorKind::ConnectionAborted.into()),
+                Ok(server_stream),
+            ]),
+        };
+        let server_state = Arc::new(Mutex::new(ServerState::new()));
+        let server = tokio::spawn(accept_loop(
+            server_state.clone(),
+            listener,
+            ConnectionConfig::default(),
+        ));
+        let mut framed = Framed::new(client_stream, LinesCodec::new());
+        assert_eq!(recv(&mut framed).await, "CONNECTED");
+        framed.send("NAME @robert").await.unwrap();
+        assert_eq!(recv(&mut framed).await, "REGISTERED");
+        assert!(!server.is_finished());
+        server.abort();
+
+        // but not past a broken listener
+        let listener = ScriptedListener {
+            accepts: VecDeque::from([Err(io::ErrorKind::InvalidInput.into())]),
+        };
+        assert!(
+            accept_loop(server_state, listener, ConnectionConfig::default())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_client_connection_keepalive_during_pipelined_flood() {
+        let server_state = Arc::new(Mutex::new(ServerState::new()));
+        let (client_stream, server_stream) = tokio::io::duplex(64 * 1024);
+        let socket_addr = "127.0.0.1:5456".parse().unwrap();
+        let client = ClientConn::new(
+            server_stream,
+            socket_addr,
+            ConnectionConfig::default(),
+            Arc::new(CommandCounts::new()),
+        );
+        let ping = client.ppt.sender.clone();
+        tokio::spawn(run_client(server_state.clone(), client));
+
+        let mut framed = Framed::new(client_stream, LinesCodec::new());
+        assert_eq!(recv(&mut framed).await, "CONNECTED");
+        framed.send("NAME @robert").await.unwrap();
+        assert_eq!(recv(&mut framed).await, "REGISTERED");
+        framed.send("JOIN #solo").await.unwrap();
+
+        // thousands of lines in one write, none of which are answered, then one that is
+        let mut flood = "SAY #solo flood\n".repeat(2000);
+        flood.push_str("TIME\n");
+        tokio::io::AsyncWriteExt::write_all(framed.get_mut(), flood.as_bytes())
+            .await
+            .unwrap();
+        ping.send(PingPongBall::SendPing).unwrap();
+
+        // reading comes first in the session loop, so only the burst limit lets the keep-alive check have its
+        // turn before the flood is worked through
+        assert_eq!(recv(&mut framed).await, "PING");
+        assert!(recv(&mut framed).await.starts_with("TIME "));
+    }
+
+    #[tokio::test]
+    async fn test_client_conn_read_ahead() {
+        let (client_stream, server_stream) = tokio::io::duplex(64 * 1024);
+        let socket_addr = "127.0.0.1:5456".parse().unwrap();
+        let mut client = ClientConn::new(
+            server_stream,
+            socket_addr,
+            ConnectionConfig::default(),
+            Arc::new(CommandCounts::new()),
+        );
+        let mut framed = Framed::new(client_stream, LinesCodec::new());
+        let flood = "SAY #solo flood\n".repeat(2000);
+        tokio::io::AsyncWriteExt::write_all(framed.get_mut(), flood.as_bytes())
+            .await
+            .unwrap();
+
+        // handling one line only pulls a bounded part of the flood off the stream
+        client_action(
+            &mut client.framed,
+            &client.config.parser,
+            &mut client.stats,
+            &client.command_counts,
+        )
+        .await
+        .unwrap();
+        assert!(client.framed.read_buffer().len() <= READ_AHEAD);
+    }
+
+    #[tokio::test]
+    async fn test_client_connection_unknown_command_notice() {
+        let server_state = Arc::new(Mutex::new(ServerState::new()));
+        let config = ConnectionConfig {
+            unknown_command_notice: true,
+            ..ConnectionConfig::default()
+        };
+        let mut framed = connect_with(server_state.clone(), config);
+        assert_eq!(recv(&mut framed).await, "CONNECTED");
+        // before registration too, since that's when people typing by hand are most lost
+        framed.send("HELLO").await.unwrap();
+        let notice = recv(&mut framed).await;
+        assert!(notice.starts_with("NOTICE unknown command"), "{}", notice);
+        framed.send("NAME @robert").await.unwrap();
+        assert_eq!(recv(&mut framed).await, "REGISTERED");
+
+        // empty lines are still ignored
+        framed.send("").await.unwrap();
+        framed.send("join #sports").await.unwrap();
+        assert_eq!(recv(&mut framed).await, notice);
+
+        // answered unknown commands count as malformed, so they can't be used to flood the client
+        let config = ConnectionConfig {
+            malformed_limit: 1,
+            ..config
+        };
+        let mut framed = connect_with(server_state.clone(), config);
+        assert_eq!(recv(&mut framed).await, "CONNECTED");
+        framed.send("HELLO").await.unwrap();
+        assert_eq!(recv(&mut framed).await, notice);
+        framed.send("HELLO").await.unwrap();
+        assert_eq!(
+            recv(&mut framed).await,
+            "NOTICE too many malformed commands"
+        );
+        assert!(framed.next().await.is_none());
+
+        // and off by default, when ignored unknown commands don't count
+        let config = ConnectionConfig {
+            malformed_limit: 1,
+            ..ConnectionConfig::default()
+        };
+        let mut framed = connect_with(server_state.clone(), config);
+        assert_eq!(recv(&mut framed).await, "CONNECTED");
+        framed.send("HELLO").await.unwrap();
+        framed.send("HELLO").await.unwrap();
+        framed.send("TIME").await.unwrap();
+        assert!(recv(&mut framed).await.starts_with("TIME "));
+    }
+
+    #[tokio::test]
+    async fn test_pong_after_keepalive_channel_closed() {
+        let (_client_stream, server_stream) = tokio::io::duplex(64);
+        let socket_addr = "127.0.0.1:5456".parse().unwrap();
+        let mut client = ClientConn::new(
+            server_stream,
+            socket_addr,
+            ConnectionConfig::default(),
+            Arc::new(CommandCounts::new()),
+        );
+        client.ppt.worker.take().unwrap().abort();
+        client.ppt.receiver.close();
+
+        let pong = Ok(ClientAction::Parsed(ParsedAction::Process(
+            IncomingMsg::Pong,
+        )));
+        let step = client.handle_client_action(pong, "client_connection").await;
+        assert!(matches!(step, Ok(Step::Handled)));
+    }
+
+    #[tokio::test]
+    async fn test_client_connection_echotest() {
+        let server_state = Arc::new(Mutex::new(ServerState::new()));
+        let mut framed = connect(server_state);
+        assert_eq!(recv(&mut framed).await, "CONNECTED");
+
+        // a monitor doesn't need a name
+        framed.send("ECHOTEST probe-42").await.unwrap();
+        assert_eq!(recv(&mut framed).await, "ECHOTEST probe-42");
+        framed.send("NAME @robert").await.unwrap();
+        assert_eq!(recv(&mut framed).await, "REGISTERED");
+        framed.send("ECHOTEST Zz_9!").await.unwrap();
+        assert_eq!(recv(&mut framed).await, "ECHOTEST Zz_9!");
+    }
+
+    #[tokio::test]
+    async fn test_client_connection_compress() {
+        let server_state = Arc::new(Mutex::new(ServerState::new()));
+        let mut kelsey = connect(server_state.clone());
+        assert_eq!(recv(&mut kelsey).await, "CONNECTED");
+        kelsey.send("NAME @kelsey").await.unwrap();
+        assert_eq!(recv(&mut kelsey).await, "REGISTERED");
+        kelsey.send("COMPRESS").await.unwrap();
+        assert_eq!(recv(&mut kelsey).await, "ERROR compression unavailable");
+
+        let config = ConnectionConfig {
+            compression: true,
+            ..ConnectionConfig::default()
+        };
+        let (client_stream, server_stream) = tokio::io::duplex(4096);
+        let socket_addr = "127.0.0.1:5456".parse().unwrap();
+        tokio::spawn(client_connection(
+            server_state.clone(),
+            server_stream,
+            socket_addr,
+            config,
+        ));
+        let mut robert = Framed::new(
+            client_stream,
+            ChatCodec::new_with_max_length(MAX_LINE_LENGTH),
+        );
+        assert_eq!(robert.next().await.unwrap().unwrap(), "CONNECTED");
+        // a connection has to register before it can be compressed
+        robert.send("COMPRESS").await.unwrap();
+        assert_eq!(
+            robert.next().await.unwrap().unwrap(),
+            "ERROR not registered"
+        );
+        robert.send("NAME @robert").await.unwrap();
+        assert_eq!(robert.next().await.unwrap().unwrap(), "REGISTERED");
+        robert.send("COMPRESS").await.unwrap();
+        assert_eq!(robert.next().await.unwrap().unwrap(), "COMPRESS");
+        robert.codec_mut().compress();
+
+        // both directions are compressed from here on
+        robert.send("COMPRESS").await.unwrap();
+        robert.send("SAY @kelsey hi").await.unwrap();
+        assert_eq!(robert.next().await.unwrap().unwrap(), "DELIVERED @kelsey");
+        assert_eq!(recv(&mut kelsey).await, "@robert SAID hi");
+        kelsey.send("SAY @robert hello").await.unwrap();
+        assert_eq!(recv(&mut kelsey).await, "DELIVERED @robert");
+        assert_eq!(robert.next().await.unwrap().unwrap(), "@kelsey SAID hello");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_client_connection_ghost_probe() {
+        let server_state = Arc::new(Mutex::new(ServerState::new()));
+        server_state
+            .lock()
+            .await
+            .set_name_lock(Duration::from_secs(60));
+        let config = ConnectionConfig {
+            ghost_probe: Some(Duration::from_millis(50)),
+            ..ConnectionConfig::default()
+        };
+
+        // @robert's client crashes without a QUIT and never reads or writes again
+        let mut ghost = connect_with(server_state.clone(), config);
+        assert_eq!(recv(&mut ghost).await, "CONNECTED");
+        ghost.send("NAME @robert").await.unwrap();
+        assert_eq!(recv(&mut ghost).await, "REGISTERED");
+        ghost.send("JOIN #sports").await.unwrap();
+        ghost.send("ROOMS").await.unwrap();
+        assert_eq!(recv(&mut ghost).await, "ROOM #sports");
+
+        let mut kelsey = connect_with(server_state.clone(), config);
+        assert_eq!(recv(&mut kelsey).await, "CONNECTED");
+        kelsey.send("NAME @kelsey").await.unwrap();
+        assert_eq!(recv(&mut kelsey).await, "REGISTERED");
+        kelsey.send("JOIN #sports").await.unwrap();
+        assert_eq!(recv(&mut ghost).await, "#sports @kelsey JOINED");
+
+        // a holder heard from within the keep-alive interval isn't probed at all
+        let mut impostor = connect_with(server_state.clone(), config);
+        assert_eq!(recv(&mut impostor).await, "CONNECTED");
+        impostor.send("NAME @kelsey").await.unwrap();
+        assert_eq!(
+            recv(&mut impostor).await,
+            "ERROR user already exists @kelsey"
+        );
+
+        // neither client is heard from for a keep-alive interval
+        tokio::time::advance(KEEPALIVE_INTERVAL).await;
+
+        // @kelsey answers the probe, so nobody can take their name
+        let mut impostor = connect_with(server_state.clone(), config);
+        assert_eq!(recv(&mut impostor).await, "CONNECTED");
+        impostor.send("NAME @kelsey").await.unwrap();
+        assert_eq!(recv(&mut kelsey).await, "PING");
+        kelsey.send("PONG").await.unwrap();
+        assert_eq!(
+            recv(&mut impostor).await,
+            "ERROR user already exists @kelsey"
+        );
+
+        // robert reconnects and the ghost is reaped in favor of the new connection, name lock or not
+        let mut robert = connect_with(server_state.clone(), config);
+        assert_eq!(recv(&mut robert).await, "CONNECTED");
+        robert.send("NAME @robert").await.unwrap();
+        assert_eq!(recv(&mut robert).await, "REGISTERED");
+        assert_eq!(recv(&mut ghost).await, "PING");
+        assert_eq!(recv(&mut ghost).await, "DISCONNECT no answer to PING");
+        assert!(ghost.next().await.is_none());
+
+        // the ghost left its rooms, and the name belongs to the new connection
+        assert_eq!(recv(&mut kelsey).await, "#sports @robert LEFT");
+        robert.send("JOIN #sports").await.unwrap();
+        assert_eq!(recv(&mut kelsey).await, "#sports @robert JOINED");
+    }
 }
@@ -1,8 +1,36 @@
 //! The main state of the server.
 //!
-use crate::messages::{Message, OutgoingMsg};
-use std::collections::{HashMap, HashSet};
-use tokio::sync::mpsc::UnboundedSender;
+use crate::{
+    command::CommandCounts,
+    config::ServerConfig,
+    messages::{Message, OutgoingMsg, RoomFilter, RoomMode},
+    store::{FileStore, MemoryStore, Store},
+};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fs,
+    net::IpAddr,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{sync::mpsc::UnboundedSender, time::Instant};
+use unicode_normalization::UnicodeNormalization;
+
+/// The name that messages from the server itself, such as announcements, are sent under. It is always
+/// reserved, so no user can pass for the server.
+pub const SYSTEM_NAME: &str = "@server";
+
+/// How many topic changes each room remembers for TOPICHISTORY.
+pub const TOPIC_HISTORY_SIZE: usize = 10;
+
+/// Most names one user may have on their buddy list, so that a client can't make the server watch names
+/// without end.
+pub const MAX_BUDDIES: usize = 100;
+
+/// How many messages relayed from linked servers each room remembers the local number of, so that a peer's
+/// later EDITED and REMOVED events can be numbered to match.
+const RELAYED_SEQS_SIZE: usize = 100;
 
 /// [User] stores the asyncronous channel that allows messages to go to a connected client and
 /// it stores which rooms a user has joined.
@@ -10,6 +38,35 @@ use tokio::sync::mpsc::UnboundedSender;
 pub struct User {
     sender: UnboundedSender<OutgoingMsg>,
     rooms: HashSet<String>,
+    /// Free-form presence text such as "busy". Purely informational.
+    status: Option<String>,
+    /// When the user last sent the server a command.
+    last_seen: Instant,
+    /// When the user's client last answered a PING. Unlike `last_seen` this isn't activity, only proof that
+    /// the connection is still there.
+    last_pong: Option<Instant>,
+    /// When the user registered, which starts their session.
+    registered_at: Instant,
+    /// When the user last changed their name, if they have since registering.
+    last_rename: Option<Instant>,
+    /// Room and private messages the user has sent this session.
+    messages_sent: u64,
+    /// Tell the user whenever someone comes online or a room is created or deleted.
+    watching: bool,
+    /// Send the user a copy of each of their own room messages as it goes out.
+    echo_room: bool,
+    /// Precede each private message to the user with the rooms they share with its sender.
+    context: bool,
+    /// Do not disturb. Private messages are refused unless they are urgent and the server lets urgent
+    /// messages through.
+    dnd: bool,
+    /// Server operator, allowed to use admin commands such as KILL.
+    oper: bool,
+    /// How many history messages to replay when the user joins a room, set with HISTLEN. `None` means as
+    /// many as the server keeps.
+    history_length: Option<usize>,
+    /// The connection the user registered on. Only that connection closing removes the user.
+    connection_id: u64,
 }
 
 impl User {
@@ -17,9 +74,28 @@ impl User {
         Self {
             sender,
             rooms: HashSet::new(),
+            status: None,
+            last_seen: Instant::now(),
+            last_pong: None,
+            registered_at: Instant::now(),
+            last_rename: None,
+            messages_sent: 0,
+            watching: false,
+            echo_room: false,
+            context: false,
+            dnd: false,
+            oper: false,
+            history_length: None,
+            connection_id: 0,
         }
     }
 
+    /// Tie the user to the connection they registered on.
+    pub fn on_connection(mut self, connection_id: u64) -> Self {
+        self.connection_id = connection_id;
+        self
+    }
+
     pub fn add_room(&mut self, name: String) {
         self.rooms.insert(name);
     }
@@ -28,6 +104,8 @@ impl User {
         self.rooms.remove(name);
     }
 
+    /// Queue a message for the user's connection. This only fails once the connection is closing, and the
+    /// user is about to be removed anyway, so most callers ignore the error.
     pub fn send(&self, message: OutgoingMsg) -> Result<(), String> {
         match self.sender.send(message) {
             Ok(_) => Ok(()),
@@ -36,16 +114,86 @@ impl User {
     }
 }
 
-/// A [Room] stores a list of users that have joined the room.
+/// A [Room] stores a list of users that have joined the room. The user that creates a room owns it. When
+/// the owner leaves, ownership passes to the remaining user that sorts first.
 #[derive(Debug, PartialEq)]
 struct Room {
     users: HashSet<String>,
+    owner: Option<String>,
+    muted: HashSet<String>,
+    /// Only the owner may speak in the room. Everyone else can still read it.
+    readonly: bool,
+    /// Nobody may join the room, so only its current members stay in it.
+    invite_only: bool,
+    /// Users the owner invited, who may join once even while the room is invite-only.
+    invited: HashSet<String>,
+    /// Only the owner may change the topic.
+    topic_locked: bool,
+    /// Send the owner a MEMBER message for every join and leave. Turned off when ownership passes on.
+    owner_notices: bool,
+    /// Number of the last message said in the room, so clients can spot messages they missed. It starts over
+    /// whenever the room is created again, and carries on from the stored history when the room is restored.
+    seq: u64,
+    /// When the last message was said in the room, or when it was created or unarchived if nothing has
+    /// been said since.
+    last_message: Instant,
+    /// Silent for too long. Nobody may speak in or join the room until its owner unarchives it.
+    archived: bool,
+    /// The room's latest topics, oldest first. The last one is the current topic.
+    topics: VecDeque<TopicChange>,
+    /// The latest messages relayed from linked servers, oldest first, as the server they came from, their
+    /// number there, and their number here.
+    relayed_seqs: VecDeque<(String, u64, u64)>,
+    /// The room's latest messages as protocol lines, oldest first, replayed to users as they join. Written to
+    /// the [Store] by [ServerState::flush_history].
+    history: Vec<String>,
+}
+
+/// A topic set on a room, and who set it when.
+#[derive(Debug, Clone, PartialEq)]
+struct TopicChange {
+    set_at: SystemTime,
+    setter: String,
+    topic: String,
 }
 
 impl Room {
-    pub fn new() -> Self {
+    pub fn new(owner: String) -> Self {
+        Self {
+            users: HashSet::from([owner.clone()]),
+            owner: Some(owner),
+            muted: HashSet::new(),
+            readonly: false,
+            invite_only: false,
+            invited: HashSet::new(),
+            topic_locked: false,
+            owner_notices: false,
+            seq: 0,
+            last_message: Instant::now(),
+            archived: false,
+            topics: VecDeque::new(),
+            relayed_seqs: VecDeque::new(),
+            history: Vec::new(),
+        }
+    }
+
+    /// A room restored from a [Store]. Nobody is in it yet, so the first user to join owns it.
+    pub fn restored() -> Self {
         Self {
             users: HashSet::new(),
+            owner: None,
+            muted: HashSet::new(),
+            readonly: false,
+            invite_only: false,
+            invited: HashSet::new(),
+            topic_locked: false,
+            owner_notices: false,
+            seq: 0,
+            last_message: Instant::now(),
+            archived: false,
+            topics: VecDeque::new(),
+            relayed_seqs: VecDeque::new(),
+            history: Vec::new(),
         }
     }
 
@@ -53,8 +201,65 @@ impl Room {
         self.users.insert(name);
     }
 
+    /// Number the next message said in the room.
+    pub fn next_seq(&mut self) -> u64 {
+        self.last_message = Instant::now();
+        self.seq += 1;
+        self.seq
+    }
+
+    /// Number a message relayed from `origin`, where it was numbered `seq`, and remember both numbers.
+    fn next_relayed_seq(&mut self, origin: &str, seq: u64) -> u64 {
+        let local_seq = self.next_seq();
+        if self.relayed_seqs.len() == RELAYED_SEQS_SIZE {
+            self.relayed_seqs.pop_front();
+        }
+        self.relayed_seqs
+            .push_back((origin.to_string(), seq, local_seq));
+        local_seq
+    }
+
+    /// The local number of message `seq` relayed from `origin`, if the room still remembers it.
+    fn relayed_seq(&self, origin: &str, seq: u64) -> Option<u64> {
+        self.relayed_seqs
+            .iter()
+            .find(|(relayed_origin, relayed_seq, _)| {
+                relayed_origin == origin && *relayed_seq == seq
+            })
+            .map(|(_, _, local_seq)| *local_seq)
+    }
+
     pub fn remove_user(&mut self, name: &str) -> bool {
-        self.users.remove(name)
+        let removed = self.users.remove(name);
+        if removed {
+            self.muted.remove(name);
+            if self.is_owner(name) {
+                self.owner = self.users.iter().min().cloned();
+                self.owner_notices = false;
+            }
+        }
+        removed
+    }
+
+    /// Swap a user's old name for their new name everywhere the room refers to them. The new name goes in
+    /// before the old one comes out, so the room never has the user under neither name.
+    pub fn rename_user(&mut self, old_name: &str, new_name: &str) {
+        if old_name == new_name {
+            return;
+        }
+        swap_name(&mut self.users, old_name, new_name);
+        swap_name(&mut self.muted, old_name, new_name);
+        if self.is_owner(old_name) {
+            self.owner = Some(new_name.to_string());
+        }
+    }
+
+    pub fn is_owner(&self, name: &str) -> bool {
+        self.owner.as_deref() == Some(name)
+    }
+
+    pub fn is_muted(&self, name: &str) -> bool {
+        self.muted.contains(name)
     }
 
     pub fn is_empty(&self) -> bool {
@@ -62,14 +267,118 @@ impl Room {
     }
 }
 
+/// A room or private message kept for operators to review.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoggedMessage {
+    pub sent_at: SystemTime,
+    /// The room or user the message was sent to.
+    pub to: String,
+    pub from: String,
+    pub message: String,
+}
+
+impl LoggedMessage {
+    fn review(&self) -> OutgoingMsg {
+        let sent_at = self
+            .sent_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        OutgoingMsg::Review(
+            sent_at,
+            self.to.clone(),
+            self.from.clone(),
+            self.message.clone(),
+        )
+    }
+}
+
+/// A copy of one room's record, taken at a single point in time.
+#[derive(Debug, PartialEq)]
+pub struct RoomSnapshot {
+    pub name: String,
+    /// The current topic, if one has been set.
+    pub topic: Option<String>,
+    /// Members sorted by name.
+    pub members: Vec<String>,
+    pub owner: Option<String>,
+    /// Muted members sorted by name.
+    pub muted: Vec<String>,
+    pub member_count: usize,
+    pub readonly: bool,
+    pub invite_only: bool,
+    pub archived: bool,
+}
+
+/// Put a user name into Unicode normalization form C so that names which look the same but are composed
+/// differently are treated as the same name.
+pub fn normalize_name(name: &str) -> String {
+    name.nfc().collect()
+}
+
+/// Replace `old_name` with `new_name` in a set of names, if it is there.
+fn swap_name(names: &mut HashSet<String>, old_name: &str, new_name: &str) {
+    if names.contains(old_name) {
+        names.insert(new_name.to_string());
+        names.remove(old_name);
+    }
+}
+
+/// Who a message to a room reaches.
+#[derive(Debug, PartialEq)]
+pub enum RoomDelivery {
+    /// The message reaches these users, sorted by name.
+    Recipients(Vec<String>),
+    /// The sender is muted in the room so the message reaches no one.
+    Muted,
+}
+
+impl std::fmt::Display for RoomDelivery {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Recipients(recipients) => write!(f, "{} recipients", recipients.len()),
+            Self::Muted => write!(f, "0 recipients muted"),
+        }
+    }
+}
+
 /// Errors that happen as a result of the server trying to process a client message.
 #[derive(Debug, PartialEq)]
 pub enum ServerError {
     RoomUnknown(String),
     UserAlreadyExists(String),
+    RoomAlreadyExists(String),
+    // UserGone(<user-name>) - the user's connection closed before it removed them
+    UserGone(String),
+    CompressionUnavailable,
+    // TooManyRooms(<limit>)
+    TooManyRooms(usize),
+    // TooManyBuddies(<limit>)
+    TooManyBuddies(usize),
     // UserNotInRoom(<user-name>, <room-name>)
     UserNotInRoom(String, String),
+    // UserAlreadyInRoom(<user-name>, <room-name>)
+    UserAlreadyInRoom(String, String),
     UserUnknown(String),
+    NotRoomOwner(String),
+    // CannotMuteSelf(<room-name>) - the owner tried to mute themselves
+    CannotMuteSelf(String),
+    NameReserved(String),
+    UserDoNotDisturb(String),
+    NotOperator(String),
+    RoomReadOnly(String),
+    RoomArchived(String),
+    RoomInviteOnly(String),
+    // LinkRefused - the connection's address isn't one of the peers allowed to LINK
+    LinkRefused,
+    /// The command needs a registered name, and the client hasn't sent NAME yet.
+    NotRegistered,
+    // NameCoolingDown(<user-name>, <seconds-left>)
+    NameCoolingDown(String, u64),
+    // MessageUnknown(<room-name>, <seq>)
+    MessageUnknown(String, u64),
+    // NotMessageSender(<room-name>, <seq>)
+    NotMessageSender(String, u64),
 }
 
 impl std::fmt::Display for ServerError {
@@ -77,10 +386,37 @@ impl std::fmt::Display for ServerError {
         match self {
             Self::RoomUnknown(name) => write!(f, "ERROR room unknown {}", name),
             Self::UserAlreadyExists(name) => write!(f, "ERROR user already exists {}", name),
+            Self::RoomAlreadyExists(name) => write!(f, "ERROR room already exists {}", name),
+            Self::UserGone(name) => write!(f, "ERROR user gone {}", name),
+            Self::CompressionUnavailable => write!(f, "ERROR compression unavailable"),
+            Self::TooManyRooms(limit) => write!(f, "ERROR too many rooms {}", limit),
+            Self::TooManyBuddies(limit) => write!(f, "ERROR too many buddies {}", limit),
             Self::UserNotInRoom(user_name, room_name) => {
                 write!(f, "ERROR user not in room {} {}", user_name, room_name)
             }
+            Self::UserAlreadyInRoom(user_name, room_name) => {
+                write!(f, "ERROR user already in room {} {}", user_name, room_name)
+            }
             Self::UserUnknown(name) => write!(f, "ERROR user unknown {}", name),
+            Self::NotRoomOwner(name) => write!(f, "ERROR not room owner {}", name),
+            Self::CannotMuteSelf(name) => write!(f, "ERROR cannot mute self {}", name),
+            Self::NameReserved(name) => write!(f, "ERROR name reserved {}", name),
+            Self::UserDoNotDisturb(name) => write!(f, "ERROR user do not disturb {}", name),
+            Self::NotOperator(name) => write!(f, "ERROR not operator {}", name),
+            Self::RoomReadOnly(name) => write!(f, "ERROR room read only {}", name),
+            Self::RoomArchived(name) => write!(f, "ERROR room archived {}", name),
+            Self::RoomInviteOnly(name) => write!(f, "ERROR room invite only {}", name),
+            Self::LinkRefused => write!(f, "ERROR link refused"),
+            Self::NotRegistered => write!(f, "ERROR not registered"),
+            Self::NameCoolingDown(name, seconds) => {
+                write!(f, "ERROR name cooling down {} {}", name, seconds)
+            }
+            Self::MessageUnknown(room_name, seq) => {
+                write!(f, "ERROR message unknown {} {}", room_name, seq)
+            }
+            Self::NotMessageSender(room_name, seq) => {
+                write!(f, "ERROR not message sender {} {}", room_name, seq)
+            }
         }
     }
 }
@@ -94,62 +430,746 @@ impl Message for ServerError {}
 pub struct ServerState {
     users: HashMap<String, User>,
     rooms: HashMap<String, Room>,
+    /// Keep rooms around after their last user leaves.
+    persistent_rooms: bool,
+    /// Most rooms one user may be in at once. `None` means no limit.
+    max_rooms_per_user: Option<usize>,
+    /// Only operators create rooms, with CREATE. JOIN never does, and rooms are kept when they empty.
+    closed_rooms: bool,
+    /// Peer servers that receive a copy of every event in a room, keyed by room name.
+    links: HashMap<String, Vec<UnboundedSender<OutgoingMsg>>>,
+    /// Addresses that peer servers may LINK from. Nobody may link when it is empty.
+    link_peers: Vec<IpAddr>,
+    /// Tags events relayed to peer servers.
+    server_name: String,
+    /// Where persistent rooms are saved.
+    store: Box<dyn Store>,
+    /// Tell the sender of a private message which rooms they share with the recipient.
+    shared_rooms_on_delivery: bool,
+    /// Deliver urgent private messages to users that have do not disturb on.
+    urgent_bypasses_dnd: bool,
+    /// Files that [ServerState::reload] reads the MOTD and reserved names from.
+    motd_file: Option<PathBuf>,
+    reserved_names_file: Option<PathBuf>,
+    /// Message of the day, sent to every client once they register.
+    motd: Vec<String>,
+    /// Names no client may register or rename to.
+    reserved_names: HashSet<String>,
+    /// Connections that haven't registered yet, and how many of them are allowed at once.
+    pending_registrations: usize,
+    max_pending_registrations: Option<usize>,
+    /// Rooms every user joins as soon as they register.
+    auto_join: Vec<String>,
+    /// Password that OPER takes to make a user an operator. Nobody can become one without it.
+    oper_password: Option<String>,
+    /// The most recent messages server-wide, oldest first, for REVIEW. Nothing is kept when the size is 0.
+    review_log: VecDeque<LoggedMessage>,
+    review_log_size: usize,
+    /// Most messages kept in each room's history in `store` and replayed to users as they join. Nothing
+    /// is kept when the size is 0.
+    history_size: usize,
+    /// How long a user has to wait after changing their name before they can change it again.
+    rename_cooldown: Duration,
+    /// How long the name of a user who disconnects stays locked, so that nobody can take it right away to
+    /// pass for them.
+    name_lock: Duration,
+    /// When each locked name was freed. Entries are dropped once their lock runs out.
+    recently_released: HashMap<String, Instant>,
+    /// Rooms whose history has changed since it was last written to `store`.
+    unsaved_history: HashSet<String>,
+    /// When each name's holder was last PINGed to find out whether it is a ghost, so that a flood of NAME
+    /// commands can't have the holder PINGed over and over.
+    ghost_probes: HashMap<String, Instant>,
+    /// The users on each name's buddy list, keyed by the name they watch. Nobody has to be online under
+    /// a name for it to be watched.
+    buddies: HashMap<String, HashSet<String>>,
+    /// How long a room may go without a message before it is archived. `None` never archives rooms.
+    archive_after: Option<Duration>,
+    /// Every command received by every connection, for PROTOSTATS.
+    command_counts: Arc<CommandCounts>,
 }
 
 impl ServerState {
+    /// A server state with every server-wide setting in `config` applied. Fails if the store, MOTD, or
+    /// reserved names file can't be read.
+    pub fn with_config(config: &ServerConfig) -> anyhow::Result<Self> {
+        let mut state = Self::new();
+        state.set_server_name(config.server_name.clone());
+        state.set_persistent_rooms(config.persistent_rooms);
+        state.set_closed_rooms(config.closed_rooms);
+        state.set_max_rooms_per_user(config.max_rooms_per_user);
+        state.set_shared_rooms_on_delivery(config.shared_rooms_on_delivery);
+        state.set_urgent_bypasses_dnd(config.urgent_bypasses_dnd);
+        state.set_max_pending_registrations(config.max_pending_registrations);
+        state.set_auto_join(config.auto_join.clone());
+        state.set_oper_password(config.oper_password.clone());
+        state.set_review_log_size(config.review_log_size);
+        state.set_history_size(config.history_size);
+        state.set_rename_cooldown(config.rename_cooldown);
+        state.set_name_lock(config.name_lock);
+        state.set_archive_after(config.archive_after);
+        state.set_link_peers(config.link_peers.clone());
+        if let Some(store) = &config.store {
+            state.set_store(Box::new(FileStore::open(store)?))?;
+        }
+        state.set_reloadable_files(config.motd.clone(), config.reserved_names.clone())?;
+        Ok(state)
+    }
+
     pub fn new() -> Self {
         Self {
             users: HashMap::new(),
             rooms: HashMap::new(),
+            persistent_rooms: false,
+            closed_rooms: false,
+            max_rooms_per_user: None,
+            links: HashMap::new(),
+            link_peers: vec![],
+            server_name: "chat-server".to_string(),
+            store: Box::new(MemoryStore::default()),
+            shared_rooms_on_delivery: false,
+            urgent_bypasses_dnd: false,
+            motd_file: None,
+            reserved_names_file: None,
+            motd: vec![],
+            reserved_names: HashSet::new(),
+            pending_registrations: 0,
+            max_pending_registrations: None,
+            auto_join: vec![],
+            oper_password: None,
+            review_log: VecDeque::new(),
+            review_log_size: 0,
+            history_size: 0,
+            rename_cooldown: Duration::ZERO,
+            name_lock: Duration::ZERO,
+            recently_released: HashMap::new(),
+            ghost_probes: HashMap::new(),
+            unsaved_history: HashSet::new(),
+            buddies: HashMap::new(),
+            archive_after: None,
+            command_counts: Arc::new(CommandCounts::new()),
+        }
+    }
+
+    pub fn server_name(&self) -> &str {
+        &self.server_name
+    }
+
+    pub fn set_server_name(&mut self, server_name: String) {
+        self.server_name = server_name;
+    }
+
+    /// Choose whether empty rooms are kept (`true`) or deleted (`false`, the default).
+    pub fn set_persistent_rooms(&mut self, persistent_rooms: bool) {
+        self.persistent_rooms = persistent_rooms;
+    }
+
+    /// Choose whether only operators can create rooms (`true`) or joining a room that doesn't exist creates
+    /// it (`false`, the default).
+    pub fn set_closed_rooms(&mut self, closed_rooms: bool) {
+        self.closed_rooms = closed_rooms;
+    }
+
+    /// Limit how many rooms one user may be in at once. `None`, the default, means no limit.
+    pub fn set_max_rooms_per_user(&mut self, max_rooms_per_user: Option<usize>) {
+        self.max_rooms_per_user = max_rooms_per_user;
+    }
+
+    pub fn max_rooms_per_user(&self) -> Option<usize> {
+        self.max_rooms_per_user
+    }
+
+    /// Choose whether DELIVERED lists the rooms the sender and recipient have in common (`true`) or not
+    /// (`false`, the default).
+    pub fn set_shared_rooms_on_delivery(&mut self, shared_rooms_on_delivery: bool) {
+        self.shared_rooms_on_delivery = shared_rooms_on_delivery;
+    }
+
+    /// Choose whether urgent private messages reach users with do not disturb on (`true`) or not (`false`,
+    /// the default).
+    pub fn set_urgent_bypasses_dnd(&mut self, urgent_bypasses_dnd: bool) {
+        self.urgent_bypasses_dnd = urgent_bypasses_dnd;
+    }
+
+    /// Limit how many connections can be registering at once. `None`, the default, means no limit.
+    pub fn set_max_pending_registrations(&mut self, max_pending_registrations: Option<usize>) {
+        self.max_pending_registrations = max_pending_registrations;
+    }
+
+    /// Choose the rooms every user joins as soon as they register. None, the default, means users start
+    /// out in no rooms.
+    pub fn set_auto_join(&mut self, auto_join: Vec<String>) {
+        self.auto_join = auto_join;
+    }
+
+    /// Join a newly registered user to every auto-join room, as if they had sent JOIN for each. The user
+    /// is sent their own JOINED for each room so they know where they landed.
+    pub fn auto_join(&mut self, user_name: &str) -> Result<(), ServerError> {
+        for room_name in self.auto_join.clone() {
+            self.join_room(room_name.clone(), user_name.to_string())?;
+            if let Some(user) = self.users.get(user_name) {
+                let _ = user.send(OutgoingMsg::Joined(room_name, user_name.to_string()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Set the password that makes a user an operator. `None`, the default, means nobody can be one.
+    pub fn set_oper_password(&mut self, oper_password: Option<String>) {
+        self.oper_password = oper_password;
+    }
+
+    /// Keep the last `review_log_size` room and private messages for operators to review. 0, the default,
+    /// keeps none.
+    pub fn set_review_log_size(&mut self, review_log_size: usize) {
+        self.review_log_size = review_log_size;
+        while self.review_log.len() > review_log_size {
+            self.review_log.pop_front();
+        }
+    }
+
+    /// Keep the last `history_size` messages of each room and replay them to users as they join. 0, the
+    /// default, keeps none.
+    pub fn set_history_size(&mut self, history_size: usize) {
+        self.history_size = history_size;
+    }
+
+    pub fn history_size(&self) -> usize {
+        self.history_size
+    }
+
+    /// Set how many history messages a user wants replayed as they join a room. The count is clamped to
+    /// [ServerState::history_size], and the clamped count is returned.
+    pub fn set_history_length(&mut self, name: &str, count: usize) -> Result<usize, ServerError> {
+        let history_size = self.history_size;
+        let user = self
+            .users
+            .get_mut(name)
+            .ok_or_else(|| ServerError::UserUnknown(name.to_string()))?;
+        let count = count.min(history_size);
+        user.history_length = Some(count);
+        Ok(count)
+    }
+
+    /// Add a message to the end of a room's history, dropping the oldest messages over the limit.
+    fn record_history(&mut self, room_name: &str, message: &OutgoingMsg) {
+        if self.history_size == 0 {
+            return;
+        }
+        if let Some(room) = self.rooms.get_mut(room_name) {
+            room.history.push(message.to_string());
+            let excess = room.history.len().saturating_sub(self.history_size);
+            room.history.drain(..excess);
+            self.unsaved_history.insert(room_name.to_string());
+        }
+    }
+
+    /// Write the history of every room whose history changed since the last flush to the store. History is
+    /// kept in memory as messages are said, so saying one doesn't mean rewriting the store.
+    pub fn flush_history(&mut self) {
+        for room_name in std::mem::take(&mut self.unsaved_history) {
+            // a room that is gone takes its history with it
+            let history = self
+                .rooms
+                .get(&room_name)
+                .map(|room| room.history.as_slice())
+                .unwrap_or_default();
+            if let Err(e) = self.store.save_history(&room_name, history) {
+                log::error!("failed to save history of {}: {:?}", room_name, e);
+            }
+        }
+    }
+
+    /// Change the text of a message that is still in a room's history, and tell the room's members. Only the
+    /// message's sender may, and only if they could say it again: not while muted, nor in a read only room
+    /// they don't own.
+    pub fn edit_message(
+        &mut self,
+        user_name: &str,
+        room_name: &str,
+        seq: u64,
+        message: String,
+    ) -> Result<(), ServerError> {
+        if self.room_delivery(user_name, room_name)? == RoomDelivery::Muted {
+            if let Some(user) = self.users.get(user_name) {
+                let _ = user.send(OutgoingMsg::Notice(format!(
+                    "you are muted in {}",
+                    room_name
+                )));
+            }
+            return Ok(());
+        }
+        let (index, from) = self.history_entry(user_name, room_name, seq, false)?;
+        if let Some(room) = self.rooms.get_mut(room_name) {
+            room.history[index] =
+                OutgoingMsg::SaidRoom(room_name.to_string(), from, seq, message.clone())
+                    .to_string();
+            self.unsaved_history.insert(room_name.to_string());
+        }
+        self.log_message(
+            room_name,
+            user_name,
+            &format!("(edited {}) {}", seq, message),
+        );
+        let edited = OutgoingMsg::Edited(room_name.to_string(), seq, message);
+        self.send_to_room(room_name, edited.clone());
+        self.relay(room_name, edited);
+        Ok(())
+    }
+
+    /// Delete a message that is still in a room's history, and tell the room's members. The message's sender
+    /// and the room's owner may.
+    pub fn delete_message(
+        &mut self,
+        user_name: &str,
+        room_name: &str,
+        seq: u64,
+    ) -> Result<(), ServerError> {
+        let (index, _) = self.history_entry(user_name, room_name, seq, true)?;
+        if let Some(room) = self.rooms.get_mut(room_name) {
+            room.history.remove(index);
+            self.unsaved_history.insert(room_name.to_string());
+        }
+        self.log_message(room_name, user_name, &format!("(deleted {})", seq));
+        let removed = OutgoingMsg::Removed(room_name.to_string(), seq);
+        self.send_to_room(room_name, removed.clone());
+        self.relay(room_name, removed);
+        Ok(())
+    }
+
+    /// Find message `seq` in a room's history for a member who may change it: its sender, or the room's owner
+    /// when `owner_may`. Returns where the message is in the room's history and who sent it. Messages that
+    /// have fallen out of the history can't be found.
+    fn history_entry(
+        &self,
+        user_name: &str,
+        room_name: &str,
+        seq: u64,
+        owner_may: bool,
+    ) -> Result<(usize, String), ServerError> {
+        let room = self
+            .rooms
+            .get(room_name)
+            .ok_or_else(|| ServerError::RoomUnknown(room_name.to_string()))?;
+        if !room.users.contains(user_name) {
+            return Err(ServerError::UserNotInRoom(
+                user_name.to_string(),
+                room_name.to_string(),
+            ));
+        }
+        if room.archived {
+            return Err(ServerError::RoomArchived(room_name.to_string()));
+        }
+        // newest first, in case an older message with the same number is still in the history
+        let (index, from) = room
+            .history
+            .iter()
+            .enumerate()
+            .rev()
+            .find_map(|(index, line)| match OutgoingMsg::parse(line) {
+                Some(OutgoingMsg::SaidRoom(_, from, said_seq, _)) if said_seq == seq => {
+                    Some((index, from))
+                }
+                _ => None,
+            })
+            .ok_or_else(|| ServerError::MessageUnknown(room_name.to_string(), seq))?;
+        if from != user_name && !(owner_may && room.is_owner(user_name)) {
+            return Err(ServerError::NotMessageSender(room_name.to_string(), seq));
+        }
+        Ok((index, from))
+    }
+
+    /// Send a message to every member of a room.
+    fn send_to_room(&self, room_name: &str, message: OutgoingMsg) {
+        if let Some(room) = self.rooms.get(room_name) {
+            for room_user_name in &room.users {
+                if let Some(user) = self.users.get(room_user_name) {
+                    // a closed channel means the user is on their way out
+                    let _ = user.send(message.clone());
+                }
+            }
+        }
+    }
+
+    /// Send a user the end of a room's history, oldest first.
+    fn replay_history(&self, room_name: &str, user_name: &str) {
+        let Some(user) = self.users.get(user_name) else {
+            return;
+        };
+        let count = user
+            .history_length
+            .unwrap_or(self.history_size)
+            .min(self.history_size);
+        let Some(room) = self.rooms.get(room_name) else {
+            return;
+        };
+        let history = &room.history;
+        for line in &history[history.len().saturating_sub(count)..] {
+            let _ = user.send(OutgoingMsg::History(line.clone()));
+        }
+    }
+
+    /// Change a room's topic and tell its members. Any member may, except in a read only or topic-locked
+    /// room, where only the owner may. The room remembers the last [TOPIC_HISTORY_SIZE] topics.
+    pub fn set_topic(
+        &mut self,
+        user_name: &str,
+        room_name: &str,
+        topic: String,
+    ) -> Result<(), ServerError> {
+        // a topic is seen by the whole room, so it is held to the same rules as saying something there
+        if self.room_delivery(user_name, room_name)? == RoomDelivery::Muted {
+            if let Some(user) = self.users.get(user_name) {
+                let _ = user.send(OutgoingMsg::Notice(format!(
+                    "you are muted in {}",
+                    room_name
+                )));
+            }
+            return Ok(());
+        }
+        let Some(room) = self.rooms.get_mut(room_name) else {
+            return Err(ServerError::RoomUnknown(room_name.to_string()));
+        };
+        if room.topic_locked && !room.is_owner(user_name) {
+            return Err(ServerError::NotRoomOwner(room_name.to_string()));
+        }
+        if room.topics.len() == TOPIC_HISTORY_SIZE {
+            room.topics.pop_front();
+        }
+        room.topics.push_back(TopicChange {
+            set_at: SystemTime::now(),
+            setter: user_name.to_string(),
+            topic: topic.clone(),
+        });
+        self.send_to_room(
+            room_name,
+            OutgoingMsg::Topic(room_name.to_string(), user_name.to_string(), topic),
+        );
+        Ok(())
+    }
+
+    /// A room's latest topics as TOPICHISTORY messages, oldest first, for a member catching up on how the
+    /// room has changed.
+    pub fn topic_history(
+        &self,
+        user_name: &str,
+        room_name: &str,
+    ) -> Result<Vec<OutgoingMsg>, ServerError> {
+        let room = self
+            .rooms
+            .get(room_name)
+            .ok_or_else(|| ServerError::RoomUnknown(room_name.to_string()))?;
+        if !room.users.contains(user_name) {
+            return Err(ServerError::UserNotInRoom(
+                user_name.to_string(),
+                room_name.to_string(),
+            ));
+        }
+        Ok(room
+            .topics
+            .iter()
+            .map(|change| {
+                let set_at = change
+                    .set_at
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs();
+                OutgoingMsg::TopicHistory(
+                    set_at,
+                    room_name.to_string(),
+                    change.setter.clone(),
+                    change.topic.clone(),
+                )
+            })
+            .collect())
+    }
+
+    /// Up to `count` of a room's most recent messages as HISTORY messages, oldest first, for a member
+    /// scrolling back. No more than the server keeps can be asked for.
+    pub fn recent(
+        &self,
+        user_name: &str,
+        room_name: &str,
+        count: usize,
+    ) -> Result<Vec<OutgoingMsg>, ServerError> {
+        let room = self
+            .rooms
+            .get(room_name)
+            .ok_or_else(|| ServerError::RoomUnknown(room_name.to_string()))?;
+        if !room.users.contains(user_name) {
+            return Err(ServerError::UserNotInRoom(
+                user_name.to_string(),
+                room_name.to_string(),
+            ));
+        }
+        let count = count.min(self.history_size);
+        let history = &room.history;
+        Ok(history[history.len().saturating_sub(count)..]
+            .iter()
+            .map(|line| OutgoingMsg::History(line.clone()))
+            .collect())
+    }
+
+    /// Make users wait `rename_cooldown` between name changes. Zero, the default, lets them rename as often as
+    /// they like.
+    pub fn set_rename_cooldown(&mut self, rename_cooldown: Duration) {
+        self.rename_cooldown = rename_cooldown;
+    }
+
+    /// Lock the names of users who disconnect or rename for `name_lock`. Zero, the default, frees them right
+    /// away.
+    pub fn set_name_lock(&mut self, name_lock: Duration) {
+        self.name_lock = name_lock;
+    }
+
+    /// Whether no client may take a name, either because it is in the reserved names file or because it is
+    /// the server's own.
+    fn is_reserved(&self, name: &str) -> bool {
+        name == SYSTEM_NAME || self.reserved_names.contains(name)
+    }
+
+    /// Refuse a name that a user who disconnected gave up less than `name_lock` ago.
+    fn check_name_lock(&mut self, name: &str) -> Result<(), ServerError> {
+        let name_lock = self.name_lock;
+        self.recently_released
+            .retain(|_, released| released.elapsed() < name_lock);
+        match self.recently_released.get(name) {
+            Some(released) => {
+                let left = name_lock - released.elapsed();
+                // round up so the client is never told to wait 0 seconds
+                let seconds = left.as_secs() + u64::from(left.subsec_nanos() > 0);
+                Err(ServerError::NameCoolingDown(name.to_string(), seconds))
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Check whether a user's last name change was long enough ago for them to change it again.
+    pub fn rename_allowed(&self, name: &str) -> bool {
+        match self.users.get(name).and_then(|user| user.last_rename) {
+            Some(last_rename) => last_rename.elapsed() >= self.rename_cooldown,
+            None => true,
+        }
+    }
+
+    /// Count a connection entering registration. Returns `false`, without counting it, if too many
+    /// connections are already registering.
+    pub fn begin_registration(&mut self) -> bool {
+        if let Some(max) = self.max_pending_registrations {
+            if self.pending_registrations >= max {
+                return false;
+            }
+        }
+        self.pending_registrations += 1;
+        true
+    }
+
+    /// Stop counting a connection that registered or went away during registration.
+    pub fn end_registration(&mut self) {
+        self.pending_registrations = self.pending_registrations.saturating_sub(1);
+    }
+
+    /// Read the MOTD from `motd_file` and the reserved names (one per line) from `reserved_names_file`,
+    /// now and on every [ServerState::reload].
+    pub fn set_reloadable_files(
+        &mut self,
+        motd_file: Option<PathBuf>,
+        reserved_names_file: Option<PathBuf>,
+    ) -> anyhow::Result<()> {
+        self.motd_file = motd_file;
+        self.reserved_names_file = reserved_names_file;
+        self.reload()
+    }
+
+    /// Re-read the MOTD and reserved names files. Nothing changes unless both files can be read.
+    pub fn reload(&mut self) -> anyhow::Result<()> {
+        let motd = match &self.motd_file {
+            Some(path) => fs::read_to_string(path)?
+                .lines()
+                .map(|line| line.to_string())
+                .collect(),
+            None => vec![],
+        };
+        let reserved_names = match &self.reserved_names_file {
+            Some(path) => fs::read_to_string(path)?
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(normalize_name)
+                .collect(),
+            None => HashSet::new(),
+        };
+        self.motd = motd;
+        self.reserved_names = reserved_names;
+        Ok(())
+    }
+
+    /// The message of the day, one line per entry.
+    pub fn motd(&self) -> &[String] {
+        &self.motd
+    }
+
+    /// Save persistent rooms to `store` and restore the rooms it already holds.
+    pub fn set_store(&mut self, store: Box<dyn Store>) -> anyhow::Result<()> {
+        for room_name in store.load_rooms()? {
+            self.rooms.entry(room_name).or_insert_with(Room::restored);
+        }
+        for room_name in store.load_archived()? {
+            if let Some(room) = self.rooms.get_mut(&room_name) {
+                room.archived = true;
+            }
+        }
+        // carry on numbering from the last stored message, so EDIT and DELETE can't mistake a new message
+        // for an old one
+        for (room_name, room) in self.rooms.iter_mut() {
+            room.history = store.load_history(room_name)?;
+            room.seq = room
+                .history
+                .iter()
+                .filter_map(|line| match OutgoingMsg::parse(line) {
+                    Some(OutgoingMsg::SaidRoom(_, _, seq, _)) => Some(seq),
+                    _ => None,
+                })
+                .max()
+                .unwrap_or(0);
+        }
+        self.store = store;
+        Ok(())
+    }
+
+    fn save_rooms(&mut self) {
+        let rooms = self.rooms();
+        if let Err(e) = self.store.save_rooms(&rooms) {
+            log::error!("failed to save rooms: {:?}", e);
+        }
+    }
+
+    fn save_archived(&mut self) {
+        let mut archived: Vec<String> = self
+            .rooms
+            .iter()
+            .filter(|(_, room)| room.archived)
+            .map(|(room_name, _)| room_name.clone())
+            .collect();
+        archived.sort();
+        if let Err(e) = self.store.save_archived(&archived) {
+            log::error!("failed to save archived rooms: {:?}", e);
         }
     }
 
-    /// Map a user name to a connected client.
+    /// Map a user name to a connected client. The name is stored in its [normalize_name] form.
     pub fn add_user(&mut self, name: String, user: User) -> Result<(), ServerError> {
+        let name = normalize_name(&name);
+        if self.is_reserved(&name) {
+            return Err(ServerError::NameReserved(name));
+        }
         if self.users.contains_key(&name) {
             return Err(ServerError::UserAlreadyExists(name));
         }
-        self.users.insert(name, user);
+        self.check_name_lock(&name)?;
+        // one presence event per registration, however many rooms the user goes on to join
+        self.notify_watchers(OutgoingMsg::Online(name.clone()));
+        self.users.insert(name.clone(), user);
+        self.notify_buddies(&name, true);
         Ok(())
     }
 
     /// Remove a user from the state. Ensure they leave every room.
     pub fn remove_user(&mut self, name: &str) -> Result<(), ServerError> {
-        match self.users.remove(name) {
-            Some(user) => {
-                // remove user from rooms
-                for room_name in user.rooms {
-                    self.leave_room(&room_name, name)?;
+        self.drop_user(name, true)
+    }
+
+    /// Remove a user who gave up their name with RESET. Unlike [ServerState::remove_user] the name isn't locked,
+    /// so they can register it again straight away.
+    pub fn reset_user(&mut self, name: &str) -> Result<(), ServerError> {
+        self.drop_user(name, false)
+    }
+
+    /// Remove a user from the state and every room they are in. With `lock_name` the name stays locked for
+    /// `name_lock` after.
+    fn drop_user(&mut self, name: &str, lock_name: bool) -> Result<(), ServerError> {
+        match self.users.get(name).map(|user| user.rooms.clone()) {
+            Some(rooms) => {
+                // remove user from rooms while they can still be looked up for the room owners. a room that
+                // can't be left mustn't keep the user around, or their name would never be freed.
+                for room_name in rooms {
+                    if let Err(e) = self.leave_room(&room_name, name) {
+                        log::error!("{} failed to leave {}: {:?}", name, room_name, e);
+                    }
+                }
+                self.users.remove(name);
+                // invites are for the user, not whoever takes the name next
+                for room in self.rooms.values_mut() {
+                    room.invited.remove(name);
                 }
+                if lock_name && !self.name_lock.is_zero() {
+                    self.recently_released
+                        .insert(name.to_string(), Instant::now());
+                }
+                // a buddy list only lasts as long as its owner's connection
+                self.buddies.retain(|_, watchers| {
+                    watchers.remove(name);
+                    !watchers.is_empty()
+                });
+                self.notify_buddies(name, false);
                 Ok(())
             }
             None => Err(ServerError::UserUnknown(name.to_string())),
         }
     }
 
+    /// Remove a user as the connection they registered on closes. Nothing happens if the name has passed to
+    /// another connection since, such as when the user was killed and someone else took the name.
+    pub fn remove_connection(&mut self, name: &str, connection_id: u64) -> Result<(), ServerError> {
+        match self.users.get(name) {
+            Some(user) if user.connection_id == connection_id => self.remove_user(name),
+            _ => Ok(()),
+        }
+    }
+
     /// Add a user to a room.
     pub fn join_room(&mut self, room_name: String, user_name: String) -> Result<(), ServerError> {
-        if !self.users.contains_key(&user_name) {
+        let Some(user) = self.users.get(&user_name) else {
             return Err(ServerError::UserUnknown(user_name));
+        };
+        self.check_joinable(&room_name, &user_name)?;
+        if let Some(max_rooms) = self.max_rooms_per_user {
+            if user.rooms.len() >= max_rooms {
+                return Err(ServerError::TooManyRooms(max_rooms));
+            }
         }
         if let Some(room) = self.rooms.get_mut(&room_name) {
-            // add user to existing room
+            // add user to existing room. the first user into an ownerless room owns it. an invite is good for
+            // one join.
             room.add_user(user_name.clone());
+            room.invited.remove(&user_name);
+            if room.owner.is_none() {
+                room.owner = Some(user_name.clone());
+            }
             // broadcast JOINED to room
             let joined_msg = OutgoingMsg::Joined(room_name.clone(), user_name.clone());
             for room_user_name in &room.users {
                 if room_user_name != &user_name {
                     if let Some(user) = self.users.get_mut(room_user_name) {
-                        user.send(joined_msg.clone()).unwrap();
+                        let _ = user.send(joined_msg.clone());
                     }
                 }
             }
+            self.notify_owner(&room_name, &user_name, true);
         } else {
             // create new room
-            let mut room = Room::new();
-            room.add_user(user_name.clone());
-            self.rooms.insert(room_name.clone(), room);
+            self.rooms
+                .insert(room_name.clone(), Room::new(user_name.clone()));
+            if self.persistent_rooms {
+                self.save_rooms();
+            }
+            self.notify_watchers(OutgoingMsg::RoomCreated(room_name.clone()));
         }
+        self.relay(
+            &room_name,
+            OutgoingMsg::Joined(room_name.clone(), user_name.clone()),
+        );
+        self.replay_history(&room_name, &user_name);
         // add room to user record
         if let Some(user) = self.users.get_mut(&user_name) {
             user.add_room(room_name);
@@ -162,16 +1182,31 @@ impl ServerState {
         if let Some(room) = self.rooms.get_mut(room_name) {
             if room.remove_user(user_name) {
                 // delete rooms that are empty
-                if room.is_empty() {
+                let deleted = room.is_empty() && !self.persistent_rooms && !self.closed_rooms;
+                if deleted {
                     self.rooms.remove(room_name);
+                    // a room created again under the same name numbers its messages from 1, so its old
+                    // history goes with it
+                    self.unsaved_history.insert(room_name.to_string());
+                    self.notify_watchers(OutgoingMsg::RoomDeleted(room_name.to_string()));
                 } else {
                     // broadcast LEFT to room
                     let left_msg = OutgoingMsg::Left(room_name.to_string(), user_name.to_string());
                     for room_user_name in &room.users {
                         if let Some(user) = self.users.get_mut(room_user_name) {
-                            user.send(left_msg.clone()).unwrap();
+                            // a closed channel means the user is on their way out
+                            let _ = user.send(left_msg.clone());
                         }
                     }
+                    self.notify_owner(room_name, user_name, false);
+                }
+                self.relay(
+                    room_name,
+                    OutgoingMsg::Left(room_name.to_string(), user_name.to_string()),
+                );
+                // the links go with the room, once they have heard the last member leave
+                if deleted {
+                    self.links.remove(room_name);
                 }
                 // remove room from user record
                 if let Some(user) = self.users.get_mut(user_name) {
@@ -189,55 +1224,592 @@ impl ServerState {
         }
     }
 
-    /// Change the user name of a connected client.
-    pub fn rename_user(&mut self, old_name: &str, new_name: &str) -> Result<(), ServerError> {
-        if let Some(user) = self.users.remove(old_name) {
-            // rename user in each room the user is in
-            for room_name in &user.rooms {
-                if let Some(room) = self.rooms.get_mut(room_name) {
-                    room.remove_user(old_name);
-                    room.add_user(new_name.to_string());
-                }
+    /// Join a user to several rooms in order, as if they had sent JOIN for each. Returns each room's result.
+    /// Once the user is in as many rooms as they may be, the rest aren't tried and the list stops at that
+    /// error.
+    pub fn join_rooms(
+        &mut self,
+        room_names: &[String],
+        user_name: &str,
+    ) -> Vec<Result<(), ServerError>> {
+        let mut results = vec![];
+        for room_name in room_names {
+            let result = self.join_room(room_name.clone(), user_name.to_string());
+            let full = matches!(result, Err(ServerError::TooManyRooms(_)));
+            results.push(result);
+            if full {
+                break;
             }
-            // rename user in main user list
-            self.users.insert(new_name.to_string(), user);
-            Ok(())
-        } else {
-            Err(ServerError::UserUnknown(old_name.to_string()))
         }
+        results
     }
 
-    /// Get a list of rooms.
-    pub fn rooms(&self) -> Vec<String> {
-        self.rooms.keys().map(|k| k.to_string()).collect()
+    /// Move a user from one room to another in a single step, so they are never in neither room. Nothing
+    /// changes unless the user is in the old room.
+    pub fn switch_room(
+        &mut self,
+        old_room_name: &str,
+        new_room_name: String,
+        user_name: String,
+    ) -> Result<(), ServerError> {
+        if !self
+            .rooms
+            .get(old_room_name)
+            .is_some_and(|room| room.users.contains(&user_name))
+        {
+            return Err(ServerError::UserNotInRoom(
+                user_name,
+                old_room_name.to_string(),
+            ));
+        }
+        if old_room_name == new_room_name {
+            return Ok(());
+        }
+        // the join can't fail once the user has left, so a room they can't join is refused up front
+        self.check_joinable(&new_room_name, &user_name)?;
+        self.leave_room(old_room_name, &user_name)?;
+        self.join_room(new_room_name, user_name)
     }
 
-    /// Get a list of users that have joined a given room.
-    pub fn users(&self, room_name: &str) -> Result<Vec<String>, ServerError> {
-        if let Some(room) = self.rooms.get(room_name) {
-            Ok(room.users.iter().map(|u| u.to_string()).collect())
-        } else {
+    /// Check that a user may join a room, if it exists, without changing anything.
+    fn check_joinable(&self, room_name: &str, user_name: &str) -> Result<(), ServerError> {
+        match self.rooms.get(room_name) {
+            // joining again would send everyone a second JOINED
+            Some(room) if room.users.contains(user_name) => Err(ServerError::UserAlreadyInRoom(
+                user_name.to_string(),
+                room_name.to_string(),
+            )),
+            Some(room) if room.archived => Err(ServerError::RoomArchived(room_name.to_string())),
+            Some(room) if room.invite_only && !room.invited.contains(user_name) => {
+                Err(ServerError::RoomInviteOnly(room_name.to_string()))
+            }
+            Some(_) => Ok(()),
+            None if self.closed_rooms => Err(ServerError::RoomUnknown(room_name.to_string())),
+            None => Ok(()),
+        }
+    }
+
+    /// Create an empty room, for operators. The first user to join it owns it.
+    pub fn create_room(&mut self, oper_name: &str, room_name: &str) -> Result<(), ServerError> {
+        self.require_oper(oper_name)?;
+        if self.rooms.contains_key(room_name) {
+            return Err(ServerError::RoomAlreadyExists(room_name.to_string()));
+        }
+        self.rooms.insert(room_name.to_string(), Room::restored());
+        if self.persistent_rooms {
+            self.save_rooms();
+        }
+        self.notify_watchers(OutgoingMsg::RoomCreated(room_name.to_string()));
+        Ok(())
+    }
+
+    /// Delete a room, for operators, whether or not anyone is in it. Its members are put out of it and told
+    /// with a DELETED message, as are watchers. Its history goes with it.
+    pub fn destroy_room(&mut self, oper_name: &str, room_name: &str) -> Result<(), ServerError> {
+        self.require_oper(oper_name)?;
+        let room = self
+            .rooms
+            .remove(room_name)
+            .ok_or_else(|| ServerError::RoomUnknown(room_name.to_string()))?;
+        let deleted = OutgoingMsg::RoomDeleted(room_name.to_string());
+        for member in &room.users {
+            if let Some(user) = self.users.get_mut(member) {
+                user.remove_room(room_name);
+                // watchers are told below
+                if !user.watching {
+                    let _ = user.send(deleted.clone());
+                }
+            }
+        }
+        self.notify_watchers(deleted);
+        self.links.remove(room_name);
+        self.unsaved_history.insert(room_name.to_string());
+        if self.persistent_rooms {
+            self.save_rooms();
+        }
+        if room.archived {
+            self.save_archived();
+        }
+        Ok(())
+    }
+
+    /// Change the user name of a connected client.
+    pub fn rename_user(&mut self, old_name: &str, new_name: &str) -> Result<(), ServerError> {
+        let new_name = normalize_name(new_name);
+        let new_name = new_name.as_str();
+        if new_name != old_name && self.is_reserved(new_name) {
+            return Err(ServerError::NameReserved(new_name.to_string()));
+        }
+        if new_name != old_name && self.users.contains_key(new_name) {
+            return Err(ServerError::UserAlreadyExists(new_name.to_string()));
+        }
+        if new_name != old_name {
+            self.check_name_lock(new_name)?;
+        }
+        if let Some(mut user) = self.users.remove(old_name) {
+            user.last_rename = Some(Instant::now());
+            // rename user in each room the user is in
+            for room_name in &user.rooms {
+                if let Some(room) = self.rooms.get_mut(room_name) {
+                    room.rename_user(old_name, new_name);
+                }
+            }
+            // invites follow the user to their new name
+            for room in self.rooms.values_mut() {
+                swap_name(&mut room.invited, old_name, new_name);
+            }
+            // rename user in main user list
+            self.users.insert(new_name.to_string(), user);
+            // buddy lists follow their owner, but watch names, so the old name goes offline
+            for watchers in self.buddies.values_mut() {
+                if watchers.remove(old_name) {
+                    watchers.insert(new_name.to_string());
+                }
+            }
+            if new_name != old_name {
+                // a name given up by a rename could be taken to pass for its old holder as easily as one given
+                // up by a disconnect
+                if !self.name_lock.is_zero() {
+                    self.recently_released
+                        .insert(old_name.to_string(), Instant::now());
+                }
+                self.notify_buddies(old_name, false);
+                self.notify_buddies(new_name, true);
+            }
+            Ok(())
+        } else {
+            Err(ServerError::UserUnknown(old_name.to_string()))
+        }
+    }
+
+    /// Get a list of rooms.
+    pub fn rooms(&self) -> Vec<String> {
+        self.filtered_rooms(None)
+    }
+
+    /// Get a list of rooms, keeping only those that match `filter` if there is one.
+    pub fn filtered_rooms(&self, filter: Option<RoomFilter>) -> Vec<String> {
+        let mut rooms: Vec<String> = self
+            .rooms
+            .iter()
+            .filter(|(_, room)| match filter {
+                None => true,
+                Some(RoomFilter::Empty) => room.is_empty(),
+                Some(RoomFilter::Active) => !room.is_empty(),
+            })
+            .map(|(name, _)| name.to_string())
+            .collect();
+        rooms.sort();
+        rooms
+    }
+
+    /// Get the rooms a user is in and how many members each has, sorted by room name.
+    pub fn rooms_with_counts(&self, user_name: &str) -> Result<Vec<(String, usize)>, ServerError> {
+        if !self.users.contains_key(user_name) {
+            return Err(ServerError::UserUnknown(user_name.to_string()));
+        }
+        let mut rooms: Vec<(String, usize)> = self
+            .rooms
+            .iter()
+            .filter(|(_, room)| room.users.contains(user_name))
+            .map(|(name, room)| (name.to_string(), room.users.len()))
+            .collect();
+        rooms.sort();
+        Ok(rooms)
+    }
+
+    /// Get a list of users that have joined a given room.
+    pub fn users(&self, room_name: &str) -> Result<Vec<String>, ServerError> {
+        if let Some(room) = self.rooms.get(room_name) {
+            let mut users: Vec<String> = room.users.iter().map(|u| u.to_string()).collect();
+            users.sort();
+            Ok(users)
+        } else {
             Err(ServerError::RoomUnknown(room_name.to_string()))
         }
     }
 
-    /// Send a user-to-user private message.
+    /// Get a list of users that have joined a given room, most recently active first.
+    pub fn active_users(&self, room_name: &str) -> Result<Vec<String>, ServerError> {
+        let mut users = self.users(room_name)?;
+        // ties are broken by name so the order is stable
+        users.sort_by_key(|name| {
+            let last_seen = self.users.get(name).map(|user| user.last_seen);
+            (std::cmp::Reverse(last_seen), name.clone())
+        });
+        Ok(users)
+    }
+
+    /// Turn do not disturb on (`true`) or off (`false`) for a user.
+    pub fn set_dnd(&mut self, name: &str, dnd: bool) -> Result<(), ServerError> {
+        let user = self
+            .users
+            .get_mut(name)
+            .ok_or_else(|| ServerError::UserUnknown(name.to_string()))?;
+        user.dnd = dnd;
+        Ok(())
+    }
+
+    /// Turn echoing of a user's own room messages on (`true`) or off (`false`, the default).
+    pub fn set_echo_room(&mut self, name: &str, echo_room: bool) -> Result<(), ServerError> {
+        let user = self
+            .users
+            .get_mut(name)
+            .ok_or_else(|| ServerError::UserUnknown(name.to_string()))?;
+        user.echo_room = echo_room;
+        Ok(())
+    }
+
+    /// Turn the rooms shared with the sender ahead of each private message on (`true`) or off (`false`, the
+    /// default).
+    pub fn set_context(&mut self, name: &str, context: bool) -> Result<(), ServerError> {
+        let user = self
+            .users
+            .get_mut(name)
+            .ok_or_else(|| ServerError::UserUnknown(name.to_string()))?;
+        user.context = context;
+        Ok(())
+    }
+
+    /// Make a user an operator if they know the operator password.
+    pub fn oper(&mut self, name: &str, password: &str) -> Result<(), ServerError> {
+        let user = self
+            .users
+            .get_mut(name)
+            .ok_or_else(|| ServerError::UserUnknown(name.to_string()))?;
+        match &self.oper_password {
+            Some(oper_password) if oper_password == password => {
+                user.oper = true;
+                Ok(())
+            }
+            _ => Err(ServerError::NotOperator(name.to_string())),
+        }
+    }
+
+    /// Check that a user is an operator before letting them use an admin command.
+    fn require_oper(&self, name: &str) -> Result<(), ServerError> {
+        match self.users.get(name) {
+            Some(user) if user.oper => Ok(()),
+            Some(_) => Err(ServerError::NotOperator(name.to_string())),
+            None => Err(ServerError::UserUnknown(name.to_string())),
+        }
+    }
+
+    /// Forcibly disconnect a user. The user is sent DISCONNECT with the reason and removed from the server,
+    /// which leaves every room they were in. Closing their channel tells their connection to shut down.
+    pub fn kill(&mut self, oper_name: &str, name: &str, reason: String) -> Result<(), ServerError> {
+        self.require_oper(oper_name)?;
+        let user = self
+            .users
+            .get(name)
+            .ok_or_else(|| ServerError::UserUnknown(name.to_string()))?;
+        // the user may already be on their way out
+        let _ = user.send(OutgoingMsg::Disconnect(reason));
+        self.remove_user(name)
+    }
+
+    /// Start (`true`) or stop (`false`) telling a user about users coming online and rooms being created
+    /// and deleted.
+    pub fn set_watching(&mut self, name: &str, watching: bool) -> Result<(), ServerError> {
+        let user = self
+            .users
+            .get_mut(name)
+            .ok_or_else(|| ServerError::UserUnknown(name.to_string()))?;
+        user.watching = watching;
+        Ok(())
+    }
+
+    fn notify_watchers(&self, message: OutgoingMsg) {
+        for user in self.users.values().filter(|user| user.watching) {
+            let _ = user.send(message.clone());
+        }
+    }
+
+    /// Put `name` on a user's buddy list so they hear whenever it comes online or goes offline, and return
+    /// whether it is online now.
+    pub fn add_buddy(&mut self, watcher: &str, name: &str) -> Result<OutgoingMsg, ServerError> {
+        if !self.users.contains_key(watcher) {
+            return Err(ServerError::UserUnknown(watcher.to_string()));
+        }
+        let name = normalize_name(name);
+        let watching = |watchers: &HashSet<String>| watchers.contains(watcher);
+        // adding someone already on the list again is fine, even when the list is full
+        if !self.buddies.get(&name).is_some_and(watching)
+            && self
+                .buddies
+                .values()
+                .filter(|watchers| watching(watchers))
+                .count()
+                >= MAX_BUDDIES
+        {
+            return Err(ServerError::TooManyBuddies(MAX_BUDDIES));
+        }
+        self.buddies
+            .entry(name.clone())
+            .or_default()
+            .insert(watcher.to_string());
+        let online = self.users.contains_key(&name);
+        Ok(OutgoingMsg::Presence(name, online))
+    }
+
+    fn notify_buddies(&self, name: &str, online: bool) {
+        if let Some(watchers) = self.buddies.get(name) {
+            for watcher in watchers {
+                if let Some(user) = self.users.get(watcher) {
+                    let _ = user.send(OutgoingMsg::Presence(name.to_string(), online));
+                }
+            }
+        }
+    }
+
+    /// Record that a user just sent the server a command.
+    pub fn touch_user(&mut self, name: &str) {
+        if let Some(user) = self.users.get_mut(name) {
+            user.last_seen = Instant::now();
+        }
+    }
+
+    /// Record that a user's client just answered a PING.
+    pub fn record_pong(&mut self, name: &str) {
+        if let Some(user) = self.users.get_mut(name) {
+            user.last_pong = Some(Instant::now());
+        }
+    }
+
+    /// PING a user's client outside of its usual keep-alive schedule, to find out whether it is still there.
+    /// Only a client that hasn't sent anything, not even a PONG, for `quiet_for` is PINGed, and no more than
+    /// once in that time. Returns whether the PING was sent.
+    pub fn ping_user(&mut self, name: &str, quiet_for: Duration) -> bool {
+        self.ghost_probes
+            .retain(|_, probed| probed.elapsed() < quiet_for);
+        let Some(user) = self.users.get(name) else {
+            return false;
+        };
+        let last_heard = user
+            .last_pong
+            .map_or(user.last_seen, |pong| pong.max(user.last_seen));
+        if last_heard.elapsed() < quiet_for || self.ghost_probes.contains_key(name) {
+            return false;
+        }
+        // a client whose channel is closed is already on its way out
+        let _ = user.send(OutgoingMsg::Ping);
+        self.ghost_probes.insert(name.to_string(), Instant::now());
+        true
+    }
+
+    /// Disconnect a user whose client hasn't sent anything, not even a PONG, since `since`, so that a new
+    /// connection can take over their name. Returns whether the name is free now.
+    pub fn reap_ghost(&mut self, name: &str, since: Instant) -> bool {
+        let Some(user) = self.users.get(name) else {
+            return true;
+        };
+        if user.last_seen >= since || user.last_pong.is_some_and(|pong| pong >= since) {
+            return false;
+        }
+        let _ = user.send(OutgoingMsg::Disconnect("no answer to PING".to_string()));
+        self.ghost_probes.remove(name);
+        // the name is reaped for the connection waiting on it, so it isn't locked like a name given up
+        self.drop_user(name, false).is_ok()
+    }
+
+    /// Get a snapshot of a room's full record.
+    pub fn room_snapshot(&self, room_name: &str) -> Result<RoomSnapshot, ServerError> {
+        let room = self
+            .rooms
+            .get(room_name)
+            .ok_or_else(|| ServerError::RoomUnknown(room_name.to_string()))?;
+        let mut members: Vec<String> = room.users.iter().cloned().collect();
+        members.sort();
+        let mut muted: Vec<String> = room.muted.iter().cloned().collect();
+        muted.sort();
+        Ok(RoomSnapshot {
+            name: room_name.to_string(),
+            topic: room.topics.back().map(|change| change.topic.clone()),
+            member_count: members.len(),
+            members,
+            owner: room.owner.clone(),
+            muted,
+            readonly: room.readonly,
+            invite_only: room.invite_only,
+            archived: room.archived,
+        })
+    }
+
+    /// Set or clear a user's presence text.
+    pub fn set_status(&mut self, name: &str, status: Option<String>) -> Result<(), ServerError> {
+        let user = self
+            .users
+            .get_mut(name)
+            .ok_or_else(|| ServerError::UserUnknown(name.to_string()))?;
+        user.status = status;
+        Ok(())
+    }
+
+    /// Look up what others can see about a user.
+    pub fn whois(&self, name: &str) -> Result<OutgoingMsg, ServerError> {
+        let user = self
+            .users
+            .get(name)
+            .ok_or_else(|| ServerError::UserUnknown(name.to_string()))?;
+        Ok(OutgoingMsg::Status(name.to_string(), user.status.clone()))
+    }
+
+    /// The rooms, sorted by name, that two users have both joined. Only rooms the caller is in can be
+    /// listed, so nothing is revealed about the target's other rooms.
+    pub fn shared_rooms(&self, caller: &str, target: &str) -> Result<Vec<String>, ServerError> {
+        let caller = self
+            .users
+            .get(caller)
+            .ok_or_else(|| ServerError::UserUnknown(caller.to_string()))?;
+        let target = self
+            .users
+            .get(target)
+            .ok_or_else(|| ServerError::UserUnknown(target.to_string()))?;
+        let mut shared_rooms: Vec<String> =
+            caller.rooms.intersection(&target.rooms).cloned().collect();
+        shared_rooms.sort();
+        Ok(shared_rooms)
+    }
+
+    /// Add a message to the review log, pushing out the oldest one once it's full.
+    fn log_message(&mut self, to: &str, from: &str, message: &str) {
+        if self.review_log_size == 0 {
+            return;
+        }
+        if self.review_log.len() == self.review_log_size {
+            self.review_log.pop_front();
+        }
+        self.review_log.push_back(LoggedMessage {
+            sent_at: SystemTime::now(),
+            to: to.to_string(),
+            from: from.to_string(),
+            message: message.to_string(),
+        });
+    }
+
+    /// The review log as REVIEW messages, oldest first. Only operators may see it.
+    pub fn review(&self, oper_name: &str) -> Result<Vec<OutgoingMsg>, ServerError> {
+        self.require_oper(oper_name)?;
+        Ok(self.review_log.iter().map(LoggedMessage::review).collect())
+    }
+
+    /// The counters that connections add the commands they receive to.
+    pub fn command_counts(&self) -> Arc<CommandCounts> {
+        self.command_counts.clone()
+    }
+
+    /// How many times each command has been received since the server started, for operators.
+    pub fn protocol_stats(&self, oper_name: &str) -> Result<Vec<OutgoingMsg>, ServerError> {
+        self.require_oper(oper_name)?;
+        Ok(self
+            .command_counts
+            .counts()
+            .into_iter()
+            .map(|(verb, count)| OutgoingMsg::ProtoStat(verb.to_string(), count))
+            .collect())
+    }
+
+    /// Count a room or private message towards a user's session stats.
+    fn count_message(&mut self, name: &str) {
+        if let Some(user) = self.users.get_mut(name) {
+            user.messages_sent += 1;
+        }
+    }
+
+    /// Report how many messages a user has sent, how many rooms they are in, and how long ago they
+    /// registered.
+    pub fn my_stats(&self, name: &str) -> Result<OutgoingMsg, ServerError> {
+        let user = self
+            .users
+            .get(name)
+            .ok_or_else(|| ServerError::UserUnknown(name.to_string()))?;
+        Ok(OutgoingMsg::MyStats(
+            user.messages_sent,
+            user.rooms.len(),
+            user.registered_at.elapsed(),
+        ))
+    }
+
+    /// The preferences a user has set for themselves, so a client that reconnects can catch up. Presence
+    /// text is only listed while it is set.
+    pub fn prefs(&self, name: &str) -> Result<Vec<OutgoingMsg>, ServerError> {
+        let user = self
+            .users
+            .get(name)
+            .ok_or_else(|| ServerError::UserUnknown(name.to_string()))?;
+        let on_off = |on: bool| if on { "ON" } else { "OFF" }.to_string();
+        let mut prefs = vec![
+            OutgoingMsg::Pref("dnd".to_string(), on_off(user.dnd)),
+            OutgoingMsg::Pref("watch".to_string(), on_off(user.watching)),
+            OutgoingMsg::Pref("echo-room".to_string(), on_off(user.echo_room)),
+            OutgoingMsg::Pref("context".to_string(), on_off(user.context)),
+            OutgoingMsg::Pref(
+                "histlen".to_string(),
+                user.history_length
+                    .map_or("all".to_string(), |length| length.to_string()),
+            ),
+        ];
+        if let Some(status) = &user.status {
+            prefs.push(OutgoingMsg::Pref("status".to_string(), status.clone()));
+        }
+        Ok(prefs)
+    }
+
+    /// Send a user-to-user private message. Returns the DELIVERED confirmation for the sender.
     pub fn say_to_user(
-        &self,
+        &mut self,
         from_user: &str,
         to_user: &str,
         message: String,
-    ) -> Result<(), ServerError> {
+        urgent: bool,
+    ) -> Result<OutgoingMsg, ServerError> {
         if let Some(to) = self.users.get(to_user) {
-            // TODO: better errors
-            to.send(OutgoingMsg::SaidUser(from_user.to_string(), message))
-                .unwrap();
-            Ok(())
+            if to.dnd && !(urgent && self.urgent_bypasses_dnd) {
+                return Err(ServerError::UserDoNotDisturb(to_user.to_string()));
+            }
+            // only copy the message if the review log keeps it
+            let logged = (self.review_log_size > 0).then(|| message.clone());
+            // only rooms the recipient is in can be shared, so nothing private to the sender is revealed.
+            // a failed send shows up again with the message itself.
+            if to.context {
+                let shared_rooms = self.shared_rooms(to_user, from_user).unwrap_or_default();
+                let _ = to.send(OutgoingMsg::Context(from_user.to_string(), shared_rooms));
+            }
+            let said = OutgoingMsg::SaidUser(from_user.to_string(), message, urgent);
+            if to.send(said).is_err() {
+                // the recipient's connection ended without removing them, such as after a panic. remove
+                // them now so nobody else sends into the void.
+                let _ = self.remove_user(to_user);
+                return Err(ServerError::UserGone(to_user.to_string()));
+            }
+            let mut shared_rooms = vec![];
+            if self.shared_rooms_on_delivery {
+                shared_rooms = self.shared_rooms(from_user, to_user).unwrap_or_default();
+            }
+            self.count_message(from_user);
+            if let Some(logged) = logged {
+                self.log_message(to_user, from_user, &logged);
+            }
+            Ok(OutgoingMsg::Delivered(to_user.to_string(), shared_rooms))
         } else {
             Err(ServerError::UserUnknown(to_user.to_string()))
         }
     }
 
+    /// Send the same private message to several users. Each one is delivered as if it were sent on its own,
+    /// so one bad recipient doesn't stop the rest. Returns each recipient's DELIVERED or error, in order.
+    pub fn say_to_users(
+        &mut self,
+        from_user: &str,
+        to_users: &[String],
+        message: String,
+    ) -> Vec<Result<OutgoingMsg, ServerError>> {
+        to_users
+            .iter()
+            .map(|to_user| self.say_to_user(from_user, to_user, message.clone(), false))
+            .collect()
+    }
+
     /// Broadcast a message to every user in a room.
     pub fn say_to_room(
         &mut self,
@@ -245,86 +1817,685 @@ impl ServerState {
         room_name: &str,
         message: String,
     ) -> Result<(), ServerError> {
-        if let Some(room) = self.rooms.get(room_name) {
-            for room_user_name in &room.users {
-                if room_user_name != user_name {
-                    if let Some(user) = self.users.get_mut(room_user_name) {
-                        // TODO: better errors
-                        user.send(OutgoingMsg::SaidRoom(
+        match self.room_delivery(user_name, room_name)? {
+            RoomDelivery::Recipients(recipients) => {
+                // only messages that go somewhere count
+                self.count_message(user_name);
+                self.log_message(room_name, user_name, &message);
+                let seq = self.rooms.get_mut(room_name).map_or(0, Room::next_seq);
+                // a recipient whose connection is closing can't take messages any more. its teardown
+                // removes it, so just let the sender know it missed out.
+                let mut undelivered = vec![];
+                for room_user_name in &recipients {
+                    if let Some(user) = self.users.get(room_user_name) {
+                        let said_room = OutgoingMsg::SaidRoom(
                             room_name.to_string(),
                             user_name.to_string(),
+                            seq,
                             message.clone(),
-                        ))
-                        .unwrap();
+                        );
+                        if user.send(said_room).is_err() {
+                            undelivered.push(room_user_name.as_str());
+                        }
                     }
                 }
+                if !undelivered.is_empty() {
+                    log::info!("{} undelivered to {}", room_name, undelivered.join(" "));
+                    if let Some(user) = self.users.get(user_name) {
+                        let _ = user.send(OutgoingMsg::Notice(format!(
+                            "undelivered in {} to {}",
+                            room_name,
+                            undelivered.join(" ")
+                        )));
+                    }
+                }
+                if let Some(user) = self.users.get(user_name).filter(|user| user.echo_room) {
+                    let _ = user.send(OutgoingMsg::EchoRoom(
+                        room_name.to_string(),
+                        user_name.to_string(),
+                        seq,
+                        message.clone(),
+                    ));
+                }
+                let said_room = OutgoingMsg::SaidRoom(
+                    room_name.to_string(),
+                    user_name.to_string(),
+                    seq,
+                    message,
+                );
+                self.record_history(room_name, &said_room);
+                self.relay(room_name, said_room);
+            }
+            // muted users stay in the room but their messages go nowhere
+            RoomDelivery::Muted => {
+                if let Some(user) = self.users.get(user_name) {
+                    let _ = user.send(OutgoingMsg::Notice(format!(
+                        "you are muted in {}",
+                        room_name
+                    )));
+                }
             }
-            Ok(())
-        } else {
-            Err(ServerError::RoomUnknown(room_name.to_string()))
         }
+        Ok(())
     }
-}
 
-impl Default for ServerState {
-    fn default() -> Self {
-        Self::new()
+    /// Send a message to every member of a room on behalf of `display_name`, for bots that bridge people
+    /// outside the server. Only operators may. The sender is shown as `display_name/oper_name`, which no user
+    /// can register, and a name that belongs to someone on the server is refused outright.
+    pub fn say_to_room_as(
+        &mut self,
+        oper_name: &str,
+        room_name: &str,
+        display_name: &str,
+        message: String,
+    ) -> Result<(), ServerError> {
+        self.require_oper(oper_name)?;
+        if self.is_reserved(display_name) {
+            return Err(ServerError::NameReserved(display_name.to_string()));
+        }
+        if self.users.contains_key(display_name) {
+            return Err(ServerError::UserAlreadyExists(display_name.to_string()));
+        }
+        // a user who just left can't be passed for either
+        self.check_name_lock(display_name)?;
+        let room = self
+            .rooms
+            .get(room_name)
+            .ok_or_else(|| ServerError::RoomUnknown(room_name.to_string()))?;
+        if room.archived {
+            return Err(ServerError::RoomArchived(room_name.to_string()));
+        }
+        // the review log shows who really sent it
+        self.log_message(
+            room_name,
+            oper_name,
+            &format!("as {}: {}", display_name, message),
+        );
+        self.broadcast_said(
+            room_name,
+            &format!("{}/{}", display_name, oper_name),
+            message,
+        );
+        Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use tokio::sync::mpsc::{self, error::TryRecvError};
+    /// Send a message from the server itself to every member of a room, under [SYSTEM_NAME]. For
+    /// announcements such as a message of the day in a lobby or a summary of who came and went.
+    pub fn system_say(&mut self, room_name: &str, message: String) -> Result<(), ServerError> {
+        let room = self
+            .rooms
+            .get(room_name)
+            .ok_or_else(|| ServerError::RoomUnknown(room_name.to_string()))?;
+        if room.archived {
+            return Err(ServerError::RoomArchived(room_name.to_string()));
+        }
+        self.broadcast_said(room_name, SYSTEM_NAME, message);
+        Ok(())
+    }
 
-    #[test]
-    fn test_server_state_add_user() {
-        let mut state = ServerState::new();
-        assert!(state.users.get("@robert").is_none());
-        let (sender, _receiver) = mpsc::unbounded_channel();
-        assert!(state
-            .add_user("@robert".to_string(), User::new(sender))
-            .is_ok());
-        assert!(state.users.get("@robert").is_some());
-        let (sender, _receiver) = mpsc::unbounded_channel();
-        assert_eq!(
-            state.add_user("@robert".to_string(), User::new(sender)),
-            Err(ServerError::UserAlreadyExists("@robert".to_string()))
+    /// Number a message and send it to every member of a room, with no sender left out, then keep it in
+    /// the room's history and relay it to linked servers.
+    fn broadcast_said(&mut self, room_name: &str, from: &str, message: String) {
+        let Some(room) = self.rooms.get_mut(room_name) else {
+            return;
+        };
+        let said_room = OutgoingMsg::SaidRoom(
+            room_name.to_string(),
+            from.to_string(),
+            room.next_seq(),
+            message,
         );
+        for room_user_name in &room.users {
+            if let Some(user) = self.users.get(room_user_name) {
+                let _ = user.send(said_room.clone());
+            }
+        }
+        self.record_history(room_name, &said_room);
+        self.relay(room_name, said_room);
     }
 
-    #[test]
-    fn test_server_state_remove_user() {
-        let mut state = ServerState::new();
-        let (sender, _receiver) = mpsc::unbounded_channel();
-        assert!(state
-            .add_user("@robert".to_string(), User::new(sender))
-            .is_ok());
-        assert!(state.users.get("@robert").is_some());
-        assert!(state.remove_user("@robert").is_ok());
-        assert!(state.users.get("@robert").is_none());
-        assert_eq!(
-            state.remove_user("@robert"),
-            Err(ServerError::UserUnknown("@robert".to_string()))
-        );
+    /// Work out who a message from a user to a room would reach without sending it.
+    pub fn room_delivery(
+        &self,
+        user_name: &str,
+        room_name: &str,
+    ) -> Result<RoomDelivery, ServerError> {
+        let room = self
+            .rooms
+            .get(room_name)
+            .ok_or_else(|| ServerError::RoomUnknown(room_name.to_string()))?;
+        // only members can speak in a room, so a SAY right after a LEAVE fails
+        if !room.users.contains(user_name) {
+            return Err(ServerError::UserNotInRoom(
+                user_name.to_string(),
+                room_name.to_string(),
+            ));
+        }
+        if room.archived {
+            return Err(ServerError::RoomArchived(room_name.to_string()));
+        }
+        if room.readonly && !room.is_owner(user_name) {
+            return Err(ServerError::RoomReadOnly(room_name.to_string()));
+        }
+        if room.is_muted(user_name) {
+            return Ok(RoomDelivery::Muted);
+        }
+        let mut recipients: Vec<String> = room
+            .users
+            .iter()
+            .filter(|room_user_name| *room_user_name != user_name)
+            .cloned()
+            .collect();
+        recipients.sort();
+        Ok(RoomDelivery::Recipients(recipients))
     }
 
-    #[tokio::test]
-    async fn test_server_state_join_room() {
-        let mut state = ServerState::new();
-        let (sender, mut robert_receiver) = mpsc::unbounded_channel();
-        assert!(state
-            .add_user("@robert".to_string(), User::new(sender))
-            .is_ok());
+    /// Let peer servers LINK from these addresses. None, the default, means nobody may link.
+    pub fn set_link_peers(&mut self, link_peers: Vec<IpAddr>) {
+        self.link_peers = link_peers;
+    }
 
-        // join room that does not exist with user that exists
-        assert!(!state.rooms.contains_key("#testroom"));
-        assert!(state
-            .join_room("#testroom".to_string(), "@robert".to_string())
-            .is_ok());
-        assert!(state.rooms.contains_key("#testroom"));
-        assert!(state
-            .users
+    /// Check that a connection from `peer` may LINK, before it is treated as a peer server.
+    pub fn check_link(&self, peer: IpAddr) -> Result<(), ServerError> {
+        if self.link_peers.contains(&peer) {
+            Ok(())
+        } else {
+            Err(ServerError::LinkRefused)
+        }
+    }
+
+    /// Relay every event in a room to a peer server. The link holds the only sender of the peer's channel, so
+    /// dropping it along with the room ends the peer's session.
+    pub fn add_link(&mut self, room_name: String, sender: UnboundedSender<OutgoingMsg>) {
+        self.links.entry(room_name).or_default().push(sender);
+    }
+
+    /// Stop relaying to a peer server.
+    pub fn remove_link(&mut self, sender: &UnboundedSender<OutgoingMsg>) {
+        for senders in self.links.values_mut() {
+            senders.retain(|s| !s.same_channel(sender));
+        }
+        self.links.retain(|_, senders| !senders.is_empty());
+    }
+
+    /// Check if any peer server is linked to a room.
+    pub fn is_linked(&self, room_name: &str) -> bool {
+        self.links.contains_key(room_name)
+    }
+
+    /// Send a room event to the peer servers linked to the room.
+    fn relay(&self, room_name: &str, message: OutgoingMsg) {
+        if let Some(senders) = self.links.get(room_name) {
+            for sender in senders {
+                // a closed link is removed when its connection ends
+                let _ = sender.send(message.clone());
+            }
+        }
+    }
+
+    /// Deliver a room event relayed from the peer server `origin` to the local members of the room. Relayed
+    /// events are never relayed again. Relayed messages are numbered in the local room, not the peer's, and
+    /// edits and removals of them are numbered to match. Those of messages the room no longer remembers are
+    /// dropped.
+    pub fn deliver_relayed(
+        &mut self,
+        origin: &str,
+        mut message: OutgoingMsg,
+    ) -> Result<(), ServerError> {
+        let room_name = match &message {
+            OutgoingMsg::SaidRoom(room_name, _, _, _)
+            | OutgoingMsg::Joined(room_name, _)
+            | OutgoingMsg::Left(room_name, _)
+            | OutgoingMsg::Edited(room_name, _, _)
+            | OutgoingMsg::Removed(room_name, _) => room_name.clone(),
+            _ => return Ok(()),
+        };
+        let room = self
+            .rooms
+            .get_mut(&room_name)
+            .ok_or_else(|| ServerError::RoomUnknown(room_name.to_string()))?;
+        match &mut message {
+            OutgoingMsg::SaidRoom(_, _, seq, _) => *seq = room.next_relayed_seq(origin, *seq),
+            OutgoingMsg::Edited(_, seq, _) | OutgoingMsg::Removed(_, seq) => {
+                match room.relayed_seq(origin, *seq) {
+                    Some(local_seq) => *seq = local_seq,
+                    None => return Ok(()),
+                }
+            }
+            _ => {}
+        }
+        for room_user_name in &room.users {
+            if let Some(user) = self.users.get(room_user_name) {
+                let _ = user.send(message.clone());
+            }
+        }
+        Ok(())
+    }
+
+    /// Silence a user in a room. Only the room owner may mute.
+    pub fn mute_user(
+        &mut self,
+        room_name: &str,
+        owner_name: &str,
+        user_name: &str,
+    ) -> Result<(), ServerError> {
+        let room = self.owned_room_member(room_name, owner_name, user_name)?;
+        // muting is for silencing others
+        if user_name == owner_name {
+            return Err(ServerError::CannotMuteSelf(room_name.to_string()));
+        }
+        room.muted.insert(user_name.to_string());
+        Ok(())
+    }
+
+    /// Let a user join a room once, even while it is invite-only, and tell them so. Only the room owner may
+    /// invite.
+    pub fn invite_user(
+        &mut self,
+        room_name: &str,
+        owner_name: &str,
+        user_name: &str,
+    ) -> Result<(), ServerError> {
+        if !self.users.contains_key(user_name) {
+            return Err(ServerError::UserUnknown(user_name.to_string()));
+        }
+        let room = self.owned_room_member(room_name, owner_name, owner_name)?;
+        if room.users.contains(user_name) {
+            return Err(ServerError::UserAlreadyInRoom(
+                user_name.to_string(),
+                room_name.to_string(),
+            ));
+        }
+        room.invited.insert(user_name.to_string());
+        if let Some(user) = self.users.get(user_name) {
+            let _ = user.send(OutgoingMsg::Notice(format!(
+                "{} invited you to {}",
+                owner_name, room_name
+            )));
+        }
+        Ok(())
+    }
+
+    /// Let a muted user speak in a room again. Only the room owner may unmute.
+    pub fn unmute_user(
+        &mut self,
+        room_name: &str,
+        owner_name: &str,
+        user_name: &str,
+    ) -> Result<(), ServerError> {
+        let room = self.owned_room_member(room_name, owner_name, user_name)?;
+        room.muted.remove(user_name);
+        Ok(())
+    }
+
+    /// Let only the owner speak in a room (`true`) or everyone again (`false`, the default). Only the room
+    /// owner may change it.
+    pub fn set_readonly(
+        &mut self,
+        room_name: &str,
+        owner_name: &str,
+        readonly: bool,
+    ) -> Result<(), ServerError> {
+        let room = self.owned_room_member(room_name, owner_name, owner_name)?;
+        room.readonly = readonly;
+        Ok(())
+    }
+
+    /// Start (`true`) or stop (`false`) sending a room's owner a MEMBER message, with the user's status, for
+    /// every join and leave. Only the owner may.
+    pub fn set_owner_notices(
+        &mut self,
+        room_name: &str,
+        owner_name: &str,
+        on: bool,
+    ) -> Result<(), ServerError> {
+        let room = self.owned_room_member(room_name, owner_name, owner_name)?;
+        room.owner_notices = on;
+        Ok(())
+    }
+
+    /// Tell a room's owner, if they asked with MEMBERSHIP, that a user joined or left the room.
+    fn notify_owner(&self, room_name: &str, user_name: &str, joined: bool) {
+        let Some(room) = self.rooms.get(room_name) else {
+            return;
+        };
+        let Some(owner) = room.owner.as_ref().filter(|_| room.owner_notices) else {
+            return;
+        };
+        if let Some(owner) = self.users.get(owner) {
+            let status = self
+                .users
+                .get(user_name)
+                .and_then(|user| user.status.clone());
+            let member = OutgoingMsg::Member(
+                room_name.to_string(),
+                user_name.to_string(),
+                joined,
+                room.users.len(),
+                status,
+            );
+            // a closed channel means the owner is on their way out
+            let _ = owner.send(member);
+        }
+    }
+
+    /// Choose how long a room may go without a message before it is archived. `None`, the default, never
+    /// archives rooms.
+    pub fn set_archive_after(&mut self, archive_after: Option<Duration>) {
+        self.archive_after = archive_after;
+    }
+
+    /// Archive every room that has gone without a message for the archive period and tell its members.
+    /// Rooms with nobody in them or no owner are left alone, since nobody could unarchive them but an
+    /// operator. Returns the rooms that were archived, sorted by name.
+    pub fn archive_idle_rooms(&mut self) -> Vec<String> {
+        let Some(archive_after) = self.archive_after else {
+            return vec![];
+        };
+        let mut archived = vec![];
+        for (room_name, room) in self.rooms.iter_mut() {
+            if room.archived
+                || room.owner.is_none()
+                || room.users.is_empty()
+                || room.last_message.elapsed() < archive_after
+            {
+                continue;
+            }
+            room.archived = true;
+            let notice = OutgoingMsg::Notice(format!(
+                "{} archived after {} seconds without messages",
+                room_name,
+                archive_after.as_secs()
+            ));
+            for room_user_name in &room.users {
+                if let Some(user) = self.users.get(room_user_name) {
+                    let _ = user.send(notice.clone());
+                }
+            }
+            archived.push(room_name.clone());
+        }
+        archived.sort();
+        if !archived.is_empty() {
+            self.save_archived();
+        }
+        archived
+    }
+
+    /// Bring an archived room back so its members can speak and others can join. Only the owner may, or an
+    /// operator, who can also bring back rooms that have lost their owner.
+    pub fn unarchive_room(&mut self, room_name: &str, user_name: &str) -> Result<(), ServerError> {
+        let room = if self.users.get(user_name).is_some_and(|user| user.oper) {
+            self.rooms
+                .get_mut(room_name)
+                .ok_or_else(|| ServerError::RoomUnknown(room_name.to_string()))?
+        } else {
+            self.owned_room_member(room_name, user_name, user_name)?
+        };
+        room.archived = false;
+        room.last_message = Instant::now();
+        let members: Vec<String> = room.users.iter().cloned().collect();
+        let notice = OutgoingMsg::Notice(format!("{} unarchived by {}", room_name, user_name));
+        for room_user_name in &members {
+            if let Some(user) = self.users.get(room_user_name) {
+                let _ = user.send(notice.clone());
+            }
+        }
+        self.save_archived();
+        Ok(())
+    }
+
+    /// Turn a room's mode on or off. Only the owner may.
+    pub fn set_mode(
+        &mut self,
+        room_name: &str,
+        owner_name: &str,
+        mode: RoomMode,
+        on: bool,
+    ) -> Result<(), ServerError> {
+        let room = self.owned_room_member(room_name, owner_name, owner_name)?;
+        match mode {
+            RoomMode::InviteOnly => room.invite_only = on,
+            RoomMode::Moderated => room.readonly = on,
+            RoomMode::TopicLocked => room.topic_locked = on,
+        }
+        Ok(())
+    }
+
+    /// The modes a room has set, for MODE.
+    pub fn modes(&self, room_name: &str) -> Result<OutgoingMsg, ServerError> {
+        let room = self
+            .rooms
+            .get(room_name)
+            .ok_or_else(|| ServerError::RoomUnknown(room_name.to_string()))?;
+        let mut modes = vec![];
+        if room.invite_only {
+            modes.push(RoomMode::InviteOnly);
+        }
+        if room.readonly {
+            modes.push(RoomMode::Moderated);
+        }
+        if room.topic_locked {
+            modes.push(RoomMode::TopicLocked);
+        }
+        Ok(OutgoingMsg::Mode(room_name.to_string(), modes))
+    }
+
+    /// Look up a room that `owner_name` owns and that `user_name` has joined.
+    fn owned_room_member(
+        &mut self,
+        room_name: &str,
+        owner_name: &str,
+        user_name: &str,
+    ) -> Result<&mut Room, ServerError> {
+        let room = self
+            .rooms
+            .get_mut(room_name)
+            .ok_or_else(|| ServerError::RoomUnknown(room_name.to_string()))?;
+        if !room.is_owner(owner_name) {
+            return Err(ServerError::NotRoomOwner(room_name.to_string()));
+        }
+        if !room.users.contains(user_name) {
+            return Err(ServerError::UserNotInRoom(
+                user_name.to_string(),
+                room_name.to_string(),
+            ));
+        }
+        Ok(room)
+    }
+}
+
+impl Default for ServerState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unnecessary_get_then_check)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::sync::mpsc::{self, error::TryRecvError, UnboundedReceiver};
+
+    /// A state with a user for each of `names`, and the receiving end of each user's channel.
+    fn state_with_users<'a>(
+        names: &[&'a str],
+    ) -> (
+        ServerState,
+        HashMap<&'a str, UnboundedReceiver<OutgoingMsg>>,
+    ) {
+        let mut state = ServerState::new();
+        let mut receivers = HashMap::new();
+        for name in names {
+            let (sender, receiver) = mpsc::unbounded_channel();
+            receivers.insert(*name, receiver);
+            state.add_user(name.to_string(), User::new(sender)).unwrap();
+        }
+        (state, receivers)
+    }
+
+    /// Throw away everything sent to a user so far.
+    fn drain(receiver: &mut UnboundedReceiver<OutgoingMsg>) {
+        while receiver.try_recv().is_ok() {}
+    }
+
+    #[test]
+    fn test_server_state_add_user() {
+        let mut state = ServerState::new();
+        assert!(state.users.get("@robert").is_none());
+        let (sender, _receiver) = mpsc::unbounded_channel();
+        assert!(state
+            .add_user("@robert".to_string(), User::new(sender))
+            .is_ok());
+        assert!(state.users.get("@robert").is_some());
+        let (sender, _receiver) = mpsc::unbounded_channel();
+        assert_eq!(
+            state.add_user("@robert".to_string(), User::new(sender)),
+            Err(ServerError::UserAlreadyExists("@robert".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_server_state_remove_user() {
+        let mut state = ServerState::new();
+        let (sender, _receiver) = mpsc::unbounded_channel();
+        assert!(state
+            .add_user("@robert".to_string(), User::new(sender))
+            .is_ok());
+        assert!(state.users.get("@robert").is_some());
+        assert!(state.remove_user("@robert").is_ok());
+        assert!(state.users.get("@robert").is_none());
+        assert_eq!(
+            state.remove_user("@robert"),
+            Err(ServerError::UserUnknown("@robert".to_string()))
+        );
+
+        // a room that can't be left doesn't keep the user around, and the other rooms are still left
+        let (sender, _receiver) = mpsc::unbounded_channel();
+        state
+            .add_user("@robert".to_string(), User::new(sender))
+            .unwrap();
+        state
+            .join_room("#sports".to_string(), "@robert".to_string())
+            .unwrap();
+        state
+            .users
+            .get_mut("@robert")
+            .unwrap()
+            .add_room("#gone".to_string());
+        assert!(state.remove_user("@robert").is_ok());
+        assert!(!state.users.contains_key("@robert"));
+        assert!(!state.rooms.contains_key("#sports"));
+
+        // nor does an owner whose connection is already closed
+        let (sender, receiver) = mpsc::unbounded_channel();
+        state
+            .add_user("@kelsey".to_string(), User::new(sender))
+            .unwrap();
+        let (sender, _receiver) = mpsc::unbounded_channel();
+        state
+            .add_user("@robert".to_string(), User::new(sender))
+            .unwrap();
+        for name in ["@kelsey", "@robert"] {
+            state
+                .join_room("#sports".to_string(), name.to_string())
+                .unwrap();
+        }
+        state.set_owner_notices("#sports", "@kelsey", true).unwrap();
+        drop(receiver);
+        assert!(state.remove_user("@robert").is_ok());
+        assert!(!state.users.contains_key("@robert"));
+    }
+
+    #[test]
+    fn test_server_state_closed_channel() {
+        let mut state = ServerState::new();
+        state.set_history_size(10);
+        // @robert's connection is closing but they haven't been removed yet
+        let (sender, receiver) = mpsc::unbounded_channel();
+        state
+            .add_user("@robert".to_string(), User::new(sender))
+            .unwrap();
+        state.set_watching("@robert", true).unwrap();
+        state.set_echo_room("@robert", true).unwrap();
+        state.add_buddy("@robert", "@kelsey").unwrap();
+        state
+            .join_room("#sports".to_string(), "@robert".to_string())
+            .unwrap();
+        state
+            .say_to_room("@robert", "#sports", "one".to_string())
+            .unwrap();
+        drop(receiver);
+
+        // nothing sent to them gets in the way of anyone else
+        let (sender, mut kelsey_receiver) = mpsc::unbounded_channel();
+        state
+            .add_user("@kelsey".to_string(), User::new(sender))
+            .unwrap();
+        state
+            .join_room("#sports".to_string(), "@kelsey".to_string())
+            .unwrap();
+        state
+            .say_to_room("@kelsey", "#sports", "two".to_string())
+            .unwrap();
+        state
+            .say_to_room("@robert", "#sports", "three".to_string())
+            .unwrap();
+        assert!(
+            std::iter::from_fn(|| kelsey_receiver.try_recv().ok()).any(|message| message
+                == OutgoingMsg::SaidRoom(
+                    "#sports".to_string(),
+                    "@robert".to_string(),
+                    3,
+                    "three".to_string()
+                ))
+        );
+        assert!(state.remove_user("@robert").is_ok());
+    }
+
+    #[test]
+    fn test_server_state_remove_connection() {
+        let mut state = ServerState::new();
+        state.set_oper_password(Some("hunter2".to_string()));
+        let (sender, _oper_receiver) = mpsc::unbounded_channel();
+        state
+            .add_user("@oper".to_string(), User::new(sender))
+            .unwrap();
+        state.oper("@oper", "hunter2").unwrap();
+        let (sender, _old_receiver) = mpsc::unbounded_channel();
+        state
+            .add_user("@robert".to_string(), User::new(sender).on_connection(1))
+            .unwrap();
+
+        // the killed user's name is taken by a new connection before the old one closes
+        state.kill("@oper", "@robert", "bye".to_string()).unwrap();
+        let (sender, _new_receiver) = mpsc::unbounded_channel();
+        state
+            .add_user("@robert".to_string(), User::new(sender).on_connection(2))
+            .unwrap();
+        assert_eq!(state.remove_connection("@robert", 1), Ok(()));
+        assert!(state.users.contains_key("@robert"));
+
+        assert_eq!(state.remove_connection("@robert", 2), Ok(()));
+        assert!(!state.users.contains_key("@robert"));
+    }
+
+    #[tokio::test]
+    async fn test_server_state_join_room() {
+        let mut state = ServerState::new();
+        let (sender, mut robert_receiver) = mpsc::unbounded_channel();
+        assert!(state
+            .add_user("@robert".to_string(), User::new(sender))
+            .is_ok());
+
+        // join room that does not exist with user that exists
+        assert!(!state.rooms.contains_key("#testroom"));
+        assert!(state
+            .join_room("#testroom".to_string(), "@robert".to_string())
+            .is_ok());
+        assert!(state.rooms.contains_key("#testroom"));
+        assert!(state
+            .users
             .get("@robert")
             .unwrap()
             .rooms
@@ -346,358 +2517,2988 @@ mod tests {
             .contains("#testroom"));
 
         assert_eq!(
-            Some(OutgoingMsg::Joined(
-                "#testroom".to_string(),
-                "@kelsey".to_string()
-            )),
-            robert_receiver.recv().await
+            Some(OutgoingMsg::Joined(
+                "#testroom".to_string(),
+                "@kelsey".to_string()
+            )),
+            robert_receiver.recv().await
+        );
+
+        // join room that does not exist with user that does not exist
+        assert_eq!(
+            state.join_room("#none".to_string(), "@notreal".to_string()),
+            Err(ServerError::UserUnknown("@notreal".to_string()))
+        );
+
+        // join room that exists with user that does not exist
+        assert_eq!(
+            state.join_room("#testroom".to_string(), "@fakey".to_string()),
+            Err(ServerError::UserUnknown("@fakey".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_server_state_leave_room() {
+        let mut state = ServerState::new();
+        let (sender, mut robert_receiver) = mpsc::unbounded_channel();
+        assert!(state
+            .add_user("@robert".to_string(), User::new(sender))
+            .is_ok());
+        assert!(state
+            .join_room("#testroom".to_string(), "@robert".to_string())
+            .is_ok());
+        assert!(state.rooms.contains_key("#testroom"));
+        assert!(state
+            .users
+            .get("@robert")
+            .unwrap()
+            .rooms
+            .contains("#testroom"));
+
+        // check the messages sent to existing users
+        let (sender, _receiver) = mpsc::unbounded_channel();
+        assert!(state
+            .add_user("@kelsey".to_string(), User::new(sender))
+            .is_ok());
+        assert!(state
+            .join_room("#testroom".to_string(), "@kelsey".to_string())
+            .is_ok());
+        assert_eq!(
+            Some(OutgoingMsg::Joined(
+                "#testroom".to_string(),
+                "@kelsey".to_string()
+            )),
+            robert_receiver.recv().await
+        );
+        assert!(state.leave_room("#testroom", "@kelsey").is_ok());
+        assert_eq!(
+            Some(OutgoingMsg::Left(
+                "#testroom".to_string(),
+                "@kelsey".to_string()
+            )),
+            robert_receiver.recv().await
+        );
+
+        assert!(state.leave_room("#testroom", "@robert").is_ok());
+        assert!(!state
+            .users
+            .get("@robert")
+            .unwrap()
+            .rooms
+            .contains("#testroom"));
+
+        // last user to leave room makes room go away
+        assert!(!state.rooms.contains_key("#testroom"));
+
+        // room does not exist
+        assert_eq!(
+            state.leave_room("#fakeroom", "@robert"),
+            Err(ServerError::RoomUnknown("#fakeroom".to_string()))
+        );
+
+        // user does not exist
+        assert!(state
+            .join_room("#testroom".to_string(), "@robert".to_string())
+            .is_ok());
+
+        assert_eq!(
+            state.leave_room("#testroom", "@kelsey"),
+            Err(ServerError::UserNotInRoom(
+                "@kelsey".to_string(),
+                "#testroom".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_server_state_remove_user_leaves_room() {
+        let mut state = ServerState::new();
+        let (sender, _receiver) = mpsc::unbounded_channel();
+        assert!(state
+            .add_user("@kelsey".to_string(), User::new(sender))
+            .is_ok());
+        assert!(state
+            .join_room("#applejuice".to_string(), "@kelsey".to_string())
+            .is_ok());
+        assert!(state.rooms.contains_key("#applejuice"));
+        assert!(state.users.contains_key("@kelsey"));
+        assert!(state.remove_user("@kelsey").is_ok());
+        assert!(!state.rooms.contains_key("#applejuice"));
+        assert!(!state.users.contains_key("@kelsey"));
+    }
+
+    #[test]
+    fn test_server_state_rename_user() {
+        let mut state = ServerState::new();
+        let (sender, _receiver) = mpsc::unbounded_channel();
+        assert!(state
+            .add_user("@kelsey".to_string(), User::new(sender))
+            .is_ok());
+        assert!(state
+            .join_room("#applejuice".to_string(), "@kelsey".to_string())
+            .is_ok());
+        assert!(state
+            .join_room("#testing123".to_string(), "@kelsey".to_string())
+            .is_ok());
+
+        // initial state
+        assert!(state.rooms.contains_key("#applejuice"));
+        assert!(state.rooms.contains_key("#testing123"));
+
+        assert!(state
+            .users
+            .get("@kelsey")
+            .unwrap()
+            .rooms
+            .contains("#applejuice"));
+        assert!(state
+            .users
+            .get("@kelsey")
+            .unwrap()
+            .rooms
+            .contains("#testing123"));
+
+        assert!(state
+            .rooms
+            .get("#applejuice")
+            .unwrap()
+            .users
+            .contains("@kelsey"));
+        assert!(state
+            .rooms
+            .get("#testing123")
+            .unwrap()
+            .users
+            .contains("@kelsey"));
+
+        // renamed state
+        assert!(state.rename_user("@kelsey", "@littleb1t").is_ok());
+
+        assert!(state.users.get("@kelsey").is_none());
+        assert!(state
+            .users
+            .get("@littleb1t")
+            .unwrap()
+            .rooms
+            .contains("#applejuice"));
+        assert!(state
+            .users
+            .get("@littleb1t")
+            .unwrap()
+            .rooms
+            .contains("#testing123"));
+
+        assert!(state
+            .rooms
+            .get("#applejuice")
+            .unwrap()
+            .users
+            .contains("@littleb1t"));
+        assert!(state
+            .rooms
+            .get("#testing123")
+            .unwrap()
+            .users
+            .contains("@littleb1t"));
+    }
+
+    #[test]
+    fn test_server_state_rename_keeps_room_membership() {
+        let (mut state, mut receivers) = state_with_users(&["@robert", "@kelsey"]);
+        for name in ["@robert", "@kelsey"] {
+            assert!(state
+                .join_room("#sports".to_string(), name.to_string())
+                .is_ok());
+        }
+        let mut robert_receiver = receivers.remove("@robert").unwrap();
+        drain(&mut robert_receiver);
+        state
+            .rooms
+            .get_mut("#sports")
+            .unwrap()
+            .muted
+            .insert("@robert".to_string());
+
+        // a renamed member still gets the room's messages, and stays muted, under the new name
+        assert!(state.rename_user("@robert", "@bob").is_ok());
+        assert_eq!(
+            state.room_delivery("@kelsey", "#sports"),
+            Ok(RoomDelivery::Recipients(vec!["@bob".to_string()]))
+        );
+        assert!(state
+            .say_to_room("@kelsey", "#sports", "welcome back".to_string())
+            .is_ok());
+        assert!(matches!(
+            robert_receiver.try_recv(),
+            Ok(OutgoingMsg::SaidRoom(room, from, _, message))
+                if room == "#sports" && from == "@kelsey" && message == "welcome back"
+        ));
+
+        let room = &state.rooms["#sports"];
+        assert!(room.users.contains("@bob"));
+        assert!(!room.users.contains("@robert"));
+        assert!(room.is_muted("@bob"));
+        assert!(!room.is_muted("@robert"));
+
+        // a rename to the same name keeps the user in the room
+        assert!(state.rename_user("@bob", "@bob").is_ok());
+        assert!(state.rooms["#sports"].users.contains("@bob"));
+    }
+
+    #[test]
+    fn test_server_state_topic_history() {
+        let (mut state, mut receivers) = state_with_users(&["@robert", "@kelsey", "@dave"]);
+        let mut robert_receiver = receivers.remove("@robert").unwrap();
+        let mut kelsey_receiver = receivers.remove("@kelsey").unwrap();
+        for name in ["@robert", "@kelsey"] {
+            assert!(state
+                .join_room("#sports".to_string(), name.to_string())
+                .is_ok());
+        }
+        drain(&mut robert_receiver);
+        assert_eq!(state.topic_history("@robert", "#sports"), Ok(vec![]));
+
+        let changes = [
+            ("@robert", "pregame"),
+            ("@kelsey", "the big game"),
+            ("@robert", "postgame"),
+        ];
+        for (setter, topic) in changes {
+            assert!(state
+                .set_topic(setter, "#sports", topic.to_string())
+                .is_ok());
+            // every member hears about each change
+            assert_eq!(
+                robert_receiver.try_recv(),
+                Ok(OutgoingMsg::Topic(
+                    "#sports".to_string(),
+                    setter.to_string(),
+                    topic.to_string()
+                ))
+            );
+        }
+
+        // oldest first, with who set each one
+        let history = state.topic_history("@kelsey", "#sports").unwrap();
+        let seen: Vec<_> = history
+            .iter()
+            .map(|message| match message {
+                OutgoingMsg::TopicHistory(_, room, setter, topic) => {
+                    assert_eq!(room, "#sports");
+                    (setter.as_str(), topic.as_str())
+                }
+                other => panic!("unexpected {:?}", other),
+            })
+            .collect();
+        assert_eq!(seen, changes);
+
+        // only members may set or read the topic
+        assert_eq!(
+            state.topic_history("@dave", "#sports"),
+            Err(ServerError::UserNotInRoom(
+                "@dave".to_string(),
+                "#sports".to_string()
+            ))
+        );
+        assert_eq!(
+            state.set_topic("@dave", "#sports", "hijacked".to_string()),
+            Err(ServerError::UserNotInRoom(
+                "@dave".to_string(),
+                "#sports".to_string()
+            ))
+        );
+        assert_eq!(
+            state.topic_history("@dave", "#nothere"),
+            Err(ServerError::RoomUnknown("#nothere".to_string()))
+        );
+
+        // the history is capped, dropping the oldest
+        for n in 0..TOPIC_HISTORY_SIZE {
+            assert!(state
+                .set_topic("@kelsey", "#sports", format!("topic {}", n))
+                .is_ok());
+        }
+        let history = state.topic_history("@robert", "#sports").unwrap();
+        assert_eq!(history.len(), TOPIC_HISTORY_SIZE);
+        assert!(matches!(
+            &history[0],
+            OutgoingMsg::TopicHistory(_, _, _, topic) if topic == "topic 0"
+        ));
+
+        // a muted member can't change the topic any more than they can speak
+        state.mute_user("#sports", "@robert", "@kelsey").unwrap();
+        drain(&mut kelsey_receiver);
+        drain(&mut robert_receiver);
+        assert_eq!(
+            state.set_topic("@kelsey", "#sports", "muted".to_string()),
+            Ok(())
+        );
+        assert_eq!(
+            kelsey_receiver.try_recv(),
+            Ok(OutgoingMsg::Notice("you are muted in #sports".to_string()))
+        );
+        assert_eq!(robert_receiver.try_recv(), Err(TryRecvError::Empty));
+        let history = state.topic_history("@robert", "#sports").unwrap();
+        assert!(matches!(
+            history.last(),
+            Some(OutgoingMsg::TopicHistory(_, _, _, topic))
+                if *topic == format!("topic {}", TOPIC_HISTORY_SIZE - 1)
+        ));
+    }
+
+    #[test]
+    fn test_server_state_rename_user_bad() {
+        let mut state = ServerState::new();
+        assert_eq!(
+            state.rename_user("@robert", "@bobert"),
+            Err(ServerError::UserUnknown("@robert".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_server_state_rooms() {
+        let mut state = ServerState::new();
+        let (sender, _receiver) = mpsc::unbounded_channel();
+        assert!(state
+            .add_user("@kelsey".to_string(), User::new(sender))
+            .is_ok());
+        assert!(state
+            .join_room("#applejuice".to_string(), "@kelsey".to_string())
+            .is_ok());
+        assert!(state
+            .join_room("#testing123".to_string(), "@kelsey".to_string())
+            .is_ok());
+        assert!(state
+            .join_room("#room_123".to_string(), "@kelsey".to_string())
+            .is_ok());
+
+        // sorted by name, not in the order they were created
+        assert_eq!(
+            state.rooms(),
+            vec!["#applejuice", "#room_123", "#testing123"]
+        );
+    }
+
+    #[test]
+    fn test_server_state_users() {
+        let mut state = ServerState::new();
+        let (sender, _receiver) = mpsc::unbounded_channel();
+        assert!(state
+            .add_user("@kelsey".to_string(), User::new(sender))
+            .is_ok());
+        assert!(state
+            .join_room("#applejuice".to_string(), "@kelsey".to_string())
+            .is_ok());
+        let (sender, _receiver) = mpsc::unbounded_channel();
+        assert!(state
+            .add_user("@robert".to_string(), User::new(sender))
+            .is_ok());
+        assert!(state
+            .join_room("#applejuice".to_string(), "@robert".to_string())
+            .is_ok());
+        let (sender, _receiver) = mpsc::unbounded_channel();
+        assert!(state
+            .add_user("@ch4ch4".to_string(), User::new(sender))
+            .is_ok());
+        assert!(state
+            .join_room("#applejuice".to_string(), "@ch4ch4".to_string())
+            .is_ok());
+
+        // sorted by name, not in the order they joined
+        assert_eq!(
+            state.users("#applejuice").unwrap(),
+            vec!["@ch4ch4", "@kelsey", "@robert"]
+        );
+
+        assert_eq!(
+            state.users("#notrealroom"),
+            Err(ServerError::RoomUnknown("#notrealroom".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_server_state_say_to_user() {
+        let (mut state, mut receivers) = state_with_users(&["@kelsey", "@robert"]);
+        let mut receiver_kelsey = receivers.remove("@kelsey").unwrap();
+
+        assert_eq!(Err(TryRecvError::Empty), receiver_kelsey.try_recv());
+
+        assert_eq!(
+            state.say_to_user(
+                "@robert",
+                "@kelsey",
+                "hi there! how are you?".to_string(),
+                false
+            ),
+            Ok(OutgoingMsg::Delivered("@kelsey".to_string(), vec![]))
+        );
+
+        assert_eq!(
+            Some(OutgoingMsg::SaidUser(
+                "@robert".to_string(),
+                "hi there! how are you?".to_string(),
+                false
+            )),
+            receiver_kelsey.recv().await
+        );
+
+        assert_eq!(
+            state.say_to_user("@robert", "@notreal", "uhoh!!!!??!!".to_string(), false),
+            Err(ServerError::UserUnknown("@notreal".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_server_state_say_to_user_urgent() {
+        let (mut state, mut receivers) = state_with_users(&["@kelsey", "@robert"]);
+        let mut receiver_kelsey = receivers.remove("@kelsey").unwrap();
+
+        // the urgent flag reaches the recipient
+        assert!(state
+            .say_to_user("@robert", "@kelsey", "wake up".to_string(), true)
+            .is_ok());
+        assert_eq!(
+            receiver_kelsey.try_recv(),
+            Ok(OutgoingMsg::SaidUser(
+                "@robert".to_string(),
+                "wake up".to_string(),
+                true
+            ))
+        );
+
+        // do not disturb refuses everything by default
+        assert!(state.set_dnd("@kelsey", true).is_ok());
+        for urgent in [false, true] {
+            assert_eq!(
+                state.say_to_user("@robert", "@kelsey", "wake up".to_string(), urgent),
+                Err(ServerError::UserDoNotDisturb("@kelsey".to_string()))
+            );
+        }
+        assert_eq!(receiver_kelsey.try_recv(), Err(TryRecvError::Empty));
+
+        // unless the server lets urgent messages through
+        state.set_urgent_bypasses_dnd(true);
+        assert_eq!(
+            state.say_to_user("@robert", "@kelsey", "hi".to_string(), false),
+            Err(ServerError::UserDoNotDisturb("@kelsey".to_string()))
+        );
+        assert!(state
+            .say_to_user("@robert", "@kelsey", "wake up".to_string(), true)
+            .is_ok());
+        assert_eq!(
+            receiver_kelsey.try_recv(),
+            Ok(OutgoingMsg::SaidUser(
+                "@robert".to_string(),
+                "wake up".to_string(),
+                true
+            ))
+        );
+    }
+
+    #[test]
+    fn test_server_state_say_to_user_shared_rooms() {
+        let (mut state, _receivers) = state_with_users(&["@kelsey", "@robert"]);
+        for (room, name) in [
+            ("#sports", "@kelsey"),
+            ("#sports", "@robert"),
+            ("#news", "@kelsey"),
+            ("#news", "@robert"),
+            ("#cooking", "@kelsey"),
+            ("#movies", "@robert"),
+        ] {
+            assert!(state.join_room(room.to_string(), name.to_string()).is_ok());
+        }
+
+        // shared rooms are only listed when the server opts in
+        assert_eq!(
+            state.say_to_user("@robert", "@kelsey", "hi".to_string(), false),
+            Ok(OutgoingMsg::Delivered("@kelsey".to_string(), vec![]))
+        );
+        state.set_shared_rooms_on_delivery(true);
+        assert_eq!(
+            state.say_to_user("@robert", "@kelsey", "hi".to_string(), false),
+            Ok(OutgoingMsg::Delivered(
+                "@kelsey".to_string(),
+                vec!["#news".to_string(), "#sports".to_string()]
+            ))
+        );
+        assert_eq!(
+            OutgoingMsg::Delivered(
+                "@kelsey".to_string(),
+                vec!["#news".to_string(), "#sports".to_string()]
+            )
+            .to_string(),
+            "DELIVERED @kelsey #news #sports"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_server_state_say_to_room() {
+        let (mut state, mut receivers) = state_with_users(&["@kelsey", "@robert", "@dave"]);
+        let mut receiver_kelsey = receivers.remove("@kelsey").unwrap();
+        let mut receiver_robert = receivers.remove("@robert").unwrap();
+        let mut receiver_dave = receivers.remove("@dave").unwrap();
+
+        assert!(state
+            .join_room("#testroom".to_string(), "@kelsey".to_string())
+            .is_ok());
+        assert!(state
+            .join_room("#testroom".to_string(), "@robert".to_string())
+            .is_ok());
+        assert!(state
+            .join_room("#testroom".to_string(), "@dave".to_string())
+            .is_ok());
+
+        assert!(state
+            .say_to_room("@dave", "#testroom", "hello my room friends!".to_string())
+            .is_ok());
+
+        // dont send room message to self
+        assert_eq!(Err(TryRecvError::Empty), receiver_dave.try_recv());
+        // drop the first messages which are the JOINED's
+        receiver_kelsey.recv().await;
+        receiver_kelsey.recv().await;
+        assert_eq!(
+            Some(OutgoingMsg::SaidRoom(
+                "#testroom".to_string(),
+                "@dave".to_string(),
+                1,
+                "hello my room friends!".to_string()
+            )),
+            receiver_kelsey.recv().await
+        );
+        // drop the first message which is the JOINED
+        receiver_robert.recv().await;
+        assert_eq!(
+            Some(OutgoingMsg::SaidRoom(
+                "#testroom".to_string(),
+                "@dave".to_string(),
+                1,
+                "hello my room friends!".to_string()
+            )),
+            receiver_robert.recv().await
+        );
+
+        assert_eq!(
+            state.say_to_room("@dave", "#notreal", "hello my room friends!".to_string()),
+            Err(ServerError::RoomUnknown("#notreal".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_server_state_room_owner() {
+        let (mut state, _receivers) = state_with_users(&["@kelsey", "@robert"]);
+        assert!(state
+            .join_room("#testroom".to_string(), "@kelsey".to_string())
+            .is_ok());
+        assert!(state
+            .join_room("#testroom".to_string(), "@robert".to_string())
+            .is_ok());
+
+        // creator owns the room and keeps it through a rename
+        assert!(state.rooms.get("#testroom").unwrap().is_owner("@kelsey"));
+        assert!(state.rename_user("@kelsey", "@littleb1t").is_ok());
+        assert!(state.rooms.get("#testroom").unwrap().is_owner("@littleb1t"));
+
+        // ownership passes on when the owner leaves
+        assert!(state.leave_room("#testroom", "@littleb1t").is_ok());
+        assert!(state.rooms.get("#testroom").unwrap().is_owner("@robert"));
+    }
+
+    #[tokio::test]
+    async fn test_server_state_mute_user() {
+        let (mut state, mut receivers) = state_with_users(&["@kelsey", "@robert"]);
+        let mut receiver_kelsey = receivers.remove("@kelsey").unwrap();
+        let mut receiver_robert = receivers.remove("@robert").unwrap();
+        assert!(state
+            .join_room("#testroom".to_string(), "@kelsey".to_string())
+            .is_ok());
+        assert!(state
+            .join_room("#testroom".to_string(), "@robert".to_string())
+            .is_ok());
+        // drop the JOINED
+        receiver_kelsey.recv().await;
+
+        // only the owner can mute
+        assert_eq!(
+            state.mute_user("#testroom", "@robert", "@kelsey"),
+            Err(ServerError::NotRoomOwner("#testroom".to_string()))
+        );
+        assert_eq!(
+            state.mute_user("#testroom", "@kelsey", "@dave"),
+            Err(ServerError::UserNotInRoom(
+                "@dave".to_string(),
+                "#testroom".to_string()
+            ))
+        );
+        assert_eq!(
+            state.mute_user("#notreal", "@kelsey", "@robert"),
+            Err(ServerError::RoomUnknown("#notreal".to_string()))
+        );
+        assert_eq!(
+            state.mute_user("#testroom", "@kelsey", "@kelsey"),
+            Err(ServerError::CannotMuteSelf("#testroom".to_string()))
+        );
+        assert!(state.mute_user("#testroom", "@kelsey", "@robert").is_ok());
+
+        // muted messages go nowhere, but the muted user still hears the room
+        assert!(state
+            .say_to_room("@robert", "#testroom", "can you hear me?".to_string())
+            .is_ok());
+        assert_eq!(Err(TryRecvError::Empty), receiver_kelsey.try_recv());
+        assert_eq!(
+            Some(OutgoingMsg::Notice(
+                "you are muted in #testroom".to_string()
+            )),
+            receiver_robert.recv().await
+        );
+        assert!(state
+            .say_to_room("@kelsey", "#testroom", "nope".to_string())
+            .is_ok());
+        assert_eq!(
+            Some(OutgoingMsg::SaidRoom(
+                "#testroom".to_string(),
+                "@kelsey".to_string(),
+                1,
+                "nope".to_string()
+            )),
+            receiver_robert.recv().await
+        );
+
+        // unmute restores the user's voice
+        assert!(state.unmute_user("#testroom", "@kelsey", "@robert").is_ok());
+        assert!(state
+            .say_to_room("@robert", "#testroom", "how about now?".to_string())
+            .is_ok());
+        assert_eq!(
+            Some(OutgoingMsg::SaidRoom(
+                "#testroom".to_string(),
+                "@robert".to_string(),
+                2,
+                "how about now?".to_string()
+            )),
+            receiver_kelsey.recv().await
+        );
+    }
+
+    #[test]
+    fn test_server_state_watch_rooms() {
+        let (mut state, mut receivers) = state_with_users(&["@kelsey", "@robert"]);
+        let mut receiver_kelsey = receivers.remove("@kelsey").unwrap();
+        let mut receiver_robert = receivers.remove("@robert").unwrap();
+        assert!(state.set_watching("@kelsey", true).is_ok());
+        assert_eq!(
+            state.set_watching("@nobody", true),
+            Err(ServerError::UserUnknown("@nobody".to_string()))
+        );
+
+        // creation fires on the first join only
+        assert!(state
+            .join_room("#sports".to_string(), "@robert".to_string())
+            .is_ok());
+        assert_eq!(
+            receiver_kelsey.try_recv(),
+            Ok(OutgoingMsg::RoomCreated("#sports".to_string()))
+        );
+        assert!(state
+            .join_room("#sports".to_string(), "@kelsey".to_string())
+            .is_ok());
+        assert_eq!(receiver_kelsey.try_recv(), Err(TryRecvError::Empty));
+
+        // deletion fires on the last leave only
+        assert!(state.leave_room("#sports", "@robert").is_ok());
+        assert_eq!(
+            receiver_kelsey.try_recv(),
+            Ok(OutgoingMsg::Left(
+                "#sports".to_string(),
+                "@robert".to_string()
+            ))
+        );
+        assert!(state.leave_room("#sports", "@kelsey").is_ok());
+        assert_eq!(
+            receiver_kelsey.try_recv(),
+            Ok(OutgoingMsg::RoomDeleted("#sports".to_string()))
+        );
+        assert_eq!(receiver_kelsey.try_recv(), Err(TryRecvError::Empty));
+
+        // users that aren't watching hear nothing about rooms they're not in
+        assert_eq!(
+            receiver_robert.try_recv(),
+            Ok(OutgoingMsg::Joined(
+                "#sports".to_string(),
+                "@kelsey".to_string()
+            ))
+        );
+        assert_eq!(receiver_robert.try_recv(), Err(TryRecvError::Empty));
+
+        assert!(state.set_watching("@kelsey", false).is_ok());
+        assert!(state
+            .join_room("#news".to_string(), "@robert".to_string())
+            .is_ok());
+        assert_eq!(receiver_kelsey.try_recv(), Err(TryRecvError::Empty));
+    }
+
+    #[test]
+    fn test_server_state_watch_online() {
+        let mut state = ServerState::new();
+        let (sender, mut receiver_kelsey) = mpsc::unbounded_channel();
+        assert!(state
+            .add_user("@kelsey".to_string(), User::new(sender))
+            .is_ok());
+        assert!(state.set_watching("@kelsey", true).is_ok());
+        for room in ["#sports", "#news"] {
+            assert!(state
+                .join_room(room.to_string(), "@kelsey".to_string())
+                .is_ok());
+        }
+        drain(&mut receiver_kelsey);
+
+        let (sender, _receiver_robert) = mpsc::unbounded_channel();
+        assert!(state
+            .add_user("@robert".to_string(), User::new(sender))
+            .is_ok());
+        assert_eq!(
+            receiver_kelsey.try_recv(),
+            Ok(OutgoingMsg::Online("@robert".to_string()))
+        );
+
+        // joining shared rooms sends the usual JOINED but no more presence events
+        for room in ["#sports", "#news"] {
+            assert!(state
+                .join_room(room.to_string(), "@robert".to_string())
+                .is_ok());
+        }
+        let rest: Vec<OutgoingMsg> =
+            std::iter::from_fn(|| receiver_kelsey.try_recv().ok()).collect();
+        assert_eq!(
+            rest,
+            vec![
+                OutgoingMsg::Joined("#sports".to_string(), "@robert".to_string()),
+                OutgoingMsg::Joined("#news".to_string(), "@robert".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_server_state_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let motd_file = dir.path().join("motd.txt");
+        let reserved_names_file = dir.path().join("reserved.txt");
+        fs::write(&motd_file, "welcome\nbe nice").unwrap();
+        fs::write(&reserved_names_file, "@admin\n\n@root\n").unwrap();
+
+        let mut state = ServerState::new();
+        assert!(state
+            .set_reloadable_files(Some(motd_file.clone()), Some(reserved_names_file.clone()))
+            .is_ok());
+        assert_eq!(state.motd(), ["welcome", "be nice"]);
+        let (sender, _receiver) = mpsc::unbounded_channel();
+        assert_eq!(
+            state.add_user("@admin".to_string(), User::new(sender.clone())),
+            Err(ServerError::NameReserved("@admin".to_string()))
+        );
+        assert!(state
+            .add_user("@robert".to_string(), User::new(sender.clone()))
+            .is_ok());
+        assert_eq!(
+            state.rename_user("@robert", "@root"),
+            Err(ServerError::NameReserved("@root".to_string()))
+        );
+
+        fs::write(&motd_file, "new motd").unwrap();
+        fs::write(&reserved_names_file, "@root").unwrap();
+        assert!(state.reload().is_ok());
+        assert_eq!(state.motd(), ["new motd"]);
+        assert!(state
+            .add_user("@admin".to_string(), User::new(sender))
+            .is_ok());
+
+        // a missing file leaves everything as it was
+        fs::remove_file(&reserved_names_file).unwrap();
+        fs::write(&motd_file, "newer motd").unwrap();
+        assert!(state.reload().is_err());
+        assert_eq!(state.motd(), ["new motd"]);
+    }
+
+    #[test]
+    fn test_server_state_store_rooms() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.json");
+
+        let mut state = ServerState::new();
+        state.set_persistent_rooms(true);
+        assert!(state
+            .set_store(Box::new(crate::store::FileStore::open(&path).unwrap()))
+            .is_ok());
+        let (sender, _receiver) = mpsc::unbounded_channel();
+        assert!(state
+            .add_user("@kelsey".to_string(), User::new(sender))
+            .is_ok());
+        for room_name in ["#sports", "#news"] {
+            assert!(state
+                .join_room(room_name.to_string(), "@kelsey".to_string())
+                .is_ok());
+        }
+
+        // a restarted server gets the rooms back, empty and without an owner
+        drop(state);
+        let mut state = ServerState::new();
+        state.set_persistent_rooms(true);
+        assert!(state
+            .set_store(Box::new(crate::store::FileStore::open(&path).unwrap()))
+            .is_ok());
+        assert_eq!(state.rooms(), vec!["#news", "#sports"]);
+        assert_eq!(state.room_snapshot("#sports").unwrap().owner, None);
+    }
+
+    #[test]
+    fn test_server_state_persistent_rooms() {
+        for persistent_rooms in [false, true] {
+            let mut state = ServerState::new();
+            state.set_persistent_rooms(persistent_rooms);
+            let (sender, _receiver) = mpsc::unbounded_channel();
+            assert!(state
+                .add_user("@kelsey".to_string(), User::new(sender))
+                .is_ok());
+            assert!(state
+                .join_room("#applejuice".to_string(), "@kelsey".to_string())
+                .is_ok());
+            assert!(state.leave_room("#applejuice", "@kelsey").is_ok());
+
+            if persistent_rooms {
+                // the empty room survives and can still be listed
+                assert_eq!(state.rooms(), vec!["#applejuice".to_string()]);
+                assert_eq!(state.users("#applejuice"), Ok(vec![]));
+                // and the next user in becomes the owner
+                assert!(state
+                    .join_room("#applejuice".to_string(), "@kelsey".to_string())
+                    .is_ok());
+                assert!(state.rooms.get("#applejuice").unwrap().is_owner("@kelsey"));
+            } else {
+                assert!(state.rooms().is_empty());
+                assert_eq!(
+                    state.users("#applejuice"),
+                    Err(ServerError::RoomUnknown("#applejuice".to_string()))
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_server_state_check_link() {
+        let mut state = ServerState::new();
+        let peer: IpAddr = "10.0.0.2".parse().unwrap();
+        // nobody may link until peers are allowed
+        assert_eq!(state.check_link(peer), Err(ServerError::LinkRefused));
+
+        state.set_link_peers(vec![peer]);
+        assert_eq!(state.check_link(peer), Ok(()));
+        assert_eq!(
+            state.check_link("10.0.0.3".parse().unwrap()),
+            Err(ServerError::LinkRefused)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_server_state_links() {
+        let mut state = ServerState::new();
+        let (sender_kelsey, mut receiver_kelsey) = mpsc::unbounded_channel();
+        assert!(state
+            .add_user("@kelsey".to_string(), User::new(sender_kelsey))
+            .is_ok());
+        let (sender_link, mut receiver_link) = mpsc::unbounded_channel();
+        state.add_link("#testroom".to_string(), sender_link.clone());
+        assert!(state.is_linked("#testroom"));
+
+        // local events are relayed
+        assert!(state
+            .join_room("#testroom".to_string(), "@kelsey".to_string())
+            .is_ok());
+        assert!(state
+            .say_to_room("@kelsey", "#testroom", "hi peers".to_string())
+            .is_ok());
+        assert_eq!(
+            Some(OutgoingMsg::Joined(
+                "#testroom".to_string(),
+                "@kelsey".to_string()
+            )),
+            receiver_link.recv().await
+        );
+        assert_eq!(
+            Some(OutgoingMsg::SaidRoom(
+                "#testroom".to_string(),
+                "@kelsey".to_string(),
+                1,
+                "hi peers".to_string()
+            )),
+            receiver_link.recv().await
+        );
+
+        // relayed events reach local members but aren't relayed again
+        let relayed = OutgoingMsg::SaidRoom(
+            "#testroom".to_string(),
+            "@robert".to_string(),
+            7,
+            "hi from afar".to_string(),
+        );
+        assert!(state.deliver_relayed("beta", relayed).is_ok());
+        // numbered after the local message, not by the peer
+        assert_eq!(
+            Some(OutgoingMsg::SaidRoom(
+                "#testroom".to_string(),
+                "@robert".to_string(),
+                2,
+                "hi from afar".to_string(),
+            )),
+            receiver_kelsey.recv().await
+        );
+        assert_eq!(Err(TryRecvError::Empty), receiver_link.try_recv());
+
+        // edits of relayed messages are numbered to match, as long as they come from the same peer
+        let edited = OutgoingMsg::Edited("#testroom".to_string(), 7, "hi again".to_string());
+        assert!(state.deliver_relayed("gamma", edited.clone()).is_ok());
+        assert!(state.deliver_relayed("beta", edited).is_ok());
+        assert_eq!(
+            Some(OutgoingMsg::Edited(
+                "#testroom".to_string(),
+                2,
+                "hi again".to_string()
+            )),
+            receiver_kelsey.recv().await
+        );
+        assert_eq!(Err(TryRecvError::Empty), receiver_link.try_recv());
+
+        state.remove_link(&sender_link);
+        assert!(!state.is_linked("#testroom"));
+    }
+
+    #[tokio::test]
+    async fn test_server_state_room_delivery() {
+        let (mut state, mut receivers) = state_with_users(&["@dave", "@kelsey", "@robert"]);
+        for name in ["@dave", "@kelsey", "@robert"] {
+            assert!(state
+                .join_room("#testroom".to_string(), name.to_string())
+                .is_ok());
+        }
+        // drop the JOINED's
+        receivers.values_mut().for_each(drain);
+
+        let delivery = state.room_delivery("@dave", "#testroom").unwrap();
+        assert_eq!(
+            delivery,
+            RoomDelivery::Recipients(vec!["@kelsey".to_string(), "@robert".to_string()])
+        );
+        // previewing sends nothing
+        for receiver in receivers.values_mut() {
+            assert_eq!(Err(TryRecvError::Empty), receiver.try_recv());
+        }
+
+        // the preview matches the real broadcast
+        assert!(state
+            .say_to_room("@dave", "#testroom", "count me".to_string())
+            .is_ok());
+        let reached = receivers
+            .values_mut()
+            .map(|receiver| receiver.try_recv())
+            .filter(Result::is_ok)
+            .count();
+        assert_eq!(delivery.to_string(), format!("{} recipients", reached));
+
+        assert!(state.mute_user("#testroom", "@dave", "@robert").is_ok());
+        assert_eq!(
+            state.room_delivery("@robert", "#testroom"),
+            Ok(RoomDelivery::Muted)
+        );
+        assert_eq!(
+            state.room_delivery("@robert", "#notreal"),
+            Err(ServerError::RoomUnknown("#notreal".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_server_state_normalized_names() {
+        let composed = "@jos\u{e9}";
+        let decomposed = "@jose\u{301}";
+        assert_ne!(composed, decomposed);
+
+        let mut state = ServerState::new();
+        let (sender, _receiver) = mpsc::unbounded_channel();
+        assert!(state
+            .add_user(decomposed.to_string(), User::new(sender))
+            .is_ok());
+        assert!(state.users.contains_key(composed));
+
+        // the other form of the same name collides on registration
+        let (sender, _receiver) = mpsc::unbounded_channel();
+        assert_eq!(
+            state.add_user(composed.to_string(), User::new(sender)),
+            Err(ServerError::UserAlreadyExists(composed.to_string()))
+        );
+
+        // and on rename
+        let (sender, _receiver) = mpsc::unbounded_channel();
+        assert!(state
+            .add_user("@robert".to_string(), User::new(sender))
+            .is_ok());
+        assert_eq!(
+            state.rename_user("@robert", decomposed),
+            Err(ServerError::UserAlreadyExists(composed.to_string()))
+        );
+        assert!(state.users.contains_key("@robert"));
+    }
+
+    #[test]
+    fn test_server_state_room_snapshot() {
+        let (mut state, _receivers) = state_with_users(&["@robert", "@kelsey", "@dave"]);
+        for name in ["@robert", "@kelsey", "@dave"] {
+            assert!(state
+                .join_room("#testroom".to_string(), name.to_string())
+                .is_ok());
+        }
+        assert_eq!(state.room_snapshot("#testroom").unwrap().topic, None);
+        assert!(state
+            .set_topic("@dave", "#testroom", "testing".to_string())
+            .is_ok());
+        assert!(state.mute_user("#testroom", "@robert", "@kelsey").is_ok());
+
+        assert_eq!(
+            state.room_snapshot("#testroom"),
+            Ok(RoomSnapshot {
+                name: "#testroom".to_string(),
+                topic: Some("testing".to_string()),
+                members: vec![
+                    "@dave".to_string(),
+                    "@kelsey".to_string(),
+                    "@robert".to_string()
+                ],
+                owner: Some("@robert".to_string()),
+                muted: vec!["@kelsey".to_string()],
+                member_count: 3,
+                readonly: false,
+                invite_only: false,
+                archived: false,
+            })
+        );
+
+        // the snapshot follows membership changes
+        assert!(state.leave_room("#testroom", "@robert").is_ok());
+        let snapshot = state.room_snapshot("#testroom").unwrap();
+        assert_eq!(
+            snapshot.members,
+            vec!["@dave".to_string(), "@kelsey".to_string()]
+        );
+        assert_eq!(snapshot.owner, Some("@dave".to_string()));
+        assert_eq!(snapshot.member_count, 2);
+        // and always carries the latest topic
+        assert!(state
+            .set_topic("@dave", "#testroom", "still testing".to_string())
+            .is_ok());
+        assert_eq!(
+            state.room_snapshot("#testroom").unwrap().topic,
+            Some("still testing".to_string())
+        );
+
+        assert_eq!(
+            state.room_snapshot("#notreal"),
+            Err(ServerError::RoomUnknown("#notreal".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_server_state_status() {
+        let mut state = ServerState::new();
+        let (sender, _receiver) = mpsc::unbounded_channel();
+        assert!(state
+            .add_user("@kelsey".to_string(), User::new(sender))
+            .is_ok());
+        assert_eq!(
+            state.whois("@kelsey"),
+            Ok(OutgoingMsg::Status("@kelsey".to_string(), None))
+        );
+
+        assert!(state
+            .set_status("@kelsey", Some("busy grabbing coffee".to_string()))
+            .is_ok());
+        assert_eq!(
+            state.whois("@kelsey").unwrap().to_string(),
+            "STATUS @kelsey busy grabbing coffee"
+        );
+
+        assert!(state.set_status("@kelsey", None).is_ok());
+        assert_eq!(
+            state.whois("@kelsey").unwrap().to_string(),
+            "STATUS @kelsey"
+        );
+
+        assert_eq!(
+            state.whois("@notreal"),
+            Err(ServerError::UserUnknown("@notreal".to_string()))
+        );
+        assert_eq!(
+            state.set_status("@notreal", None),
+            Err(ServerError::UserUnknown("@notreal".to_string()))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_active_users() {
+        let (mut server_state, _receivers) = state_with_users(&["@robert", "@kelsey", "@sam"]);
+        for name in ["@robert", "@kelsey", "@sam"] {
+            server_state
+                .join_room("#sports".to_string(), name.to_string())
+                .unwrap();
+        }
+        assert!(server_state.active_users("#nope").is_err());
+        tokio::time::advance(Duration::from_secs(1)).await;
+        server_state.touch_user("@sam");
+        tokio::time::advance(Duration::from_secs(1)).await;
+        server_state.touch_user("@robert");
+        // @kelsey hasn't done anything since registering
+        assert_eq!(
+            server_state.active_users("#sports").unwrap(),
+            vec!["@robert", "@sam", "@kelsey"]
+        );
+        tokio::time::advance(Duration::from_secs(1)).await;
+        server_state.touch_user("@kelsey");
+        assert_eq!(
+            server_state.active_users("#sports").unwrap(),
+            vec!["@kelsey", "@robert", "@sam"]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_server_state_my_stats() {
+        let (mut state, _receivers) = state_with_users(&["@robert", "@kelsey"]);
+        assert_eq!(
+            state.my_stats("@robert").unwrap().to_string(),
+            "MYSTATS 0 0 0"
+        );
+
+        state
+            .join_room("#sports".to_string(), "@robert".to_string())
+            .unwrap();
+        state
+            .join_room("#news".to_string(), "@robert".to_string())
+            .unwrap();
+        state
+            .say_to_room("@robert", "#sports", "hi".to_string())
+            .unwrap();
+        state
+            .say_to_user("@robert", "@kelsey", "hey".to_string(), false)
+            .unwrap();
+        // messages that fail to send don't count
+        assert!(state
+            .say_to_room("@robert", "#nope", "hello?".to_string())
+            .is_err());
+        // nor do messages from a muted user, which reach nobody
+        state
+            .join_room("#sports".to_string(), "@kelsey".to_string())
+            .unwrap();
+        state.mute_user("#sports", "@robert", "@kelsey").unwrap();
+        state
+            .say_to_room("@kelsey", "#sports", "let me talk".to_string())
+            .unwrap();
+        tokio::time::advance(Duration::from_secs(90)).await;
+        assert_eq!(
+            state.my_stats("@robert"),
+            Ok(OutgoingMsg::MyStats(2, 2, Duration::from_secs(90)))
+        );
+        assert_eq!(
+            state.my_stats("@kelsey").unwrap().to_string(),
+            "MYSTATS 0 1 90"
+        );
+        assert_eq!(
+            state.my_stats("@notreal"),
+            Err(ServerError::UserUnknown("@notreal".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_server_state_say_to_room_not_joined() {
+        let (mut state, _receivers) = state_with_users(&["@robert", "@kelsey"]);
+        for name in ["@robert", "@kelsey"] {
+            state
+                .join_room("#sports".to_string(), name.to_string())
+                .unwrap();
+        }
+        state.leave_room("#sports", "@robert").unwrap();
+        assert_eq!(
+            state.say_to_room("@robert", "#sports", "hello?".to_string()),
+            Err(ServerError::UserNotInRoom(
+                "@robert".to_string(),
+                "#sports".to_string()
+            ))
+        );
+        assert_eq!(
+            state.room_delivery("@robert", "#sports"),
+            Err(ServerError::UserNotInRoom(
+                "@robert".to_string(),
+                "#sports".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_server_state_oper_and_kill() {
+        let (mut state, mut receivers) = state_with_users(&["@admin", "@robert", "@kelsey"]);
+        for name in ["@admin", "@robert", "@kelsey"] {
+            state
+                .join_room("#sports".to_string(), name.to_string())
+                .unwrap();
+        }
+        receivers.values_mut().for_each(drain);
+
+        // nobody is an operator until the password is set and given
+        assert_eq!(
+            state.oper("@admin", "hunter2"),
+            Err(ServerError::NotOperator("@admin".to_string()))
+        );
+        state.set_oper_password(Some("hunter2".to_string()));
+        assert_eq!(
+            state.oper("@admin", "wrong"),
+            Err(ServerError::NotOperator("@admin".to_string()))
+        );
+        assert_eq!(
+            state.kill("@kelsey", "@robert", "bye".to_string()),
+            Err(ServerError::NotOperator("@kelsey".to_string()))
+        );
+        assert!(state.oper("@admin", "hunter2").is_ok());
+        assert_eq!(
+            state.kill("@admin", "@nobody", "bye".to_string()),
+            Err(ServerError::UserUnknown("@nobody".to_string()))
+        );
+
+        assert!(state
+            .kill("@admin", "@robert", "spamming".to_string())
+            .is_ok());
+        assert_eq!(
+            receivers.get_mut("@robert").unwrap().try_recv(),
+            Ok(OutgoingMsg::Disconnect("spamming".to_string()))
+        );
+        // the user's channel is closed so their connection shuts down
+        assert_eq!(
+            receivers.get_mut("@robert").unwrap().try_recv(),
+            Err(mpsc::error::TryRecvError::Disconnected)
+        );
+        assert_eq!(
+            receivers.get_mut("@kelsey").unwrap().try_recv(),
+            Ok(OutgoingMsg::Left(
+                "#sports".to_string(),
+                "@robert".to_string()
+            ))
+        );
+        assert_eq!(state.users("#sports").unwrap(), ["@admin", "@kelsey"]);
+    }
+
+    #[test]
+    fn test_server_state_readonly() {
+        let (mut state, mut receivers) = state_with_users(&["@robert", "@kelsey"]);
+        for name in ["@robert", "@kelsey"] {
+            state
+                .join_room("#news".to_string(), name.to_string())
+                .unwrap();
+        }
+        // drop the JOINED
+        receivers.get_mut("@robert").unwrap().try_recv().unwrap();
+
+        // only the owner can make a room read only
+        assert_eq!(
+            state.set_readonly("#news", "@kelsey", true),
+            Err(ServerError::NotRoomOwner("#news".to_string()))
+        );
+        assert_eq!(
+            state.set_readonly("#nope", "@robert", true),
+            Err(ServerError::RoomUnknown("#nope".to_string()))
+        );
+        assert!(state.set_readonly("#news", "@robert", true).is_ok());
+        assert!(state.room_snapshot("#news").unwrap().readonly);
+
+        // the owner can still speak and everyone hears it
+        assert!(state
+            .say_to_room("@robert", "#news", "big news".to_string())
+            .is_ok());
+        assert_eq!(
+            receivers.get_mut("@kelsey").unwrap().try_recv(),
+            Ok(OutgoingMsg::SaidRoom(
+                "#news".to_string(),
+                "@robert".to_string(),
+                1,
+                "big news".to_string()
+            ))
+        );
+
+        // members are turned away and nothing is delivered
+        assert_eq!(
+            state.say_to_room("@kelsey", "#news", "wow".to_string()),
+            Err(ServerError::RoomReadOnly("#news".to_string()))
+        );
+        assert_eq!(
+            receivers.get_mut("@robert").unwrap().try_recv(),
+            Err(TryRecvError::Empty)
+        );
+
+        assert!(state.set_readonly("#news", "@robert", false).is_ok());
+        assert!(state
+            .say_to_room("@kelsey", "#news", "wow".to_string())
+            .is_ok());
+    }
+
+    #[test]
+    fn test_server_state_review_log() {
+        let (mut state, _receivers) = state_with_users(&["@admin", "@robert", "@kelsey"]);
+        state.set_oper_password(Some("hunter2".to_string()));
+        for name in ["@admin", "@robert", "@kelsey"] {
+            state
+                .join_room("#sports".to_string(), name.to_string())
+                .unwrap();
+        }
+        state.oper("@admin", "hunter2").unwrap();
+
+        // nothing is kept until the log is turned on
+        state
+            .say_to_room("@robert", "#sports", "not kept".to_string())
+            .unwrap();
+        assert_eq!(state.review("@admin"), Ok(vec![]));
+
+        state.set_review_log_size(2);
+        state
+            .say_to_room("@robert", "#sports", "first".to_string())
+            .unwrap();
+        state
+            .say_to_user("@kelsey", "@robert", "second".to_string(), false)
+            .unwrap();
+        state
+            .say_to_room("@kelsey", "#sports", "third".to_string())
+            .unwrap();
+
+        // the oldest message was pushed out
+        let review: Vec<(String, String, String)> = state
+            .review_log
+            .iter()
+            .map(|logged| {
+                (
+                    logged.to.clone(),
+                    logged.from.clone(),
+                    logged.message.clone(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            review,
+            [
+                (
+                    "@robert".to_string(),
+                    "@kelsey".to_string(),
+                    "second".to_string()
+                ),
+                (
+                    "#sports".to_string(),
+                    "@kelsey".to_string(),
+                    "third".to_string()
+                ),
+            ]
+        );
+        let messages = state.review("@admin").unwrap();
+        assert_eq!(messages.len(), 2);
+        assert!(messages[1].to_string().starts_with("REVIEW "));
+        assert!(messages[1].to_string().ends_with(" #sports @kelsey third"));
+
+        // only operators can review
+        assert_eq!(
+            state.review("@robert"),
+            Err(ServerError::NotOperator("@robert".to_string()))
+        );
+
+        // shrinking the log drops the oldest messages
+        state.set_review_log_size(1);
+        assert_eq!(state.review_log.len(), 1);
+        assert_eq!(state.review_log[0].message, "third");
+    }
+
+    #[test]
+    fn test_server_state_history_replay() {
+        let mut state = ServerState::new();
+        state.set_history_size(3);
+        let (sender, _robert_receiver) = mpsc::unbounded_channel();
+        state
+            .add_user("@robert".to_string(), User::new(sender))
+            .unwrap();
+        state
+            .join_room("#sports".to_string(), "@robert".to_string())
+            .unwrap();
+        for message in ["one", "two", "three", "four"] {
+            state
+                .say_to_room("@robert", "#sports", message.to_string())
+                .unwrap();
+        }
+
+        // keep every receiver so that later JOINEDs have somewhere to go
+        let mut receivers = vec![];
+        let mut join = |name: &str, history_length: Option<usize>| {
+            let (sender, mut receiver) = mpsc::unbounded_channel();
+            state.add_user(name.to_string(), User::new(sender)).unwrap();
+            if let Some(count) = history_length {
+                state.set_history_length(name, count).unwrap();
+            }
+            state
+                .join_room("#sports".to_string(), name.to_string())
+                .unwrap();
+            let mut replayed = vec![];
+            while let Ok(message) = receiver.try_recv() {
+                replayed.push(message.to_string());
+            }
+            receivers.push(receiver);
+            replayed
+        };
+
+        // everything the server keeps by default, oldest first
+        assert_eq!(
+            join("@kelsey", None),
+            vec![
+                "HISTORY #sports @robert SAID 2 two",
+                "HISTORY #sports @robert SAID 3 three",
+                "HISTORY #sports @robert SAID 4 four",
+            ]
+        );
+        // only the most recent messages the user asked for
+        assert_eq!(
+            join("@andrew", Some(1)),
+            vec!["HISTORY #sports @robert SAID 4 four"]
+        );
+        assert!(join("@sarah", Some(0)).is_empty());
+        // asking for more than the server keeps gets what it keeps
+        assert_eq!(join("@james", Some(100)).len(), 3);
+        assert_eq!(state.set_history_length("@james", 100), Ok(3));
+    }
+
+    #[test]
+    fn test_server_state_filtered_rooms() {
+        let mut state = ServerState::new();
+        state.set_persistent_rooms(true);
+        let (sender, _receiver) = mpsc::unbounded_channel();
+        state
+            .add_user("@robert".to_string(), User::new(sender))
+            .unwrap();
+        for room_name in ["#sports", "#music", "#news"] {
+            state
+                .join_room(room_name.to_string(), "@robert".to_string())
+                .unwrap();
+        }
+        // persistent rooms stay around once they are empty
+        state.leave_room("#music", "@robert").unwrap();
+
+        assert_eq!(
+            state.filtered_rooms(None),
+            vec!["#music", "#news", "#sports"]
+        );
+        assert_eq!(
+            state.filtered_rooms(Some(RoomFilter::Empty)),
+            vec!["#music"]
+        );
+        assert_eq!(
+            state.filtered_rooms(Some(RoomFilter::Active)),
+            vec!["#news", "#sports"]
+        );
+    }
+
+    #[test]
+    fn test_server_state_echo_room() {
+        let (mut state, mut receivers) = state_with_users(&["@robert", "@kelsey"]);
+        let mut robert_receiver = receivers.remove("@robert").unwrap();
+        let mut kelsey_receiver = receivers.remove("@kelsey").unwrap();
+        for name in ["@robert", "@kelsey"] {
+            state
+                .join_room("#sports".to_string(), name.to_string())
+                .unwrap();
+        }
+        drain(&mut robert_receiver);
+
+        // the sender hears nothing back by default
+        state
+            .say_to_room("@robert", "#sports", "quiet".to_string())
+            .unwrap();
+        assert!(robert_receiver.try_recv().is_err());
+
+        state.set_echo_room("@robert", true).unwrap();
+        state
+            .say_to_room("@robert", "#sports", "loud".to_string())
+            .unwrap();
+        assert_eq!(
+            robert_receiver
+                .try_recv()
+                .map(|message| message.to_string()),
+            Ok("ECHO #sports @robert SAID 2 loud".to_string())
+        );
+        // everyone else still gets a plain SAID
+        assert_eq!(
+            kelsey_receiver
+                .try_recv()
+                .map(|message| message.to_string()),
+            Ok("#sports @robert SAID 1 quiet".to_string())
+        );
+        assert_eq!(
+            kelsey_receiver
+                .try_recv()
+                .map(|message| message.to_string()),
+            Ok("#sports @robert SAID 2 loud".to_string())
+        );
+
+        state.set_echo_room("@robert", false).unwrap();
+        state
+            .say_to_room("@robert", "#sports", "quiet again".to_string())
+            .unwrap();
+        assert!(robert_receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_server_state_shared_rooms() {
+        let (mut state, _receivers) = state_with_users(&["@robert", "@kelsey"]);
+        for (name, rooms) in [
+            ("@robert", vec!["#sports", "#music", "#news"]),
+            ("@kelsey", vec!["#news", "#sports", "#secret"]),
+        ] {
+            for room_name in rooms {
+                state
+                    .join_room(room_name.to_string(), name.to_string())
+                    .unwrap();
+            }
+        }
+
+        // #secret is only @kelsey's, so @robert doesn't learn about it
+        assert_eq!(
+            state.shared_rooms("@robert", "@kelsey"),
+            Ok(vec!["#news".to_string(), "#sports".to_string()])
+        );
+        assert_eq!(
+            state.shared_rooms("@kelsey", "@robert"),
+            state.shared_rooms("@robert", "@kelsey")
+        );
+        assert_eq!(
+            state.shared_rooms("@robert", "@nobody"),
+            Err(ServerError::UserUnknown("@nobody".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_server_state_with_config() {
+        let config = ServerConfig::builder()
+            .server_name("alpha")
+            .persistent_rooms(true)
+            .history_size(5)
+            .rename_cooldown(std::time::Duration::from_secs(30))
+            .auto_join(vec!["#lobby".to_string()])
+            .max_pending_registrations(Some(1))
+            .build()
+            .unwrap();
+        let mut state = ServerState::with_config(&config).unwrap();
+        assert_eq!(state.server_name(), "alpha");
+        assert!(state.persistent_rooms);
+        assert_eq!(state.history_size(), 5);
+        assert_eq!(state.rename_cooldown, std::time::Duration::from_secs(30));
+        assert_eq!(state.auto_join, vec!["#lobby"]);
+        assert!(state.begin_registration());
+        assert!(!state.begin_registration());
+
+        // a file that can't be read is an error, not a silently empty MOTD
+        let config = ServerConfig::builder()
+            .motd(Some(PathBuf::from("/nonexistent/motd")))
+            .build()
+            .unwrap();
+        assert!(ServerState::with_config(&config).is_err());
+    }
+
+    #[test]
+    fn test_server_state_say_to_room_as() {
+        let (mut state, mut receivers) = state_with_users(&["@bot", "@robert", "@kelsey"]);
+        state.set_oper_password(Some("hunter2".to_string()));
+        for name in ["@robert", "@kelsey"] {
+            state
+                .join_room("#sports".to_string(), name.to_string())
+                .unwrap();
+        }
+        receivers.values_mut().for_each(drain);
+
+        // only operators can post as someone else
+        assert_eq!(
+            state.say_to_room_as("@robert", "#sports", "@kelsey", "fake".to_string()),
+            Err(ServerError::NotOperator("@robert".to_string()))
+        );
+        assert!(receivers.get_mut("@kelsey").unwrap().try_recv().is_err());
+
+        state.oper("@bot", "hunter2").unwrap();
+        assert_eq!(
+            state.say_to_room_as("@bot", "#nowhere", "@irc-sam", "hi".to_string()),
+            Err(ServerError::RoomUnknown("#nowhere".to_string()))
+        );
+        // nobody on the server can be passed for, least of all the server itself
+        assert_eq!(
+            state.say_to_room_as("@bot", "#sports", "@kelsey", "fake".to_string()),
+            Err(ServerError::UserAlreadyExists("@kelsey".to_string()))
+        );
+        assert_eq!(
+            state.say_to_room_as("@bot", "#sports", SYSTEM_NAME, "fake".to_string()),
+            Err(ServerError::NameReserved(SYSTEM_NAME.to_string()))
+        );
+        state.set_name_lock(Duration::from_secs(60));
+        let (sender, _receiver) = mpsc::unbounded_channel();
+        state
+            .add_user("@dave".to_string(), User::new(sender))
+            .unwrap();
+        state.remove_user("@dave").unwrap();
+        assert!(matches!(
+            state.say_to_room_as("@bot", "#sports", "@dave", "fake".to_string()),
+            Err(ServerError::NameCoolingDown(_, _))
+        ));
+        assert!(receivers.get_mut("@kelsey").unwrap().try_recv().is_err());
+
+        // the bot doesn't have to be in the room, and every member sees the display name tagged with the bot
+        assert!(state
+            .say_to_room_as("@bot", "#sports", "@irc-sam", "good game".to_string())
+            .is_ok());
+        for name in ["@robert", "@kelsey"] {
+            assert_eq!(
+                receivers.get_mut(name).unwrap().try_recv(),
+                Ok(OutgoingMsg::SaidRoom(
+                    "#sports".to_string(),
+                    "@irc-sam/@bot".to_string(),
+                    1,
+                    "good game".to_string()
+                ))
+            );
+        }
+        assert!(receivers.get_mut("@bot").unwrap().try_recv().is_err());
+    }
+
+    #[test]
+    fn test_server_state_switch_room() {
+        let (mut state, mut receivers) = state_with_users(&["@robert", "@kelsey", "@dakota"]);
+        for (name, room_name) in [
+            ("@robert", "#sports"),
+            ("@kelsey", "#sports"),
+            ("@dakota", "#music"),
+        ] {
+            state
+                .join_room(room_name.to_string(), name.to_string())
+                .unwrap();
+        }
+        receivers.values_mut().for_each(drain);
+
+        state
+            .switch_room("#sports", "#music".to_string(), "@robert".to_string())
+            .unwrap();
+        assert_eq!(state.users("#sports"), Ok(vec!["@kelsey".to_string()]));
+        let mut music = state.users("#music").unwrap();
+        music.sort();
+        assert_eq!(music, vec!["@dakota".to_string(), "@robert".to_string()]);
+        assert_eq!(
+            receivers
+                .get_mut("@kelsey")
+                .unwrap()
+                .try_recv()
+                .map(|message| message.to_string()),
+            Ok("#sports @robert LEFT".to_string())
+        );
+        assert_eq!(
+            receivers
+                .get_mut("@dakota")
+                .unwrap()
+                .try_recv()
+                .map(|message| message.to_string()),
+            Ok("#music @robert JOINED".to_string())
+        );
+
+        // switching into a room the user is already in leaves them where they were
+        state
+            .join_room("#sports".to_string(), "@robert".to_string())
+            .unwrap();
+        drain(receivers.get_mut("@kelsey").unwrap());
+        assert_eq!(
+            state.switch_room("#music", "#sports".to_string(), "@robert".to_string()),
+            Err(ServerError::UserAlreadyInRoom(
+                "@robert".to_string(),
+                "#sports".to_string()
+            ))
+        );
+        assert_eq!(
+            state.switch_room("#music", "#music".to_string(), "@robert".to_string()),
+            Ok(())
+        );
+        let mut music = state.users("#music").unwrap();
+        music.sort();
+        assert_eq!(music, vec!["@dakota".to_string(), "@robert".to_string()]);
+        assert_eq!(
+            receivers.get_mut("@kelsey").unwrap().try_recv(),
+            Err(TryRecvError::Empty)
+        );
+        assert_eq!(
+            receivers.get_mut("@dakota").unwrap().try_recv(),
+            Err(TryRecvError::Empty)
+        );
+        state.leave_room("#sports", "@robert").unwrap();
+        drain(receivers.get_mut("@kelsey").unwrap());
+
+        // @kelsey isn't in #music, so #news isn't joined either
+        assert_eq!(
+            state.switch_room("#music", "#news".to_string(), "@kelsey".to_string()),
+            Err(ServerError::UserNotInRoom(
+                "@kelsey".to_string(),
+                "#music".to_string()
+            ))
+        );
+        assert!(state.users("#news").is_err());
+        assert_eq!(state.users("#sports"), Ok(vec!["@kelsey".to_string()]));
+    }
+
+    #[test]
+    fn test_server_state_room_seq() {
+        let (mut state, mut receivers) = state_with_users(&["@robert", "@kelsey"]);
+        for name in ["@robert", "@kelsey"] {
+            state
+                .join_room("#sports".to_string(), name.to_string())
+                .unwrap();
+        }
+        drain(receivers.get_mut("@robert").unwrap());
+
+        // every message in the room is numbered, whoever sends it
+        for (name, message) in [("@robert", "one"), ("@kelsey", "two"), ("@robert", "three")] {
+            state
+                .say_to_room(name, "#sports", message.to_string())
+                .unwrap();
+        }
+        let seqs = |receiver: &mut mpsc::UnboundedReceiver<OutgoingMsg>| {
+            let mut seqs = vec![];
+            while let Ok(OutgoingMsg::SaidRoom(_, _, seq, _)) = receiver.try_recv() {
+                seqs.push(seq);
+            }
+            seqs
+        };
+        assert_eq!(seqs(receivers.get_mut("@kelsey").unwrap()), vec![1, 3]);
+        assert_eq!(seqs(receivers.get_mut("@robert").unwrap()), vec![2]);
+
+        // a room created again starts over, without the history of the old one
+        state.set_history_size(10);
+        state
+            .say_to_room("@robert", "#sports", "four".to_string())
+            .unwrap();
+        for name in ["@robert", "@kelsey"] {
+            state.leave_room("#sports", name).unwrap();
+        }
+        for name in ["@robert", "@kelsey"] {
+            state
+                .join_room("#sports".to_string(), name.to_string())
+                .unwrap();
+        }
+        drain(receivers.get_mut("@robert").unwrap());
+        assert_eq!(state.recent("@robert", "#sports", 10), Ok(vec![]));
+        state
+            .say_to_room("@kelsey", "#sports", "again".to_string())
+            .unwrap();
+        assert_eq!(seqs(receivers.get_mut("@robert").unwrap()), vec![1]);
+    }
+
+    #[test]
+    fn test_server_state_room_seq_restored() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.json");
+        let mut state = ServerState::new();
+        state.set_persistent_rooms(true);
+        state.set_history_size(10);
+        state
+            .set_store(Box::new(FileStore::open(&path).unwrap()))
+            .unwrap();
+        let (sender, _receiver) = mpsc::unbounded_channel();
+        state
+            .add_user("@robert".to_string(), User::new(sender))
+            .unwrap();
+        state
+            .join_room("#sports".to_string(), "@robert".to_string())
+            .unwrap();
+        for message in ["one", "two"] {
+            state
+                .say_to_room("@robert", "#sports", message.to_string())
+                .unwrap();
+        }
+        // history is only written to the store when it is flushed
+        assert!(FileStore::open(&path)
+            .unwrap()
+            .load_history("#sports")
+            .unwrap()
+            .is_empty());
+        state.flush_history();
+        drop(state);
+        assert_eq!(
+            FileStore::open(&path)
+                .unwrap()
+                .load_history("#sports")
+                .unwrap()
+                .len(),
+            2
+        );
+
+        // after a restart the room carries on from the last stored message
+        let mut state = ServerState::new();
+        state.set_persistent_rooms(true);
+        state.set_history_size(10);
+        state
+            .set_store(Box::new(FileStore::open(&path).unwrap()))
+            .unwrap();
+        let mut receivers = vec![];
+        for name in ["@robert", "@kelsey"] {
+            let (sender, receiver) = mpsc::unbounded_channel();
+            receivers.push(receiver);
+            state.add_user(name.to_string(), User::new(sender)).unwrap();
+            state
+                .join_room("#sports".to_string(), name.to_string())
+                .unwrap();
+        }
+        state
+            .say_to_room("@robert", "#sports", "three".to_string())
+            .unwrap();
+        let mut seqs = vec![];
+        while let Ok(message) = receivers[1].try_recv() {
+            if let OutgoingMsg::SaidRoom(_, _, seq, _) = message {
+                seqs.push(seq);
+            }
+        }
+        assert_eq!(seqs, vec![3]);
+    }
+
+    #[test]
+    fn test_server_state_buddies() {
+        let mut state = ServerState::new();
+        let (sender, mut kelsey_receiver) = mpsc::unbounded_channel();
+        state
+            .add_user("@kelsey".to_string(), User::new(sender))
+            .unwrap();
+
+        // watching someone who isn't online yet is fine
+        assert_eq!(
+            state.add_buddy("@kelsey", "@robert"),
+            Ok(OutgoingMsg::Presence("@robert".to_string(), false))
+        );
+        assert_eq!(
+            state.add_buddy("@nobody", "@robert"),
+            Err(ServerError::UserUnknown("@nobody".to_string()))
+        );
+
+        // a buddy list only holds so many names, though the ones on it can be added again
+        for n in 1..MAX_BUDDIES {
+            state.add_buddy("@kelsey", &format!("@fan{}", n)).unwrap();
+        }
+        assert_eq!(
+            state.add_buddy("@kelsey", "@dave"),
+            Err(ServerError::TooManyBuddies(MAX_BUDDIES))
+        );
+        assert!(state.add_buddy("@kelsey", "@robert").is_ok());
+        let (sender, _receiver) = mpsc::unbounded_channel();
+        state
+            .add_user("@dave".to_string(), User::new(sender))
+            .unwrap();
+        assert!(state.add_buddy("@dave", "@robert").is_ok());
+        state.remove_user("@dave").unwrap();
+
+        let (sender, robert_receiver) = mpsc::unbounded_channel();
+        state
+            .add_user("@robert".to_string(), User::new(sender))
+            .unwrap();
+        assert_eq!(
+            kelsey_receiver.try_recv(),
+            Ok(OutgoingMsg::Presence("@robert".to_string(), true))
+        );
+        assert_eq!(
+            state.add_buddy("@kelsey", "@robert"),
+            Ok(OutgoingMsg::Presence("@robert".to_string(), true))
+        );
+
+        // a new name is someone else, so the old one goes offline
+        state.rename_user("@robert", "@bob").unwrap();
+        assert_eq!(
+            kelsey_receiver.try_recv(),
+            Ok(OutgoingMsg::Presence("@robert".to_string(), false))
+        );
+        state.rename_user("@bob", "@robert").unwrap();
+        assert_eq!(
+            kelsey_receiver.try_recv(),
+            Ok(OutgoingMsg::Presence("@robert".to_string(), true))
+        );
+
+        state.remove_user("@robert").unwrap();
+        drop(robert_receiver);
+        assert_eq!(
+            kelsey_receiver.try_recv(),
+            Ok(OutgoingMsg::Presence("@robert".to_string(), false))
+        );
+        assert!(kelsey_receiver.try_recv().is_err());
+
+        // once the watcher is gone, nobody hears about @robert
+        state.remove_user("@kelsey").unwrap();
+        assert!(state.buddies.is_empty());
+    }
+
+    #[test]
+    fn test_server_state_prefs() {
+        let (mut state, _receivers) = state_with_users(&["@robert", "@kelsey"]);
+        state.set_history_size(50);
+        let prefs = |state: &ServerState, name| {
+            state.prefs(name).map(|prefs| {
+                prefs
+                    .iter()
+                    .map(|pref| pref.to_string())
+                    .collect::<Vec<_>>()
+            })
+        };
+        assert_eq!(
+            prefs(&state, "@robert"),
+            Ok(vec![
+                "PREF dnd OFF".to_string(),
+                "PREF watch OFF".to_string(),
+                "PREF echo-room OFF".to_string(),
+                "PREF context OFF".to_string(),
+                "PREF histlen all".to_string(),
+            ])
+        );
+
+        state.set_dnd("@robert", true).unwrap();
+        state.set_watching("@robert", true).unwrap();
+        state.set_echo_room("@robert", true).unwrap();
+        state.set_context("@robert", true).unwrap();
+        state.set_history_length("@robert", 5).unwrap();
+        state
+            .set_status("@robert", Some("out to lunch".to_string()))
+            .unwrap();
+        assert_eq!(
+            prefs(&state, "@robert"),
+            Ok(vec![
+                "PREF dnd ON".to_string(),
+                "PREF watch ON".to_string(),
+                "PREF echo-room ON".to_string(),
+                "PREF context ON".to_string(),
+                "PREF histlen 5".to_string(),
+                "PREF status out to lunch".to_string(),
+            ])
+        );
+        // nobody else's preferences changed
+        assert_eq!(
+            prefs(&state, "@kelsey").unwrap()[0],
+            "PREF dnd OFF".to_string()
+        );
+        assert_eq!(
+            state.prefs("@nobody"),
+            Err(ServerError::UserUnknown("@nobody".to_string()))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_server_state_archive_idle_rooms() {
+        let (mut state, mut receivers) = state_with_users(&["@robert", "@kelsey", "@dave"]);
+        for (room_name, name) in [
+            ("#sports", "@robert"),
+            ("#sports", "@kelsey"),
+            ("#news", "@dave"),
+        ] {
+            state
+                .join_room(room_name.to_string(), name.to_string())
+                .unwrap();
+        }
+        receivers.values_mut().for_each(drain);
+
+        // nothing is archived until a period is set
+        tokio::time::advance(Duration::from_secs(600)).await;
+        assert!(state.archive_idle_rooms().is_empty());
+
+        state.set_archive_after(Some(Duration::from_secs(300)));
+        state
+            .say_to_room("@dave", "#news", "still here".to_string())
+            .unwrap();
+        assert_eq!(state.archive_idle_rooms(), vec!["#sports".to_string()]);
+        assert_eq!(
+            receivers.get_mut("@kelsey").unwrap().try_recv(),
+            Ok(OutgoingMsg::Notice(
+                "#sports archived after 300 seconds without messages".to_string()
+            ))
         );
+        assert!(state.room_snapshot("#sports").unwrap().archived);
+        // each room is only archived once
+        assert!(state.archive_idle_rooms().is_empty());
 
-        // join room that does not exist with user that does not exist
+        // an archived room can't be spoken in or joined, but its members can still leave
         assert_eq!(
-            state.join_room("#none".to_string(), "@notreal".to_string()),
-            Err(ServerError::UserUnknown("@notreal".to_string()))
+            state.say_to_room("@kelsey", "#sports", "hello?".to_string()),
+            Err(ServerError::RoomArchived("#sports".to_string()))
+        );
+        assert_eq!(
+            state.join_room("#sports".to_string(), "@dave".to_string()),
+            Err(ServerError::RoomArchived("#sports".to_string()))
+        );
+        assert_eq!(
+            state.switch_room("#news", "#sports".to_string(), "@dave".to_string()),
+            Err(ServerError::RoomArchived("#sports".to_string()))
         );
+        assert_eq!(state.users("#news"), Ok(vec!["@dave".to_string()]));
 
-        // join room that exists with user that does not exist
+        // only the owner can bring it back, which starts the clock over
         assert_eq!(
-            state.join_room("#testroom".to_string(), "@fakey".to_string()),
-            Err(ServerError::UserUnknown("@fakey".to_string()))
+            state.unarchive_room("#sports", "@kelsey"),
+            Err(ServerError::NotRoomOwner("#sports".to_string()))
+        );
+        state.unarchive_room("#sports", "@robert").unwrap();
+        assert_eq!(
+            receivers.get_mut("@kelsey").unwrap().try_recv(),
+            Ok(OutgoingMsg::Notice(
+                "#sports unarchived by @robert".to_string()
+            ))
+        );
+        state
+            .say_to_room("@kelsey", "#sports", "we're back".to_string())
+            .unwrap();
+        tokio::time::advance(Duration::from_secs(299)).await;
+        assert!(state.archive_idle_rooms().is_empty());
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert_eq!(
+            state.archive_idle_rooms(),
+            vec!["#news".to_string(), "#sports".to_string()]
         );
     }
 
-    #[tokio::test]
-    async fn test_server_state_leave_room() {
+    #[tokio::test(start_paused = true)]
+    async fn test_server_state_archive_restored_rooms() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.json");
         let mut state = ServerState::new();
-        let (sender, mut robert_receiver) = mpsc::unbounded_channel();
-        assert!(state
+        state.set_persistent_rooms(true);
+        state
+            .set_store(Box::new(FileStore::open(&path).unwrap()))
+            .unwrap();
+        state.set_archive_after(Some(Duration::from_secs(300)));
+        let (sender, _receiver) = mpsc::unbounded_channel();
+        state
             .add_user("@robert".to_string(), User::new(sender))
-            .is_ok());
-        assert!(state
-            .join_room("#testroom".to_string(), "@robert".to_string())
-            .is_ok());
-        assert!(state.rooms.contains_key("#testroom"));
-        assert!(state
-            .users
-            .get("@robert")
-            .unwrap()
-            .rooms
-            .contains("#testroom"));
+            .unwrap();
+        for room_name in ["#sports", "#news"] {
+            state
+                .join_room(room_name.to_string(), "@robert".to_string())
+                .unwrap();
+        }
+        state.leave_room("#news", "@robert").unwrap();
 
-        // check the messages sent to existing users
+        // the empty room is left alone, since it has no owner to bring it back
+        tokio::time::advance(Duration::from_secs(300)).await;
+        assert_eq!(state.archive_idle_rooms(), vec!["#sports".to_string()]);
+
+        // the archived room stays archived across a restart, with nobody in it
+        drop(state);
+        let mut state = ServerState::new();
+        state.set_persistent_rooms(true);
+        state
+            .set_store(Box::new(FileStore::open(&path).unwrap()))
+            .unwrap();
+        state.set_archive_after(Some(Duration::from_secs(300)));
+        state.set_oper_password(Some("hunter2".to_string()));
+        assert!(state.room_snapshot("#sports").unwrap().archived);
+        assert!(!state.room_snapshot("#news").unwrap().archived);
+        tokio::time::advance(Duration::from_secs(300)).await;
+        assert!(state.archive_idle_rooms().is_empty());
+
+        // an operator can unarchive a room without an owner
         let (sender, _receiver) = mpsc::unbounded_channel();
+        state
+            .add_user("@dave".to_string(), User::new(sender))
+            .unwrap();
+        assert_eq!(
+            state.join_room("#sports".to_string(), "@dave".to_string()),
+            Err(ServerError::RoomArchived("#sports".to_string()))
+        );
+        assert_eq!(
+            state.unarchive_room("#sports", "@dave"),
+            Err(ServerError::NotRoomOwner("#sports".to_string()))
+        );
+        state.oper("@dave", "hunter2").unwrap();
+        state.unarchive_room("#sports", "@dave").unwrap();
         assert!(state
-            .add_user("@kelsey".to_string(), User::new(sender))
+            .join_room("#sports".to_string(), "@dave".to_string())
             .is_ok());
+
+        // and that is saved too
+        drop(state);
+        let mut state = ServerState::new();
+        state.set_persistent_rooms(true);
+        state
+            .set_store(Box::new(FileStore::open(&path).unwrap()))
+            .unwrap();
+        assert!(!state.room_snapshot("#sports").unwrap().archived);
+    }
+
+    #[test]
+    fn test_server_state_context() {
+        let (mut state, mut receivers) = state_with_users(&["@robert", "@kelsey"]);
+        for (name, rooms) in [
+            ("@robert", vec!["#project", "#news", "#secret"]),
+            ("@kelsey", vec!["#news", "#project", "#music"]),
+        ] {
+            for room_name in rooms {
+                state
+                    .join_room(room_name.to_string(), name.to_string())
+                    .unwrap();
+            }
+        }
+        receivers.values_mut().for_each(drain);
+
+        // off by default
+        state
+            .say_to_user("@robert", "@kelsey", "hi".to_string(), false)
+            .unwrap();
+        assert_eq!(
+            receivers.get_mut("@kelsey").unwrap().try_recv(),
+            Ok(OutgoingMsg::SaidUser(
+                "@robert".to_string(),
+                "hi".to_string(),
+                false
+            ))
+        );
+
+        // only rooms @kelsey is in too, so @robert's #secret stays secret
+        state.set_context("@kelsey", true).unwrap();
+        state
+            .say_to_user("@robert", "@kelsey", "hi again".to_string(), false)
+            .unwrap();
+        assert_eq!(
+            receivers.get_mut("@kelsey").unwrap().try_recv(),
+            Ok(OutgoingMsg::Context(
+                "@robert".to_string(),
+                vec!["#news".to_string(), "#project".to_string()]
+            ))
+        );
+        assert_eq!(
+            receivers.get_mut("@kelsey").unwrap().try_recv(),
+            Ok(OutgoingMsg::SaidUser(
+                "@robert".to_string(),
+                "hi again".to_string(),
+                false
+            ))
+        );
+        // the sender's own setting doesn't matter
+        state
+            .say_to_user("@kelsey", "@robert", "hello".to_string(), false)
+            .unwrap();
+        assert_eq!(
+            receivers.get_mut("@robert").unwrap().try_recv(),
+            Ok(OutgoingMsg::SaidUser(
+                "@kelsey".to_string(),
+                "hello".to_string(),
+                false
+            ))
+        );
+        assert_eq!(
+            state.set_context("@nobody", true),
+            Err(ServerError::UserUnknown("@nobody".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_server_state_say_to_room_undelivered() {
+        let (mut state, mut receivers) = state_with_users(&["@robert", "@kelsey", "@dave"]);
+        for name in ["@robert", "@kelsey", "@dave"] {
+            state
+                .join_room("#sports".to_string(), name.to_string())
+                .unwrap();
+        }
+        receivers.values_mut().for_each(drain);
+
+        // @dave's connection went away but hasn't been torn down yet
+        drop(receivers.remove("@dave"));
         assert!(state
-            .join_room("#testroom".to_string(), "@kelsey".to_string())
+            .say_to_room("@robert", "#sports", "good game".to_string())
             .is_ok());
         assert_eq!(
-            Some(OutgoingMsg::Joined(
-                "#testroom".to_string(),
-                "@kelsey".to_string()
-            )),
-            robert_receiver.recv().await
+            receivers.get_mut("@kelsey").unwrap().try_recv(),
+            Ok(OutgoingMsg::SaidRoom(
+                "#sports".to_string(),
+                "@robert".to_string(),
+                1,
+                "good game".to_string()
+            ))
         );
-        assert!(state.leave_room("#testroom", "@kelsey").is_ok());
         assert_eq!(
-            Some(OutgoingMsg::Left(
-                "#testroom".to_string(),
-                "@kelsey".to_string()
-            )),
-            robert_receiver.recv().await
+            receivers.get_mut("@robert").unwrap().try_recv(),
+            Ok(OutgoingMsg::Notice(
+                "undelivered in #sports to @dave".to_string()
+            ))
         );
+    }
 
-        assert!(state.leave_room("#testroom", "@robert").is_ok());
-        assert!(!state
-            .users
-            .get("@robert")
-            .unwrap()
-            .rooms
-            .contains("#testroom"));
+    #[test]
+    fn test_server_state_room_modes() {
+        let (mut state, _receivers) = state_with_users(&["@robert", "@kelsey", "@dave"]);
+        for name in ["@robert", "@kelsey"] {
+            state
+                .join_room("#sports".to_string(), name.to_string())
+                .unwrap();
+        }
+        assert_eq!(
+            state.modes("#sports").map(|modes| modes.to_string()),
+            Ok("MODE #sports +".to_string())
+        );
 
-        // last user to leave room makes room go away
-        assert!(!state.rooms.contains_key("#testroom"));
+        assert_eq!(
+            state.set_mode("#sports", "@kelsey", RoomMode::InviteOnly, true),
+            Err(ServerError::NotRoomOwner("#sports".to_string()))
+        );
+        state
+            .set_mode("#sports", "@robert", RoomMode::InviteOnly, true)
+            .unwrap();
+        state
+            .set_mode("#sports", "@robert", RoomMode::Moderated, true)
+            .unwrap();
+        assert_eq!(
+            state.modes("#sports").map(|modes| modes.to_string()),
+            Ok("MODE #sports +im".to_string())
+        );
+        assert!(state.room_snapshot("#sports").unwrap().readonly);
 
-        // room does not exist
+        // newcomers are turned away, including members who leave and try to come back
         assert_eq!(
-            state.leave_room("#fakeroom", "@robert"),
-            Err(ServerError::RoomUnknown("#fakeroom".to_string()))
+            state.join_room("#sports".to_string(), "@dave".to_string()),
+            Err(ServerError::RoomInviteOnly("#sports".to_string()))
+        );
+        state.leave_room("#sports", "@kelsey").unwrap();
+        assert_eq!(
+            state.join_room("#sports".to_string(), "@kelsey".to_string()),
+            Err(ServerError::RoomInviteOnly("#sports".to_string()))
         );
+        state
+            .join_room("#news".to_string(), "@dave".to_string())
+            .unwrap();
+        assert_eq!(
+            state.switch_room("#news", "#sports".to_string(), "@dave".to_string()),
+            Err(ServerError::RoomInviteOnly("#sports".to_string()))
+        );
+        assert_eq!(state.users("#news"), Ok(vec!["@dave".to_string()]));
 
-        // user does not exist
+        state
+            .set_mode("#sports", "@robert", RoomMode::InviteOnly, false)
+            .unwrap();
         assert!(state
-            .join_room("#testroom".to_string(), "@robert".to_string())
+            .join_room("#sports".to_string(), "@dave".to_string())
             .is_ok());
+        assert_eq!(
+            state.modes("#sports").map(|modes| modes.to_string()),
+            Ok("MODE #sports +m".to_string())
+        );
+    }
+
+    #[test]
+    fn test_server_state_invite() {
+        let (mut state, mut receivers) = state_with_users(&["@robert", "@kelsey", "@dave"]);
+        for name in ["@robert", "@kelsey"] {
+            state
+                .join_room("#sports".to_string(), name.to_string())
+                .unwrap();
+        }
+        state
+            .set_mode("#sports", "@robert", RoomMode::InviteOnly, true)
+            .unwrap();
+        receivers.values_mut().for_each(drain);
 
+        // only the owner invites, and only users who are here and not in the room already
         assert_eq!(
-            state.leave_room("#testroom", "@kelsey"),
-            Err(ServerError::UserNotInRoom(
+            state.invite_user("#sports", "@kelsey", "@dave"),
+            Err(ServerError::NotRoomOwner("#sports".to_string()))
+        );
+        assert_eq!(
+            state.invite_user("#sports", "@robert", "@nobody"),
+            Err(ServerError::UserUnknown("@nobody".to_string()))
+        );
+        assert_eq!(
+            state.invite_user("#sports", "@robert", "@kelsey"),
+            Err(ServerError::UserAlreadyInRoom(
                 "@kelsey".to_string(),
-                "#testroom".to_string()
+                "#sports".to_string()
             ))
         );
-    }
 
-    #[test]
-    fn test_server_state_remove_user_leaves_room() {
-        let mut state = ServerState::new();
-        let (sender, _receiver) = mpsc::unbounded_channel();
-        assert!(state
-            .add_user("@kelsey".to_string(), User::new(sender))
-            .is_ok());
+        state.invite_user("#sports", "@robert", "@dave").unwrap();
+        assert_eq!(
+            receivers.get_mut("@dave").unwrap().try_recv(),
+            Ok(OutgoingMsg::Notice(
+                "@robert invited you to #sports".to_string()
+            ))
+        );
+        state
+            .join_room("#sports".to_string(), "@dave".to_string())
+            .unwrap();
+
+        // an invite is good for one join
+        state.leave_room("#sports", "@dave").unwrap();
+        assert_eq!(
+            state.join_room("#sports".to_string(), "@dave".to_string()),
+            Err(ServerError::RoomInviteOnly("#sports".to_string()))
+        );
+
+        // it follows the user through a rename and lets them SWITCH in, but not past their connection
+        state.invite_user("#sports", "@robert", "@dave").unwrap();
+        state.rename_user("@dave", "@david").unwrap();
+        state
+            .join_room("#news".to_string(), "@david".to_string())
+            .unwrap();
         assert!(state
-            .join_room("#applejuice".to_string(), "@kelsey".to_string())
+            .switch_room("#news", "#sports".to_string(), "@david".to_string())
             .is_ok());
-        assert!(state.rooms.contains_key("#applejuice"));
-        assert!(state.users.contains_key("@kelsey"));
-        assert!(state.remove_user("@kelsey").is_ok());
-        assert!(!state.rooms.contains_key("#applejuice"));
-        assert!(!state.users.contains_key("@kelsey"));
+        state.leave_room("#sports", "@david").unwrap();
+        state.invite_user("#sports", "@robert", "@david").unwrap();
+        state.remove_user("@david").unwrap();
+        let (sender, _receiver) = mpsc::unbounded_channel();
+        state
+            .add_user("@david".to_string(), User::new(sender))
+            .unwrap();
+        assert_eq!(
+            state.join_room("#sports".to_string(), "@david".to_string()),
+            Err(ServerError::RoomInviteOnly("#sports".to_string()))
+        );
     }
 
     #[test]
-    fn test_server_state_rename_user() {
-        let mut state = ServerState::new();
-        let (sender, _receiver) = mpsc::unbounded_channel();
+    fn test_server_state_topic_locked() {
+        let (mut state, _receivers) = state_with_users(&["@robert", "@kelsey"]);
+        for name in ["@robert", "@kelsey"] {
+            state
+                .join_room("#sports".to_string(), name.to_string())
+                .unwrap();
+        }
+        assert_eq!(
+            state.set_mode("#sports", "@kelsey", RoomMode::TopicLocked, true),
+            Err(ServerError::NotRoomOwner("#sports".to_string()))
+        );
+        state
+            .set_mode("#sports", "@robert", RoomMode::TopicLocked, true)
+            .unwrap();
+        assert_eq!(
+            state.modes("#sports").map(|modes| modes.to_string()),
+            Ok("MODE #sports +t".to_string())
+        );
+
+        // only the owner changes a locked topic, though everyone can still speak
+        assert_eq!(
+            state.set_topic("@kelsey", "#sports", "hijacked".to_string()),
+            Err(ServerError::NotRoomOwner("#sports".to_string()))
+        );
         assert!(state
-            .add_user("@kelsey".to_string(), User::new(sender))
+            .say_to_room("@kelsey", "#sports", "fine".to_string())
             .is_ok());
         assert!(state
-            .join_room("#applejuice".to_string(), "@kelsey".to_string())
+            .set_topic("@robert", "#sports", "the big game".to_string())
             .is_ok());
+
+        state
+            .set_mode("#sports", "@robert", RoomMode::TopicLocked, false)
+            .unwrap();
         assert!(state
-            .join_room("#testing123".to_string(), "@kelsey".to_string())
+            .set_topic("@kelsey", "#sports", "after the game".to_string())
             .is_ok());
+        assert_eq!(
+            state.room_snapshot("#sports").unwrap().topic,
+            Some("after the game".to_string())
+        );
+    }
 
-        // initial state
-        assert!(state.rooms.contains_key("#applejuice"));
-        assert!(state.rooms.contains_key("#testing123"));
+    #[test]
+    fn test_server_state_owner_notices() {
+        let (mut state, mut receivers) = state_with_users(&["@robert", "@kelsey", "@dave"]);
+        state
+            .join_room("#sports".to_string(), "@robert".to_string())
+            .unwrap();
+        state
+            .join_room("#sports".to_string(), "@kelsey".to_string())
+            .unwrap();
+        assert_eq!(
+            state.set_owner_notices("#sports", "@kelsey", true),
+            Err(ServerError::NotRoomOwner("#sports".to_string()))
+        );
+        state.set_owner_notices("#sports", "@robert", true).unwrap();
+        receivers.values_mut().for_each(drain);
 
-        assert!(state
-            .users
-            .get("@kelsey")
-            .unwrap()
-            .rooms
-            .contains("#applejuice"));
-        assert!(state
-            .users
-            .get("@kelsey")
-            .unwrap()
-            .rooms
-            .contains("#testing123"));
+        state.set_status("@dave", Some("busy".to_string())).unwrap();
+        state
+            .join_room("#sports".to_string(), "@dave".to_string())
+            .unwrap();
+        state.leave_room("#sports", "@kelsey").unwrap();
 
-        assert!(state
-            .rooms
-            .get("#applejuice")
-            .unwrap()
-            .users
-            .contains("@kelsey"));
-        assert!(state
-            .rooms
-            .get("#testing123")
-            .unwrap()
-            .users
-            .contains("@kelsey"));
+        // the owner gets the standard messages and a MEMBER message for each
+        let robert = receivers.get_mut("@robert").unwrap();
+        for expected in [
+            "#sports @dave JOINED",
+            "MEMBER #sports @dave JOINED 3 busy",
+            "#sports @kelsey LEFT",
+            "MEMBER #sports @kelsey LEFT 2",
+        ] {
+            assert_eq!(robert.try_recv().unwrap().to_string(), expected);
+        }
+        assert!(robert.try_recv().is_err());
+        // everyone else gets only the standard ones
+        let kelsey = receivers.get_mut("@kelsey").unwrap();
+        assert_eq!(
+            kelsey.try_recv().unwrap().to_string(),
+            "#sports @dave JOINED"
+        );
+        assert!(kelsey.try_recv().is_err());
 
-        // renamed state
-        assert!(state.rename_user("@kelsey", "@littleb1t").is_ok());
+        // a user who disconnects still has their status in the notice
+        state.remove_user("@dave").unwrap();
+        let robert = receivers.get_mut("@robert").unwrap();
+        assert_eq!(robert.try_recv().unwrap().to_string(), "#sports @dave LEFT");
+        assert_eq!(
+            robert.try_recv().unwrap().to_string(),
+            "MEMBER #sports @dave LEFT 1 busy"
+        );
+    }
 
-        assert!(state.users.get("@kelsey").is_none());
-        assert!(state
-            .users
-            .get("@littleb1t")
-            .unwrap()
-            .rooms
-            .contains("#applejuice"));
-        assert!(state
-            .users
-            .get("@littleb1t")
-            .unwrap()
-            .rooms
-            .contains("#testing123"));
+    #[test]
+    fn test_server_state_say_to_users() {
+        let (mut state, mut receivers) = state_with_users(&["@robert", "@kelsey", "@dave"]);
 
-        assert!(state
-            .rooms
-            .get("#applejuice")
-            .unwrap()
-            .users
-            .contains("@littleb1t"));
-        assert!(state
-            .rooms
-            .get("#testing123")
-            .unwrap()
-            .users
-            .contains("@littleb1t"));
+        let to_users = ["@kelsey", "@nobody", "@dave"].map(String::from);
+        assert_eq!(
+            state.say_to_users("@robert", &to_users, "lunch?".to_string()),
+            vec![
+                Ok(OutgoingMsg::Delivered("@kelsey".to_string(), vec![])),
+                Err(ServerError::UserUnknown("@nobody".to_string())),
+                Ok(OutgoingMsg::Delivered("@dave".to_string(), vec![])),
+            ]
+        );
+        for name in ["@kelsey", "@dave"] {
+            assert_eq!(
+                receivers.get_mut(name).unwrap().try_recv().unwrap(),
+                OutgoingMsg::SaidUser("@robert".to_string(), "lunch?".to_string(), false)
+            );
+        }
+        assert!(receivers.get_mut("@robert").unwrap().try_recv().is_err());
     }
 
     #[test]
-    fn test_server_state_rename_user_bad() {
-        let mut state = ServerState::new();
+    fn test_server_state_join_room_twice() {
+        let (mut state, mut receivers) = state_with_users(&["@robert", "@kelsey"]);
+        state
+            .join_room("#sports".to_string(), "@robert".to_string())
+            .unwrap();
+        state
+            .join_room("#sports".to_string(), "@kelsey".to_string())
+            .unwrap();
+        let robert = receivers.get_mut("@robert").unwrap();
         assert_eq!(
-            state.rename_user("@robert", "@bobert"),
-            Err(ServerError::UserUnknown("@robert".to_string()))
+            robert.try_recv().unwrap().to_string(),
+            "#sports @kelsey JOINED"
         );
-    }
 
-    #[test]
-    fn test_server_state_rooms() {
-        let mut state = ServerState::new();
-        let (sender, _receiver) = mpsc::unbounded_channel();
-        assert!(state
-            .add_user("@kelsey".to_string(), User::new(sender))
-            .is_ok());
-        assert!(state
-            .join_room("#applejuice".to_string(), "@kelsey".to_string())
-            .is_ok());
-        assert!(state
-            .join_room("#testing123".to_string(), "@kelsey".to_string())
-            .is_ok());
-        assert!(state
-            .join_room("#room_123".to_string(), "@kelsey".to_string())
-            .is_ok());
+        assert_eq!(
+            state.join_room("#sports".to_string(), "@kelsey".to_string()),
+            Err(ServerError::UserAlreadyInRoom(
+                "@kelsey".to_string(),
+                "#sports".to_string()
+            ))
+        );
+        // no second JOINED goes out
+        assert!(receivers.get_mut("@robert").unwrap().try_recv().is_err());
+        assert_eq!(
+            state.users("#sports"),
+            Ok(vec!["@kelsey".to_string(), "@robert".to_string()])
+        );
 
-        let mut rooms = state.rooms();
-        rooms.sort();
-        let mut expected = vec![
-            "#applejuice".to_string(),
-            "#testing123".to_string(),
-            "#room_123".to_string(),
-        ];
-        expected.sort();
-        assert_eq!(rooms, expected);
+        // switching into a room the user is already in changes nothing
+        state
+            .join_room("#news".to_string(), "@kelsey".to_string())
+            .unwrap();
+        assert_eq!(
+            state.switch_room("#news", "#sports".to_string(), "@kelsey".to_string()),
+            Err(ServerError::UserAlreadyInRoom(
+                "@kelsey".to_string(),
+                "#sports".to_string()
+            ))
+        );
+        assert_eq!(state.users("#news"), Ok(vec!["@kelsey".to_string()]));
     }
 
-    #[test]
-    fn test_server_state_users() {
-        let mut state = ServerState::new();
+    #[tokio::test(start_paused = true)]
+    async fn test_server_state_name_lock() {
+        let (mut state, _receivers) = state_with_users(&["@robert", "@kelsey"]);
+        state.set_name_lock(Duration::from_secs(30));
+        state.remove_user("@robert").unwrap();
+
+        tokio::time::advance(Duration::from_millis(10_500)).await;
         let (sender, _receiver) = mpsc::unbounded_channel();
-        assert!(state
-            .add_user("@kelsey".to_string(), User::new(sender))
-            .is_ok());
-        assert!(state
-            .join_room("#applejuice".to_string(), "@kelsey".to_string())
-            .is_ok());
+        assert_eq!(
+            state.add_user("@robert".to_string(), User::new(sender)),
+            Err(ServerError::NameCoolingDown("@robert".to_string(), 20))
+        );
+        assert_eq!(
+            state.rename_user("@kelsey", "@robert"),
+            Err(ServerError::NameCoolingDown("@robert".to_string(), 20))
+        );
+
+        tokio::time::advance(Duration::from_secs(20)).await;
         let (sender, _receiver) = mpsc::unbounded_channel();
         assert!(state
             .add_user("@robert".to_string(), User::new(sender))
             .is_ok());
-        assert!(state
-            .join_room("#applejuice".to_string(), "@robert".to_string())
-            .is_ok());
+        assert!(state.recently_released.is_empty());
+
+        // a name given up by a rename is locked too
+        state.rename_user("@kelsey", "@kelsey2").unwrap();
+        let (sender, _receiver) = mpsc::unbounded_channel();
+        assert_eq!(
+            state.add_user("@kelsey".to_string(), User::new(sender)),
+            Err(ServerError::NameCoolingDown("@kelsey".to_string(), 30))
+        );
+        assert_eq!(
+            state.rename_user("@robert", "@kelsey"),
+            Err(ServerError::NameCoolingDown("@kelsey".to_string(), 30))
+        );
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert!(state.rename_user("@kelsey2", "@kelsey").is_ok());
+
+        // without a lock names are freed right away
+        state.set_name_lock(Duration::ZERO);
+        state.remove_user("@robert").unwrap();
         let (sender, _receiver) = mpsc::unbounded_channel();
         assert!(state
-            .add_user("@ch4ch4".to_string(), User::new(sender))
-            .is_ok());
-        assert!(state
-            .join_room("#applejuice".to_string(), "@ch4ch4".to_string())
+            .add_user("@robert".to_string(), User::new(sender))
             .is_ok());
+    }
 
-        let mut users = state.users("#applejuice").unwrap();
-        users.sort();
-        let mut expected = vec![
-            "@ch4ch4".to_string(),
-            "@robert".to_string(),
-            "@kelsey".to_string(),
-        ];
-        expected.sort();
-        assert_eq!(users, expected);
+    #[test]
+    fn test_server_state_system_say() {
+        let (mut state, mut receivers) = state_with_users(&["@robert", "@kelsey", "@dave"]);
+        for name in ["@robert", "@kelsey"] {
+            state
+                .join_room("#lobby".to_string(), name.to_string())
+                .unwrap();
+        }
+        receivers.values_mut().for_each(drain);
 
+        state
+            .system_say("#lobby", "maintenance at noon".to_string())
+            .unwrap();
+        // every member hears it, and nobody outside the room does
+        for name in ["@robert", "@kelsey"] {
+            assert_eq!(
+                receivers
+                    .get_mut(name)
+                    .unwrap()
+                    .try_recv()
+                    .unwrap()
+                    .to_string(),
+                "#lobby @server SAID 1 maintenance at noon"
+            );
+        }
+        assert!(receivers.get_mut("@dave").unwrap().try_recv().is_err());
         assert_eq!(
-            state.users("#notrealroom"),
-            Err(ServerError::RoomUnknown("#notrealroom".to_string()))
+            state.system_say("#nowhere", "hello".to_string()),
+            Err(ServerError::RoomUnknown("#nowhere".to_string()))
+        );
+
+        // nobody can take the server's name
+        let (sender, _receiver) = mpsc::unbounded_channel();
+        assert_eq!(
+            state.add_user(SYSTEM_NAME.to_string(), User::new(sender)),
+            Err(ServerError::NameReserved("@server".to_string()))
+        );
+        assert_eq!(
+            state.rename_user("@dave", SYSTEM_NAME),
+            Err(ServerError::NameReserved("@server".to_string()))
         );
     }
 
-    #[tokio::test]
-    async fn test_server_state_say_to_user() {
-        let mut state = ServerState::new();
-        let (sender_kelsey, mut receiver_kelsey) = mpsc::unbounded_channel();
-        assert!(state
-            .add_user("@kelsey".to_string(), User::new(sender_kelsey))
-            .is_ok());
-        let (sender_robert, _receiver_robert) = mpsc::unbounded_channel();
-        assert!(state
-            .add_user("@robert".to_string(), User::new(sender_robert))
-            .is_ok());
+    #[test]
+    fn test_server_state_edit_and_delete_message() {
+        let (mut state, mut receivers) = state_with_users(&["@robert", "@kelsey", "@mike"]);
+        state.set_history_size(2);
+        state.set_review_log_size(10);
+        let (sender_link, mut receiver_link) = mpsc::unbounded_channel();
+        state.add_link("#sports".to_string(), sender_link);
+        for name in ["@robert", "@kelsey", "@mike"] {
+            state
+                .join_room("#sports".to_string(), name.to_string())
+                .unwrap();
+        }
+        for (name, message) in [("@kelsey", "one"), ("@kelsey", "two"), ("@mike", "three")] {
+            state
+                .say_to_room(name, "#sports", message.to_string())
+                .unwrap();
+        }
+        receivers.values_mut().for_each(drain);
 
-        assert_eq!(Err(TryRecvError::Empty), receiver_kelsey.try_recv());
+        // only the sender may edit, and only while the message is in the history
+        assert_eq!(
+            state.edit_message("@mike", "#sports", 2, "hijacked".to_string()),
+            Err(ServerError::NotMessageSender("#sports".to_string(), 2))
+        );
+        assert_eq!(
+            state.edit_message("@kelsey", "#sports", 1, "too late".to_string()),
+            Err(ServerError::MessageUnknown("#sports".to_string(), 1))
+        );
+        assert_eq!(
+            state.edit_message("@kelsey", "#sports", 2, "two!".to_string()),
+            Ok(())
+        );
+        for receiver in receivers.values_mut() {
+            assert_eq!(
+                receiver.try_recv().unwrap(),
+                OutgoingMsg::Edited("#sports".to_string(), 2, "two!".to_string())
+            );
+        }
+        state.flush_history();
+        assert_eq!(
+            state.store.load_history("#sports").unwrap()[0],
+            "#sports @kelsey SAID 2 two!"
+        );
+        // linked servers and the review log see the edit too
+        let relayed: Vec<OutgoingMsg> =
+            std::iter::from_fn(|| receiver_link.try_recv().ok()).collect();
+        assert_eq!(
+            relayed.last(),
+            Some(&OutgoingMsg::Edited(
+                "#sports".to_string(),
+                2,
+                "two!".to_string()
+            ))
+        );
+        let review = state.review_log.back().unwrap();
+        assert_eq!(
+            (review.from.as_str(), review.message.as_str()),
+            ("@kelsey", "(edited 2) two!")
+        );
 
-        assert!(state
-            .say_to_user("@robert", "@kelsey", "hi there! how are you?".to_string())
-            .is_ok());
+        // editing is held to the same rules as saying
+        state.mute_user("#sports", "@robert", "@kelsey").unwrap();
+        assert_eq!(
+            state.edit_message("@kelsey", "#sports", 2, "muted".to_string()),
+            Ok(())
+        );
+        assert_eq!(
+            receivers.get_mut("@kelsey").unwrap().try_recv().unwrap(),
+            OutgoingMsg::Notice("you are muted in #sports".to_string())
+        );
+        state.unmute_user("#sports", "@robert", "@kelsey").unwrap();
+        state
+            .set_mode("#sports", "@robert", RoomMode::Moderated, true)
+            .unwrap();
+        assert_eq!(
+            state.edit_message("@kelsey", "#sports", 2, "read only".to_string()),
+            Err(ServerError::RoomReadOnly("#sports".to_string()))
+        );
+        state
+            .set_mode("#sports", "@robert", RoomMode::Moderated, false)
+            .unwrap();
+        receivers.values_mut().for_each(drain);
+        state.flush_history();
+        assert_eq!(
+            state.store.load_history("#sports").unwrap()[0],
+            "#sports @kelsey SAID 2 two!"
+        );
 
+        // the sender or the owner may delete
         assert_eq!(
-            Some(OutgoingMsg::SaidUser(
-                "@robert".to_string(),
-                "hi there! how are you?".to_string()
-            )),
-            receiver_kelsey.recv().await
+            state.delete_message("@kelsey", "#sports", 3),
+            Err(ServerError::NotMessageSender("#sports".to_string(), 3))
+        );
+        assert_eq!(state.delete_message("@robert", "#sports", 3), Ok(()));
+        assert_eq!(state.delete_message("@kelsey", "#sports", 2), Ok(()));
+        for receiver in receivers.values_mut() {
+            assert_eq!(
+                receiver.try_recv().unwrap(),
+                OutgoingMsg::Removed("#sports".to_string(), 3)
+            );
+            assert_eq!(
+                receiver.try_recv().unwrap(),
+                OutgoingMsg::Removed("#sports".to_string(), 2)
+            );
+        }
+        assert_eq!(
+            receiver_link.try_recv().unwrap(),
+            OutgoingMsg::Removed("#sports".to_string(), 3)
+        );
+        state.flush_history();
+        assert!(state.store.load_history("#sports").unwrap().is_empty());
+        assert_eq!(
+            state.delete_message("@kelsey", "#sports", 2),
+            Err(ServerError::MessageUnknown("#sports".to_string(), 2))
         );
+    }
 
+    #[test]
+    fn test_server_state_rooms_with_counts() {
+        let (mut state, _receivers) = state_with_users(&["@robert", "@kelsey", "@mike"]);
+        for (room, name) in [
+            ("#sports", "@robert"),
+            ("#sports", "@kelsey"),
+            ("#sports", "@mike"),
+            ("#chess", "@kelsey"),
+            ("#books", "@robert"),
+            ("#books", "@mike"),
+        ] {
+            state.join_room(room.to_string(), name.to_string()).unwrap();
+        }
         assert_eq!(
-            state.say_to_user("@robert", "@notreal", "uhoh!!!!??!!".to_string()),
-            Err(ServerError::UserUnknown("@notreal".to_string()))
+            state.rooms_with_counts("@robert"),
+            Ok(vec![("#books".to_string(), 2), ("#sports".to_string(), 3)])
+        );
+        assert_eq!(
+            state.rooms_with_counts("@kelsey"),
+            Ok(vec![("#chess".to_string(), 1), ("#sports".to_string(), 3)])
+        );
+
+        state.leave_room("#sports", "@robert").unwrap();
+        assert_eq!(
+            state.rooms_with_counts("@robert"),
+            Ok(vec![("#books".to_string(), 2)])
+        );
+        assert_eq!(
+            state.rooms_with_counts("@mike"),
+            Ok(vec![("#books".to_string(), 2), ("#sports".to_string(), 2)])
+        );
+        assert_eq!(
+            state.rooms_with_counts("@nobody"),
+            Err(ServerError::UserUnknown("@nobody".to_string()))
         );
     }
 
-    #[tokio::test]
-    async fn test_server_state_say_to_room() {
-        let mut state = ServerState::new();
-        let (sender_kelsey, mut receiver_kelsey) = mpsc::unbounded_channel();
-        assert!(state
-            .add_user("@kelsey".to_string(), User::new(sender_kelsey))
-            .is_ok());
-        let (sender_robert, mut receiver_robert) = mpsc::unbounded_channel();
-        assert!(state
-            .add_user("@robert".to_string(), User::new(sender_robert))
-            .is_ok());
-        let (sender_dave, mut receiver_dave) = mpsc::unbounded_channel();
-        assert!(state
-            .add_user("@dave".to_string(), User::new(sender_dave))
-            .is_ok());
+    #[test]
+    fn test_server_state_closed_rooms() {
+        let (mut state, mut receivers) = state_with_users(&["@admin", "@robert"]);
+        state.set_closed_rooms(true);
+        state.set_oper_password(Some("hunter2".to_string()));
+        state.oper("@admin", "hunter2").unwrap();
 
+        // JOIN doesn't create rooms
+        assert_eq!(
+            state.join_room("#sports".to_string(), "@robert".to_string()),
+            Err(ServerError::RoomUnknown("#sports".to_string()))
+        );
+        assert!(state.rooms().is_empty());
+
+        // only operators do
+        assert_eq!(
+            state.create_room("@robert", "#sports"),
+            Err(ServerError::NotOperator("@robert".to_string()))
+        );
+        assert_eq!(state.create_room("@admin", "#sports"), Ok(()));
+        assert_eq!(
+            state.create_room("@admin", "#sports"),
+            Err(ServerError::RoomAlreadyExists("#sports".to_string()))
+        );
+        assert_eq!(
+            state.join_room("#sports".to_string(), "@robert".to_string()),
+            Ok(())
+        );
+        assert!(state.rooms["#sports"].is_owner("@robert"));
+
+        // and the room is still there once everyone has left
+        state.leave_room("#sports", "@robert").unwrap();
+        assert_eq!(state.rooms(), vec!["#sports"]);
+
+        // until an operator destroys it, putting out anyone still in it
+        state
+            .join_room("#sports".to_string(), "@robert".to_string())
+            .unwrap();
+        drain(receivers.get_mut("@robert").unwrap());
+        assert_eq!(
+            state.destroy_room("@robert", "#sports"),
+            Err(ServerError::NotOperator("@robert".to_string()))
+        );
+        assert_eq!(state.destroy_room("@admin", "#sports"), Ok(()));
+        assert_eq!(
+            receivers.get_mut("@robert").unwrap().try_recv(),
+            Ok(OutgoingMsg::RoomDeleted("#sports".to_string()))
+        );
+        assert!(state.rooms().is_empty());
+        assert!(state.users["@robert"].rooms.is_empty());
+        assert_eq!(
+            state.destroy_room("@admin", "#sports"),
+            Err(ServerError::RoomUnknown("#sports".to_string()))
+        );
+        assert_eq!(
+            state.join_room("#sports".to_string(), "@robert".to_string()),
+            Err(ServerError::RoomUnknown("#sports".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_server_state_say_to_user_gone() {
+        let (mut state, mut receivers) = state_with_users(&["@robert", "@kelsey"]);
+        let mut robert_receiver = receivers.remove("@robert").unwrap();
+        let kelsey_receiver = receivers.remove("@kelsey").unwrap();
+        for name in ["@robert", "@kelsey"] {
+            state
+                .join_room("#sports".to_string(), name.to_string())
+                .unwrap();
+        }
+        drain(&mut robert_receiver);
+
+        // @kelsey's connection is gone but they are still in the map
+        drop(kelsey_receiver);
+        assert_eq!(
+            state.say_to_user("@robert", "@kelsey", "hi".to_string(), false),
+            Err(ServerError::UserGone("@kelsey".to_string()))
+        );
+        assert!(state.whois("@kelsey").is_err());
+        assert_eq!(state.users("#sports"), Ok(vec!["@robert".to_string()]));
+        assert_eq!(
+            robert_receiver.try_recv().unwrap(),
+            OutgoingMsg::Left("#sports".to_string(), "@kelsey".to_string())
+        );
+
+        // from then on they are unknown like anyone else
+        assert_eq!(
+            state.say_to_user("@robert", "@kelsey", "hi".to_string(), false),
+            Err(ServerError::UserUnknown("@kelsey".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_server_state_recent() {
+        let (mut state, _receivers) = state_with_users(&["@robert", "@kelsey"]);
+        state.set_history_size(3);
+        state
+            .join_room("#sports".to_string(), "@robert".to_string())
+            .unwrap();
+        for message in ["one", "two", "three", "four"] {
+            state
+                .say_to_room("@robert", "#sports", message.to_string())
+                .unwrap();
+        }
+        let history = |messages: &[(u64, &str)]| -> Vec<OutgoingMsg> {
+            messages
+                .iter()
+                .map(|(seq, message)| {
+                    OutgoingMsg::History(format!("#sports @robert SAID {} {}", seq, message))
+                })
+                .collect()
+        };
+
+        // the newest messages, oldest first
+        assert_eq!(
+            state.recent("@robert", "#sports", 2),
+            Ok(history(&[(3, "three"), (4, "four")]))
+        );
+        // clamped to what the server keeps
+        assert_eq!(
+            state.recent("@robert", "#sports", 20),
+            Ok(history(&[(2, "two"), (3, "three"), (4, "four")]))
+        );
+
+        assert_eq!(
+            state.recent("@kelsey", "#sports", 2),
+            Err(ServerError::UserNotInRoom(
+                "@kelsey".to_string(),
+                "#sports".to_string()
+            ))
+        );
+        assert_eq!(
+            state.recent("@robert", "#karate", 2),
+            Err(ServerError::RoomUnknown("#karate".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_server_state_join_rooms() {
+        let (mut state, mut receivers) = state_with_users(&["@robert", "@kelsey"]);
+        state.set_max_rooms_per_user(Some(3));
+        state
+            .join_room("#chess".to_string(), "@kelsey".to_string())
+            .unwrap();
+        state
+            .join_room("#sports".to_string(), "@robert".to_string())
+            .unwrap();
+
+        let rooms = ["#chess", "#sports", "#books", "#cards", "#movies"].map(String::from);
+        assert_eq!(
+            state.join_rooms(&rooms, "@robert"),
+            vec![
+                Ok(()),
+                Err(ServerError::UserAlreadyInRoom(
+                    "@robert".to_string(),
+                    "#sports".to_string()
+                )),
+                Ok(()),
+                Err(ServerError::TooManyRooms(3)),
+            ]
+        );
+        // the rooms before the limit were joined and the rest weren't tried
+        assert_eq!(
+            state.rooms_with_counts("@robert"),
+            Ok(vec![
+                ("#books".to_string(), 1),
+                ("#chess".to_string(), 2),
+                ("#sports".to_string(), 1),
+            ])
+        );
+        assert_eq!(state.rooms(), vec!["#books", "#chess", "#sports"]);
+        // each join went through the usual flow
+        assert_eq!(
+            receivers.get_mut("@kelsey").unwrap().try_recv().unwrap(),
+            OutgoingMsg::Joined("#chess".to_string(), "@robert".to_string())
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_server_state_reap_ghost() {
+        let quiet_for = Duration::from_secs(90);
+        let mut state = ServerState::new();
+        state.set_name_lock(Duration::from_secs(60));
+        let (ghost_sender, mut ghost_receiver) = mpsc::unbounded_channel();
+        let (live_sender, mut live_receiver) = mpsc::unbounded_channel();
         assert!(state
-            .join_room("#testroom".to_string(), "@kelsey".to_string())
-            .is_ok());
-        assert!(state
-            .join_room("#testroom".to_string(), "@robert".to_string())
+            .add_user("@robert".to_string(), User::new(ghost_sender))
             .is_ok());
         assert!(state
-            .join_room("#testroom".to_string(), "@dave".to_string())
+            .add_user("@kelsey".to_string(), User::new(live_sender))
             .is_ok());
-
         assert!(state
-            .say_to_room("@dave", "#testroom", "hello my room friends!".to_string())
+            .join_room("#sports".to_string(), "@robert".to_string())
             .is_ok());
+        drain(&mut ghost_receiver);
 
-        // dont send room message to self
-        assert_eq!(Err(TryRecvError::Empty), receiver_dave.try_recv());
-        // drop the first messages which are the JOINED's
-        receiver_kelsey.recv().await;
-        receiver_kelsey.recv().await;
+        // nobody is PINGed while their client has been heard from within the keep-alive interval
+        assert!(!state.ping_user("@robert", quiet_for));
         assert_eq!(
-            Some(OutgoingMsg::SaidRoom(
-                "#testroom".to_string(),
-                "@dave".to_string(),
-                "hello my room friends!".to_string()
-            )),
-            receiver_kelsey.recv().await
+            ghost_receiver.try_recv(),
+            Err(mpsc::error::TryRecvError::Empty)
         );
-        // drop the first message which is the JOINED
-        receiver_robert.recv().await;
+
+        tokio::time::advance(quiet_for).await;
+        let since = Instant::now();
+        assert!(state.ping_user("@robert", quiet_for));
+        assert!(state.ping_user("@kelsey", quiet_for));
+        assert!(!state.ping_user("@dave", quiet_for));
+        assert_eq!(ghost_receiver.try_recv(), Ok(OutgoingMsg::Ping));
+        assert_eq!(live_receiver.try_recv(), Ok(OutgoingMsg::Ping));
+
+        // a name is probed once per interval however many connections ask for it
+        assert!(!state.ping_user("@robert", quiet_for));
         assert_eq!(
-            Some(OutgoingMsg::SaidRoom(
-                "#testroom".to_string(),
-                "@dave".to_string(),
-                "hello my room friends!".to_string()
-            )),
-            receiver_robert.recv().await
+            ghost_receiver.try_recv(),
+            Err(mpsc::error::TryRecvError::Empty)
         );
 
+        // a user who answers keeps their name
+        state.record_pong("@kelsey");
+        assert!(!state.reap_ghost("@kelsey", since));
+        assert!(state.users.contains_key("@kelsey"));
+
+        // a user who doesn't is disconnected and leaves their rooms
+        assert!(state.reap_ghost("@robert", since));
         assert_eq!(
-            state.say_to_room("@dave", "#notreal", "hello my room friends!".to_string()),
-            Err(ServerError::RoomUnknown("#notreal".to_string()))
+            ghost_receiver.try_recv(),
+            Ok(OutgoingMsg::Disconnect("no answer to PING".to_string()))
         );
+        assert!(!state.users.contains_key("@robert"));
+        assert!(!state.rooms.contains_key("#sports"));
+
+        // the reaped name isn't locked, so the connection that asked for it can take it
+        let (sender, _receiver) = mpsc::unbounded_channel();
+        assert!(state
+            .add_user("@robert".to_string(), User::new(sender))
+            .is_ok());
+
+        // a name nobody holds is free
+        assert!(state.reap_ghost("@dave", since));
     }
 }
@@ -0,0 +1,298 @@
+//! Storage for the parts of the server state that outlive a restart: persistent rooms and which of them are
+//! archived, and room history.
+//! [ServerState](crate::server_state::ServerState) only talks to the [Store] trait so that new backends can be
+//! added without touching the core logic.
+//!
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    fs,
+    path::Path,
+    sync::mpsc::{self, Receiver, Sender},
+    thread::{self, JoinHandle},
+};
+
+/// Load and save everything the server keeps across restarts. Messages are stored as protocol lines. Saving
+/// is called with the server state locked, so it mustn't wait on the disk.
+pub trait Store: Debug + Send {
+    fn load_rooms(&self) -> anyhow::Result<Vec<String>>;
+    fn save_rooms(&mut self, rooms: &[String]) -> anyhow::Result<()>;
+    fn load_archived(&self) -> anyhow::Result<Vec<String>>;
+    fn save_archived(&mut self, rooms: &[String]) -> anyhow::Result<()>;
+    fn load_history(&self, room_name: &str) -> anyhow::Result<Vec<String>>;
+    fn save_history(&mut self, room_name: &str, messages: &[String]) -> anyhow::Result<()>;
+    /// Wait until everything saved so far has been written.
+    fn sync(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Everything a [Store] holds.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+struct Stored {
+    rooms: Vec<String>,
+    /// Missing from stores saved before rooms could be archived.
+    #[serde(default)]
+    archived: Vec<String>,
+    history: HashMap<String, Vec<String>>,
+}
+
+impl Stored {
+    fn history(&self, room_name: &str) -> Vec<String> {
+        self.history.get(room_name).cloned().unwrap_or_default()
+    }
+
+    fn set_history(&mut self, room_name: &str, messages: &[String]) {
+        if messages.is_empty() {
+            self.history.remove(room_name);
+        } else {
+            self.history
+                .insert(room_name.to_string(), messages.to_vec());
+        }
+    }
+}
+
+/// A [Store] that forgets everything when the server stops. This is the default.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    stored: Stored,
+}
+
+impl Store for MemoryStore {
+    fn load_rooms(&self) -> anyhow::Result<Vec<String>> {
+        Ok(self.stored.rooms.clone())
+    }
+
+    fn save_rooms(&mut self, rooms: &[String]) -> anyhow::Result<()> {
+        self.stored.rooms = rooms.to_vec();
+        Ok(())
+    }
+
+    fn load_archived(&self) -> anyhow::Result<Vec<String>> {
+        Ok(self.stored.archived.clone())
+    }
+
+    fn save_archived(&mut self, rooms: &[String]) -> anyhow::Result<()> {
+        self.stored.archived = rooms.to_vec();
+        Ok(())
+    }
+
+    fn load_history(&self, room_name: &str) -> anyhow::Result<Vec<String>> {
+        Ok(self.stored.history(room_name))
+    }
+
+    fn save_history(&mut self, room_name: &str, messages: &[String]) -> anyhow::Result<()> {
+        self.stored.set_history(room_name, messages);
+        Ok(())
+    }
+}
+
+/// A [Store] that keeps everything in a single JSON file. The file is read once when the store is opened, and
+/// everything is served from memory after that. Every save rewrites the whole file, but on a thread of its
+/// own, so the caller never waits on the disk. Saves that queue up while a write is underway are folded into
+/// one write of the latest contents.
+#[derive(Debug)]
+pub struct FileStore {
+    stored: Stored,
+    writer: Option<(Sender<Write>, JoinHandle<()>)>,
+}
+
+/// Work for the thread that writes a [FileStore]'s file.
+#[derive(Debug)]
+enum Write {
+    /// Replace the file with these contents.
+    Contents(String),
+    /// Answer once everything queued before has been written.
+    Sync(Sender<()>),
+}
+
+impl FileStore {
+    /// Use the JSON file at `path`, reading what it holds. The file is created on the first save if it
+    /// doesn't exist.
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let stored = if path.exists() {
+            serde_json::from_str(&fs::read_to_string(&path)?)?
+        } else {
+            Stored::default()
+        };
+        let (sender, receiver) = mpsc::channel();
+        let writer = thread::spawn(move || write_file(&path, receiver));
+        Ok(Self {
+            stored,
+            writer: Some((sender, writer)),
+        })
+    }
+
+    fn update(&mut self, change: impl FnOnce(&mut Stored)) -> anyhow::Result<()> {
+        change(&mut self.stored);
+        let contents = serde_json::to_string_pretty(&self.stored)?;
+        self.send(Write::Contents(contents))
+    }
+
+    fn send(&self, write: Write) -> anyhow::Result<()> {
+        match &self.writer {
+            Some((sender, _)) => sender
+                .send(write)
+                .map_err(|_| anyhow::anyhow!("store writer stopped")),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Write each update to `path` in turn until the [FileStore] is dropped, skipping to the latest contents
+/// when several are waiting.
+fn write_file(path: &Path, receiver: Receiver<Write>) {
+    let mut pending = None;
+    loop {
+        // wait only when there's nothing left to write
+        let write = match pending {
+            Some(_) => receiver.try_recv().ok(),
+            None => receiver.recv().ok(),
+        };
+        match write {
+            Some(Write::Contents(contents)) => pending = Some(contents),
+            Some(Write::Sync(done)) => {
+                if let Some(contents) = pending.take() {
+                    write_contents(path, &contents);
+                }
+                let _ = done.send(());
+            }
+            None => match pending.take() {
+                Some(contents) => write_contents(path, &contents),
+                // the store was dropped and everything is written
+                None => return,
+            },
+        }
+    }
+}
+
+fn write_contents(path: &Path, contents: &str) {
+    // write a temporary file first so a crash mid-write can't corrupt the store
+    let temporary = path.with_extension("tmp");
+    let result = fs::write(&temporary, contents).and_then(|()| fs::rename(&temporary, path));
+    if let Err(e) = result {
+        log::error!("failed to write store {}: {:?}", path.display(), e);
+    }
+}
+
+impl Drop for FileStore {
+    /// Finish writing everything saved before the store goes away.
+    fn drop(&mut self) {
+        if let Some((sender, writer)) = self.writer.take() {
+            drop(sender);
+            let _ = writer.join();
+        }
+    }
+}
+
+impl Store for FileStore {
+    fn load_rooms(&self) -> anyhow::Result<Vec<String>> {
+        Ok(self.stored.rooms.clone())
+    }
+
+    fn save_rooms(&mut self, rooms: &[String]) -> anyhow::Result<()> {
+        self.update(|stored| stored.rooms = rooms.to_vec())
+    }
+
+    fn load_archived(&self) -> anyhow::Result<Vec<String>> {
+        Ok(self.stored.archived.clone())
+    }
+
+    fn save_archived(&mut self, rooms: &[String]) -> anyhow::Result<()> {
+        self.update(|stored| stored.archived = rooms.to_vec())
+    }
+
+    fn load_history(&self, room_name: &str) -> anyhow::Result<Vec<String>> {
+        Ok(self.stored.history(room_name))
+    }
+
+    fn save_history(&mut self, room_name: &str, messages: &[String]) -> anyhow::Result<()> {
+        self.update(|stored| stored.set_history(room_name, messages))
+    }
+
+    fn sync(&mut self) -> anyhow::Result<()> {
+        let (done, wait) = mpsc::channel();
+        self.send(Write::Sync(done))?;
+        wait.recv()
+            .map_err(|_| anyhow::anyhow!("store writer stopped"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(store: &mut dyn Store) {
+        assert!(store.load_rooms().unwrap().is_empty());
+        assert!(store.load_archived().unwrap().is_empty());
+        assert!(store.load_history("#sports").unwrap().is_empty());
+
+        let rooms = vec!["#news".to_string(), "#sports".to_string()];
+        store.save_rooms(&rooms).unwrap();
+        store.save_archived(&rooms[..1]).unwrap();
+        let history = vec![
+            "#sports @kelsey SAID good game".to_string(),
+            "#sports @robert SAID it was".to_string(),
+        ];
+        store.save_history("#sports", &history).unwrap();
+
+        assert_eq!(store.load_rooms().unwrap(), rooms);
+        assert_eq!(store.load_archived().unwrap(), vec!["#news"]);
+        assert_eq!(store.load_history("#sports").unwrap(), history);
+        assert!(store.load_history("#news").unwrap().is_empty());
+
+        // saving nothing clears the entry
+        store.save_history("#sports", &[]).unwrap();
+        assert!(store.load_history("#sports").unwrap().is_empty());
+        store.save_history("#sports", &history).unwrap();
+    }
+
+    #[test]
+    fn test_memory_store_round_trip() {
+        round_trip(&mut MemoryStore::default());
+    }
+
+    #[test]
+    fn test_file_store_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.json");
+        let mut store = FileStore::open(&path).unwrap();
+        round_trip(&mut store);
+
+        // a second store on the same file sees what the first one saved once it has been written
+        store.sync().unwrap();
+        let reopened = FileStore::open(&path).unwrap();
+        assert_eq!(reopened.load_rooms().unwrap(), vec!["#news", "#sports"]);
+        assert_eq!(reopened.load_history("#sports").unwrap().len(), 2);
+
+        // dropping a store finishes its writes
+        store.save_rooms(&["#chess".to_string()]).unwrap();
+        drop(store);
+        let reopened = FileStore::open(&path).unwrap();
+        assert_eq!(reopened.load_rooms().unwrap(), vec!["#chess"]);
+    }
+
+    #[test]
+    fn test_file_store_bad_json() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.json");
+        fs::write(&path, "not json").unwrap();
+        assert!(FileStore::open(&path).is_err());
+    }
+
+    #[test]
+    fn test_file_store_before_archiving() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.json");
+        fs::write(
+            &path,
+            r##"{"rooms": ["#news"], "offline_messages": {}, "history": {}}"##,
+        )
+        .unwrap();
+        let store = FileStore::open(&path).unwrap();
+        assert_eq!(store.load_rooms().unwrap(), vec!["#news"]);
+        assert!(store.load_archived().unwrap().is_empty());
+    }
+}
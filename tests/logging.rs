@@ -0,0 +1,11 @@
+//! Installs the process-wide logger, which is why this lives in its own test binary: in the library's
+//! tests it would print every later test's log output.
+//!
+use chat_project::logging;
+
+#[test]
+fn test_init_twice() {
+    // the first call installs the logger and the second leaves it alone
+    assert!(logging::init());
+    assert!(!logging::init());
+}
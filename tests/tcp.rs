@@ -0,0 +1,66 @@
+//! Runs the server on a real TCP listener and talks to it the way a client on the network would.
+//!
+use chat_project::{
+    server::{run_server, ConnectionConfig},
+    server_state::ServerState,
+};
+use futures::{SinkExt, StreamExt};
+use std::sync::Arc;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::Mutex,
+};
+use tokio_util::codec::{Framed, LinesCodec};
+
+/// Run the server on an ephemeral port, the same way `chat-server` does, and return the address.
+async fn start_server() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let server_state = Arc::new(Mutex::new(ServerState::new()));
+    tokio::spawn(run_server(
+        server_state,
+        listener,
+        ConnectionConfig::default(),
+    ));
+    address
+}
+
+async fn connect(address: &str) -> Framed<TcpStream, LinesCodec> {
+    let stream = TcpStream::connect(address).await.unwrap();
+    Framed::new(stream, LinesCodec::new())
+}
+
+async fn recv(framed: &mut Framed<TcpStream, LinesCodec>) -> String {
+    framed.next().await.unwrap().unwrap()
+}
+
+#[tokio::test]
+async fn test_tcp_session() {
+    let address = start_server().await;
+
+    let mut robert = connect(&address).await;
+    assert_eq!(recv(&mut robert).await, "CONNECTED");
+    robert.send("NAME @robert").await.unwrap();
+    assert_eq!(recv(&mut robert).await, "REGISTERED");
+    robert.send("JOIN #sports").await.unwrap();
+
+    let mut kelsey = connect(&address).await;
+    assert_eq!(recv(&mut kelsey).await, "CONNECTED");
+    kelsey.send("NAME @kelsey").await.unwrap();
+    assert_eq!(recv(&mut kelsey).await, "REGISTERED");
+    kelsey.send("JOIN #sports").await.unwrap();
+    assert_eq!(recv(&mut robert).await, "#sports @kelsey JOINED");
+
+    robert.send("SAY #sports good game").await.unwrap();
+    assert_eq!(recv(&mut kelsey).await, "#sports @robert SAID 1 good game");
+    kelsey.send("SAY @robert thanks").await.unwrap();
+    assert_eq!(recv(&mut robert).await, "@kelsey SAID thanks");
+    assert_eq!(recv(&mut kelsey).await, "DELIVERED @robert");
+
+    // quitting closes the socket and takes the user out of the room
+    kelsey.send("QUIT").await.unwrap();
+    assert!(kelsey.next().await.is_none());
+    assert_eq!(recv(&mut robert).await, "#sports @kelsey LEFT");
+    robert.send("QUIT").await.unwrap();
+    assert!(robert.next().await.is_none());
+}